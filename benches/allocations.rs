@@ -0,0 +1,68 @@
+//! Heap allocations made by laying out a 29-page document, counted by a
+//! wrapper around the system allocator.
+//!
+//! Run with `cargo bench --bench allocations`; it prints the count for each
+//! stage rather than timing anything.
+
+use latex_rs::{CompileOptions, compile_to_pages, parse_preserving_comments};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The system allocator, counting every allocation and reallocation.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// The allocations `f` makes.
+fn count<T>(f: impl FnOnce() -> T) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    std::hint::black_box(f());
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn main() {
+    let paragraph = "The quick brown fox jumps over the lazy dog, while \\textbf{bold} \
+                     and \\emph{emphasised} words, a formula $x^2 + y_i$ and a~tie \
+                     keep the layout honest. Affine fluffy waffles fill the line. ";
+    let mut doc = String::new();
+    for n in 0..25 {
+        doc.push_str(&format!("\\section{{Section {}}}\n\n", n + 1));
+        for _ in 0..9 {
+            doc.push_str(&paragraph.repeat(3));
+            doc.push_str("\n\n");
+        }
+    }
+    let opts = CompileOptions::default();
+    let words = doc.split_whitespace().count();
+    // Warm the caches that live for the whole process, such as the fonts.
+    let pages = compile_to_pages(&doc, &opts).unwrap();
+
+    let parse = count(|| parse_preserving_comments(&doc).unwrap());
+    let compile = count(|| compile_to_pages(&doc, &opts).unwrap());
+    println!("{} pages, {} words", pages.len(), words);
+    println!("parse:  {:>8} allocations", parse);
+    println!(
+        "layout: {:>8} allocations, parse and expansion included",
+        compile
+    );
+    println!("        {:>8.2} per word", compile as f64 / words as f64);
+}
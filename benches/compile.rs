@@ -0,0 +1,59 @@
+//! Timings for each stage of compiling a 50-page document.
+//!
+//! Run with `cargo bench`; compare against a saved run with
+//! `cargo bench -- --save-baseline before` and `--baseline before`.
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use latex_rs::{
+    CompileOptions, LayoutCache, compile_to_pages, compile_to_pages_with_cache, compile_with,
+    parse_preserving_comments,
+};
+use std::hint::black_box;
+
+/// A document of `sections` sections, each about a page of paragraphs with
+/// styled words, math, a list and a footnote.
+fn document(sections: usize) -> String {
+    let paragraph = "The quick brown fox jumps over the lazy dog, while \\textbf{bold} \
+                     and \\emph{emphasised} words, a formula $x^2 + y_i$ and a~tie \
+                     keep the layout honest. Affine fluffy waffles fill the line. ";
+    let mut doc = String::new();
+    for n in 0..sections {
+        doc.push_str(&format!("\\section{{Section {}}}\n\n", n + 1));
+        for _ in 0..9 {
+            doc.push_str(&paragraph.repeat(3));
+            doc.push_str("\n\n");
+        }
+        doc.push_str(
+            "\\begin{itemize} \\item one \\item two\\footnote{A note.} \\end{itemize}\n\n",
+        );
+    }
+    doc
+}
+
+fn stages(c: &mut Criterion) {
+    let doc = document(50);
+    let opts = CompileOptions::default();
+    assert!(compile_to_pages(&doc, &opts).unwrap().len() >= 50);
+
+    let mut group = c.benchmark_group("50 pages");
+    group.sample_size(10);
+    group.bench_function("parse", |b| {
+        b.iter(|| parse_preserving_comments(black_box(&doc)).unwrap())
+    });
+    group.bench_function("layout", |b| {
+        b.iter(|| compile_to_pages(black_box(&doc), &opts).unwrap())
+    });
+    // Laying out again with every paragraph cached, as after an edit,
+    // leaves finding the cached paragraphs and breaking the lines.
+    let mut cache = LayoutCache::default();
+    group.bench_function("relayout", |b| {
+        b.iter(|| compile_to_pages_with_cache(black_box(&doc), &opts, &mut cache).unwrap())
+    });
+    group.bench_function("render", |b| {
+        b.iter(|| compile_with(black_box(&doc), &opts).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, stages);
+criterion_main!(benches);
@@ -1,17 +1,379 @@
-#[derive(Clone, Debug, PartialEq)]
-pub enum TextStyle {
+use serde::{Deserialize, Deserializer, Serialize, de};
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::path::PathBuf;
+
+use crate::units::Length;
+
+/// The typeface a run of text is set in.
+#[derive(Clone, Debug, PartialEq, Hash, Default, Serialize)]
+pub enum FontFace {
+    #[default]
     Normal,
     Bold,
     Italic,
+    Monospace,
+}
+
+/// A color as 8-bit red, green and blue channels.
+///
+/// Deserializes from the same names and hex codes [`Rgb::parse`] reads.
+#[derive(Clone, Copy, Debug, PartialEq, Hash, Default, Serialize)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const BLACK: Rgb = Rgb::new(0, 0, 0);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Rgb { r, g, b }
+    }
+
+    /// Resolve a color name (`red`, `green`, `blue`, `black`) or `#RRGGBB`
+    /// hex code.
+    pub fn parse(spec: &str) -> Option<Self> {
+        match spec.trim() {
+            "black" => Some(Rgb::BLACK),
+            "red" => Some(Rgb::new(255, 0, 0)),
+            "green" => Some(Rgb::new(0, 128, 0)),
+            "blue" => Some(Rgb::new(0, 0, 255)),
+            spec => spec
+                .strip_prefix('#')
+                .filter(|hex| hex.len() == 6 && hex.is_ascii())
+                .and_then(|hex| {
+                    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+                    Some(Rgb::new(channel(0)?, channel(2)?, channel(4)?))
+                }),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Rgb {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let spec = String::deserialize(deserializer)?;
+        Rgb::parse(&spec).ok_or_else(|| de::Error::custom(format!("unknown color `{}`", spec)))
+    }
+}
+
+/// Where a run sits relative to the baseline; scripts only occur in math.
+#[derive(Clone, Copy, Debug, PartialEq, Hash, Default, Serialize)]
+pub enum Script {
+    #[default]
+    Baseline,
+    Superscript,
+    Subscript,
+    /// Above a fraction bar.
+    Numerator,
+    /// Below a fraction bar.
+    Denominator,
+}
+
+/// How a run of text is drawn: its typeface plus any decorations.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TextStyle {
+    pub face: FontFace,
+    pub underline: bool,
+    /// Struck through with a line at half the x-height, by `\sout`.
+    pub strikeout: bool,
+    pub color: Rgb,
+    pub script: Script,
+    /// Font size relative to the body text.
+    pub scale: f64,
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        TextStyle {
+            face: FontFace::default(),
+            underline: false,
+            strikeout: false,
+            color: Rgb::BLACK,
+            script: Script::default(),
+            scale: 1.0,
+        }
+    }
+}
+
+impl Hash for TextStyle {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (&self.face, self.underline, self.strikeout).hash(state);
+        (self.color, self.script, Length::pt(self.scale)).hash(state);
+    }
+}
+
+impl From<FontFace> for TextStyle {
+    fn from(face: FontFace) -> Self {
+        TextStyle {
+            face,
+            ..TextStyle::default()
+        }
+    }
+}
+
+/// The size declarations, each with the size it sets relative to the body
+/// text, as in LaTeX's 10pt classes.
+pub(crate) const SIZES: [(&str, f64); 10] = [
+    ("tiny", 0.5),
+    ("scriptsize", 0.7),
+    ("footnotesize", 0.8),
+    ("small", 0.9),
+    ("normalsize", 1.0),
+    ("large", 1.2),
+    ("Large", 1.44),
+    ("LARGE", 1.728),
+    ("huge", 2.074),
+    ("Huge", 2.488),
+];
+
+/// One row of a table: a list of cells, each a node sequence.
+pub type TableRow = Vec<Vec<Node>>;
+
+/// How a `tabular` column sets its cells: its `l`, `c` or `r` specifier.
+#[derive(Clone, Copy, Debug, PartialEq, Hash, Default, Serialize)]
+pub enum ColumnAlign {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+/// The arguments of a `tabular`: its column specifier, such as `l|cr`,
+/// and for a `tabular*`, its width.
+#[derive(Clone, Debug, PartialEq, Hash, Default, Serialize)]
+pub struct TableSpec {
+    /// How each column sets its cells, in order.
+    pub align: Vec<ColumnAlign>,
+    /// Whether a vertical rule `|` stands before each column; the last
+    /// entry is for one after the last column.
+    pub rules: Vec<bool>,
+    pub width: Option<TableWidth>,
+}
+
+/// The width a `tabular*` is spread out to.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum TableWidth {
+    Fixed(Length),
+    /// A fraction of the width of the text, as in `0.8\textwidth`.
+    Text(f64),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+impl Hash for TableWidth {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match *self {
+            TableWidth::Fixed(length) => length.hash(state),
+            TableWidth::Text(fraction) => Length::pt(fraction).hash(state),
+        }
+    }
+}
+
+/// The environment a list is set in, which decides how its items are
+/// marked.
+#[derive(Clone, Copy, Debug, PartialEq, Hash, Default, Serialize)]
+pub enum ListKind {
+    /// `itemize`: each item is bulleted.
+    #[default]
+    Itemize,
+    /// `enumerate`: each item is numbered.
+    Enumerate,
+    /// `description`: each item begins with its term, in bold.
+    Description,
+}
+
+/// One `\item` of a list.
+#[derive(Clone, Debug, PartialEq, Hash, Default, Serialize)]
+pub struct ListItem {
+    /// The `[...]` after `\item`: the term of a description, or what marks
+    /// the item in place of its bullet or number.
+    pub label: Option<Vec<Node>>,
+    pub body: Vec<Node>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum Node {
     Text(String),
     StyledText(String, TextStyle),
     // Whitespace(usize),
-    Macro { name: String, args: Vec<Node> },
+    Macro {
+        name: String,
+        /// Optional `[...]` arguments, in source order.
+        opt_args: Vec<Node>,
+        args: Vec<Node>,
+        /// Invoked with a star, e.g. `\section*`.
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        starred: bool,
+    },
     Seq(Vec<Node>),
+    /// A `\begin{name}...\end{name}` block.
+    Environment {
+        name: String,
+        body: Vec<Node>,
+    },
+    /// A `tabular` environment: rows of cells, each cell a node sequence.
+    Table {
+        /// Boxed, as few nodes are tables, to keep every node small.
+        spec: Box<TableSpec>,
+        rows: Vec<TableRow>,
+        /// The rows an `\hline` is drawn above; `rows.len()` stands for
+        /// one below the last row.
+        hlines: Vec<usize>,
+    },
+    /// A numbered `\section` (level 1) or `\subsection` (level 2).
+    Heading {
+        level: u8,
+        /// The dotted section number, e.g. `2.1`.
+        number: String,
+        title: Vec<Node>,
+    },
+    /// The block `\maketitle` sets from `\title`, `\author` and `\date`;
+    /// each is empty when it was not given, or given empty.
+    TitleBlock {
+        title: Vec<Node>,
+        author: Vec<Node>,
+        date: Vec<Node>,
+    },
+    /// The end of a paragraph: a blank line or `\par`.
+    ParBreak,
+    /// `\noindent`: the paragraph it starts has no first-line indentation.
+    NoIndent,
+    /// A forced line break `\\`, or `\\[length]` with extra space below.
+    LineBreak {
+        space: Option<Length>,
+        /// `\\*`: the page must not break after the line it ends.
+        no_page_break: bool,
+    },
+    /// Inline math `$...$`.
+    Math(Vec<Node>),
+    /// The body of a `verbatim` environment, exactly as written.
+    Verbatim(String),
+    /// A `\footnote`: a numbered mark where it stands, and its body set at
+    /// the foot of the page.
+    Footnote(Vec<Node>),
+    /// `\label{key}`: a name for the section it is in, numbered `number`
+    /// (empty before the first section), and for the page it falls on.
+    Label {
+        key: String,
+        number: String,
+    },
+    /// `\ref{key}`, or `\pageref{key}` with `page` set: the section number
+    /// or page of label `key`, which layout fills in.
+    Ref {
+        key: String,
+        page: bool,
+    },
+    /// `base^x` in math: the script argument, raised.
+    Superscript(Box<Node>),
+    /// `base_x` in math: the script argument, lowered.
+    Subscript(Box<Node>),
+    /// `\frac{num}{den}` in math: `num` stacked over `den`, with a bar
+    /// between them.
+    Frac {
+        num: Vec<Node>,
+        den: Vec<Node>,
+    },
+    /// A `%` comment, kept only when parsing for source tools.
+    Comment(String),
+    /// `\phantom{...}`: room for `body`, which is not drawn. `\hphantom`
+    /// keeps only its `width`, and `\vphantom` only its `height` and depth.
+    Phantom {
+        body: Vec<Node>,
+        width: bool,
+        height: bool,
+    },
+    /// A tie `~`: an interword space that never becomes a line break.
+    NonBreakingSpace,
+    /// `\-` inside a word: the word may break here, with a hyphen.
+    DiscretionaryHyphen,
+    /// Horizontal space from `\hspace`, joining the words on either side;
+    /// negative values move the following material left.
+    HSpace(Length),
+    /// Vertical space from `\vspace`, in points; negative values pull
+    /// the following material up.
+    VSpace(f64),
+    /// `\vfill`: vertical space that stretches to fill the rest of the
+    /// page, shared equally with any other fills on it.
+    VFill,
+    /// An image from `\includegraphics`, set as a block of its own.
+    Image {
+        path: PathBuf,
+        /// The requested width; the natural width when `None`.
+        width: Option<Length>,
+        /// Size in pixels, read from the file during expansion.
+        pixels: (u32, u32),
+    },
+    /// A filled rectangle from `\rule{width}{height}`, set as a block of its
+    /// own; `\hrule` gives one with no width, spanning the line.
+    Rule {
+        width: Option<Length>,
+        height: Length,
+    },
+    /// An `itemize` (bulleted), `enumerate` (numbered) or `description`
+    /// list.
+    List {
+        kind: ListKind,
+        items: Vec<ListItem>,
+    },
+}
+
+/// Hashes every field, so that layout can recognise a paragraph it has
+/// set before; the points of [`Node::VSpace`] hash as a [`Length`].
+impl Hash for Node {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            Node::Text(text) | Node::Verbatim(text) | Node::Comment(text) => text.hash(state),
+            Node::StyledText(text, style) => (text, style).hash(state),
+            Node::Macro {
+                name,
+                opt_args,
+                args,
+                starred,
+            } => (name, opt_args, args, starred).hash(state),
+            Node::Seq(nodes) | Node::Math(nodes) | Node::Footnote(nodes) => nodes.hash(state),
+            Node::Environment { name, body } => (name, body).hash(state),
+            Node::Table { spec, rows, hlines } => (spec, rows, hlines).hash(state),
+            Node::Heading {
+                level,
+                number,
+                title,
+            } => (level, number, title).hash(state),
+            Node::TitleBlock {
+                title,
+                author,
+                date,
+            } => (title, author, date).hash(state),
+            Node::ParBreak
+            | Node::NoIndent
+            | Node::NonBreakingSpace
+            | Node::DiscretionaryHyphen
+            | Node::VFill => {}
+            Node::LineBreak {
+                space,
+                no_page_break,
+            } => (space, no_page_break).hash(state),
+            Node::Label { key, number } => (key, number).hash(state),
+            Node::Ref { key, page } => (key, page).hash(state),
+            Node::Superscript(script) | Node::Subscript(script) => script.hash(state),
+            Node::Frac { num, den } => (num, den).hash(state),
+            Node::Phantom {
+                body,
+                width,
+                height,
+            } => (body, width, height).hash(state),
+            Node::HSpace(length) => length.hash(state),
+            Node::VSpace(pt) => Length::pt(*pt).hash(state),
+            Node::Image {
+                path,
+                width,
+                pixels,
+            } => (path, width, pixels).hash(state),
+            Node::Rule { width, height } => (width, height).hash(state),
+            Node::List { kind, items } => (kind, items).hash(state),
+        }
+    }
 }
 
 impl Node {
@@ -24,7 +386,9 @@ impl Node {
     pub fn r#macro<T: Into<String>>(name: T, args: Vec<Node>) -> Self {
         Node::Macro {
             name: name.into(),
+            opt_args: Vec::new(),
             args,
+            starred: false,
         }
     }
 
@@ -32,11 +396,555 @@ impl Node {
     pub fn seq(children: Vec<Node>) -> Self {
         Node::Seq(children)
     }
+
+    /// Whether no inter-word space separates this node from its siblings.
+    pub(crate) fn joins_neighbours(&self) -> bool {
+        matches!(
+            self,
+            Node::NonBreakingSpace
+                | Node::DiscretionaryHyphen
+                | Node::HSpace(_)
+                | Node::Phantom { .. }
+        )
+    }
+
+    /// This node as TeX source, which parses back to it.
+    ///
+    /// A sequence is written as the body of a document, without braces
+    /// around it; sequences inside it are written as `{...}` groups. Nodes
+    /// that only expansion makes are written as the commands that make them,
+    /// such as `\section` for a heading, which expand back to them.
+    pub fn to_tex(&self) -> String {
+        let mut tex = String::new();
+        match self {
+            Node::Seq(children) => write_seq(&mut tex, children),
+            node => write_node(&mut tex, node),
+        }
+        tex
+    }
+}
+
+/// Write `nodes` one after the other, with the spaces between them that
+/// keep each a node of its own.
+fn write_seq(tex: &mut String, nodes: &[Node]) {
+    let mut prev: Option<&Node> = None;
+    for node in nodes {
+        // A comment ends its line, and a paragraph break is blank lines;
+        // more blank lines would make one break of two, so a break that
+        // follows another is a `\par`.
+        if *node == Node::ParBreak && prev == Some(&Node::ParBreak) {
+            tex.push_str("\\par");
+        } else {
+            if prev.is_some() && !tex.ends_with('\n') && *node != Node::ParBreak {
+                tex.push(' ');
+            }
+            write_node(tex, node);
+        }
+        prev = Some(node);
+    }
+}
+
+/// Write `nodes` after a space, when there are any, as the body of an
+/// environment or a table cell.
+fn write_body(tex: &mut String, nodes: &[Node]) {
+    if !nodes.is_empty() {
+        tex.push(' ');
+        write_seq(tex, nodes);
+    }
+}
+
+/// Write `nodes` as the brace-delimited group `{...}`.
+fn write_group(tex: &mut String, nodes: &[Node]) {
+    tex.push('{');
+    write_seq(tex, nodes);
+    tex.push('}');
+}
+
+/// Write `node` as a command argument, a `{...}` group.
+fn write_arg(tex: &mut String, node: &Node) {
+    match node {
+        Node::Seq(children) => write_group(tex, children),
+        other => write_group(tex, std::slice::from_ref(other)),
+    }
+}
+
+/// Write `text` with the characters TeX gives a meaning escaped.
+fn write_text(tex: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '\\' => tex.push_str("\\textbackslash{}"),
+            '{' | '}' | '%' | '$' | '&' | '_' => {
+                tex.push('\\');
+                tex.push(c);
+            }
+            c => tex.push(c),
+        }
+    }
+}
+
+/// Write `\name{arg}...` for each of `args`.
+fn write_command(tex: &mut String, name: &str, args: &[&[Node]]) {
+    tex.push('\\');
+    tex.push_str(name);
+    for arg in args {
+        write_group(tex, arg);
+    }
+}
+
+/// Write the column specifier of a table of `rows`, which has a column for
+/// every cell of its longest row; those `columns` leave out are `l`.
+fn write_columns(tex: &mut String, columns: &TableSpec, rows: &[TableRow]) {
+    let count = rows
+        .iter()
+        .map(Vec::len)
+        .max()
+        .unwrap_or(0)
+        .max(columns.align.len());
+    tex.push('{');
+    for j in 0..=count {
+        if columns.rules.get(j) == Some(&true) {
+            tex.push('|');
+        }
+        if j < count {
+            tex.push(match columns.align.get(j) {
+                Some(ColumnAlign::Center) => 'c',
+                Some(ColumnAlign::Right) => 'r',
+                Some(ColumnAlign::Left) | None => 'l',
+            });
+        }
+    }
+    tex.push('}');
+}
+
+/// Write `length` in points, which reads back as the same length.
+fn write_length(tex: &mut String, length: Length) {
+    tex.push_str(&format!("{}pt", length.to_pt()));
+}
+
+/// Write `text` in `style` as the commands that set text so.
+fn write_styled_text(tex: &mut String, text: &str, style: &TextStyle) {
+    let mut open = Vec::new();
+    if style.scale != 1.0
+        && let Some((size, _)) = SIZES.iter().find(|(_, scale)| *scale == style.scale)
+    {
+        open.push(format!("{{\\{} ", size));
+    }
+    if style.color != Rgb::BLACK {
+        let Rgb { r, g, b } = style.color;
+        open.push(format!("\\textcolor{{#{:02X}{:02X}{:02X}}}{{", r, g, b));
+    }
+    if style.strikeout {
+        open.push("\\sout{".into());
+    }
+    if style.underline {
+        open.push("\\underline{".into());
+    }
+    match style.face {
+        FontFace::Normal => {}
+        FontFace::Bold => open.push("\\textbf{".into()),
+        FontFace::Italic => open.push("\\textit{".into()),
+        FontFace::Monospace => open.push("\\texttt{".into()),
+    }
+    for command in &open {
+        tex.push_str(command);
+    }
+    write_text(tex, text);
+    tex.extend(std::iter::repeat_n('}', open.len()));
+}
+
+/// Write `node` as TeX source; a sequence as a group.
+fn write_node(tex: &mut String, node: &Node) {
+    match node {
+        Node::Text(text) => write_text(tex, text),
+        Node::StyledText(text, style) => write_styled_text(tex, text, style),
+        Node::Macro {
+            name,
+            opt_args,
+            args,
+            starred,
+        } => {
+            tex.push('\\');
+            tex.push_str(name);
+            if *starred {
+                tex.push('*');
+            }
+            for opt in opt_args {
+                tex.push('[');
+                match opt {
+                    Node::Seq(children) => write_seq(tex, children),
+                    other => write_node(tex, other),
+                }
+                tex.push(']');
+            }
+            for arg in args {
+                write_arg(tex, arg);
+            }
+        }
+        Node::Seq(children) => write_group(tex, children),
+        Node::Environment { name, body } => {
+            tex.push_str(&format!("\\begin{{{}}}", name));
+            write_body(tex, body);
+            tex.push_str(&format!(" \\end{{{}}}", name));
+        }
+        Node::Table { spec, rows, hlines } => {
+            let name = if spec.width.is_some() {
+                "tabular*"
+            } else {
+                "tabular"
+            };
+            tex.push_str(&format!("\\begin{{{}}}", name));
+            match spec.width {
+                Some(TableWidth::Fixed(length)) => {
+                    tex.push('{');
+                    write_length(tex, length);
+                    tex.push('}');
+                }
+                Some(TableWidth::Text(fraction)) => {
+                    tex.push_str(&format!("{{{}\\textwidth}}", fraction));
+                }
+                None => {}
+            }
+            write_columns(tex, spec, rows);
+            for (i, row) in rows.iter().enumerate() {
+                if i > 0 {
+                    tex.push_str(" \\\\");
+                }
+                if hlines.contains(&i) {
+                    tex.push_str(" \\hline");
+                }
+                for (j, cell) in row.iter().enumerate() {
+                    if j > 0 {
+                        tex.push_str(" &");
+                    }
+                    write_body(tex, cell);
+                }
+            }
+            // A `\\` before the end closes an empty last row, which
+            // would otherwise read as no row at all.
+            let empty_last = rows
+                .last()
+                .is_some_and(|row| row.len() == 1 && row[0].is_empty());
+            let rule_below = hlines.contains(&rows.len());
+            if empty_last || (rule_below && !rows.is_empty()) {
+                tex.push_str(" \\\\");
+            }
+            if rule_below {
+                tex.push_str(" \\hline");
+            }
+            tex.push_str(&format!(" \\end{{{}}}", name));
+        }
+        Node::Heading {
+            level,
+            number,
+            title,
+        } => {
+            tex.push_str(if *level == 1 {
+                "\\section"
+            } else {
+                "\\subsection"
+            });
+            if number.is_empty() {
+                tex.push('*');
+            }
+            write_group(tex, title);
+        }
+        Node::TitleBlock {
+            title,
+            author,
+            date,
+        } => {
+            for (name, part) in [("title", title), ("author", author), ("date", date)] {
+                write_command(tex, name, &[part]);
+                tex.push(' ');
+            }
+            tex.push_str("\\maketitle");
+        }
+        Node::ParBreak => tex.push_str("\n\n"),
+        Node::NoIndent => tex.push_str("\\noindent"),
+        Node::LineBreak {
+            space,
+            no_page_break,
+        } => {
+            tex.push_str("\\\\");
+            if *no_page_break {
+                tex.push('*');
+            }
+            if let Some(space) = space {
+                tex.push('[');
+                write_length(tex, *space);
+                tex.push(']');
+            }
+        }
+        Node::Math(body) => {
+            tex.push('$');
+            write_seq(tex, body);
+            tex.push('$');
+        }
+        Node::Verbatim(text) => {
+            tex.push_str("\\begin{verbatim}");
+            tex.push_str(text);
+            tex.push_str("\\end{verbatim}");
+        }
+        Node::Footnote(body) => write_command(tex, "footnote", &[body]),
+        Node::Phantom {
+            body,
+            width,
+            height,
+        } => {
+            let name = match (width, height) {
+                (true, false) => "hphantom",
+                (false, true) => "vphantom",
+                _ => "phantom",
+            };
+            write_command(tex, name, &[body]);
+        }
+        Node::Label { key, .. } => write_command(tex, "label", &[&[Node::text(key)]]),
+        Node::Ref { key, page } => {
+            let name = if *page { "pageref" } else { "ref" };
+            write_command(tex, name, &[&[Node::text(key)]]);
+        }
+        Node::Superscript(arg) | Node::Subscript(arg) => {
+            tex.push(if matches!(node, Node::Superscript(_)) {
+                '^'
+            } else {
+                '_'
+            });
+            match &**arg {
+                // A script takes one character or command without braces.
+                Node::Text(text) if text.chars().count() == 1 => write_text(tex, text),
+                arg @ Node::Macro { args, .. } if args.is_empty() => write_node(tex, arg),
+                arg => write_arg(tex, arg),
+            }
+        }
+        Node::Frac { num, den } => write_command(tex, "frac", &[num, den]),
+        Node::Comment(text) => {
+            tex.push('%');
+            tex.push_str(text);
+            tex.push('\n');
+        }
+        Node::NonBreakingSpace => tex.push('~'),
+        Node::DiscretionaryHyphen => tex.push_str("\\-"),
+        Node::HSpace(length) => {
+            tex.push_str("\\hspace{");
+            write_length(tex, *length);
+            tex.push('}');
+        }
+        Node::VSpace(pt) => {
+            tex.push_str("\\vspace{");
+            write_length(tex, Length::pt(*pt));
+            tex.push('}');
+        }
+        Node::VFill => tex.push_str("\\vfill"),
+        Node::Image { path, width, .. } => {
+            tex.push_str("\\includegraphics");
+            if let Some(width) = width {
+                tex.push_str("[width=");
+                write_length(tex, *width);
+                tex.push(']');
+            }
+            write_arg(tex, &Node::text(path.to_string_lossy()));
+        }
+        Node::Rule { width: None, .. } => tex.push_str("\\hrule"),
+        Node::Rule {
+            width: Some(width),
+            height,
+        } => {
+            tex.push_str("\\rule{");
+            write_length(tex, *width);
+            tex.push_str("}{");
+            write_length(tex, *height);
+            tex.push('}');
+        }
+        Node::List { kind, items } => {
+            let name = match kind {
+                ListKind::Itemize => "itemize",
+                ListKind::Enumerate => "enumerate",
+                ListKind::Description => "description",
+            };
+            tex.push_str(&format!("\\begin{{{}}}", name));
+            for item in items {
+                tex.push_str(" \\item");
+                if let Some(label) = &item.label {
+                    tex.push('[');
+                    write_seq(tex, label);
+                    tex.push(']');
+                }
+                write_body(tex, &item.body);
+            }
+            tex.push_str(&format!(" \\end{{{}}}", name));
+        }
+    }
+}
+
+/// A pass over the tree that rebuilds it, node by node.
+///
+/// Every method has a default that reproduces its input, recursing into
+/// children through [`walk`], so a pass overrides only the nodes it cares
+/// about. Overrides that still want the children visited call `walk` or the
+/// other `visit_*` methods themselves.
+pub trait Visitor {
+    /// What makes the pass fail; `std::convert::Infallible` if nothing can.
+    type Error;
+
+    /// Visit any node; the default dispatches on its kind through [`walk`].
+    fn visit(&mut self, node: &Node) -> Result<Node, Self::Error> {
+        walk(self, node)
+    }
+
+    /// Visit a sequence of sibling nodes, such as a group or an environment body.
+    fn visit_seq(&mut self, nodes: &[Node]) -> Result<Vec<Node>, Self::Error> {
+        nodes.iter().map(|node| self.visit(node)).collect()
+    }
+
+    fn visit_text(&mut self, text: &str) -> Result<Node, Self::Error> {
+        Ok(Node::Text(text.to_string()))
+    }
+
+    fn visit_styled_text(&mut self, text: &str, style: &TextStyle) -> Result<Node, Self::Error> {
+        Ok(Node::StyledText(text.to_string(), style.clone()))
+    }
+
+    fn visit_macro(
+        &mut self,
+        name: &str,
+        opt_args: &[Node],
+        args: &[Node],
+        starred: bool,
+    ) -> Result<Node, Self::Error> {
+        Ok(Node::Macro {
+            name: name.to_string(),
+            opt_args: opt_args
+                .iter()
+                .map(|arg| self.visit(arg))
+                .collect::<Result<_, _>>()?,
+            args: args
+                .iter()
+                .map(|arg| self.visit(arg))
+                .collect::<Result<_, _>>()?,
+            starred,
+        })
+    }
+}
+
+/// Rebuild `node` with `visitor`: text and macros go to their `visit_*`
+/// methods, and the children of every other node are visited in turn.
+pub fn walk<V: Visitor + ?Sized>(visitor: &mut V, node: &Node) -> Result<Node, V::Error> {
+    let node = match node {
+        Node::Text(text) => return visitor.visit_text(text),
+        Node::StyledText(text, style) => return visitor.visit_styled_text(text, style),
+        Node::Macro {
+            name,
+            opt_args,
+            args,
+            starred,
+        } => return visitor.visit_macro(name, opt_args, args, *starred),
+        Node::Seq(children) => Node::Seq(visitor.visit_seq(children)?),
+        Node::Environment { name, body } => Node::Environment {
+            name: name.clone(),
+            body: visitor.visit_seq(body)?,
+        },
+        Node::Table { spec, rows, hlines } => return walk_table(visitor, spec, rows, hlines),
+        Node::Heading {
+            level,
+            number,
+            title,
+        } => Node::Heading {
+            level: *level,
+            number: number.clone(),
+            title: visitor.visit_seq(title)?,
+        },
+        Node::TitleBlock {
+            title,
+            author,
+            date,
+        } => Node::TitleBlock {
+            title: visitor.visit_seq(title)?,
+            author: visitor.visit_seq(author)?,
+            date: visitor.visit_seq(date)?,
+        },
+        Node::Math(body) => Node::Math(visitor.visit_seq(body)?),
+        Node::Footnote(body) => Node::Footnote(visitor.visit_seq(body)?),
+        Node::Phantom {
+            body,
+            width,
+            height,
+        } => return walk_phantom(visitor, body, *width, *height),
+        Node::Superscript(arg) => Node::Superscript(Box::new(visitor.visit(arg)?)),
+        Node::Subscript(arg) => Node::Subscript(Box::new(visitor.visit(arg)?)),
+        Node::Frac { num, den } => Node::Frac {
+            num: visitor.visit_seq(num)?,
+            den: visitor.visit_seq(den)?,
+        },
+        Node::List { kind, items } => Node::List {
+            kind: *kind,
+            items: items
+                .iter()
+                .map(|item| {
+                    Ok(ListItem {
+                        label: match &item.label {
+                            Some(label) => Some(visitor.visit_seq(label)?),
+                            None => None,
+                        },
+                        body: visitor.visit_seq(&item.body)?,
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+        },
+        Node::ParBreak
+        | Node::NoIndent
+        | Node::LineBreak { .. }
+        | Node::Comment(_)
+        | Node::Verbatim(_)
+        | Node::Label { .. }
+        | Node::Ref { .. }
+        | Node::NonBreakingSpace
+        | Node::DiscretionaryHyphen
+        | Node::HSpace(_)
+        | Node::VSpace(_)
+        | Node::VFill
+        | Node::Image { .. }
+        | Node::Rule { .. } => node.clone(),
+    };
+    Ok(node)
+}
+
+/// Rebuild a table around its cells visited. Kept out of [`walk`], as
+/// [`walk_phantom`] is.
+fn walk_table<V: Visitor + ?Sized>(
+    visitor: &mut V,
+    spec: &TableSpec,
+    rows: &[TableRow],
+    hlines: &[usize],
+) -> Result<Node, V::Error> {
+    Ok(Node::Table {
+        spec: Box::new(spec.clone()),
+        rows: rows
+            .iter()
+            .map(|row| row.iter().map(|cell| visitor.visit_seq(cell)).collect())
+            .collect::<Result<_, _>>()?,
+        hlines: hlines.to_vec(),
+    })
+}
+
+/// Rebuild a phantom around `body` visited. Kept out of [`walk`], so that
+/// deeply nested groups do not pay for its frame.
+fn walk_phantom<V: Visitor + ?Sized>(
+    visitor: &mut V,
+    body: &[Node],
+    width: bool,
+    height: bool,
+) -> Result<Node, V::Error> {
+    Ok(Node::Phantom {
+        body: visitor.visit_seq(body)?,
+        width,
+        height,
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Node;
+    use super::{ListItem, ListKind, Node, Visitor};
+    use std::convert::Infallible;
 
     #[test]
     fn test_node_eq() {
@@ -48,14 +956,60 @@ mod tests {
     #[test]
     fn test_macro_node() {
         let m = Node::r#macro("test", vec![Node::text("arg")]);
-        if let Node::Macro { name, args } = m {
+        if let Node::Macro {
+            name,
+            opt_args,
+            args,
+            starred,
+        } = m
+        {
             assert_eq!(name, "test");
+            assert!(opt_args.is_empty());
+            assert!(!starred);
             assert_eq!(args, vec![Node::Text("arg".into())]);
         } else {
             panic!("Expected Macro variant");
         }
     }
 
+    #[test]
+    fn test_visitor_counts_text() {
+        struct CountText(usize);
+        impl Visitor for CountText {
+            type Error = Infallible;
+            fn visit_text(&mut self, text: &str) -> Result<Node, Infallible> {
+                self.0 += 1;
+                Ok(Node::text(text))
+            }
+        }
+
+        let tree = Node::seq(vec![
+            Node::text("a"),
+            Node::r#macro("textbf", vec![Node::seq(vec![Node::text("b")])]),
+            Node::Environment {
+                name: "center".into(),
+                body: vec![Node::text("c"), Node::Math(vec![Node::text("d")])],
+            },
+            Node::List {
+                kind: ListKind::Itemize,
+                items: vec![
+                    ListItem {
+                        label: None,
+                        body: vec![Node::text("e")],
+                    },
+                    ListItem {
+                        label: Some(vec![Node::text("f")]),
+                        body: vec![Node::NonBreakingSpace],
+                    },
+                ],
+            },
+        ]);
+        let mut count = CountText(0);
+        let Ok(rebuilt) = count.visit(&tree);
+        assert_eq!(count.0, 6);
+        assert_eq!(rebuilt, tree);
+    }
+
     #[test]
     fn test_sequence() {
         let seq = Node::seq(vec![Node::text("A"), Node::text("B")]);
@@ -64,4 +1018,52 @@ mod tests {
             Node::Seq(vec![Node::Text("A".into()), Node::Text("B".into())])
         );
     }
+
+    #[test]
+    fn test_to_tex_round_trips() {
+        use crate::lexer::Comments;
+        use crate::parser::parse_with;
+
+        assert_eq!(
+            crate::parser::parse(r"\textbf{a}   b").unwrap().to_tex(),
+            r"\textbf{a} b"
+        );
+        for input in [
+            r"\textbf{a} b",
+            r"{a {b {c} \emph{d}}} e",
+            "One\n\ntwo\\\\[4pt] three\\\\* five\\par four",
+            "a\n\n\\par\\par b\n\n\n\\par c \\par\n\n",
+            r"\section*[short]{T\%x} 50\% of \$5 \& C:\textbackslash temp a\_b a~b hy\-phen",
+            r"$x^2 + y_{i} \frac{1}{2} a^\alpha$",
+            r"\begin{center} a \begin{quote}b\end{quote}\end{center}",
+            r"\begin{itemize} \item a \item \begin{enumerate}\item b\end{enumerate} \end{itemize}",
+            r"\begin{description} \item[\textbf{x} y] a \item[] b \item c \end{description}",
+            r"\begin{tabular}{ll} a & \textbf{b} \\ & c \\ \end{tabular}",
+            r"\begin{tabular}{|l|c r|} \hline a & b & c \\ \hline d \\ \hline \end{tabular}",
+            r"\begin{tabular}{l} a \\ \\ \end{tabular} \begin{tabular}{l} \\ \hline \\ \hline \end{tabular}",
+            r"\begin{tabular*}{0.5\textwidth}{lr} a & b \end{tabular*} \begin{tabular*}{3cm}{c} x \end{tabular*}",
+            "\\begin{verbatim}  x  {%\n\\end{verbatim} [a] {b % note\n}c",
+        ] {
+            let ast = parse_with(input, Comments::Keep).unwrap();
+            let tex = ast.to_tex();
+            assert_eq!(
+                parse_with(&tex, Comments::Keep).unwrap(),
+                ast,
+                "{:?} was written as {:?}",
+                input,
+                tex
+            );
+        }
+    }
+
+    #[test]
+    fn test_to_tex_writes_expanded_nodes_as_their_commands() {
+        let expand = |tex: &str| crate::expand::expand_macros(&crate::parser::parse(tex)?);
+        let ast = expand(
+            r"\section{A} {\large\textcolor{red}{\underline{\textbf{b}}}} \footnote{c} $\frac{1}{x}$
+            \noindent \hspace{3mm} \rule{1cm}{2pt} \hrule \vfill \label{k} \ref{k} \pageref{k}",
+        )
+        .unwrap();
+        assert_eq!(expand(&ast.to_tex()).unwrap(), ast);
+    }
 }
@@ -1,99 +1,1608 @@
-use crate::ast::TextStyle;
-use crate::layout::{LayoutNode, Page};
-use printpdf::{Mm, PdfDocument};
-use std::io::{BufWriter, Cursor};
+use crate::ast::{
+    ColumnAlign, FontFace, ListKind, Node, Rgb, Script, TableWidth, TextStyle, Visitor, walk,
+};
+use crate::error::CompileError;
+use crate::expand::civil_from_days;
+use crate::layout::{Alignment, LayoutNode, Page, Pagination, Role, line_text, run_metrics};
+use crate::options::{CompileOptions, Fonts, LEADING};
+use crate::outline::{self, Bookmark};
+use crate::pdf::{self, Writer};
+use crate::shaping::Shaper;
+use crate::subset::subset;
+use crate::tagged::Structure;
+use crate::units::Length;
+use image::RgbImage;
+use std::borrow::{Borrow, Cow};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Distance of the underline below the baseline, as a fraction of the font size.
+const UNDERLINE_OFFSET: f64 = 0.12;
+
+/// Stroke width of underlines and strikeouts, in points.
+const UNDERLINE_THICKNESS_PT: f64 = 0.6;
+
+/// Both IDs of every document compiled with
+/// [`deterministic`](CompileOptions::deterministic) set, as long as the
+/// random ones others get.
+const FIXED_ID: &[u8; 32] = b"00000000000000000000000000000000";
+
+/// Set the fill color to an 8-bit color, in the unit-range RGB of PDF.
+fn fill_color(content: &mut String, c: Rgb) {
+    let unit = |v: u8| f64::from(v) / 255.0;
+    let _ = writeln!(
+        content,
+        "{:.3} {:.3} {:.3} rg",
+        unit(c.r),
+        unit(c.g),
+        unit(c.b)
+    );
+}
+
+/// Font files read and parsed once, then reused by every document rendered
+/// with the cache; worth keeping around when compiling many documents.
+///
+/// Each document still embeds its own copy of the fonts it uses.
+#[derive(Debug, Default)]
+pub struct FontCache {
+    files: Mutex<HashMap<PathBuf, FontData>>,
+    /// The bundled faces, in the order of [`FACES`], once parsed.
+    bundled: [OnceLock<FontData>; 4],
+    /// How many font files have been parsed.
+    parses: AtomicUsize,
+}
+
+/// The cache used when none is given.
+static SHARED_FONTS: LazyLock<FontCache> = LazyLock::new(FontCache::new);
+
+impl FontCache {
+    pub fn new() -> Self {
+        FontCache::default()
+    }
+
+    /// The process-wide cache behind [`compile`](crate::compile) and the
+    /// other functions that do not take one.
+    pub fn shared() -> &'static FontCache {
+        &SHARED_FONTS
+    }
+
+    /// The font file at `path`, read and parsed the first time it is asked for.
+    fn load(&self, path: &Path) -> Result<FontData, CompileError> {
+        // A panic elsewhere while holding the lock cannot leave the map half-written.
+        let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(data) = files.get(path) {
+            return Ok(data.clone());
+        }
+        let error = |message: String| CompileError::Font {
+            path: path.to_path_buf(),
+            message,
+        };
+        let bytes = fs::read(path).map_err(|e| error(e.to_string()))?;
+        let data = self.parse(Bytes::File(bytes.into())).map_err(error)?;
+        files.insert(path.to_path_buf(), data.clone());
+        Ok(data)
+    }
+
+    /// The bundled file for `face`, parsed the first time it is asked for.
+    fn bundled(&self, face: &FontFace) -> FontData {
+        self.bundled[face_index(face)]
+            .get_or_init(|| {
+                self.parse(Bytes::Bundled(bundled_font(face)))
+                    .expect("the bundled fonts parse")
+            })
+            .clone()
+    }
+
+    /// `bytes` with their metrics, or why they are not a font.
+    fn parse(&self, bytes: Bytes) -> Result<FontData, String> {
+        self.parses.fetch_add(1, Ordering::Relaxed);
+        let face = ttf_parser::Face::parse(bytes.as_ref(), 0).map_err(|e| e.to_string())?;
+        let metrics = Arc::new(Metrics::new(&face));
+        Ok(FontData { bytes, metrics })
+    }
+}
+
+/// The bytes of a font file: compiled in, or read from disk.
+#[derive(Debug, Clone)]
+enum Bytes {
+    Bundled(&'static [u8]),
+    File(Arc<[u8]>),
+}
+
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Bytes::Bundled(data) => data,
+            Bytes::File(data) => data,
+        }
+    }
+}
+
+/// A font file, compiled in or loaded through a [`FontCache`], with the
+/// metrics parsed from it there.
+#[derive(Debug, Clone)]
+pub(crate) struct FontData {
+    bytes: Bytes,
+    metrics: Arc<Metrics>,
+}
+
+impl FontData {
+    pub(crate) fn bytes(&self) -> &[u8] {
+        self.bytes.as_ref()
+    }
+
+    pub(crate) fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+}
+
+/// What drawing and shaping text need of a font, read from the file once:
+/// looking these up in a `ttf_parser::Face` means parsing the file again
+/// each time.
+#[derive(Debug)]
+pub(crate) struct Metrics {
+    units_per_em: u16,
+    ascender: i16,
+    descender: i16,
+    /// The height of the lowercase letters, in ems: as the font gives it,
+    /// else the top of its `x`, else half an em.
+    x_height: f64,
+    /// The glyph each character the font maps is drawn with, by character.
+    glyphs: Vec<(char, u16)>,
+    /// Each glyph's advance, in font units.
+    advances: Vec<u16>,
+    /// What the font descriptor of an embedded copy gives, in font units:
+    /// the box every glyph fits in, as left, bottom, right and top, and the
+    /// height of the capitals.
+    bbox: [i16; 4],
+    cap_height: i16,
+    /// Degrees the italic face slants, counterclockwise.
+    italic_angle: f32,
+    /// The PostScript name, which an embedded copy goes by.
+    postscript_name: Option<String>,
+    /// The pairs of glyphs each horizontal `kern` subtable that lists them
+    /// kerns, left glyph in the high half, in order, with the kerning in
+    /// font units.
+    kerns: Vec<Vec<(u32, i16)>>,
+    /// Whether a horizontal `kern` subtable is not a list of pairs, so that
+    /// kerning must be looked up in the file itself.
+    kern_classes: bool,
+}
+
+impl Metrics {
+    fn new(face: &ttf_parser::Face) -> Self {
+        let mut glyphs = Vec::new();
+        for table in face.tables().cmap.iter().flat_map(|cmap| cmap.subtables) {
+            if table.is_unicode() {
+                table.codepoints(|codepoint| {
+                    if let (Some(c), Some(glyph)) =
+                        (char::from_u32(codepoint), table.glyph_index(codepoint))
+                    {
+                        glyphs.push((c, glyph.0));
+                    }
+                });
+            }
+        }
+        // The first subtable to map a character decides its glyph.
+        glyphs.sort_by_key(|&(c, _)| c);
+        glyphs.dedup_by_key(|&mut (c, _)| c);
+        let advances = (0..face.number_of_glyphs())
+            .map(|glyph| {
+                face.glyph_hor_advance(ttf_parser::GlyphId(glyph))
+                    .unwrap_or(0)
+            })
+            .collect();
+        let mut kerns = Vec::new();
+        let mut kern_classes = false;
+        let subtables = face
+            .tables()
+            .kern
+            .into_iter()
+            .flat_map(|kern| kern.subtables);
+        for table in subtables.filter(|s| s.horizontal && !s.variable && !s.has_cross_stream) {
+            match table.format {
+                ttf_parser::kern::Format::Format0(table) => {
+                    kerns.push(table.pairs.into_iter().map(|p| (p.pair, p.value)).collect());
+                }
+                ttf_parser::kern::Format::Format2(_) | ttf_parser::kern::Format::Format3(_) => {
+                    kern_classes = true
+                }
+                // A state machine kerns nothing looked up by pair.
+                _ => {}
+            }
+        }
+        let x_top = || {
+            let bbox = face.glyph_bounding_box(face.glyph_index('x')?)?;
+            Some(bbox.y_max)
+        };
+        let x_height = face.x_height().or_else(x_top).map_or(0.5, |height| {
+            f64::from(height) / f64::from(face.units_per_em())
+        });
+        let bbox = face.global_bounding_box();
+        let postscript_name = face
+            .names()
+            .into_iter()
+            .filter(|name| name.name_id == ttf_parser::name_id::POST_SCRIPT_NAME)
+            .find_map(|name| name.to_string());
+        Metrics {
+            units_per_em: face.units_per_em(),
+            ascender: face.ascender(),
+            descender: face.descender(),
+            x_height,
+            glyphs,
+            advances,
+            bbox: [bbox.x_min, bbox.y_min, bbox.x_max, bbox.y_max],
+            cap_height: face.capital_height().unwrap_or(face.ascender()),
+            italic_angle: face.italic_angle().unwrap_or(0.0),
+            postscript_name,
+            kerns,
+            kern_classes,
+        }
+    }
+
+    /// The glyph `c` is drawn with, if the font has one.
+    pub(crate) fn glyph(&self, c: char) -> Option<u16> {
+        let at = self.glyphs.binary_search_by_key(&c, |&(c, _)| c).ok()?;
+        Some(self.glyphs[at].1)
+    }
+
+    /// How far `text` advances, in ems.
+    pub(crate) fn advance(&self, text: &str) -> f64 {
+        let em = f64::from(self.units_per_em);
+        text.chars()
+            .filter_map(|c| self.advances.get(usize::from(self.glyph(c)?)))
+            .map(|&units| f64::from(units) / em)
+            .sum()
+    }
+
+    /// The kerning between the glyphs of `left` and `right`, in ems, if the
+    /// pair is listed; `None` too when it must be looked up in the file.
+    pub(crate) fn kern(&self, left: char, right: char) -> Option<f64> {
+        let pair = u32::from(self.glyph(left)?) << 16 | u32::from(self.glyph(right)?);
+        // The first subtable to kern a pair decides it.
+        let units = self.kerns.iter().find_map(|pairs| {
+            let at = pairs.binary_search_by_key(&pair, |&(pair, _)| pair).ok()?;
+            Some(pairs[at].1)
+        })?;
+        Some(f64::from(units) / f64::from(self.units_per_em))
+    }
+
+    /// Whether some kerning is not in [`kern`](Metrics::kern), but must be
+    /// looked up in the file.
+    pub(crate) fn kern_classes(&self) -> bool {
+        self.kern_classes
+    }
+
+    pub(crate) fn units_per_em(&self) -> u16 {
+        self.units_per_em
+    }
+}
+
+impl AsRef<Metrics> for FontData {
+    fn as_ref(&self) -> &Metrics {
+        &self.metrics
+    }
+}
+
+/// The faces in the order [`write_pdf`] loads them, before the fallback font.
+const FACES: [FontFace; 4] = [
+    FontFace::Normal,
+    FontFace::Bold,
+    FontFace::Italic,
+    FontFace::Monospace,
+];
+
+/// Where `face` is in [`FACES`].
+fn face_index(face: &FontFace) -> usize {
+    match face {
+        FontFace::Normal => 0,
+        FontFace::Bold => 1,
+        FontFace::Italic => 2,
+        FontFace::Monospace => 3,
+    }
+}
+
+/// The bundled DejaVu file for `face`.
+fn bundled_font(face: &FontFace) -> &'static [u8] {
+    match face {
+        FontFace::Normal => include_bytes!("../fonts/DejaVuSans.ttf"),
+        FontFace::Bold => include_bytes!("../fonts/DejaVuSans-Bold.ttf"),
+        FontFace::Italic => include_bytes!("../fonts/DejaVuSans-Oblique.ttf"),
+        FontFace::Monospace => include_bytes!("../fonts/DejaVuSansMono.ttf"),
+    }
+}
+
+/// The font file `face` is set in: the one `fonts` names, loaded through
+/// `cache`, or the bundled face when there is none.
+pub(crate) fn font_data(
+    fonts: &Fonts,
+    face: &FontFace,
+    cache: &FontCache,
+) -> Result<FontData, CompileError> {
+    match fonts.path(face) {
+        Some(path) => cache.load(path),
+        None => Ok(cache.bundled(face)),
+    }
+}
+
+/// A font file the document draws from, with the glyphs drawn so far.
+struct Embedded {
+    id: u32,
+    /// Each glyph drawn, with the character it was drawn for.
+    glyphs: BTreeMap<u16, char>,
+}
+
+/// Write `font`, embedding `data`, as a composite font drawn by glyph id,
+/// with the widths and a `ToUnicode` map of the glyphs drawn from it only;
+/// and with only their outlines when `subset_fonts` is set and the file can
+/// be cut down.
+fn write_font(
+    pdf: &mut Writer,
+    font: &Embedded,
+    data: &FontData,
+    subset_fonts: bool,
+) -> io::Result<()> {
+    let metrics = data.metrics();
+    let (cid_font, descriptor, file, to_unicode) =
+        (pdf.reserve(), pdf.reserve(), pdf.reserve(), pdf.reserve());
+    // Widths and the descriptor are in thousandths of an em.
+    let scale = |units: f64| (units * 1000.0 / f64::from(metrics.units_per_em)).round();
+    let name: String = match &metrics.postscript_name {
+        Some(name) => name
+            .chars()
+            .filter(|c| c.is_ascii_graphic() && !"()<>[]{}/%#".contains(*c))
+            .collect(),
+        None => String::new(),
+    };
+    let name = match name.is_empty() {
+        true => format!("F{}", font.id),
+        false => name,
+    };
+
+    pdf.object(
+        font.id,
+        &format!(
+            "<</Type/Font/Subtype/Type0/BaseFont/{}/Encoding/Identity-H/DescendantFonts[{} 0 R]/ToUnicode {} 0 R>>",
+            name, cid_font, to_unicode
+        ),
+    )?;
+    let mut widths = String::new();
+    for &glyph in font.glyphs.keys() {
+        let advance = metrics
+            .advances
+            .get(usize::from(glyph))
+            .copied()
+            .unwrap_or(0);
+        let _ = write!(widths, "{}[{}]", glyph, scale(f64::from(advance)));
+    }
+    pdf.object(
+        cid_font,
+        &format!(
+            "<</Type/Font/Subtype/CIDFontType2/BaseFont/{}/CIDSystemInfo<</Registry(Adobe)/Ordering(Identity)/Supplement 0>>/FontDescriptor {} 0 R/DW 1000/W[{}]/CIDToGIDMap/Identity>>",
+            name, descriptor, widths
+        ),
+    )?;
+    let [left, bottom, right, top] = metrics.bbox.map(|units| scale(f64::from(units)));
+    pdf.object(
+        descriptor,
+        &format!(
+            "<</Type/FontDescriptor/FontName/{}/Flags 32/FontBBox[{} {} {} {}]/ItalicAngle {}/Ascent {}/Descent {}/CapHeight {}/StemV 80/FontFile2 {} 0 R>>",
+            name,
+            left,
+            bottom,
+            right,
+            top,
+            metrics.italic_angle,
+            scale(f64::from(metrics.ascender)),
+            scale(f64::from(metrics.descender)),
+            scale(f64::from(metrics.cap_height)),
+            file
+        ),
+    )?;
+
+    let bytes = match subset_fonts {
+        true => subset(data.bytes(), font.glyphs.keys().copied()),
+        false => None,
+    };
+    let bytes = bytes.map_or(Cow::Borrowed(data.bytes()), Cow::Owned);
+    let entries = format!("/Length1 {}/Filter/FlateDecode", bytes.len());
+    pdf.stream(file, &entries, &pdf::deflate(&bytes))?;
+    pdf.stream(to_unicode, "", cmap(&font.glyphs).as_bytes())
+}
+
+/// The `ToUnicode` CMap of a font whose `glyphs` were each drawn for the
+/// character paired with it.
+fn cmap(glyphs: &BTreeMap<u16, char>) -> String {
+    let mut cmap = String::from(
+        "/CIDInit /ProcSet findresource begin\n12 dict begin\nbegincmap\n\
+         /CIDSystemInfo <</Registry (Adobe) /Ordering (UCS) /Supplement 0>> def\n\
+         /CMapName /Adobe-Identity-UCS def\n/CMapType 2 def\n\
+         1 begincodespacerange\n<0000> <ffff>\nendcodespacerange\n",
+    );
+    let glyphs: Vec<(&u16, &char)> = glyphs.iter().collect();
+    // A section may map at most 100 glyphs.
+    for chunk in glyphs.chunks(100) {
+        let _ = writeln!(cmap, "{} beginbfchar", chunk.len());
+        for (glyph, c) in chunk {
+            let _ = write!(cmap, "<{:04x}> <", glyph);
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                let _ = write!(cmap, "{:04x}", unit);
+            }
+            cmap.push_str(">\n");
+        }
+        cmap.push_str("endbfchar\n");
+    }
+    cmap.push_str("endcmap\nCMapName currentdict /CMap defineresource pop\nend\nend");
+    cmap
+}
+
+/// `text` split into the runs `font` can draw and those only `fallback`
+/// can. Characters neither has a glyph for, which would silently be
+/// leave out, are replaced by `replacement` and added to `missing`.
+fn font_runs<'f, 't, F: AsRef<Metrics>>(
+    font: &'f F,
+    fallback: Option<&'f F>,
+    text: &'t str,
+    replacement: char,
+    missing: &mut Vec<char>,
+) -> Vec<(&'f F, Cow<'t, str>)> {
+    let has_glyph = |font: &F, c| font.as_ref().glyph(c).is_some();
+    if text.chars().all(|c| has_glyph(font, c)) {
+        return vec![(font, Cow::Borrowed(text))];
+    }
+    let mut runs: Vec<(&F, Cow<str>)> = Vec::new();
+    for c in text.chars() {
+        let (font, c) = match fallback {
+            _ if has_glyph(font, c) => (font, c),
+            Some(fallback) if has_glyph(fallback, c) => (fallback, c),
+            _ => {
+                if !missing.contains(&c) {
+                    missing.push(c);
+                }
+                (font, replacement)
+            }
+        };
+        match runs.last_mut() {
+            Some((last, run)) if std::ptr::eq(*last, font) => run.to_mut().push(c),
+            _ => runs.push((font, Cow::Owned(c.to_string()))),
+        }
+    }
+    runs
+}
+
+/// Begin a text object setting `glyphs` in font `slot` at `size`, with the
+/// baseline starting at (`x`, `y`), all in points.
+fn show_text(content: &mut String, slot: usize, glyphs: &[u16], size: f64, (x, y): (f64, f64)) {
+    let _ = write!(
+        content,
+        "BT\n/F{} {:.2} Tf\n{:.2} {:.2} Td\n<",
+        slot, size, x, y
+    );
+    for glyph in glyphs {
+        let _ = write!(content, "{:04X}", glyph);
+    }
+    content.push_str("> Tj\nET\n");
+}
+
+/// Draw a horizontal line from `x0` to `x1` at height `y`, as thick as an
+/// underline.
+fn stroke(content: &mut String, x0: f64, x1: f64, y: f64) {
+    let _ = write!(
+        content,
+        "{} w\n{:.2} {:.2} m\n{:.2} {:.2} l\nS\n",
+        UNDERLINE_THICKNESS_PT, x0, y, x1, y
+    );
+}
+
+/// Fill the rectangle between the corners `(x0, y0)` and `(x1, y1)` in the
+/// current fill color.
+fn fill_rect(content: &mut String, (x0, y0): (f64, f64), (x1, y1): (f64, f64)) {
+    let _ = write!(
+        content,
+        "{x0:.2} {y0:.2} m\n{x1:.2} {y0:.2} l\n{x1:.2} {y1:.2} l\n{x0:.2} {y1:.2} l\nf\n"
+    );
+}
+
+/// The warning for the characters in `missing`, which no font had a glyph
+/// for, so that `replacement` was drawn instead.
+fn missing_glyph_warning(missing: &[char], replacement: char) -> String {
+    let codepoints: Vec<String> = missing
+        .iter()
+        .map(|&c| format!("U+{:04X}", u32::from(c)))
+        .collect();
+    format!(
+        "No glyph for {} in the fonts; drawn as {}",
+        codepoints.join(", "),
+        replacement
+    )
+}
 
 /// Render a sequence of laid-out pages into a PDF document.
 ///
 /// # Parameters
 /// - `pages`: the output of your `layout()` function.
+/// - `opts`: the settings the pages were laid out with, for the paper and the fonts to embed,
+///   and which of the pages to render.
+/// - `fonts`: where font files named in `opts` are loaded from.
 ///
 /// # Returns
-/// A `Vec<u8>` containing the PDF file.
-pub fn render_pdf(pages: &[Page]) -> Vec<u8> {
-    // 1) Create a new A4 PDF (210×297 mm)
-    let (doc, first_page, first_layer) =
-        PdfDocument::new("latex_rs_document", Mm(210.0), Mm(297.0), "Layer 1");
-
-    // 2) Embed your three font faces
-    let font_normal = doc
-        .add_external_font(Cursor::new(
-            include_bytes!("../fonts/DejaVuSans.ttf") as &[u8]
-        ))
-        .unwrap();
-    let font_bold = doc
-        .add_external_font(Cursor::new(
-            include_bytes!("../fonts/DejaVuSans-Bold.ttf") as &[u8]
-        ))
-        .unwrap();
-    let font_italic = doc
-        .add_external_font(Cursor::new(
-            include_bytes!("../fonts/DejaVuSans-Oblique.ttf") as &[u8],
-        ))
-        .unwrap();
-
-    // 3) Metrics & margins
-    let font_size_pt = 12.0;
-    let line_height_mm = Mm(font_size_pt * 1.2 * 25.4 / 72.0);
-    let margin_left = Mm(10.0);
-    let margin_top = Mm(10.0);
-
-    // 4) Draw each page
-    for (idx, page) in pages.iter().enumerate() {
-        // Get the right page & layer
-        let (page_idx, layer_idx) = if idx == 0 {
-            (first_page, first_layer)
-        } else {
-            doc.add_page(Mm(210.0), Mm(297.0), format!("Layer {}", idx + 1))
-        };
-        let layer = doc.get_page(page_idx).get_layer(layer_idx);
+/// A `Vec<u8>` containing the PDF file, or an error if a font or an image
+/// cannot be loaded, or if none of the pages asked for exist.
+pub fn render_pdf(
+    pages: &[Page],
+    opts: &CompileOptions,
+    fonts: &FontCache,
+) -> Result<Vec<u8>, CompileError> {
+    let mut buffer = Vec::new();
+    write_pdf(pages, opts, fonts, &mut buffer)?;
+    Ok(buffer)
+}
+
+/// Render laid-out pages as a PDF document straight into `w`, returning
+/// warnings about the rendering, which are also logged.
+///
+/// Each page is written to `w` as soon as it is drawn, and dropped then when
+/// `pages` hands them over by value; the fonts, drawn from by any page, and
+/// the outline and structure tree follow the last one. Fails like
+/// [`render_pdf`], or when writing to `w` does.
+pub fn write_pdf<P: Borrow<Page>>(
+    pages: impl IntoIterator<Item = P, IntoIter: ExactSizeIterator>,
+    opts: &CompileOptions,
+    cache: &FontCache,
+    w: &mut dyn io::Write,
+) -> Result<Vec<String>, CompileError> {
+    let pages = pages.into_iter();
+    let page_count = pages.len();
+    let selected = (0..page_count).any(|idx| opts.renders_page(idx));
+    if let (false, Some(range)) = (selected, opts.pages) {
+        return Err(CompileError::NoPagesInRange {
+            first: range.first,
+            last: range.last,
+            page_count,
+        });
+    }
+
+    // 1) Load the four font faces, bundled unless the options name files,
+    // then the fallback font
+    let fonts = &opts.fonts;
+    let mut files = Vec::new();
+    for face in &FACES {
+        files.push(font_data(fonts, face, cache)?);
+    }
+    if let Some(path) = &fonts.fallback {
+        files.push(cache.load(path)?);
+    }
+    let shaper = Shaper::new(opts, cache)?;
+    let fallback = files.get(FACES.len());
+    // Files with the same bytes are embedded once, as the first of them.
+    let first: Vec<usize> = files
+        .iter()
+        .map(|file| {
+            files
+                .iter()
+                .position(|other| std::ptr::eq(other.bytes(), file.bytes()))
+                .unwrap_or(0)
+        })
+        .collect();
+
+    // 2) Metrics & margins, in points
+    let font_size_pt = opts.font_size.to_pt();
+    let line_height = opts.line_height();
+    let margin = opts.margin.to_pt();
+    let underline_offset = font_size_pt * UNDERLINE_OFFSET;
+
+    // 3) Start the file; what refers to objects written later, such as the
+    // fonts and the page tree, reserves their numbers
+    let io = |e: io::Error| CompileError::Io(e.to_string());
+    let mut pdf = Writer::new(w).map_err(io)?;
+    let page_tree = pdf.reserve();
+    let mut structure = opts.tagged_pdf.then(|| Structure::new(&mut pdf));
+    let mut embedded: Vec<Option<Embedded>> = files.iter().map(|_| None).collect();
+    let mut images: Vec<(PathBuf, u32)> = Vec::new();
+
+    // 4) Draw each page and write it, noting where the headings are
+    let mut kids = Vec::new();
+    let mut bookmarks: Vec<Bookmark> = Vec::new();
+    let mut heading_block = None;
+    let mut missing = Vec::new();
+    let selected = pages
+        .enumerate()
+        .filter(|&(idx, _)| opts.renders_page(idx))
+        .map(|(_, page)| page);
+    for (idx, page) in selected.enumerate() {
+        let page = page.borrow();
+        let page_width = opts.page_size.width().to_pt();
+        let page_height = page_height(page, opts).to_pt();
+        let page_id = pdf.reserve();
+        let mut content = String::new();
+        let mut page_fonts = BTreeSet::new();
+        let mut page_images = BTreeSet::new();
+
+        // Paint the background under everything else on the page
+        if let Some(color) = opts.background_color {
+            fill_color(&mut content, color);
+            fill_rect(&mut content, (0.0, 0.0), (page_width, page_height));
+            fill_color(&mut content, Rgb::BLACK);
+        }
 
         // Start at top-left (printing origin is bottom-left in PDF)
-        let mut current_y = Mm(297.0) - margin_top;
+        let top = page_height - margin;
+        let mut current_y = top;
+        let mut column = 0;
 
         for line in &page.lines {
-            let mut current_x = margin_left;
+            // Each column starts again at the top of the page
+            if line.column != column {
+                column = line.column;
+                current_y = top;
+            }
+            current_y -= line.space_before + line.extra_height;
+            let mut current_x = margin + line.x_offset;
+
+            // A heading broken over several lines is one bookmark.
+            if let Role::Heading(level) = line.role {
+                let text = line_text(line);
+                match bookmarks.last_mut() {
+                    Some(last) if heading_block == Some(line.block) => {
+                        last.title.push(' ');
+                        last.title.push_str(&text);
+                    }
+                    _ => bookmarks.push(Bookmark {
+                        level,
+                        title: text,
+                        page: idx,
+                        top: current_y + line_height,
+                    }),
+                }
+                heading_block = Some(line.block);
+            }
 
             for hbox in &line.boxes {
                 for node in &hbox.items {
                     match node {
                         // Styled text runs
                         LayoutNode::Run(run) => {
-                            let font = match run.style {
-                                TextStyle::Normal => &font_normal,
-                                TextStyle::Bold => &font_bold,
-                                TextStyle::Italic => &font_italic,
-                            };
-                            // Emit the text
-                            layer.use_text(&run.text, font_size_pt, current_x, current_y, font);
+                            let face = face_index(&run.style.face);
+                            let font = &files[face];
+                            // Emit the text, in color if the run asks for it
+                            if run.style.color != Rgb::BLACK {
+                                fill_color(&mut content, run.style.color);
+                            }
+                            let (size, rise) =
+                                run_metrics(&run.style, font_size_pt, font_size_pt * LEADING);
+                            // Each piece starts where kerning has moved it, and
+                            // goes on in the fallback font where it must.
+                            for (offset, piece) in shaper.pieces(&run.style.face, &run.text) {
+                                let piece = shaper.ligate(&run.style.face, piece);
+                                let mut x = current_x + offset * size;
+                                let mut runs = font_runs(
+                                    font,
+                                    fallback,
+                                    &piece,
+                                    opts.missing_glyph,
+                                    &mut missing,
+                                )
+                                .into_iter()
+                                .peekable();
+                                while let Some((file, text)) = runs.next() {
+                                    // Only a run with more after it needs measuring.
+                                    let width = match runs.peek() {
+                                        Some(_) => file.metrics().advance(&text) * size,
+                                        None => 0.0,
+                                    };
+                                    let slot = match fallback {
+                                        Some(fallback) if std::ptr::eq(file, fallback) => {
+                                            first[FACES.len()]
+                                        }
+                                        _ => first[face],
+                                    };
+                                    let drawn = embedded[slot].get_or_insert_with(|| Embedded {
+                                        id: pdf.reserve(),
+                                        glyphs: BTreeMap::new(),
+                                    });
+                                    let mut glyphs = Vec::new();
+                                    for c in text.chars() {
+                                        // What maps to .notdef is left out.
+                                        match file.metrics().glyph(c) {
+                                            Some(glyph) if glyph != 0 => {
+                                                drawn.glyphs.entry(glyph).or_insert(c);
+                                                glyphs.push(glyph);
+                                            }
+                                            _ => {}
+                                        }
+                                    }
+                                    page_fonts.insert(slot);
+                                    if let Some(structure) = &mut structure {
+                                        structure.begin(
+                                            &mut pdf,
+                                            &mut content,
+                                            line.role,
+                                            line.block,
+                                        );
+                                    }
+                                    show_text(
+                                        &mut content,
+                                        slot,
+                                        &glyphs,
+                                        size,
+                                        (x, current_y + rise),
+                                    );
+                                    if structure.is_some() {
+                                        Structure::end(&mut content);
+                                    }
+                                    x += width;
+                                }
+                            }
+                            if run.style.color != Rgb::BLACK {
+                                fill_color(&mut content, Rgb::BLACK);
+                            }
+
+                            // Advance X by the width layout measured for the run
+                            if run.style.underline {
+                                let y = current_y - underline_offset;
+                                stroke(&mut content, current_x, current_x + run.width, y);
+                            }
+                            if run.style.strikeout {
+                                let x_height = font.metrics().x_height;
+                                let y = current_y + rise + x_height * size / 2.0;
+                                stroke(&mut content, current_x, current_x + run.width, y);
+                            }
+                            current_x += run.width;
+                        }
 
-                            // Advance X by approx. half the font size per character
-                            let advance_mm =
-                                Mm(run.text.len() as f64 * font_size_pt * 0.5 * 25.4 / 72.0);
-                            current_x += advance_mm;
+                        // Glue (inter-word spacing) and fixed space
+                        LayoutNode::Glue(w)
+                        | LayoutNode::Kern(w)
+                        | LayoutNode::Phantom { width: w, .. } => {
+                            current_x += *w;
                         }
 
-                        // Glue (inter-word spacing)
-                        LayoutNode::Glue(w) => {
-                            let glue_mm = Mm(*w * 25.4 / 72.0);
-                            current_x += glue_mm;
+                        // Images, each written the first time it is drawn
+                        LayoutNode::Image {
+                            path,
+                            width,
+                            height,
+                        } => {
+                            let at = match images.iter().position(|(drawn, _)| drawn == path) {
+                                Some(at) => at,
+                                None => {
+                                    let id = pdf.reserve();
+                                    write_image(&mut pdf, id, &load_image(path)?).map_err(io)?;
+                                    images.push((path.clone(), id));
+                                    images.len() - 1
+                                }
+                            };
+                            page_images.insert(at);
+                            let _ = write!(
+                                content,
+                                "q\n{:.2} 0 0 {:.2} {:.2} {:.2} cm\n/Im{} Do\nQ\n",
+                                width, height, current_x, current_y, at
+                            );
+                            current_x += *width;
+                        }
+
+                        // Rules, filled in the current (black) fill color
+                        LayoutNode::Rule {
+                            width,
+                            height,
+                            raise,
+                        } => {
+                            let (x0, x1) = (current_x, current_x + *width);
+                            let y0 = current_y + *raise;
+                            fill_rect(&mut content, (x0, y0), (x1, y0 + *height));
+                            current_x = x1;
                         }
+
+                        // The note itself is set at the foot of the page
+                        LayoutNode::Footnote(_) => {}
                     }
                 }
             }
 
             // Move down one line
-            current_y -= line_height_mm;
+            current_y -= line_height;
         }
+
+        // Write the page, the first object after its content
+        let contents = pdf.reserve();
+        pdf.stream(contents, "", content.as_bytes()).map_err(io)?;
+        let mut resources = String::new();
+        if !page_fonts.is_empty() {
+            resources.push_str("/Font<<");
+            for &slot in &page_fonts {
+                let id = embedded[slot].as_ref().expect("drawn from above").id;
+                let _ = write!(resources, "/F{} {} 0 R", slot, id);
+            }
+            resources.push_str(">>");
+        }
+        if !page_images.is_empty() {
+            resources.push_str("/XObject<<");
+            for &at in &page_images {
+                let _ = write!(resources, "/Im{} {} 0 R", at, images[at].1);
+            }
+            resources.push_str(">>");
+        }
+        let mut dict = format!(
+            "<</Type/Page/Parent {} 0 R/MediaBox[0 0 {:.2} {:.2}]/Resources<<{}>>/Contents {} 0 R",
+            page_tree, page_width, page_height, resources, contents
+        );
+        if let Some(structure) = &mut structure {
+            let _ = write!(dict, "/StructParents {}", structure.pages());
+            structure.finish_page(&mut pdf, page_id).map_err(io)?;
+        }
+        dict.push_str(">>");
+        pdf.object(page_id, &dict).map_err(io)?;
+        kids.push(page_id);
     }
 
-    // 5) Serialize PDF to bytes
-    let mut buffer = Vec::new();
-    {
-        let mut writer = BufWriter::new(&mut buffer);
-        doc.save(&mut writer).expect("Failed to write PDF");
+    let mut warnings = Vec::new();
+    if !missing.is_empty() {
+        warnings.push(missing_glyph_warning(&missing, opts.missing_glyph));
+    }
+    for warning in &warnings {
+        log::warn!("{}", warning);
+    }
+
+    // 5) End the file with the fonts, the page tree, an outline of the
+    // headings and the structure tree if tagged
+    for (font, file) in embedded.iter().zip(&files) {
+        if let Some(font) = font {
+            write_font(&mut pdf, font, file, opts.subset_fonts).map_err(io)?;
+        }
+    }
+    let kid_refs: Vec<String> = kids.iter().map(|id| format!("{} 0 R", id)).collect();
+    let tree = format!(
+        "<</Type/Pages/Kids[{}]/Count {}>>",
+        kid_refs.join(" "),
+        kids.len()
+    );
+    pdf.object(page_tree, &tree).map_err(io)?;
+    let mut catalog = format!("<</Type/Catalog/Pages {} 0 R", page_tree);
+    if bookmarks.is_empty() {
+        catalog.push_str("/PageMode/UseNone");
+    } else {
+        let root = pdf.reserve();
+        outline::write_outline(&mut pdf, root, &kids, &bookmarks).map_err(io)?;
+        let _ = write!(catalog, "/PageMode/UseOutlines/Outlines {} 0 R", root);
+    }
+    if let Some(structure) = structure {
+        let root = structure.finish(&mut pdf).map_err(io)?;
+        let _ = write!(
+            catalog,
+            "/MarkInfo<</Marked true>>/StructTreeRoot {} 0 R",
+            root
+        );
+    }
+    catalog.push_str(">>");
+    let root = pdf.reserve();
+    pdf.object(root, &catalog).map_err(io)?;
+
+    // Nothing changes from one deterministic run to the next: the file is
+    // dated `opts.now`, or the Unix epoch, and has a fixed ID.
+    let (date, id) = match opts.deterministic {
+        true => (opts.now.unwrap_or(UNIX_EPOCH), *FIXED_ID),
+        false => {
+            let now = opts.now.unwrap_or_else(SystemTime::now);
+            (now, random_id(now))
+        }
+    };
+    let date = pdf_date(date);
+    let info = pdf.reserve();
+    let dict = format!(
+        "<</Producer(latex_rs)/CreationDate({})/ModDate({})>>",
+        date, date
+    );
+    pdf.object(info, &dict).map_err(io)?;
+    pdf.finish(root, info, &id).map_err(io)?;
+    Ok(warnings)
+}
+
+/// `time` as a PDF date, in UTC.
+fn pdf_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (year, month, day) = civil_from_days(secs / 86_400);
+    let secs = secs % 86_400;
+    format!(
+        "D:{:04}{:02}{:02}{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// An ID for a file made at `time`, as hex digits, that no other file is
+/// likely to have.
+fn random_id(time: SystemTime) -> [u8; 32] {
+    let mut id = [0; 32];
+    for half in id.chunks_mut(16) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos()));
+        half.copy_from_slice(format!("{:016x}", hasher.finish()).as_bytes());
+    }
+    id
+}
+
+/// Write `image` as image object `id`, drawn as a unit square.
+fn write_image(pdf: &mut Writer, id: u32, image: &RgbImage) -> io::Result<()> {
+    let entries = format!(
+        "/Type/XObject/Subtype/Image/Width {}/Height {}/ColorSpace/DeviceRGB/BitsPerComponent 8/Filter/FlateDecode",
+        image.width(),
+        image.height()
+    );
+    pdf.stream(id, &entries, &pdf::deflate(image.as_raw()))
+}
+
+/// How tall `page` is drawn: as the paper, or in continuous mode as its
+/// lines with a margin above and below them.
+fn page_height(page: &Page, opts: &CompileOptions) -> Length {
+    match opts.pagination {
+        Pagination::Paged => opts.page_size.height(),
+        Pagination::Continuous => {
+            let line_height = opts.line_height();
+            let lines: f64 = page
+                .lines
+                .iter()
+                .map(|line| line.space_before + line.extra_height + line_height)
+                .sum();
+            Length::pt(lines + 2.0 * opts.margin.to_pt())
+        }
+    }
+}
+
+/// The pages `opts` asks to render, in order.
+fn selected_pages<'p>(pages: &'p [Page], opts: &CompileOptions) -> Vec<&'p Page> {
+    pages
+        .iter()
+        .enumerate()
+        .filter(|&(idx, _)| opts.renders_page(idx))
+        .map(|(_, page)| page)
+        .collect()
+}
+
+/// Decode the image at `path` as 8-bit RGB, which embeds without an alpha mask.
+fn load_image(path: &Path) -> Result<RgbImage, CompileError> {
+    let image = image::open(path).map_err(|e| CompileError::Image {
+        path: path.to_path_buf(),
+        message: e.to_string(),
+    })?;
+    Ok(image.to_rgb8())
+}
+
+/// Render laid-out pages as a single SVG document.
+///
+/// Pages are stacked top to bottom in one drawing, each in its own `<g>`
+/// group. Runs become `<text>` elements placed exactly as [`render_pdf`]
+/// places them, measured in points from the top-left corner.
+///
+/// # Parameters
+/// - `pages`: the output of your `layout()` function.
+/// - `opts`: the settings the pages were laid out with, for the paper and the line spacing,
+///   and which of the pages to render.
+pub fn render_svg(pages: &[Page], opts: &CompileOptions) -> String {
+    let pages = selected_pages(pages, opts);
+    let page_w = opts.page_size.width().to_pt();
+    let heights: Vec<f64> = pages
+        .iter()
+        .map(|page| page_height(page, opts).to_pt())
+        .collect();
+    let margin = opts.margin.to_pt();
+    let font_size = opts.font_size.to_pt();
+    let line_height = opts.line_height();
+    let total_h = match heights.len() {
+        0 => opts.page_size.height().to_pt(),
+        _ => heights.iter().sum(),
+    };
+
+    // Writing to a String cannot fail, so the fmt::Results below are ignored.
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{:.2}pt" height="{:.2}pt" viewBox="0 0 {:.2} {:.2}">"#,
+        page_w, total_h, page_w, total_h
+    );
+    let mut page_top = 0.0;
+    for (page, page_h) in pages.iter().zip(heights) {
+        let _ = writeln!(
+            svg,
+            r#"<g transform="translate(0 {:.2})" font-family="DejaVu Sans" font-size="{}">"#,
+            page_top, font_size
+        );
+        page_top += page_h;
+        let _ = writeln!(
+            svg,
+            r#"<rect width="{:.2}" height="{:.2}" fill="white"/>"#,
+            page_w, page_h
+        );
+        let mut y = margin;
+        let mut column = 0;
+        for line in &page.lines {
+            if line.column != column {
+                column = line.column;
+                y = margin;
+            }
+            y += line.space_before + line.extra_height;
+            let mut x = margin + line.x_offset;
+            for hbox in &line.boxes {
+                for node in &hbox.items {
+                    match node {
+                        LayoutNode::Run(run) => {
+                            let (size, rise) =
+                                run_metrics(&run.style, font_size, font_size * LEADING);
+                            let _ = write!(svg, r#"<text x="{:.2}" y="{:.2}""#, x, y - rise);
+                            if size != font_size {
+                                let _ = write!(svg, r#" font-size="{:.1}""#, size);
+                            }
+                            match run.style.face {
+                                FontFace::Normal => {}
+                                FontFace::Bold => svg.push_str(r#" font-weight="bold""#),
+                                FontFace::Italic => svg.push_str(r#" font-style="italic""#),
+                                FontFace::Monospace => {
+                                    svg.push_str(r#" font-family="DejaVu Sans Mono""#)
+                                }
+                            }
+                            match (run.style.underline, run.style.strikeout) {
+                                (false, false) => {}
+                                (true, false) => svg.push_str(r#" text-decoration="underline""#),
+                                (false, true) => svg.push_str(r#" text-decoration="line-through""#),
+                                (true, true) => {
+                                    svg.push_str(r#" text-decoration="underline line-through""#)
+                                }
+                            }
+                            // Verbatim lines keep their runs of spaces.
+                            if run.text.contains(' ') {
+                                svg.push_str(r#" xml:space="preserve""#);
+                            }
+                            let Rgb { r, g, b } = run.style.color;
+                            if run.style.color != Rgb::BLACK {
+                                let _ = write!(svg, r##" fill="#{:02x}{:02x}{:02x}""##, r, g, b);
+                            }
+                            let _ = writeln!(svg, ">{}</text>", xml_escape(&run.text));
+                            x += run.width;
+                        }
+                        LayoutNode::Glue(w)
+                        | LayoutNode::Kern(w)
+                        | LayoutNode::Phantom { width: w, .. } => x += w,
+                        LayoutNode::Image {
+                            path,
+                            width,
+                            height,
+                        } => {
+                            let _ = writeln!(
+                                svg,
+                                r#"<image href="{}" x="{:.2}" y="{:.2}" width="{:.2}" height="{:.2}"/>"#,
+                                xml_escape(&path.to_string_lossy()),
+                                x,
+                                y - height,
+                                width,
+                                height
+                            );
+                            x += width;
+                        }
+                        LayoutNode::Rule {
+                            width,
+                            height,
+                            raise,
+                        } => {
+                            let _ = writeln!(
+                                svg,
+                                r#"<rect x="{:.2}" y="{:.2}" width="{:.2}" height="{:.2}"/>"#,
+                                x,
+                                y - raise - height,
+                                width,
+                                height
+                            );
+                            x += width;
+                        }
+                        LayoutNode::Footnote(_) => {}
+                    }
+                }
+            }
+            y += line_height;
+        }
+        svg.push_str("</g>\n");
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Escape the characters that are special in XML text and attributes.
+fn xml_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Render the expanded document as HTML, which reflows in a browser.
+///
+/// Unlike [`render_svg`], nothing is placed: paragraphs, headings, lists and
+/// tables become the elements of the same name, and styled text becomes
+/// `<strong>`, `<em>` and `<code>`, so that the browser breaks the lines to
+/// fit its window. Footnotes are listed at the end, linked from their marks.
+///
+/// # Parameters
+/// - `doc`: the expanded document, as `layout()` takes it.
+/// - `opts`: the settings for the line spacing, paragraph indentation and columns.
+pub fn render_html(doc: &Node, opts: &CompileOptions) -> String {
+    let mut labels = Labels::default();
+    let _ = labels.visit(doc);
+    let mut html = Html {
+        labels: labels.0,
+        ..Html::default()
+    };
+    html.node(doc);
+    html.end_paragraph();
+    if !html.footnotes.is_empty() {
+        html.out.push_str("<section class=\"footnotes\">\n<ol>\n");
+        // A footnote may have footnotes of its own, numbered after the rest.
+        let mut i = 0;
+        while i < html.footnotes.len() {
+            let note = html.footnotes[i].clone();
+            i += 1;
+            let open = format!(r#"<li id="fn{}">"#, i);
+            html.flow(&open, "</li>\n", &note);
+        }
+        html.out.push_str("</ol>\n</section>\n");
+    }
+
+    let mut page = String::new();
+    page.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<style>\n");
+    let _ = writeln!(
+        page,
+        "body {{ max-width: 40em; margin: auto; line-height: {}; }}",
+        LEADING * opts.line_spacing
+    );
+    let _ = writeln!(
+        page,
+        "p + p {{ text-indent: {}pt; }}",
+        opts.paragraph_indent.to_pt()
+    );
+    page.push_str("p.noindent { text-indent: 0; }\nli > p { margin: 0; }\n");
+    page.push_str(".math { font-style: italic; }\n");
+    page.push_str("table { border-collapse: collapse; }\n");
+    page.push_str(".samepage { break-inside: avoid; }\n");
+    let _ = writeln!(page, "pre {{ tab-size: {}; }}", opts.tab_width);
+    if opts.columns > 1 {
+        let _ = writeln!(
+            page,
+            "main {{ column-count: {}; column-gap: {}pt; }}",
+            opts.columns,
+            opts.column_gap.to_pt()
+        );
+    }
+    page.push_str("</style>\n</head>\n<body>\n<main>\n");
+    page.push_str(&html.out);
+    page.push_str("</main>\n</body>\n</html>\n");
+    page
+}
+
+/// The section number of every label in a document, by key.
+#[derive(Default)]
+struct Labels(HashMap<String, String>);
+
+impl Visitor for Labels {
+    type Error = Infallible;
+
+    fn visit(&mut self, node: &Node) -> Result<Node, Infallible> {
+        if let Node::Label { key, number } = node {
+            self.0.insert(key.clone(), number.clone());
+        }
+        walk(self, node)
+    }
+}
+
+/// The body of an HTML document, as it is written.
+#[derive(Default)]
+struct Html {
+    out: String,
+    labels: HashMap<String, String>,
+    /// A `<p>`, or an element holding only text such as a heading, is open.
+    in_paragraph: bool,
+    /// The next word follows the last without a space, as after a tie.
+    tied: bool,
+    /// The next paragraph is not indented, after `\noindent`.
+    noindent: bool,
+    /// Inside math, where no spaces are written between the atoms.
+    math: bool,
+    /// Footnote bodies met so far, in order.
+    footnotes: Vec<Vec<Node>>,
+}
+
+impl Html {
+    fn node(&mut self, node: &Node) {
+        match node {
+            Node::Text(text) => self.words(text, &TextStyle::default()),
+            Node::StyledText(text, style) => self.words(text, style),
+            Node::Seq(children) => self.all(children),
+            Node::Macro { args, .. } => self.all(args),
+            Node::Environment { name, body } => match name.as_str() {
+                "quote" | "quotation" => self.flow("<blockquote>\n", "</blockquote>\n", body),
+                "abstract" => self.flow(
+                    "<blockquote class=\"abstract\">\n<p class=\"noindent\"><strong>Abstract</strong></p>\n",
+                    "</blockquote>\n",
+                    body,
+                ),
+                _ => {
+                    let open = match Alignment::from_environment(name) {
+                        Some(Alignment::Left) => r#"<div style="text-align: left">"#.to_string(),
+                        Some(Alignment::Center) => {
+                            r#"<div style="text-align: center">"#.to_string()
+                        }
+                        Some(Alignment::Right) => r#"<div style="text-align: right">"#.to_string(),
+                        None => format!(r#"<div class="{}">"#, xml_escape(name)),
+                    };
+                    self.flow(&(open + "\n"), "</div>\n", body);
+                }
+            },
+            Node::Table { spec, rows, hlines } => {
+                self.end_paragraph();
+                match spec.width {
+                    Some(TableWidth::Fixed(length)) => {
+                        let _ = writeln!(
+                            self.out,
+                            r#"<table style="width: {:.2}pt">"#,
+                            length.to_pt()
+                        );
+                    }
+                    Some(TableWidth::Text(fraction)) => {
+                        let _ = writeln!(
+                            self.out,
+                            r#"<table style="width: {}%">"#,
+                            fraction * 100.0
+                        );
+                    }
+                    None => self.out.push_str("<table>\n"),
+                }
+                let n_cols = rows
+                    .iter()
+                    .map(Vec::len)
+                    .max()
+                    .unwrap_or(0)
+                    .max(spec.align.len());
+                let rule = |j: usize| spec.rules.get(j) == Some(&true);
+                for (i, row) in rows.iter().enumerate() {
+                    self.out.push_str("<tr>");
+                    for (j, cell) in row.iter().enumerate() {
+                        let mut style = Vec::new();
+                        match spec.align.get(j) {
+                            Some(ColumnAlign::Center) => style.push("text-align: center"),
+                            Some(ColumnAlign::Right) => style.push("text-align: right"),
+                            Some(ColumnAlign::Left) | None => {}
+                        }
+                        if rule(j) {
+                            style.push("border-left: 1px solid");
+                        }
+                        if j + 1 == n_cols && rule(n_cols) {
+                            style.push("border-right: 1px solid");
+                        }
+                        if hlines.contains(&i) {
+                            style.push("border-top: 1px solid");
+                        }
+                        if i + 1 == rows.len() && hlines.contains(&rows.len()) {
+                            style.push("border-bottom: 1px solid");
+                        }
+                        let open = match style.is_empty() {
+                            true => "<td>".to_string(),
+                            false => format!(r#"<td style="{}">"#, style.join("; ")),
+                        };
+                        self.phrasing(&open, "</td>", cell);
+                    }
+                    self.out.push_str("</tr>\n");
+                }
+                self.out.push_str("</table>\n");
+            }
+            Node::Heading {
+                level,
+                number,
+                title,
+            } => {
+                let tag = format!("h{}", level + 1);
+                let open = match number.as_str() {
+                    "" => format!("<{}>", tag),
+                    number => format!("<{}>{} ", tag, xml_escape(number)),
+                };
+                self.phrasing(&open, &format!("</{}>\n", tag), title);
+            }
+            Node::TitleBlock {
+                title,
+                author,
+                date,
+            } => {
+                self.end_paragraph();
+                self.out.push_str("<header>\n");
+                for (open, close, part) in [
+                    ("<h1>", "</h1>\n", title),
+                    ("<p class=\"author\">", "</p>\n", author),
+                    ("<p class=\"date\">", "</p>\n", date),
+                ] {
+                    if !part.is_empty() {
+                        self.phrasing(open, close, part);
+                    }
+                }
+                self.out.push_str("</header>\n");
+            }
+            Node::ParBreak => self.end_paragraph(),
+            Node::NoIndent => self.noindent = true,
+            Node::LineBreak { .. } if self.in_paragraph => {
+                self.out.push_str("<br>\n");
+                self.tied = true;
+            }
+            Node::Math(body) => {
+                self.space();
+                self.out.push_str("<span class=\"math\">");
+                self.math_atoms(body);
+                self.out.push_str("</span>");
+            }
+            Node::Verbatim(text) => {
+                self.end_paragraph();
+                let _ = writeln!(self.out, "<pre>{}</pre>", xml_escape(text.trim_matches('\n')));
+            }
+            Node::Footnote(body) => {
+                self.footnotes.push(body.clone());
+                self.open_paragraph();
+                let _ = write!(
+                    self.out,
+                    r##"<sup><a href="#fn{0}">{0}</a></sup>"##,
+                    self.footnotes.len()
+                );
+            }
+            Node::Label { key, .. } => {
+                let _ = write!(self.out, r#"<a id="{}"></a>"#, xml_escape(key));
+            }
+            // With no pages to name, a page reference names the section too.
+            Node::Ref { key, .. } => {
+                self.space();
+                match self.labels.get(key) {
+                    Some(number) => {
+                        let _ = write!(
+                            self.out,
+                            r##"<a href="#{}">{}</a>"##,
+                            xml_escape(key),
+                            xml_escape(number)
+                        );
+                    }
+                    None => self.out.push_str("??"),
+                }
+            }
+            Node::Superscript(arg) => {
+                self.open_paragraph();
+                self.out.push_str("<sup>");
+                self.math_atoms(std::slice::from_ref(arg));
+                self.out.push_str("</sup>");
+            }
+            Node::Subscript(arg) => {
+                self.open_paragraph();
+                self.out.push_str("<sub>");
+                self.math_atoms(std::slice::from_ref(arg));
+                self.out.push_str("</sub>");
+            }
+            Node::Frac { num, den } => {
+                self.space();
+                self.out.push_str("<sup>");
+                self.math_atoms(num);
+                self.out.push_str("</sup>&frasl;<sub>");
+                self.math_atoms(den);
+                self.out.push_str("</sub>");
+            }
+            Node::NonBreakingSpace => {
+                self.open_paragraph();
+                self.out.push_str("&nbsp;");
+                self.tied = true;
+            }
+            Node::DiscretionaryHyphen => {
+                self.out.push_str("&shy;");
+                self.tied = true;
+            }
+            Node::HSpace(width) => {
+                self.open_paragraph();
+                let _ = write!(
+                    self.out,
+                    r#"<span style="margin-left: {:.2}pt"></span>"#,
+                    width.to_pt()
+                );
+                self.tied = true;
+            }
+            Node::Phantom {
+                body,
+                width,
+                height,
+            } => {
+                self.open_paragraph();
+                let style = match (width, height) {
+                    (true, false) => "visibility: hidden; display: inline-block; height: 0",
+                    (false, true) => "visibility: hidden; display: inline-block; width: 0",
+                    _ => "visibility: hidden",
+                };
+                let _ = write!(self.out, r#"<span style="{}">"#, style);
+                self.tied = true;
+                self.all(body);
+                self.out.push_str("</span>");
+                self.tied = true;
+            }
+            Node::VSpace(height) => {
+                self.end_paragraph();
+                let _ = writeln!(self.out, r#"<div style="margin-top: {:.2}pt"></div>"#, height);
+            }
+            Node::Image { path, width, .. } => {
+                self.end_paragraph();
+                let _ = write!(
+                    self.out,
+                    r#"<img src="{}" alt="""#,
+                    xml_escape(&path.to_string_lossy())
+                );
+                if let Some(width) = width {
+                    let _ = write!(self.out, r#" style="width: {:.2}pt""#, width.to_pt());
+                }
+                self.out.push_str(">\n");
+            }
+            Node::Rule { width, height } => {
+                self.end_paragraph();
+                let _ = write!(self.out, r#"<hr style="height: {:.2}pt"#, height.to_pt());
+                if let Some(width) = width {
+                    let _ = write!(self.out, "; width: {:.2}pt", width.to_pt());
+                }
+                self.out.push_str("\">\n");
+            }
+            Node::List { kind, items } => {
+                let tag = match kind {
+                    ListKind::Itemize => "ul",
+                    ListKind::Enumerate => "ol",
+                    ListKind::Description => "dl",
+                };
+                self.end_paragraph();
+                let _ = writeln!(self.out, "<{}>", tag);
+                for item in items {
+                    match (kind, &item.label) {
+                        (ListKind::Description, label) => {
+                            self.phrasing("<dt>", "</dt>\n", label.as_deref().unwrap_or_default());
+                            self.flow("<dd>", "</dd>\n", &item.body);
+                        }
+                        // The label is set in place of the marker.
+                        (_, Some(label)) => {
+                            let nodes: Vec<Node> = label.iter().chain(&item.body).cloned().collect();
+                            self.flow(r#"<li style="list-style: none">"#, "</li>\n", &nodes);
+                        }
+                        (_, None) => self.flow("<li>", "</li>\n", &item.body),
+                    }
+                }
+                let _ = writeln!(self.out, "</{}>", tag);
+            }
+            // A page of HTML is as long as its content, so fills have nothing to fill.
+            Node::LineBreak { .. } | Node::Comment(_) | Node::VFill => {}
+        }
+    }
+
+    fn all(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            self.node(node);
+        }
+    }
+
+    /// `nodes` as math, with no spaces between them.
+    fn math_atoms(&mut self, nodes: &[Node]) {
+        let outer = std::mem::replace(&mut self.math, true);
+        self.all(nodes);
+        self.math = outer;
+    }
+
+    /// `nodes` inside an element that holds paragraphs, such as a list item.
+    fn flow(&mut self, open: &str, close: &str, nodes: &[Node]) {
+        self.end_paragraph();
+        self.out.push_str(open);
+        self.all(nodes);
+        self.end_paragraph();
+        self.out.push_str(close);
+    }
+
+    /// `nodes` inside an element that holds only text, such as a heading.
+    fn phrasing(&mut self, open: &str, close: &str, nodes: &[Node]) {
+        self.end_paragraph();
+        self.out.push_str(open);
+        self.in_paragraph = true;
+        self.tied = true;
+        self.all(nodes);
+        self.out.push_str(close);
+        self.in_paragraph = false;
+    }
+
+    /// The words of `text`, in the elements that give them `style`.
+    fn words(&mut self, text: &str, style: &TextStyle) {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        if !words.is_empty() {
+            self.space();
+            let close = self.open_style(style);
+            self.out.push_str(&xml_escape(&words.join(" ")));
+            self.out.push_str(&close);
+        }
+        // Space inside a run of text separates it from what follows, too.
+        if text.ends_with(char::is_whitespace) {
+            self.tied = false;
+        }
+    }
+
+    /// Open the elements that give text `style`, returning the tags that close them.
+    fn open_style(&mut self, style: &TextStyle) -> String {
+        let mut tags = Vec::new();
+        match style.script {
+            Script::Baseline => {}
+            Script::Superscript | Script::Numerator => tags.push(("<sup>".to_string(), "</sup>")),
+            Script::Subscript | Script::Denominator => tags.push(("<sub>".to_string(), "</sub>")),
+        }
+        match style.face {
+            FontFace::Normal => {}
+            FontFace::Bold => tags.push(("<strong>".to_string(), "</strong>")),
+            FontFace::Italic => tags.push(("<em>".to_string(), "</em>")),
+            FontFace::Monospace => tags.push(("<code>".to_string(), "</code>")),
+        }
+        if style.underline {
+            tags.push(("<u>".to_string(), "</u>"));
+        }
+        if style.strikeout {
+            tags.push(("<s>".to_string(), "</s>"));
+        }
+        let mut css = Vec::new();
+        if style.color != Rgb::BLACK {
+            let Rgb { r, g, b } = style.color;
+            css.push(format!("color: #{:02x}{:02x}{:02x}", r, g, b));
+        }
+        if style.scale != 1.0 && style.script == Script::Baseline {
+            css.push(format!("font-size: {}em", style.scale));
+        }
+        if !css.is_empty() {
+            tags.push((format!(r#"<span style="{}">"#, css.join("; ")), "</span>"));
+        }
+        for (open, _) in &tags {
+            self.out.push_str(open);
+        }
+        tags.iter().rev().map(|(_, close)| *close).collect()
+    }
+
+    /// Open a paragraph for text, unless one is open.
+    fn open_paragraph(&mut self) {
+        if !self.in_paragraph {
+            self.out.push_str(match std::mem::take(&mut self.noindent) {
+                true => "<p class=\"noindent\">",
+                false => "<p>",
+            });
+            self.in_paragraph = true;
+            self.tied = true;
+        }
+    }
+
+    /// Open a paragraph, or separate what follows from the last word.
+    fn space(&mut self) {
+        self.open_paragraph();
+        if !self.tied && !self.math {
+            self.out.push(' ');
+        }
+        self.tied = false;
+    }
+
+    fn end_paragraph(&mut self) {
+        if self.in_paragraph {
+            self.out.push_str("</p>\n");
+            self.in_paragraph = false;
+        }
     }
-    buffer
 }
 
 #[cfg(test)]
@@ -101,12 +1610,411 @@ mod tests {
     use super::*;
     use crate::ast::Node;
     use crate::layout::layout;
+    use crate::options::CompileOptions;
 
     #[test]
     fn test_render_pdf_header() {
         let ast = Node::Seq(vec![Node::Text("Hello PDF".into())]);
-        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0);
-        let pdf_data = render_pdf(&pages);
+        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let pdf_data = render_pdf(&pages, &CompileOptions::default(), &FontCache::new()).unwrap();
         assert!(pdf_data.starts_with(b"%PDF-"));
     }
+
+    /// Run `src` through the whole pipeline, returning the pages and the PDF.
+    fn render(src: &str) -> (Vec<Page>, Vec<u8>) {
+        let ast = crate::expand::expand_macros(&crate::parser::parse(src).unwrap()).unwrap();
+        let pages = layout(&ast, 500.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let pdf = render_pdf(&pages, &CompileOptions::default(), &FontCache::new()).unwrap();
+        (pages, pdf)
+    }
+
+    /// The single run on the first line of the first page.
+    fn first_run(pages: &[Page]) -> &crate::layout::StyledRun {
+        match &pages[0].lines[0].boxes[0].items[0] {
+            LayoutNode::Run(run) => run,
+            other => panic!("Expected a run, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_textit_round_trip() {
+        let (pages, pdf) = render(r"\textit{slanted}");
+        assert_eq!(first_run(&pages).style.face, FontFace::Italic);
+        assert!(pdf.starts_with(b"%PDF-"));
+    }
+
+    #[test]
+    fn test_texttt_round_trip() {
+        let (pages, pdf) = render(r"\texttt{code}");
+        assert_eq!(first_run(&pages).style.face, FontFace::Monospace);
+        // Monospace advances are wider than the proportional 6pt.
+        assert!((pages[0].lines[0].width - 4.0 * 7.2).abs() < 1e-9);
+        assert!(pdf.windows(14).any(|w| w == b"DejaVuSansMono"));
+    }
+
+    #[test]
+    fn test_textbackslash_round_trip() {
+        let (pages, pdf) = render(r"C:\textbackslash{}temp");
+        assert_eq!(first_run(&pages).text, "C:\\temp");
+        // The glyph drawn maps back to a backslash.
+        assert!(pdf.windows(6).any(|w| w == b"<005c>"));
+    }
+
+    #[test]
+    fn test_underline_round_trip() {
+        let (pages, pdf) = render(r"\underline{ruled}");
+        assert!(first_run(&pages).style.underline);
+        // The underline is stroked as a path: a move-to, a line-to, then `S`.
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.contains(" m\n") && text.contains(" l\nS"));
+        let (_, plain) = render("ruled");
+        assert!(!String::from_utf8_lossy(&plain).contains(" l\nS"));
+    }
+
+    #[test]
+    fn test_strikeout_is_stroked_at_half_the_x_height() {
+        let (pages, pdf) = render(r"\sout{struck}");
+        let run = first_run(&pages);
+        assert!(run.style.strikeout);
+        // The path is `x0 y m`, `x1 y l`, `S`, in points from the bottom
+        // left, to two decimals.
+        let text = String::from_utf8_lossy(&pdf);
+        let end = text.find(" l\nS").expect("a stroked path");
+        let path: Vec<f64> = text[..end]
+            .rsplit('\n')
+            .take(2)
+            .flat_map(|line| line.split(' ').filter_map(|n| n.parse().ok()))
+            .collect();
+        let (to, from) = (&path[..2], &path[2..4]);
+        let line = &pages[0].lines[0];
+        let opts = CompileOptions::default();
+        let top = opts.page_size.height().to_pt() - opts.margin.to_pt();
+        let baseline = top - line.space_before - line.extra_height;
+        // DejaVu Sans has an x-height of 1120 units to the 2048 em.
+        let y = baseline + 1120.0 / 2048.0 * opts.font_size.to_pt() / 2.0;
+        assert!((from[0] - opts.margin.to_pt()).abs() < 0.01);
+        assert!((to[0] - from[0] - run.width).abs() < 0.01);
+        assert!((from[1] - y).abs() < 0.01 && (to[1] - y).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_rules_are_filled_rectangles() {
+        let (pages, pdf) = render(r"above \rule{3cm}{1pt} \hrule below");
+        let widths: Vec<f64> = pages[0]
+            .lines
+            .iter()
+            .flat_map(|line| &line.boxes)
+            .flat_map(|hb| &hb.items)
+            .filter_map(|item| match item {
+                LayoutNode::Rule { width, .. } => Some(*width),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(widths.len(), 2);
+        assert!((widths[0] - Length::cm(3.0).to_pt()).abs() < 1e-9);
+        // `\hrule` spans the whole 500pt line.
+        assert_eq!(widths[1], 500.0);
+        // Four corners, closed and filled with `f`.
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.contains(" l\nf"));
+        let (_, plain) = render("above below");
+        assert!(!String::from_utf8_lossy(&plain).contains(" l\nf"));
+    }
+
+    #[test]
+    fn test_background_color_fills_the_whole_page_first() {
+        let ast = Node::Seq(vec![Node::Text("Slide".into())]);
+        let opts = CompileOptions {
+            background_color: Some(Rgb::new(0xfd, 0xf6, 0xe3)),
+            ..CompileOptions::default()
+        };
+        let pages = layout(&ast, 500.0, 20.0, 6.0, 6.0, &opts);
+        let pdf = render_pdf(&pages, &opts, &FontCache::new()).unwrap();
+        let text = String::from_utf8_lossy(&pdf);
+        // The page, from the origin to its far corner in points, filled in
+        // the background color before any text is begun.
+        let (width, height) = (opts.page_size.width(), opts.page_size.height());
+        let rect = format!(
+            "0.00 0.00 m\n{w:.2} 0.00 l\n{w:.2} {h:.2} l\n0.00 {h:.2} l\nf",
+            w = width.to_pt(),
+            h = height.to_pt()
+        );
+        let fill = text.find(&rect).expect("a full-page rectangle");
+        let stream = text[..fill].rfind("stream").unwrap();
+        assert!(!text[stream..fill].contains("BT\n"));
+        assert!(text[fill..].contains("BT\n"));
+        let color: Vec<f64> = text[..fill]
+            .lines()
+            .last()
+            .and_then(|line| line.strip_suffix(" rg"))
+            .expect("a fill color set just before")
+            .split(' ')
+            .map(|n| n.parse().unwrap())
+            .collect();
+        let expected = [0xfd, 0xf6, 0xe3].map(|c: u8| f64::from(c) / 255.0);
+        assert!(
+            color
+                .iter()
+                .zip(expected)
+                .all(|(c, e)| (c - e).abs() < 0.01)
+        );
+        // Without one, no rectangle is drawn.
+        let plain = render_pdf(&pages, &CompileOptions::default(), &FontCache::new()).unwrap();
+        assert!(!String::from_utf8_lossy(&plain).contains(" l\nf"));
+    }
+
+    #[test]
+    fn test_render_svg_words() {
+        let (pages, _) = render(r"Hello \textbf{bold} a<b");
+        let svg = render_svg(&pages, &CompileOptions::default());
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.contains(">Hello</text>"));
+        assert!(svg.contains(r#"font-weight="bold">bold</text>"#));
+        assert!(svg.contains(">a&lt;b</text>"));
+        assert_eq!(svg.matches("<text ").count(), 3);
+    }
+
+    #[test]
+    fn test_render_html_is_semantic() {
+        let html = |src: &str| {
+            let ast = crate::expand::expand_macros(&crate::parser::parse(src).unwrap()).unwrap();
+            render_html(&ast, &CompileOptions::default())
+        };
+        assert!(html(r"\textbf{Hi}").contains("<p><strong>Hi</strong></p>"));
+        let page = html(
+            r"\section{Intro} Some \emph{styled} a<b.
+
+            \begin{itemize} \item One \end{itemize}",
+        );
+        assert!(page.starts_with("<!DOCTYPE html>"));
+        assert!(page.contains("<h2>1 Intro</h2>\n<p>Some <em>styled</em> a&lt;b.</p>\n"));
+        assert!(page.contains("<ul>\n<li><p>One</p>\n</li>\n</ul>"));
+        let terms = html(r"\begin{description} \item[Apple] A fruit \end{description}");
+        assert!(terms.contains("<dl>\n<dt>Apple</dt>\n<dd><p>A fruit</p>\n</dd>\n</dl>"));
+        let table = html(r"\begin{tabular}{l|r} \hline a & b \end{tabular}");
+        assert!(table.contains(
+            r#"<tr><td style="border-top: 1px solid">a</td><td style="text-align: right; border-left: 1px solid; border-top: 1px solid">b</td></tr>"#
+        ));
+    }
+
+    #[test]
+    fn test_second_column_starts_at_the_top() {
+        let opts = CompileOptions {
+            columns: 2,
+            ..CompileOptions::default()
+        };
+        let ast = Node::Seq(
+            (0..80)
+                .flat_map(|i| {
+                    [
+                        Node::Text(format!("l{}", i)),
+                        Node::LineBreak {
+                            space: None,
+                            no_page_break: false,
+                        },
+                    ]
+                })
+                .collect(),
+        );
+        let pages = layout(&ast, 210.0, 20.0, 6.0, 6.0, &opts);
+        let svg = render_svg(&pages, &opts);
+        let text_tag = |word: &str| -> &str {
+            let end = svg.find(&format!(">{}</text>", word)).unwrap();
+            &svg[svg[..end].rfind("<text ").unwrap()..end]
+        };
+        let (left, right) = (text_tag("l0"), text_tag("l40"));
+        assert!(left.contains(r#"x="28.35""#));
+        assert!(right.contains(r#"x="138.35""#));
+        assert_eq!(left.split(" y=").nth(1), right.split(" y=").nth(1));
+    }
+
+    #[test]
+    fn test_vspace_moves_lines_down() {
+        let y_of = |svg: &str, word: &str| -> f64 {
+            let end = svg.find(&format!(">{}</text>", word)).unwrap();
+            let start = svg[..end].rfind(r#" y=""#).unwrap() + 4;
+            svg[start..end].trim_end_matches('"').parse().unwrap()
+        };
+        let (pages, _) = render(r"above \vspace{10mm} below");
+        let svg = render_svg(&pages, &CompileOptions::default());
+        let gap = y_of(&svg, "below") - y_of(&svg, "above");
+        // One 14.4pt line plus 10mm (28.35pt) of space.
+        assert!((gap - (14.4 + 28.35)).abs() < 0.01, "gap was {}", gap);
+    }
+
+    #[test]
+    fn test_superscript_is_raised() {
+        let (pages, pdf) = render("$x^2$");
+        assert!(pdf.starts_with(b"%PDF-"));
+        let svg = render_svg(&pages, &CompileOptions::default());
+        assert!(svg.contains(r#"y="28.35">x</text>"#));
+        // 70% of 12pt, raised by 30% of the 14.4pt line height.
+        assert!(svg.contains(r#"y="24.03" font-size="8.4">2</text>"#));
+    }
+
+    #[test]
+    fn test_includegraphics_embeds_an_image() {
+        let (pages, pdf) = render(r"before \includegraphics[width=3cm]{fixtures/red.png} after");
+        assert_eq!(pages[0].lines.len(), 3);
+        let image_line = &pages[0].lines[1];
+        // 3cm wide at a 2:1 aspect ratio, less the 20pt line height.
+        assert!((image_line.extra_height - (Length::cm(1.5).to_pt() - 20.0)).abs() < 1e-9);
+        let pdf = String::from_utf8_lossy(&pdf);
+        assert!(pdf.contains("/Subtype /Image") || pdf.contains("/Subtype/Image"));
+        assert!(pdf.contains(" Do"));
+    }
+
+    #[test]
+    fn test_missing_glyphs_are_replaced() {
+        let (cache, fonts) = (FontCache::new(), Fonts::default());
+        let sans = font_data(&fonts, &FontFace::Normal, &cache).unwrap();
+        let mono = font_data(&fonts, &FontFace::Monospace, &cache).unwrap();
+        let mut missing = Vec::new();
+        let mut runs = |fallback, text| {
+            font_runs(&sans, fallback, text, '\u{25a1}', &mut missing)
+                .into_iter()
+                .map(|(font, text)| (std::ptr::eq(font, &sans), text.into_owned()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(runs(None, "café"), [(true, "café".into())]);
+        assert_eq!(
+            runs(None, "漢字 ok"),
+            [(true, "\u{25a1}\u{25a1} ok".into())]
+        );
+        // U+2312 is only in the monospace face.
+        assert_eq!(
+            runs(Some(&mono), "a\u{2312}b"),
+            [
+                (true, "a".into()),
+                (false, "\u{2312}".into()),
+                (true, "b".into())
+            ]
+        );
+        // The replacement itself must be drawable.
+        assert_eq!(runs(None, "\u{25a1}"), [(true, "\u{25a1}".into())]);
+        assert_eq!(missing, ['漢', '字']);
+    }
+
+    #[test]
+    fn test_bad_font_path_is_an_error() {
+        let ast = Node::Seq(vec![Node::Text("Hello".into())]);
+        let opts = CompileOptions::default();
+        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &opts);
+        let with_bold = |path: &str| CompileOptions {
+            fonts: crate::options::Fonts {
+                bold: Some(PathBuf::from(path)),
+                ..Default::default()
+            },
+            ..CompileOptions::default()
+        };
+
+        let missing = render_pdf(&pages, &with_bold("fonts/missing.ttf"), &FontCache::new());
+        assert!(matches!(missing, Err(CompileError::Font { .. })));
+        // An image is not a font.
+        let not_a_font = render_pdf(&pages, &with_bold("fixtures/red.png"), &FontCache::new());
+        match not_a_font {
+            Err(CompileError::Font { path, .. }) => {
+                assert_eq!(path, PathBuf::from("fixtures/red.png"))
+            }
+            other => panic!("Expected a font error, got {:?}", other.map(|_| ())),
+        }
+        // Any real font file works in place of the bundled one.
+        let other_face = render_pdf(
+            &pages,
+            &with_bold("fonts/DejaVuSansMono.ttf"),
+            &FontCache::new(),
+        );
+        assert!(other_face.unwrap().starts_with(b"%PDF-"));
+    }
+
+    #[test]
+    fn test_font_files_are_read_once_per_cache() {
+        let path = std::env::temp_dir().join(format!("latex_rs-{}-cached.ttf", std::process::id()));
+        fs::copy("fonts/DejaVuSansMono.ttf", &path).unwrap();
+        let opts = CompileOptions {
+            fonts: crate::options::Fonts {
+                normal: Some(path.clone()),
+                ..Default::default()
+            },
+            ..CompileOptions::default()
+        };
+        let pages = layout(&Node::text("Hello"), 100.0, 20.0, 6.0, 6.0, &opts);
+
+        let cache = FontCache::new();
+        assert!(render_pdf(&pages, &opts, &cache).is_ok());
+        fs::remove_file(&path).unwrap();
+        // The second document is built from the bytes read for the first.
+        let second = render_pdf(&pages, &opts, &cache).unwrap();
+        assert!(second.starts_with(b"%PDF-"));
+        assert!(matches!(
+            render_pdf(&pages, &opts, &FontCache::new()),
+            Err(CompileError::Font { .. })
+        ));
+    }
+
+    #[test]
+    fn test_a_second_document_parses_no_fonts() {
+        let opts = CompileOptions {
+            kerning: true,
+            ligatures: true,
+            fonts: crate::options::Fonts {
+                fallback: Some("fonts/DejaVuSansMono.ttf".into()),
+                ..Default::default()
+            },
+            ..CompileOptions::default()
+        };
+        let src = "AV \\textbf{fine} \\textit{flow} \\texttt{x} \u{2312} \\sout{x}";
+        let ast = crate::expand::expand_macros(&crate::parser::parse(src).unwrap()).unwrap();
+        let pages = layout(&ast, 400.0, 20.0, 6.0, 6.0, &opts);
+        let cache = FontCache::new();
+        let first = render_pdf(&pages, &opts, &cache).unwrap();
+        let parses = cache.parses.load(Ordering::Relaxed);
+        // Each file once.
+        assert_eq!(parses, 5);
+        let second = render_pdf(&pages, &opts, &cache).unwrap();
+        assert_eq!(cache.parses.load(Ordering::Relaxed), parses);
+        assert_eq!(first.len(), second.len());
+    }
+
+    #[test]
+    fn test_only_drawn_glyphs_of_drawn_faces_are_embedded() {
+        let (pages, pdf) = render("Hello");
+        let count =
+            |pdf: &[u8], what: &[u8]| pdf.windows(what.len()).filter(|w| *w == what).count();
+        assert_eq!(count(&pdf, b"/FontFile2"), 1);
+
+        let whole = CompileOptions {
+            subset_fonts: false,
+            ..CompileOptions::default()
+        };
+        let whole = render_pdf(&pages, &whole, &FontCache::new()).unwrap();
+        assert_eq!(count(&whole, b"/FontFile2"), 1);
+        assert!(pdf.len() < whole.len());
+        let font_len = |pdf: &[u8]| {
+            let at = pdf.windows(9).position(|w| w == b"/Length1 ").unwrap() + 9;
+            let digits = pdf[at..].iter().take_while(|b| b.is_ascii_digit()).count();
+            std::str::from_utf8(&pdf[at..at + digits])
+                .unwrap()
+                .parse::<usize>()
+                .unwrap()
+        };
+        assert!(font_len(&pdf) * 10 < font_len(&whole));
+
+        // Two faces set in the same file embed it once.
+        let mono = PathBuf::from("fonts/DejaVuSansMono.ttf");
+        let opts = CompileOptions {
+            fonts: crate::options::Fonts {
+                normal: Some(mono.clone()),
+                bold: Some(mono),
+                ..Default::default()
+            },
+            ..CompileOptions::default()
+        };
+        let ast =
+            crate::expand::expand_macros(&crate::parser::parse(r"Hello \textbf{world}").unwrap())
+                .unwrap();
+        let pages = layout(&ast, 500.0, 20.0, 6.0, 6.0, &opts);
+        let pdf = render_pdf(&pages, &opts, &FontCache::new()).unwrap();
+        assert_eq!(count(&pdf, b"/FontFile2"), 1);
+    }
 }
@@ -1,5 +1,6 @@
-use clap::Parser;
-use std::path::PathBuf;
+use clap::{Parser, ValueEnum};
+use latex_rs::{CompileOptions, Length, PageRange, PageSize};
+use std::path::{Path, PathBuf};
 
 /// Command-line arguments for the `latexc` binary.
 #[derive(Parser)]
@@ -8,11 +9,254 @@ use std::path::PathBuf;
 #[command(version = "0.1.0")]
 #[command(about = "Compile a .tex file to PDF using latex_rs library", long_about = None)]
 pub struct Cli {
-    /// Input TeX source file (.tex)
+    /// Input TeX source file (.tex); reads from stdin when omitted
     #[arg(short, long, value_name = "FILE")]
-    pub input: PathBuf,
+    pub input: Option<PathBuf>,
 
-    /// Output PDF file
-    #[arg(short, long, value_name = "PDF")]
-    pub output: PathBuf,
+    /// Output file, or `-` to write to stdout (required when emitting a document)
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+
+    /// What to produce: the rendered document, or the parsed AST as JSON on stdout
+    #[arg(long, value_enum, default_value_t = Emit::Pdf)]
+    pub emit: Emit,
+
+    /// File format of the rendered document
+    #[arg(long, value_enum, default_value_t = Format::Pdf)]
+    pub format: Format,
+
+    /// TOML file of compile options, such as `page_size = "letter"`
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Render only these pages of the document, e.g. `2-3` or `5`
+    #[arg(long, value_name = "RANGE", value_parser = page_range)]
+    pub pages: Option<PageRange>,
+
+    /// Paper to set the document on, `a4` or `letter`
+    #[arg(long, value_name = "PAPER", value_parser = page_size)]
+    pub page_size: Option<PageSize>,
+
+    /// Margin on every side of the page, e.g. `25mm` or `1in`
+    #[arg(long, value_name = "LENGTH", value_parser = length)]
+    pub margin: Option<Length>,
+
+    /// Size of the body text, e.g. `11pt`
+    #[arg(long, value_name = "LENGTH", value_parser = length)]
+    pub font_size: Option<Length>,
+
+    /// Stretch the spaces of paragraph lines so that they fill the measure
+    #[arg(long, conflicts_with = "ragged_right")]
+    pub justify: bool,
+
+    /// Leave the right edge of paragraphs ragged, even if the config file
+    /// justifies them
+    #[arg(long)]
+    pub ragged_right: bool,
+
+    /// Keep running and recompile whenever the input file changes
+    #[arg(short, long, requires = "input")]
+    pub watch: bool,
+
+    /// Print diagnostics, such as the page geometry, to stderr
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// Print where and why each line of a PDF was broken to stderr
+    #[arg(long)]
+    pub trace: bool,
+
+    /// Print the document's word, character and paragraph counts instead
+    /// of compiling it
+    #[arg(long, conflicts_with_all = ["watch", "trace"])]
+    pub stats: bool,
+
+    /// Open the written document in the system's default viewer
+    #[arg(long, conflicts_with = "watch")]
+    pub open: bool,
+}
+
+/// The kind of output `latexc` produces.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Emit {
+    Pdf,
+    Ast,
+}
+
+/// The file format of a rendered document.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Format {
+    Pdf,
+    Svg,
+    Html,
+}
+
+fn page_range(spec: &str) -> Result<PageRange, String> {
+    PageRange::parse(spec).ok_or_else(|| "expected a page or pages such as 5 or 2-3".to_string())
+}
+
+fn page_size(spec: &str) -> Result<PageSize, String> {
+    match spec.to_ascii_lowercase().as_str() {
+        "a4" => Ok(PageSize::A4),
+        "letter" => Ok(PageSize::Letter),
+        _ => Err("expected a4 or letter".to_string()),
+    }
+}
+
+fn length(spec: &str) -> Result<Length, String> {
+    Length::parse(spec).ok_or_else(|| "expected a length such as 12pt or 2cm".to_string())
+}
+
+impl Cli {
+    /// Set the options the flags give in `opts`, over what the config file
+    /// set there.
+    pub fn override_options(&self, opts: &mut CompileOptions) {
+        if self.pages.is_some() {
+            opts.pages = self.pages;
+        }
+        if let Some(page_size) = self.page_size {
+            opts.page_size = page_size;
+        }
+        if let Some(margin) = self.margin {
+            opts.margin = margin;
+        }
+        if let Some(font_size) = self.font_size {
+            opts.font_size = font_size;
+        }
+        if self.justify || self.ragged_right {
+            opts.justify = self.justify;
+        }
+        opts.trace |= self.trace;
+    }
+
+    /// Whether the document should be written to stdout instead of a file.
+    pub fn writes_to_stdout(&self) -> bool {
+        self.output.as_deref() == Some(Path::new("-"))
+    }
+
+    /// The written document `--open` should open, if it was given: the
+    /// output file, or a warning when the document went to stdout and
+    /// there is no file to open.
+    pub fn file_to_open(&self) -> Result<Option<&Path>, &'static str> {
+        match self.output.as_deref() {
+            _ if !self.open => Ok(None),
+            Some(path) if path != Path::new("-") => Ok(Some(path)),
+            _ => Err("--open has no file to open when the document is written to stdout"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stdin_stdout() {
+        let cli = Cli::parse_from(["latexc", "-o", "-"]);
+        assert!(cli.input.is_none());
+        assert!(cli.writes_to_stdout());
+    }
+
+    #[test]
+    fn test_file_paths() {
+        let cli = Cli::parse_from(["latexc", "-i", "doc.tex", "-o", "doc.pdf"]);
+        assert_eq!(cli.input, Some(PathBuf::from("doc.tex")));
+        assert!(!cli.writes_to_stdout());
+    }
+
+    #[test]
+    fn test_emit_ast() {
+        let cli = Cli::parse_from(["latexc", "-i", "doc.tex", "--emit", "ast"]);
+        assert_eq!(cli.emit, Emit::Ast);
+        assert!(cli.output.is_none());
+    }
+
+    #[test]
+    fn test_format_svg() {
+        let cli = Cli::parse_from(["latexc", "-o", "doc.svg", "--format", "svg"]);
+        assert_eq!(cli.format, Format::Svg);
+        assert_eq!(cli.emit, Emit::Pdf);
+    }
+
+    #[test]
+    fn test_open_the_output_file() {
+        let cli = Cli::parse_from(["latexc", "-o", "doc.pdf", "--open"]);
+        assert_eq!(cli.file_to_open(), Ok(Some(Path::new("doc.pdf"))));
+        let cli = Cli::parse_from(["latexc", "-o", "doc.pdf"]);
+        assert_eq!(cli.file_to_open(), Ok(None));
+        // Written to stdout, the document is only warned about.
+        let cli = Cli::parse_from(["latexc", "-o", "-", "--open"]);
+        assert!(cli.file_to_open().is_err());
+    }
+
+    #[test]
+    fn test_watch_needs_an_input_file() {
+        let cli = Cli::parse_from(["latexc", "-i", "doc.tex", "-o", "doc.pdf", "--watch"]);
+        assert!(cli.watch);
+        assert!(Cli::try_parse_from(["latexc", "-o", "doc.pdf", "--watch"]).is_err());
+    }
+
+    #[test]
+    fn test_config_file() {
+        let cli = Cli::parse_from(["latexc", "-o", "doc.pdf", "--config", "doc.toml"]);
+        assert_eq!(cli.config, Some(PathBuf::from("doc.toml")));
+        assert!(
+            Cli::parse_from(["latexc", "-o", "doc.pdf"])
+                .config
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_page_range() {
+        let cli = Cli::parse_from(["latexc", "-o", "doc.pdf", "--pages", "2-3"]);
+        assert_eq!(cli.pages, Some(PageRange { first: 2, last: 3 }));
+        let cli = Cli::parse_from(["latexc", "-o", "doc.pdf", "--pages", "4"]);
+        assert_eq!(cli.pages, Some(PageRange { first: 4, last: 4 }));
+        for bad in ["3-2", "0", "two"] {
+            assert!(Cli::try_parse_from(["latexc", "-o", "doc.pdf", "--pages", bad]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_page_settings() {
+        let cli = Cli::parse_from([
+            "latexc",
+            "-o",
+            "doc.pdf",
+            "--page-size",
+            "Letter",
+            "--margin",
+            "1in",
+            "--font-size",
+            "11pt",
+            "--justify",
+        ]);
+        assert_eq!(cli.page_size, Some(PageSize::Letter));
+        assert_eq!(cli.margin.map(Length::to_pt), Some(72.0));
+        assert_eq!(cli.font_size.map(Length::to_pt), Some(11.0));
+        assert!(cli.justify);
+        for bad in [
+            ["--page-size", "a5"],
+            ["--margin", "wide"],
+            ["--justify", "--ragged-right"],
+        ] {
+            assert!(Cli::try_parse_from(["latexc", "-o", "doc.pdf", bad[0], bad[1]]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_quiet_by_default() {
+        assert!(!Cli::parse_from(["latexc", "-o", "doc.pdf"]).verbose);
+        assert!(Cli::parse_from(["latexc", "-o", "doc.pdf", "-v"]).verbose);
+        assert!(!Cli::parse_from(["latexc", "-o", "doc.pdf"]).trace);
+        assert!(Cli::parse_from(["latexc", "-o", "doc.pdf", "--trace"]).trace);
+    }
+
+    #[test]
+    fn test_stats_need_no_output() {
+        let cli = Cli::parse_from(["latexc", "-i", "doc.tex", "--stats"]);
+        assert!(cli.stats && cli.output.is_none());
+        assert!(Cli::try_parse_from(["latexc", "-i", "doc.tex", "--stats", "--watch"]).is_err());
+    }
 }
@@ -0,0 +1,133 @@
+//! Compile options from a `--config` file.
+//!
+//! The file is TOML whose keys are the fields of `CompileOptions`, with
+//! tables such as `[fonts]` and `[footer]` for the nested ones. Keys left
+//! out keep their defaults.
+
+use latex_rs::CompileOptions;
+use std::fs;
+use std::path::Path;
+
+/// The options the config file at `path` sets, the defaults for the rest.
+pub fn load(path: &Path) -> Result<CompileOptions, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// The options set by config file `text`.
+fn parse(text: &str) -> Result<CompileOptions, String> {
+    toml::from_str(text).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Cli;
+    use clap::Parser;
+    use latex_rs::{Alignment, LineBreakMode, PageSize, Rgb, compile_with};
+
+    #[test]
+    fn test_settings_and_defaults() {
+        let opts = parse(
+            r#"
+            # Two columns of Letter, double spaced
+            page_size = "letter"
+            line_break = "knuth_plass"   # total fit
+            columns = 2
+            column_gap = "0.5in"
+            line_spacing = 2.0
+            background_color = '#fdf6e3'
+
+            [fonts]
+            monospace = 'C:\fonts\mono.ttf'
+
+            [footer]
+            text = "Page {page}"
+            align = "center"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(opts.page_size, PageSize::Letter);
+        assert_eq!(opts.line_break, LineBreakMode::KnuthPlass);
+        assert_eq!(opts.columns, 2);
+        assert_eq!(opts.column_gap.to_pt(), 36.0);
+        assert_eq!(opts.line_spacing, 2.0);
+        assert_eq!(opts.background_color, Some(Rgb::new(0xfd, 0xf6, 0xe3)));
+        assert_eq!(
+            opts.fonts.monospace.as_deref(),
+            Some(Path::new(r"C:\fonts\mono.ttf"))
+        );
+        assert!(opts.fonts.normal.is_none());
+        let footer = opts.footer.unwrap();
+        assert_eq!(
+            (footer.text.as_str(), footer.align),
+            ("Page {page}", Alignment::Center)
+        );
+        assert!(opts.header.is_none());
+        assert!(!opts.hyphenate);
+    }
+
+    #[test]
+    fn test_mistakes_are_reported() {
+        assert!(parse("colums = 2").unwrap_err().contains("colums"));
+        assert!(
+            parse("columns = 2\ncolumns = 3")
+                .unwrap_err()
+                .contains("line 2")
+        );
+        assert!(parse("column_gap = \"wide\"").unwrap_err().contains("wide"));
+        assert!(
+            parse("background_color = \"#fdf6e\"")
+                .unwrap_err()
+                .contains("#fdf6e")
+        );
+        assert!(parse("[fonts").unwrap_err().contains("line 1"));
+    }
+
+    #[test]
+    fn test_flags_override_the_file() {
+        let file = r#"
+            page_size = "letter"
+            margin = "20mm"
+            font_size = "10pt"
+            justify = true
+            "#;
+        let options = |args: &[&str]| {
+            let mut opts = parse(file).unwrap();
+            let cli = Cli::parse_from(["latexc", "-o", "doc.pdf"].iter().chain(args));
+            cli.override_options(&mut opts);
+            opts
+        };
+
+        // What no flag sets is as the file has it.
+        let opts = options(&[]);
+        assert_eq!(opts.page_size, PageSize::Letter);
+        assert_eq!(opts.margin.to_mm(), 20.0);
+        assert_eq!(opts.font_size.to_pt(), 10.0);
+        assert!(opts.justify);
+
+        let opts = options(&["--margin", "1in", "--ragged-right", "--page-size", "a4"]);
+        assert_eq!(opts.page_size, PageSize::A4);
+        assert_eq!(opts.margin.to_pt(), 72.0);
+        assert_eq!(opts.font_size.to_pt(), 10.0);
+        assert!(!opts.justify);
+
+        let opts = options(&["--font-size", "14pt", "--justify"]);
+        assert_eq!(opts.font_size.to_pt(), 14.0);
+        assert_eq!(opts.margin.to_mm(), 20.0);
+        assert!(opts.justify);
+    }
+
+    #[test]
+    fn test_letter_paper_from_a_file() {
+        let name = format!("latexc-config-{}.toml", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, "page_size = \"letter\"\n").unwrap();
+        let opts = load(&path);
+        fs::remove_file(&path).unwrap();
+        let pdf = compile_with("Hello", &opts.unwrap()).unwrap();
+        let pdf = String::from_utf8_lossy(&pdf);
+        assert!(pdf.contains("/MediaBox[0 0 612.00 792.00]"));
+    }
+}
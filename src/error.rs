@@ -0,0 +1,242 @@
+use std::fmt;
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// A location in the TeX source, both as a byte offset and as line/column.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourcePos {
+    /// Byte offset into the input.
+    pub offset: usize,
+    /// 1-based line number.
+    pub line: usize,
+    /// 1-based column, counted in characters.
+    pub column: usize,
+}
+
+/// How serious a [`Diagnostic`] is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    /// The document cannot be compiled.
+    Error,
+    /// The document compiles, but likely not as intended.
+    Warning,
+}
+
+/// A problem found by [`validate`](crate::validate), for editors to show
+/// alongside the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub severity: Severity,
+    /// Byte range of the offending source.
+    pub span: Range<usize>,
+}
+
+impl Diagnostic {
+    pub(crate) fn error(message: impl Into<String>, span: Range<usize>) -> Self {
+        Diagnostic {
+            message: message.into(),
+            severity: Severity::Error,
+            span,
+        }
+    }
+}
+
+/// An error produced while compiling a document.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    /// The source could not be parsed.
+    Parse {
+        message: String,
+        pos: SourcePos,
+        /// The offending source line, for display.
+        snippet: String,
+    },
+    /// A `{` is never closed, or a `}` closes nothing.
+    UnbalancedBraces {
+        message: String,
+        pos: SourcePos,
+        snippet: String,
+    },
+    /// `\textcolor` was given a color that is neither known nor `#RRGGBB`.
+    UnknownColor(String),
+    /// A length such as `\vspace`'s argument is not a number with a known unit.
+    InvalidLength(String),
+    /// A setting in the options is out of its range.
+    InvalidOption(String),
+    /// An `\includegraphics` file is missing or not a readable image.
+    Image { path: PathBuf, message: String },
+    /// A font file given in the options is missing or not a usable font.
+    Font { path: PathBuf, message: String },
+    /// A file named by `\input` or `\include` is missing, does not parse,
+    /// or inputs itself.
+    Input { path: PathBuf, message: String },
+    /// Groups, arguments or environments are nested more than `limit` deep,
+    /// at `pos` if the parser found it.
+    NestingTooDeep {
+        limit: usize,
+        pos: Option<SourcePos>,
+    },
+    /// A command that nothing interprets, refused as the options ask; at
+    /// `pos`, its first use, if it is in the main input.
+    UnknownCommand {
+        name: String,
+        pos: Option<SourcePos>,
+    },
+    /// None of the pages the options ask to render exist.
+    NoPagesInRange {
+        first: usize,
+        last: usize,
+        page_count: usize,
+    },
+    /// The finished document could not be written out.
+    Io(String),
+}
+
+impl CompileError {
+    /// A parse error at byte `offset` of `input`.
+    pub(crate) fn parse(input: &str, offset: usize, message: impl Into<String>) -> Self {
+        let (pos, snippet) = locate(input, offset);
+        CompileError::Parse {
+            message: message.into(),
+            pos,
+            snippet,
+        }
+    }
+
+    /// An unmatched brace at byte `offset` of `input`.
+    pub(crate) fn unbalanced_braces(
+        input: &str,
+        offset: usize,
+        message: impl Into<String>,
+    ) -> Self {
+        let (pos, snippet) = locate(input, offset);
+        CompileError::UnbalancedBraces {
+            message: message.into(),
+            pos,
+            snippet,
+        }
+    }
+
+    /// Nesting past `limit` at byte `offset` of `input`.
+    pub(crate) fn nesting_too_deep(input: &str, offset: usize, limit: usize) -> Self {
+        CompileError::NestingTooDeep {
+            limit,
+            pos: Some(locate(input, offset).0),
+        }
+    }
+
+    /// This error, placed at the first use in `input` of the command it
+    /// names if it is an unknown command with no position yet.
+    pub(crate) fn locate_command(self, input: &str) -> Self {
+        match self {
+            CompileError::UnknownCommand { name, pos: None } => {
+                let command = format!("\\{}", name);
+                let offset = input.match_indices(&command).find_map(|(offset, _)| {
+                    let rest = &input[offset + command.len()..];
+                    // `\\foo` is a line break, and `\foobar` another command.
+                    let whole = !input[..offset].ends_with('\\')
+                        && !rest.starts_with(|c: char| c.is_ascii_alphabetic());
+                    whole.then_some(offset)
+                });
+                let pos = offset.map(|offset| locate(input, offset).0);
+                CompileError::UnknownCommand { name, pos }
+            }
+            other => other,
+        }
+    }
+
+    /// Where in the source the error occurred, if it is tied to a location.
+    pub fn pos(&self) -> Option<&SourcePos> {
+        match self {
+            CompileError::Parse { pos, .. } | CompileError::UnbalancedBraces { pos, .. } => {
+                Some(pos)
+            }
+            CompileError::NestingTooDeep { pos, .. } | CompileError::UnknownCommand { pos, .. } => {
+                pos.as_ref()
+            }
+            CompileError::UnknownColor(_)
+            | CompileError::InvalidLength(_)
+            | CompileError::InvalidOption(_)
+            | CompileError::Image { .. }
+            | CompileError::Font { .. }
+            | CompileError::Input { .. }
+            | CompileError::NoPagesInRange { .. }
+            | CompileError::Io(_) => None,
+        }
+    }
+}
+
+/// The position of byte `offset` of `input`, and the source line it is on.
+fn locate(input: &str, offset: usize) -> (SourcePos, String) {
+    let (line, column) = crate::lexer::line_col(input, offset);
+    let pos = SourcePos {
+        offset,
+        line,
+        column,
+    };
+    (pos, input.lines().nth(line - 1).unwrap_or("").to_string())
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::Parse {
+                message,
+                pos,
+                snippet,
+            }
+            | CompileError::UnbalancedBraces {
+                message,
+                pos,
+                snippet,
+            } => {
+                writeln!(f, "{}:{}: {}", pos.line, pos.column, message)?;
+                writeln!(f, "  | {}", snippet)?;
+                write!(f, "  | {:>width$}", "^", width = pos.column)
+            }
+            CompileError::UnknownColor(name) => write!(f, "Unknown color `{}`", name),
+            CompileError::InvalidLength(spec) => {
+                write!(
+                    f,
+                    "Invalid length `{}` (expected e.g. 3mm, 12pt or 1.5cm)",
+                    spec
+                )
+            }
+            CompileError::InvalidOption(message) => write!(f, "Invalid option: {}", message),
+            CompileError::Image { path, message } => {
+                write!(f, "Cannot read image `{}`: {}", path.display(), message)
+            }
+            CompileError::Font { path, message } => {
+                write!(f, "Cannot load font `{}`: {}", path.display(), message)
+            }
+            CompileError::Input { path, message } => {
+                write!(f, "Cannot input `{}`: {}", path.display(), message)
+            }
+            CompileError::NestingTooDeep { limit, pos } => {
+                if let Some(pos) = pos {
+                    write!(f, "{}:{}: ", pos.line, pos.column)?;
+                }
+                write!(f, "Nested more than {} levels deep", limit)
+            }
+            CompileError::UnknownCommand { name, pos } => {
+                if let Some(pos) = pos {
+                    write!(f, "{}:{}: ", pos.line, pos.column)?;
+                }
+                write!(f, "Unknown command `\\{}`", name)
+            }
+            CompileError::NoPagesInRange {
+                first,
+                last,
+                page_count,
+            } => write!(
+                f,
+                "No pages to render: asked for pages {}-{} of {}",
+                first, last, page_count
+            ),
+            CompileError::Io(message) => write!(f, "Cannot write output: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
@@ -1,76 +1,763 @@
-use crate::ast::{Node, TextStyle};
-/// Perform macro-expansion on the AST for MVP.
-/// Currently we don't support user-defined macros (\newcommand),
-/// so this pass simply:
-/// 1. Flattens nested sequences (Seq within Seq).
-/// 2. Recurses into macro arguments for built-ins (e.g. textbf) to prepare for layout.
-
-fn collect_plain_text(node: &Node) -> String {
-    match node {
-        Node::Text(s) | Node::StyledText(s, _) => s.clone(),
-        Node::Seq(children) => children
-            .iter()
-            .map(collect_plain_text)
-            .collect::<Vec<_>>()
-            .join(" "),
-        _ => String::new(), // or panic!("Unexpected node in textbf")
+//! Perform macro-expansion on the AST for MVP.
+//! Source cannot define macros (`\newcommand`), but a host program can,
+//! through a [`MacroRegistry`]. Otherwise this pass simply:
+//! 1. Flattens nested sequences (Seq within Seq).
+//! 2. Recurses into macro arguments for built-ins (e.g. textbf) to prepare for layout.
+//! 3. Applies style declarations (e.g. `\bfseries`) to the rest of their group,
+//!    and sets the rest of a group after `\centering`, `\raggedright` or
+//!    `\raggedleft` as the matching alignment environment.
+
+use crate::ast::{FontFace, Node, Rgb, SIZES, Script, TextStyle, Visitor};
+use crate::error::CompileError;
+use crate::options::{CompileOptions, FONT_SIZE_PT, MAX_NESTING, UnknownCommand};
+use crate::units::Length;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What a registered macro does with its expanded arguments.
+type MacroFn = dyn Fn(&[Node]) -> Result<Node, CompileError> + Send + Sync;
+
+/// Macros defined by the program compiling a document rather than by the
+/// document, e.g. a `\version` giving the build it was made with.
+///
+/// Registered macros are expanded before the built-in ones, which they
+/// replace when they share a name. What one returns is expanded in turn.
+///
+/// ```
+/// use latex_rs::{CompileOptions, MacroRegistry, Node};
+///
+/// let mut macros = MacroRegistry::new();
+/// macros.register("version", |_| Ok(Node::text("1.4.2")));
+/// let opts = CompileOptions {
+///     macros,
+///     ..CompileOptions::default()
+/// };
+/// assert!(latex_rs::compile_with(r"Built by version \version.", &opts).is_ok());
+/// ```
+#[derive(Clone, Default)]
+pub struct MacroRegistry {
+    macros: HashMap<String, Arc<MacroFn>>,
+}
+
+impl MacroRegistry {
+    pub fn new() -> Self {
+        MacroRegistry::default()
+    }
+
+    /// Define `\name`, which `expand` turns into a node, given the
+    /// invocation's arguments, already expanded. A later definition of the
+    /// same name replaces the earlier one.
+    pub fn register<F>(&mut self, name: impl Into<String>, expand: F) -> &mut Self
+    where
+        F: Fn(&[Node]) -> Result<Node, CompileError> + Send + Sync + 'static,
+    {
+        self.macros.insert(name.into(), Arc::new(expand));
+        self
     }
 }
 
-/// Expand macros in the AST (identity + flattening pass).
-pub fn expand_macros(ast: &Node) -> Node {
-    match ast {
-        // For a sequence, recursively expand children and flatten nested Seq.
+impl fmt::Debug for MacroRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<&String> = self.macros.keys().collect();
+        names.sort();
+        f.debug_set().entries(names).finish()
+    }
+}
+
+/// Thickness of an `\hrule`, as in TeX.
+const HRULE_THICKNESS: Length = Length::pt(0.4);
+
+pub(crate) fn collect_plain_text(node: &Node) -> String {
+    match node {
+        Node::Text(s) | Node::StyledText(s, _) => s.clone(),
+        Node::NonBreakingSpace => '\u{a0}'.to_string(),
+        Node::DiscretionaryHyphen => '\u{ad}'.to_string(),
         Node::Seq(children) => {
-            let mut flat_children = Vec::new();
+            // Siblings are separated by one space, except on either side of
+            // a tie or a discretionary hyphen. Those that give no text, such
+            // as an empty group, give no space either.
+            let mut text = String::new();
+            let mut tied = true;
             for child in children {
-                let exp = expand_macros(child);
-                match exp {
-                    Node::Seq(inner) => flat_children.extend(inner),
-                    other => flat_children.push(other),
+                let piece = collect_plain_text(child);
+                if child.joins_neighbours() {
+                    text.push_str(&piece);
+                    tied = true;
+                } else if !piece.is_empty() {
+                    if !tied {
+                        text.push(' ');
+                    }
+                    text.push_str(&piece);
+                    tied = false;
                 }
             }
-            Node::Seq(flat_children)
+            text
         }
+        _ => String::new(), // or panic!("Unexpected node in textbf")
+    }
+}
+
+/// Expand macros in the AST (identity + flattening pass).
+pub fn expand_macros(ast: &Node) -> Result<Node, CompileError> {
+    Expander::default().visit(ast)
+}
+
+/// Expand macros in the AST with the settings in `opts`, such as the date
+/// `\today` gives.
+pub(crate) fn expand_with(ast: &Node, opts: &CompileOptions) -> Result<Node, CompileError> {
+    Expander {
+        now: opts.now,
+        max_nesting: opts.max_nesting,
+        macros: opts.macros.clone(),
+        unknown_commands: opts.unknown_commands,
+        font_size: opts.font_size.to_pt(),
+        ..Expander::default()
+    }
+    .visit(ast)
+}
 
-        // Whitespace nodes are unchanged; they'll become glue later.
-        // Node::Whitespace(n) => Node::Whitespace(*n),
+/// State carried through one expansion of a document.
+struct Expander {
+    /// Section counters at the current point, outermost first.
+    sections: Vec<u32>,
+    /// The style set by enclosing declarations.
+    scope: TextStyle,
+    /// The time `\today` is taken from, instead of the system clock.
+    now: Option<SystemTime>,
+    /// How many groups enclose the current node.
+    depth: usize,
+    /// How many may, before expansion gives up.
+    max_nesting: usize,
+    /// What `\title`, `\author` and `\date` last gave, for `\maketitle`.
+    title: Vec<Node>,
+    author: Vec<Node>,
+    date: Option<Vec<Node>>,
+    /// Macros the host program defined.
+    macros: MacroRegistry,
+    /// What becomes of commands that nothing interprets.
+    unknown_commands: UnknownCommand,
+    /// Size of the body text, in points, which fixed spaces are measured in.
+    font_size: f64,
+}
 
-        // For macro invocations, expand their arguments.
-        // We leave the Macro node intact so layout/backends can interpret built-in commands
-        Node::Macro { name, args } => {
-            // First, expand children of the macro
-            let expanded_args = args.iter().map(expand_macros).collect::<Vec<_>>();
+impl Default for Expander {
+    fn default() -> Self {
+        Expander {
+            sections: Vec::new(),
+            scope: TextStyle::default(),
+            now: None,
+            depth: 0,
+            max_nesting: MAX_NESTING,
+            title: Vec::new(),
+            author: Vec::new(),
+            date: None,
+            macros: MacroRegistry::default(),
+            unknown_commands: UnknownCommand::default(),
+            font_size: FONT_SIZE_PT,
+        }
+    }
+}
+
+impl Visitor for Expander {
+    type Error = CompileError;
 
-            // If it’s a styling macro with exactly one argument that is text (or Seq of Text),
-            // collapse it into a StyledText node:
-            if name == "textbf" && expanded_args.len() == 1 {
-                // Flatten that one argument into a single String
-                let content = collect_plain_text(&expanded_args[0]);
-                return Node::StyledText(content, TextStyle::Bold);
+    /// Expand a list of sibling nodes, flattening any resulting sequences.
+    ///
+    /// The siblings form one group: a declaration among them restyles or
+    /// realigns the siblings after it, but not the text following the group.
+    fn visit_seq(&mut self, nodes: &[Node]) -> Result<Vec<Node>, CompileError> {
+        // The document's own sequence is not counted.
+        if self.depth > self.max_nesting {
+            return Err(CompileError::NestingTooDeep {
+                limit: self.max_nesting,
+                pos: None,
+            });
+        }
+        self.depth += 1;
+        let outer = self.scope.clone();
+        let mut flat = Vec::new();
+        for (i, node) in nodes.iter().enumerate() {
+            if let Some(declared) = declaration(node, &self.scope) {
+                self.scope = declared;
+                continue;
             }
-            if name == "emph" && expanded_args.len() == 1 {
-                let content = collect_plain_text(&expanded_args[0]);
-                return Node::StyledText(content, TextStyle::Italic);
+            if let Some(name) = alignment_declaration(node) {
+                let body = self.visit_seq(&nodes[i + 1..])?;
+                flat.push(Node::Environment {
+                    name: name.into(),
+                    body,
+                });
+                break;
             }
+            match self.visit(node)? {
+                Node::Seq(inner) => flat.extend(inner),
+                other => flat.push(other),
+            }
+        }
+        self.scope = outer;
+        self.depth -= 1;
+        Ok(flat)
+    }
+
+    fn visit_text(&mut self, text: &str) -> Result<Node, CompileError> {
+        Ok(if self.scope == TextStyle::default() {
+            Node::Text(text.to_string())
+        } else {
+            Node::StyledText(text.to_string(), self.scope.clone())
+        })
+    }
+
+    fn visit_styled_text(&mut self, text: &str, style: &TextStyle) -> Result<Node, CompileError> {
+        Ok(Node::StyledText(text.to_string(), nest(&self.scope, style)))
+    }
+
+    /// Expand the arguments of a macro invocation, then interpret the
+    /// registered and built-in commands.
+    fn visit_macro(
+        &mut self,
+        name: &str,
+        opt_args: &[Node],
+        args: &[Node],
+        starred: bool,
+    ) -> Result<Node, CompileError> {
+        // A styling macro sets its argument as a declaration sets the rest
+        // of its group, so that math, breaks and nested styles in it stay.
+        if let (Some(style), [arg]) = (styling_macro(name), args)
+            && !self.macros.macros.contains_key(name)
+        {
+            let outer = self.scope.clone();
+            self.scope = nest(&outer, &style);
+            let styled = self.visit(arg);
+            self.scope = outer;
+            return styled;
+        }
+
+        // First, expand children of the macro
+        let expanded_args = args
+            .iter()
+            .map(|arg| self.visit(arg))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Macros the host defined come first. What they return is expanded
+        // as one more level of nesting, so one that returns itself is stopped.
+        if let Some(expand) = self.macros.macros.get(name).cloned() {
+            let node = expand(&expanded_args)?;
+            if self.depth > self.max_nesting {
+                return Err(CompileError::NestingTooDeep {
+                    limit: self.max_nesting,
+                    pos: None,
+                });
+            }
+            self.depth += 1;
+            let expanded = self.visit(&node);
+            self.depth -= 1;
+            return expanded;
+        }
+
+        self.builtin(name, opt_args, args, expanded_args, starred)
+    }
+}
+
+impl Expander {
+    /// Interpret built-in command `name`, whose arguments are expanded, and
+    /// which was invoked with a star if `starred`. Other macros are left
+    /// intact, with their arguments flattened, so layout can interpret them.
+    ///
+    /// Kept apart from `visit_macro`, so that registered macros expanding
+    /// into one another do not pay for this frame at every level.
+    fn builtin(
+        &mut self,
+        name: &str,
+        opt_args: &[Node],
+        args: &[Node],
+        expanded_args: Vec<Node>,
+        starred: bool,
+    ) -> Result<Node, CompileError> {
+        // `\textcolor{color}{text}` recolours its text but keeps its structure.
+        if name == "textcolor" && expanded_args.len() == 2 {
+            let color = parse_color(&collect_plain_text(&expanded_args[0]))?;
+            return Ok(recolor(&expanded_args[1], color));
+        }
+
+        // `\hspace{length}` becomes a fixed horizontal gap.
+        if name == "hspace" && expanded_args.len() == 1 {
+            let spec = collect_plain_text(&expanded_args[0]);
+            let width = Length::parse(&spec).ok_or(CompileError::InvalidLength(spec))?;
+            return Ok(Node::HSpace(width));
+        }
+
+        // `\quad`, `\,` and the other fixed spaces are gaps of so many ems
+        // of the text they are in.
+        if let Some(ems) = fixed_space(name)
+            && expanded_args.is_empty()
+        {
+            let em = self.font_size * self.scope.scale;
+            return Ok(Node::HSpace(Length::pt(ems * em)));
+        }
+
+        // `\vspace{length}` becomes vertical space, measured in points.
+        if name == "vspace" && expanded_args.len() == 1 {
+            let spec = collect_plain_text(&expanded_args[0]);
+            let height = Length::parse(&spec).ok_or(CompileError::InvalidLength(spec))?;
+            return Ok(Node::VSpace(height.to_pt()));
+        }
+
+        // The `\TeX` and `\LaTeX` logos are set as plain words.
+        if let ("TeX" | "LaTeX", []) = (name, args) {
+            return self.visit_text(name);
+        }
+
+        // `\today` is the date of compilation, unless the options fix one.
+        if let ("today", []) = (name, args) {
+            let now = self.now.unwrap_or_else(SystemTime::now);
+            return self.visit_text(&format_date(now));
+        }
+
+        // `\rule{width}{height}` and `\hrule` become filled rectangles.
+        if name == "rule" && expanded_args.len() == 2 {
+            let [width, height] = [&expanded_args[0], &expanded_args[1]].map(|arg| {
+                let spec = collect_plain_text(arg);
+                Length::parse(&spec).ok_or(CompileError::InvalidLength(spec))
+            });
+            return Ok(Node::Rule {
+                width: Some(width?),
+                height: height?,
+            });
+        }
+        if name == "hrule" && expanded_args.is_empty() {
+            return Ok(Node::Rule {
+                width: None,
+                height: HRULE_THICKNESS,
+            });
+        }
+        if name == "noindent" && expanded_args.is_empty() {
+            return Ok(Node::NoIndent);
+        }
+        if name == "vfill" && expanded_args.is_empty() {
+            return Ok(Node::VFill);
+        }
 
-            // Otherwise, flatten Seq arguments as before
-            let mut flat_args = Vec::new();
-            for arg in expanded_args {
-                match arg {
-                    Node::Seq(inner) => flat_args.extend(inner),
-                    other => flat_args.push(other),
+        // `\section{title}` and `\subsection{title}` are numbered here, in
+        // document order; `\section*` and `\subsection*` are not numbered,
+        // and leave the count as it was.
+        if let Some(level) = heading_level(name)
+            && expanded_args.len() == 1
+        {
+            let number = if starred {
+                String::new()
+            } else {
+                self.next_number(level)
+            };
+            let title = match expanded_args.into_iter().next() {
+                Some(Node::Seq(inner)) => inner,
+                Some(other) => vec![other],
+                None => Vec::new(),
+            };
+            return Ok(Node::Heading {
+                level,
+                number,
+                title,
+            });
+        }
+
+        // `\title`, `\author` and `\date` set nothing where they stand, but
+        // are kept for `\maketitle`, which dates the title `\today` unless
+        // told otherwise.
+        if let ("title" | "author" | "date", [_]) = (name, expanded_args.as_slice()) {
+            let value = match expanded_args.into_iter().next() {
+                Some(Node::Seq(inner)) => inner,
+                Some(other) => vec![other],
+                None => Vec::new(),
+            };
+            match name {
+                "title" => self.title = value,
+                "author" => self.author = value,
+                _ => self.date = Some(value),
+            }
+            return Ok(Node::Seq(Vec::new()));
+        }
+        if let ("maketitle", []) = (name, args) {
+            if self.title.is_empty() {
+                log::warn!("\\maketitle without a \\title");
+            }
+            let date = match &self.date {
+                Some(date) => date.clone(),
+                None => {
+                    vec![self.visit_text(&format_date(self.now.unwrap_or_else(SystemTime::now)))?]
                 }
+            };
+            return Ok(Node::TitleBlock {
+                title: self.title.clone(),
+                author: self.author.clone(),
+                date,
+            });
+        }
+
+        // `\footnote{text}` is numbered by layout, in the order notes are set.
+        if name == "footnote" && expanded_args.len() == 1 {
+            let body = match expanded_args.into_iter().next() {
+                Some(Node::Seq(inner)) => inner,
+                Some(other) => vec![other],
+                None => Vec::new(),
+            };
+            return Ok(Node::Footnote(body));
+        }
+
+        // `\phantom{text}` and its variants take up room for their text,
+        // which layout measures but does not draw.
+        if let Some((width, height)) = phantom(name)
+            && expanded_args.len() == 1
+        {
+            let body = match expanded_args.into_iter().next() {
+                Some(Node::Seq(inner)) => inner,
+                Some(other) => vec![other],
+                None => Vec::new(),
+            };
+            return Ok(Node::Phantom {
+                body,
+                width,
+                height,
+            });
+        }
+
+        // `\frac{num}{den}` is stacked by layout.
+        if name == "frac" && expanded_args.len() == 2 {
+            let mut parts = expanded_args.into_iter().map(|arg| match arg {
+                Node::Seq(inner) => inner,
+                other => vec![other],
+            });
+            let (num, den) = (
+                parts.next().unwrap_or_default(),
+                parts.next().unwrap_or_default(),
+            );
+            return Ok(Node::Frac { num, den });
+        }
+
+        // `\label{key}` names the current section; `\ref{key}` and
+        // `\pageref{key}` are left for layout, which knows every label and
+        // its page, even those that come after the reference.
+        if let ("label" | "ref" | "pageref", [key]) = (name, expanded_args.as_slice()) {
+            let key = collect_plain_text(key);
+            return Ok(match name {
+                "label" => Node::Label {
+                    key,
+                    number: self.number(),
+                },
+                _ => Node::Ref {
+                    key,
+                    page: name == "pageref",
+                },
+            });
+        }
+
+        // `\includegraphics[width=...]{path}` must name a readable image.
+        if name == "includegraphics" && expanded_args.len() == 1 {
+            let path = PathBuf::from(collect_plain_text(&expanded_args[0]));
+            let width = match graphics_option(opt_args, "width") {
+                Some(spec) => Some(Length::parse(&spec).ok_or(CompileError::InvalidLength(spec))?),
+                None => None,
+            };
+            let pixels = image::image_dimensions(&path).map_err(|e| CompileError::Image {
+                path: path.clone(),
+                message: e.to_string(),
+            })?;
+            return Ok(Node::Image {
+                path,
+                width,
+                pixels,
+            });
+        }
+
+        // Nothing interprets the command, so its name is lost; the options
+        // say whether that is worth mentioning.
+        match self.unknown_commands {
+            UnknownCommand::Error => {
+                return Err(CompileError::UnknownCommand {
+                    name: name.to_string(),
+                    pos: None,
+                });
             }
-            Node::Macro {
-                name: name.clone(),
-                args: flat_args,
+            UnknownCommand::Warn => {
+                log::warn!(
+                    "Unknown command `\\{}`; its arguments are set as text",
+                    name
+                )
+            }
+            UnknownCommand::Ignore => {}
+        }
+
+        // Otherwise, flatten Seq arguments as before
+        let mut flat_args = Vec::new();
+        for arg in expanded_args {
+            match arg {
+                Node::Seq(inner) => flat_args.extend(inner),
+                other => flat_args.push(other),
             }
         }
+        Ok(Node::Macro {
+            name: name.to_string(),
+            opt_args: opt_args
+                .iter()
+                .map(|arg| self.visit(arg))
+                .collect::<Result<_, _>>()?,
+            args: flat_args,
+            starred,
+        })
+    }
 
-        Node::Text(s) => Node::Text(s.clone()),
+    /// Step the counter of a level-`level` heading and return its number,
+    /// resetting the counters of deeper levels.
+    fn next_number(&mut self, level: u8) -> String {
+        let depth = usize::from(level);
+        self.sections.resize(depth, 0);
+        self.sections[depth - 1] += 1;
+        self.number()
+    }
 
-        Node::StyledText(s, style) => Node::StyledText(s.clone(), style.clone()),
+    /// The dotted number of the current section, empty before the first.
+    fn number(&self) -> String {
+        self.sections
+            .iter()
+            .map(u32::to_string)
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// The UTC date of `time` as LaTeX's `\today` writes it, e.g. `January 2, 2025`.
+fn format_date(time: SystemTime) -> String {
+    let days = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) / 86_400;
+    let (year, month, day) = civil_from_days(days);
+    format!("{} {}, {}", MONTHS[month as usize - 1], day, year)
+}
+
+/// The year, month and day of the date `days` after 1 January 1970.
+pub(crate) fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Howard Hinnant's `civil_from_days`, counting from 1 March 0000 so that
+    // leap days fall at the end of the year.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Whether the built-in command `name` means nothing without its arguments.
+/// Other commands, registered macros among them, may take none.
+pub(crate) fn takes_argument(name: &str) -> bool {
+    styling_macro(name).is_some()
+        || heading_level(name).is_some()
+        || phantom(name).is_some()
+        || matches!(
+            name,
+            "textcolor"
+                | "hspace"
+                | "vspace"
+                | "rule"
+                | "title"
+                | "author"
+                | "date"
+                | "footnote"
+                | "frac"
+                | "label"
+                | "ref"
+                | "pageref"
+                | "includegraphics"
+        )
+}
+
+/// The style applied by a one-argument styling macro such as `\textbf`.
+fn styling_macro(name: &str) -> Option<TextStyle> {
+    let style = match name {
+        "textbf" => FontFace::Bold.into(),
+        "emph" | "textit" => FontFace::Italic.into(),
+        "texttt" => FontFace::Monospace.into(),
+        "underline" => TextStyle {
+            underline: true,
+            ..TextStyle::default()
+        },
+        "sout" => TextStyle {
+            strikeout: true,
+            ..TextStyle::default()
+        },
+        _ => return None,
+    };
+    Some(style)
+}
+
+/// How a style declaration such as `\bfseries` or `\large` changes the
+/// style in effect.
+fn declaration(node: &Node, style: &TextStyle) -> Option<TextStyle> {
+    let Node::Macro { name, .. } = node else {
+        return None;
+    };
+    let face = match name.as_str() {
+        "bfseries" => FontFace::Bold,
+        "itshape" => FontFace::Italic,
+        "normalfont" => FontFace::Normal,
+        size => {
+            return Some(TextStyle {
+                scale: size_scale(size)?,
+                ..style.clone()
+            });
+        }
+    };
+    Some(TextStyle {
+        face,
+        ..style.clone()
+    })
+}
+
+/// The size a size declaration such as `\large` sets, relative to the body
+/// text.
+fn size_scale(name: &str) -> Option<f64> {
+    SIZES
+        .iter()
+        .find(|(size, _)| *size == name)
+        .map(|&(_, scale)| scale)
+}
+
+/// The width of a fixed space such as `\quad`, in ems; `\!` takes a thin
+/// space back.
+fn fixed_space(name: &str) -> Option<f64> {
+    match name {
+        "," => Some(3.0 / 18.0),
+        ";" => Some(5.0 / 18.0),
+        "!" => Some(-3.0 / 18.0),
+        "quad" => Some(1.0),
+        "qquad" => Some(2.0),
+        _ => None,
+    }
+}
+
+/// Whether phantom command `name` keeps the width, and the height, of what
+/// it stands for.
+fn phantom(name: &str) -> Option<(bool, bool)> {
+    match name {
+        "phantom" => Some((true, true)),
+        "hphantom" => Some((true, false)),
+        "vphantom" => Some((false, true)),
+        _ => None,
+    }
+}
+
+/// The alignment environment whose lines an alignment declaration such as
+/// `\centering` sets the rest of its group like.
+fn alignment_declaration(node: &Node) -> Option<&'static str> {
+    let Node::Macro { name, .. } = node else {
+        return None;
+    };
+    match name.as_str() {
+        "centering" => Some("center"),
+        "raggedright" => Some("flushleft"),
+        "raggedleft" => Some("flushright"),
+        _ => None,
+    }
+}
+
+/// The style of text styled `inner` within a scope styled `outer`.
+///
+/// The inner face, color, script and size win unless they are the defaults; underlining
+/// and striking out from either apply.
+fn nest(outer: &TextStyle, inner: &TextStyle) -> TextStyle {
+    TextStyle {
+        face: if inner.face == FontFace::Normal {
+            outer.face.clone()
+        } else {
+            inner.face.clone()
+        },
+        underline: outer.underline || inner.underline,
+        strikeout: outer.strikeout || inner.strikeout,
+        color: if inner.color == Rgb::BLACK {
+            outer.color
+        } else {
+            inner.color
+        },
+        script: if inner.script == Script::Baseline {
+            outer.script
+        } else {
+            inner.script
+        },
+        scale: if inner.scale == 1.0 {
+            outer.scale
+        } else {
+            inner.scale
+        },
+    }
+}
+
+/// Resolve a color name (`red`, `green`, `blue`, `black`) or `#RRGGBB` hex code.
+fn parse_color(spec: &str) -> Result<Rgb, CompileError> {
+    Rgb::parse(spec).ok_or_else(|| CompileError::UnknownColor(spec.trim().to_string()))
+}
+
+/// Give every text run under `node` the color `color`.
+fn recolor(node: &Node, color: Rgb) -> Node {
+    match node {
+        Node::Text(s) => Node::StyledText(
+            s.clone(),
+            TextStyle {
+                color,
+                ..TextStyle::default()
+            },
+        ),
+        Node::StyledText(s, style) => Node::StyledText(
+            s.clone(),
+            TextStyle {
+                color,
+                ..style.clone()
+            },
+        ),
+        Node::Seq(children) => Node::Seq(children.iter().map(|c| recolor(c, color)).collect()),
+        other => other.clone(),
+    }
+}
+
+/// The value of `key` in `key=value` options such as `[width=3cm, angle=90]`.
+fn graphics_option(opt_args: &[Node], key: &str) -> Option<String> {
+    opt_args
+        .iter()
+        .map(collect_plain_text)
+        .flat_map(|opts| {
+            opts.split(',')
+                .filter_map(|opt| {
+                    let (k, v) = opt.split_once('=')?;
+                    (k.trim() == key).then(|| v.trim().to_string())
+                })
+                .collect::<Vec<_>>()
+        })
+        .next()
+}
+
+/// The level of a sectioning command such as `\section`.
+fn heading_level(name: &str) -> Option<u8> {
+    match name {
+        "section" => Some(1),
+        "subsection" => Some(2),
+        _ => None,
     }
 }
 
@@ -92,23 +779,423 @@ mod tests {
             Node::Text("C".into()),
             Node::Text("D".into()),
         ]);
-        assert_eq!(expand_macros(&nested), expected);
+        assert_eq!(expand_macros(&nested).unwrap(), expected);
+    }
+
+    #[test]
+    fn registered_macros_expand_first() {
+        let mut macros = MacroRegistry::new();
+        macros
+            .register("version", |_| Ok(Node::text("1.4.2")))
+            .register("shout", |args| Ok(Node::r#macro("textbf", args.to_vec())))
+            .register("textit", |_| Ok(Node::text("plain")))
+            .register("loop", |_| Ok(Node::r#macro("loop", vec![])));
+        let opts = CompileOptions {
+            macros,
+            ..CompileOptions::default()
+        };
+        let expand = |src| expand_with(&crate::parser::parse(src).unwrap(), &opts);
+        assert_eq!(
+            expand(r"v\version \shout{hey} \textit{x}").unwrap(),
+            Node::Seq(vec![
+                Node::Text("v".into()),
+                Node::Text("1.4.2".into()),
+                Node::StyledText("hey".into(), FontFace::Bold.into()),
+                Node::Text("plain".into()),
+            ])
+        );
+        assert!(matches!(
+            expand(r"\loop"),
+            Err(CompileError::NestingTooDeep { .. })
+        ));
+    }
+
+    #[test]
+    fn styled_groups_keep_single_spaces() {
+        let bold = |src| {
+            let pages = crate::compile_to_pages(src, &CompileOptions::default()).unwrap();
+            let line = &pages[0].lines[0];
+            for hbox in &line.boxes {
+                for item in &hbox.items {
+                    if let crate::layout::LayoutNode::Run(run) = item {
+                        assert_eq!(run.style.face, FontFace::Bold, "{:?} in {}", run.text, src);
+                    }
+                }
+            }
+            crate::layout::line_text(line)
+        };
+        assert_eq!(bold(r"\textbf{a b}"), "a b");
+        assert_eq!(bold(r"\textbf{ a }"), "a");
+        assert_eq!(bold(r"\textbf{a {b}}"), "a b");
+        assert_eq!(bold(r"\textbf{a {} b}"), "a b");
+        assert_eq!(bold(r"\textbf{{}a{}}"), "a");
+        // The tie holds both words in one box.
+        assert_eq!(bold(r"\textbf{a~{b}}"), "a b");
+        let pages = crate::compile_to_pages(r"\textbf{a~{b}}", &CompileOptions::default());
+        assert_eq!(pages.unwrap()[0].lines[0].boxes.len(), 1);
+    }
+
+    #[test]
+    fn styling_keeps_what_its_argument_holds() {
+        let expand = |src| match expand_macros(&crate::parser::parse(src).unwrap()).unwrap() {
+            Node::Seq(nodes) => nodes,
+            other => panic!("Expected a Seq, got {:?}", other),
+        };
+        let bold = TextStyle::from(FontFace::Bold);
+        let b = |text: &str| Node::StyledText(text.into(), bold.clone());
+
+        let math = expand(r"\textbf{a $x^2$ b}");
+        assert_eq!((&math[0], &math[2]), (&b("a"), &b("b")));
+        assert!(matches!(&math[1], Node::Math(body) if body.len() == 2));
+
+        let italic = TextStyle::from(FontFace::Italic);
+        assert_eq!(
+            expand(r"\textbf{a \textit{b} c}"),
+            [b("a"), Node::StyledText("b".into(), italic), b("c")]
+        );
+        assert_eq!(
+            expand(r"\textbf{\textcolor{red}{hot}}"),
+            [Node::StyledText(
+                "hot".into(),
+                TextStyle {
+                    color: Rgb::new(255, 0, 0),
+                    ..bold.clone()
+                }
+            )]
+        );
+        assert_eq!(
+            expand(r"\underline{\textbf{x}}"),
+            [Node::StyledText(
+                "x".into(),
+                TextStyle {
+                    underline: true,
+                    ..bold.clone()
+                }
+            )]
+        );
+
+        let broken = expand(r"\textbf{a \\ b}");
+        assert_eq!((&broken[0], &broken[2]), (&b("a"), &b("b")));
+        assert!(matches!(broken[1], Node::LineBreak { .. }));
+        assert_eq!(
+            expand(r"\textbf{a\hspace{1cm}b}"),
+            [b("a"), Node::HSpace(Length::cm(1.0)), b("b")]
+        );
     }
 
     #[test]
     fn recurses_into_macros() {
         let mac = Node::Macro {
             name: "cmd".into(),
+            opt_args: vec![],
             args: vec![Node::Seq(vec![Node::Text("X".into())])],
+            starred: false,
         };
-        let expanded = expand_macros(&mac);
+        let expanded = expand_macros(&mac).unwrap();
         // Should flatten the inner Seq in args but keep top-level Macro
         match expanded {
-            Node::Macro { name, args } => {
+            Node::Macro { name, args, .. } => {
                 assert_eq!(name, "cmd");
                 assert_eq!(args, vec![Node::Text("X".into())]);
             }
             _ => panic!("Expected Macro node"),
         }
     }
+
+    #[test]
+    fn textcolor_sets_run_color() {
+        let ast = crate::parser::parse(r"\textcolor{red}{hot \textbf{pan}}").unwrap();
+        let expanded = expand_macros(&ast).unwrap();
+        let red = Rgb::new(255, 0, 0);
+        assert_eq!(
+            expanded,
+            Node::Seq(vec![
+                Node::StyledText(
+                    "hot".into(),
+                    TextStyle {
+                        color: red,
+                        ..TextStyle::default()
+                    }
+                ),
+                Node::StyledText(
+                    "pan".into(),
+                    TextStyle {
+                        color: red,
+                        ..FontFace::Bold.into()
+                    }
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn textcolor_accepts_hex() {
+        assert_eq!(parse_color("#1A2b3C"), Ok(Rgb::new(0x1a, 0x2b, 0x3c)));
+        assert!(parse_color("#12345").is_err());
+    }
+
+    #[test]
+    fn unknown_color_is_an_error() {
+        let ast = crate::parser::parse(r"\textcolor{mauve}{x}").unwrap();
+        assert_eq!(
+            expand_macros(&ast),
+            Err(CompileError::UnknownColor("mauve".into()))
+        );
+    }
+
+    #[test]
+    fn declarations_are_scoped_to_their_group() {
+        let ast = crate::parser::parse(r"{\bfseries a b} c").unwrap();
+        let bold = TextStyle::from(FontFace::Bold);
+        assert_eq!(
+            expand_macros(&ast).unwrap(),
+            Node::Seq(vec![
+                Node::StyledText("a".into(), bold.clone()),
+                Node::StyledText("b".into(), bold),
+                Node::Text("c".into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn alignment_declarations_set_the_rest_of_their_group() {
+        let ast = crate::parser::parse(r"a {b \raggedright c {\centering d}} e").unwrap();
+        let env = |name: &str, body| Node::Environment {
+            name: name.into(),
+            body,
+        };
+        assert_eq!(
+            expand_macros(&ast).unwrap(),
+            Node::Seq(vec![
+                Node::text("a"),
+                Node::text("b"),
+                env(
+                    "flushleft",
+                    vec![Node::text("c"), env("center", vec![Node::text("d")])]
+                ),
+                Node::text("e"),
+            ])
+        );
+    }
+
+    #[test]
+    fn size_declarations_scale_the_rest_of_their_group() {
+        let ast = crate::parser::parse(r"{\large a \textbf{b} \normalsize c} d").unwrap();
+        let large = TextStyle {
+            scale: 1.2,
+            ..TextStyle::default()
+        };
+        assert_eq!(
+            expand_macros(&ast).unwrap(),
+            Node::Seq(vec![
+                Node::StyledText("a".into(), large.clone()),
+                Node::StyledText(
+                    "b".into(),
+                    TextStyle {
+                        face: FontFace::Bold,
+                        ..large
+                    }
+                ),
+                Node::Text("c".into()),
+                Node::Text("d".into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn normalfont_resets_the_face() {
+        let ast = crate::parser::parse(r"\itshape a {\normalfont b} \textbf{c}").unwrap();
+        assert_eq!(
+            expand_macros(&ast).unwrap(),
+            Node::Seq(vec![
+                Node::StyledText("a".into(), FontFace::Italic.into()),
+                Node::Text("b".into()),
+                Node::StyledText("c".into(), FontFace::Bold.into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn logos_become_text() {
+        let ast = crate::parser::parse(r"\LaTeX{} \textbf{\TeX}").unwrap();
+        assert_eq!(
+            expand_macros(&ast).unwrap(),
+            Node::Seq(vec![
+                Node::Text("LaTeX".into()),
+                Node::StyledText("TeX".into(), FontFace::Bold.into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn deep_trees_are_refused() {
+        let mut tree = Node::text("x");
+        for _ in 0..300 {
+            tree = Node::Seq(vec![tree]);
+        }
+        assert_eq!(
+            expand_macros(&tree),
+            Err(CompileError::NestingTooDeep {
+                limit: 256,
+                pos: None
+            })
+        );
+    }
+
+    #[test]
+    fn dates_are_written_out() {
+        let at = |secs| format_date(UNIX_EPOCH + std::time::Duration::from_secs(secs));
+        assert_eq!(at(0), "January 1, 1970");
+        assert_eq!(at(1_735_776_000), "January 2, 2025");
+        assert_eq!(at(1_709_164_800 + 86_399), "February 29, 2024");
+        assert_eq!(at(951_782_400), "February 29, 2000");
+        assert_eq!(at(1_767_225_599), "December 31, 2025");
+    }
+
+    #[test]
+    fn today_uses_the_fixed_date() {
+        let opts = CompileOptions {
+            now: Some(UNIX_EPOCH + std::time::Duration::from_secs(1_735_776_000)),
+            ..CompileOptions::default()
+        };
+        let ast = crate::parser::parse(r"Printed \today.").unwrap();
+        assert_eq!(
+            expand_with(&ast, &opts).unwrap(),
+            Node::Seq(vec![
+                Node::Text("Printed".into()),
+                Node::Text("January 2, 2025".into()),
+                Node::Text(".".into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn hspace_keeps_its_length() {
+        let ast = crate::parser::parse(r"a\hspace{-2cm}b").unwrap();
+        assert_eq!(
+            expand_macros(&ast).unwrap(),
+            Node::Seq(vec![
+                Node::Text("a".into()),
+                Node::HSpace(Length::cm(-2.0)),
+                Node::Text("b".into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn fixed_spaces_are_ems_of_their_text() {
+        let ast = crate::parser::parse(r"a\,b\;c\!d\qquad{\Large e\quad f}").unwrap();
+        let space = |pt: f64| Node::HSpace(Length::pt(pt));
+        let large = |s: &str| {
+            Node::StyledText(
+                s.into(),
+                TextStyle {
+                    scale: 1.44,
+                    ..TextStyle::default()
+                },
+            )
+        };
+        assert_eq!(
+            expand_macros(&ast).unwrap(),
+            Node::Seq(vec![
+                Node::text("a"),
+                space(2.0),
+                Node::text("b"),
+                space(5.0 / 18.0 * 12.0),
+                Node::text("c"),
+                space(-2.0),
+                Node::text("d"),
+                space(24.0),
+                large("e"),
+                space(1.44 * 12.0),
+                large("f"),
+            ])
+        );
+    }
+
+    #[test]
+    fn vspace_lengths_are_in_points() {
+        let ast = crate::parser::parse(r"\vspace{10mm}").unwrap();
+        match expand_macros(&ast).unwrap() {
+            Node::Seq(nodes) => match nodes[..] {
+                [Node::VSpace(h)] => assert!((h - 28.3465).abs() < 1e-3),
+                _ => panic!("Expected a single VSpace, got {:?}", nodes),
+            },
+            other => panic!("Expected a Seq, got {:?}", other),
+        }
+        let ast = crate::parser::parse(r"\vspace{3furlongs}").unwrap();
+        assert_eq!(
+            expand_macros(&ast),
+            Err(CompileError::InvalidLength("3furlongs".into()))
+        );
+    }
+
+    #[test]
+    fn sections_are_numbered_in_order() {
+        let ast = crate::parser::parse(
+            r"\section{Intro} \subsection{Scope} \section{Method} \subsection{Data}",
+        )
+        .unwrap();
+        let Node::Seq(nodes) = expand_macros(&ast).unwrap() else {
+            panic!("Expected a Seq");
+        };
+        let numbers: Vec<(u8, &str)> = nodes
+            .iter()
+            .map(|node| match node {
+                Node::Heading { level, number, .. } => (*level, number.as_str()),
+                other => panic!("Expected a Heading, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(numbers, [(1, "1"), (2, "1.1"), (1, "2"), (2, "2.1")]);
+        assert!(matches!(
+            &nodes[2],
+            Node::Heading { title, .. } if title == &[Node::Text("Method".into())]
+        ));
+    }
+
+    #[test]
+    fn starred_sections_are_not_numbered() {
+        let ast = crate::parser::parse(
+            r"\section*{Preface} \section{Intro} \subsection*{Aside} \subsection{Scope}",
+        )
+        .unwrap();
+        let Node::Seq(nodes) = expand_macros(&ast).unwrap() else {
+            panic!("Expected a Seq");
+        };
+        let numbers: Vec<&str> = nodes
+            .iter()
+            .map(|node| match node {
+                Node::Heading { number, .. } => number.as_str(),
+                other => panic!("Expected a Heading, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(numbers, ["", "1", "", "1.1"]);
+    }
+
+    #[test]
+    fn includegraphics_reads_the_image_size() {
+        let ast = crate::parser::parse(r"\includegraphics[width=2cm]{fixtures/red.png}").unwrap();
+        let Node::Seq(nodes) = expand_macros(&ast).unwrap() else {
+            panic!("Expected a Seq");
+        };
+        assert_eq!(
+            nodes,
+            [Node::Image {
+                path: PathBuf::from("fixtures/red.png"),
+                width: Some(Length::cm(2.0)),
+                pixels: (4, 2),
+            }]
+        );
+    }
+
+    #[test]
+    fn missing_image_is_an_error() {
+        let ast = crate::parser::parse(r"\includegraphics{fixtures/nope.png}").unwrap();
+        match expand_macros(&ast) {
+            Err(CompileError::Image { path, .. }) => {
+                assert_eq!(path, PathBuf::from("fixtures/nope.png"))
+            }
+            other => panic!("Expected an image error, got {:?}", other),
+        }
+    }
 }
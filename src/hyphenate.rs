@@ -0,0 +1,128 @@
+//! Liang's hyphenation algorithm, as used by TeX, with American English patterns.
+//!
+//! Each pattern is a letter string interleaved with digits, e.g. `hy3ph`. To
+//! hyphenate a word, every pattern matching a substring of `.word.` votes a
+//! level on the gaps it covers; the maximum level per gap wins and odd levels
+//! mark allowed break points.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// The pattern source, in the whitespace-separated format of TeX's `\patterns`.
+const EN_US_PATTERNS: &str = include_str!("../patterns/hyph-en-us.txt");
+
+/// Fewest letters left before a hyphen, as TeX's `\lefthyphenmin`.
+const LEFT_HYPHEN_MIN: usize = 2;
+
+/// Fewest letters carried after a hyphen, as TeX's `\righthyphenmin`.
+const RIGHT_HYPHEN_MIN: usize = 3;
+
+/// Compiled patterns: letters of each pattern mapped to its gap levels.
+struct Patterns {
+    levels: HashMap<String, Vec<u8>>,
+    longest: usize,
+}
+
+impl Patterns {
+    fn parse(source: &str) -> Self {
+        let mut levels = HashMap::new();
+        let mut longest = 0;
+        for line in source.lines() {
+            let line = line.split('%').next().unwrap_or("");
+            for pattern in line.split_whitespace() {
+                let mut letters = String::new();
+                let mut gaps = vec![0];
+                for c in pattern.chars() {
+                    match c.to_digit(10) {
+                        Some(d) => *gaps.last_mut().expect("gaps is never empty") = d as u8,
+                        None => {
+                            letters.push(c);
+                            gaps.push(0);
+                        }
+                    }
+                }
+                longest = longest.max(letters.len());
+                levels.insert(letters, gaps);
+            }
+        }
+        Patterns { levels, longest }
+    }
+
+    fn en_us() -> &'static Patterns {
+        static PATTERNS: OnceLock<Patterns> = OnceLock::new();
+        PATTERNS.get_or_init(|| Patterns::parse(EN_US_PATTERNS))
+    }
+}
+
+/// Byte offsets within `word` at which it may be hyphenated, in increasing order.
+///
+/// Only the leading run of ASCII letters is considered, so trailing
+/// punctuation such as `word,` is never split off on its own.
+pub fn hyphenation_points(word: &str) -> Vec<usize> {
+    let letters = word
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(word.len());
+    if letters < LEFT_HYPHEN_MIN + RIGHT_HYPHEN_MIN {
+        return Vec::new();
+    }
+    let patterns = Patterns::en_us();
+    let dotted = format!(".{}.", word[..letters].to_ascii_lowercase());
+
+    // levels[i] is the level of the gap before dotted[i].
+    let mut levels = vec![0u8; dotted.len() + 1];
+    for start in 0..dotted.len() {
+        let end_max = (start + patterns.longest).min(dotted.len());
+        for end in start + 1..=end_max {
+            if let Some(gaps) = patterns.levels.get(&dotted[start..end]) {
+                for (k, &level) in gaps.iter().enumerate() {
+                    levels[start + k] = levels[start + k].max(level);
+                }
+            }
+        }
+    }
+
+    // The gap before letter i of the word is the gap before dotted[i + 1].
+    (LEFT_HYPHEN_MIN..=letters - RIGHT_HYPHEN_MIN)
+        .filter(|&i| levels[i + 1] % 2 == 1)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hyphenate(word: &str) -> String {
+        let mut out = String::new();
+        let mut last = 0;
+        for i in hyphenation_points(word) {
+            out.push_str(&word[last..i]);
+            out.push('-');
+            last = i;
+        }
+        out.push_str(&word[last..]);
+        out
+    }
+
+    #[test]
+    fn test_hyphenates_common_words() {
+        assert_eq!(hyphenate("hyphenation"), "hy-phen-ation");
+        assert_eq!(hyphenate("typesetting"), "type-set-ting");
+    }
+
+    #[test]
+    fn test_short_words_are_not_split() {
+        assert!(hyphenation_points("the").is_empty());
+        assert!(hyphenation_points("word").is_empty());
+    }
+
+    #[test]
+    fn test_ignores_trailing_punctuation() {
+        let points = hyphenation_points("computer,");
+        assert!(
+            points
+                .iter()
+                .all(|&i| i <= "computer".len() - RIGHT_HYPHEN_MIN)
+        );
+        assert!(!points.is_empty());
+    }
+}
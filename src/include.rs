@@ -0,0 +1,152 @@
+//! `\input{file}` and `\include{file}`: documents split across several
+//! source files, spliced back together before expansion.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::ast::{Node, Visitor};
+use crate::error::CompileError;
+use crate::expand::collect_plain_text;
+use crate::lexer::Comments;
+use crate::options::{CompileOptions, SpecialChars};
+use crate::parser;
+
+/// How many files deep `\input` may nest, counting the main file.
+const MAX_INPUT_DEPTH: usize = 16;
+
+/// Replace every `\input{name}` and `\include{name}` in `ast` with the
+/// parsed contents of `name.tex`, looked up in `dir`.
+///
+/// `main` is the file `ast` was read from, if any, so that it cannot be
+/// input into itself.
+pub(crate) fn resolve_inputs(
+    ast: &Node,
+    dir: &Path,
+    main: Option<&Path>,
+    opts: &CompileOptions,
+) -> Result<Node, CompileError> {
+    Inputs {
+        dir,
+        open: main.into_iter().map(canonical).collect(),
+        max_nesting: opts.max_nesting,
+        special_chars: opts.special_chars,
+    }
+    .visit(ast)
+}
+
+struct Inputs<'a> {
+    /// Where file names are looked up.
+    dir: &'a Path,
+    /// The files being read, outermost first, to catch one inputting itself.
+    open: Vec<PathBuf>,
+    max_nesting: usize,
+    special_chars: SpecialChars,
+}
+
+impl Inputs<'_> {
+    /// Read and parse the file `\input{name}` names, with its own inputs
+    /// resolved.
+    fn input(&mut self, name: &str) -> Result<Vec<Node>, CompileError> {
+        let mut path = self.dir.join(name);
+        if path.extension().is_none() {
+            path.set_extension("tex");
+        }
+        let error = |message: String| CompileError::Input {
+            path: path.clone(),
+            message,
+        };
+        if self.open.len() >= MAX_INPUT_DEPTH {
+            return Err(error(format!(
+                "files are input more than {} deep",
+                MAX_INPUT_DEPTH
+            )));
+        }
+        let source = fs::read_to_string(&path).map_err(|e| error(e.to_string()))?;
+        let key = canonical(&path);
+        if self.open.contains(&key) {
+            return Err(error("the file inputs itself".into()));
+        }
+        let ast = parser::parse_limited(
+            &source,
+            Comments::Skip,
+            self.max_nesting,
+            self.special_chars,
+        )
+        .map_err(|e| error(e.to_string()))?;
+
+        self.open.push(key);
+        let nodes = match ast {
+            Node::Seq(children) => self.visit_seq(&children),
+            other => self.visit(&other).map(|node| vec![node]),
+        };
+        self.open.pop();
+        nodes
+    }
+}
+
+impl Visitor for Inputs<'_> {
+    type Error = CompileError;
+
+    /// Splice the contents of input files among the siblings; unlike a
+    /// group, an input file does not limit the reach of declarations in it.
+    fn visit_seq(&mut self, nodes: &[Node]) -> Result<Vec<Node>, CompileError> {
+        let mut spliced = Vec::new();
+        for node in nodes {
+            match node {
+                Node::Macro { name, args, .. }
+                    if (name == "input" || name == "include") && args.len() == 1 =>
+                {
+                    spliced.extend(self.input(&collect_plain_text(&args[0]))?);
+                }
+                _ => spliced.push(self.visit(node)?),
+            }
+        }
+        Ok(spliced)
+    }
+}
+
+/// `path` with symbolic links and `..` resolved, so that two spellings of
+/// one file compare equal; as given if it cannot be resolved.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(source: &str, dir: &Path) -> Result<Node, CompileError> {
+        let ast = parser::parse(source).unwrap();
+        resolve_inputs(&ast, dir, None, &CompileOptions::default())
+    }
+
+    #[test]
+    fn input_splices_the_file_in_place() {
+        let dir = std::env::temp_dir().join("latex_rs_input_splices");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("chapter.tex"), r"\bfseries Inside").unwrap();
+        let resolved = resolve(r"Before \input{chapter} after", &dir).unwrap();
+        assert_eq!(
+            resolved,
+            parser::parse(r"Before \bfseries Inside after").unwrap()
+        );
+    }
+
+    #[test]
+    fn a_file_that_inputs_itself_is_an_error() {
+        let dir = std::env::temp_dir().join("latex_rs_input_cycle");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.tex"), r"A \input{b}").unwrap();
+        fs::write(dir.join("b.tex"), r"B \input{a.tex}").unwrap();
+        let err = resolve(r"\input{a}", &dir).unwrap_err();
+        assert!(matches!(err, CompileError::Input { path, .. } if path.ends_with("a.tex")));
+    }
+
+    #[test]
+    fn a_missing_file_is_an_error() {
+        let err = resolve(r"\input{nope}", Path::new("fixtures")).unwrap_err();
+        assert!(
+            matches!(err, CompileError::Input { path, .. } if path == Path::new("fixtures/nope.tex"))
+        );
+    }
+}
@@ -1,23 +1,70 @@
-use crate::ast::{Node, TextStyle};
+use serde::{Deserialize, Serialize};
+use smallvec::{SmallVec, smallvec};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::path::PathBuf;
+
+use crate::ast::{
+    ColumnAlign, FontFace, ListKind, Node, Script, TableRow, TableSpec, TableWidth, TextStyle,
+};
+use crate::backend::FontCache;
+use crate::expand::collect_plain_text;
+use crate::hyphenate::hyphenation_points;
+#[cfg(test)]
+use crate::options::FONT_SIZE_PT;
+use crate::options::{CompileOptions, LEADING, MARGIN, PageSize, RunningText};
+use crate::shaping::Shaper;
+use crate::units::Length;
 
 /// A run of text with an associated style.
 #[derive(Debug, Clone)]
 pub struct StyledRun {
     pub text: String,
     pub style: TextStyle,
+    /// Advance of the whole run, as measured by layout; renderers move by this.
+    pub width: f64,
 }
 
-/// One primitive in the layout: either a styled run or flexible glue.
+/// One primitive in the layout: a styled run, flexible glue, an image or a rule.
 #[derive(Debug, Clone)]
 pub enum LayoutNode {
     Run(StyledRun),
     Glue(f64),
+    /// Fixed space from `\hspace`: it never stretches, and the line is never
+    /// broken at it. Negative widths move the following items left.
+    Kern(f64),
+    /// An image standing on the baseline, sized in points.
+    Image {
+        path: PathBuf,
+        width: f64,
+        height: f64,
+    },
+    /// A filled rectangle, sized in points, with its bottom `raise` above
+    /// the baseline.
+    Rule {
+        width: f64,
+        height: f64,
+        raise: f64,
+    },
+    /// Where footnote `n` (counting from 0) is referenced. It takes no
+    /// space; the note is set at the foot of the page the line falls on.
+    Footnote(usize),
+    /// Room for material from `\phantom` that is not drawn: `width` wide,
+    /// reaching `height` above the baseline and `depth` below it.
+    Phantom {
+        width: f64,
+        height: f64,
+        depth: f64,
+    },
 }
 
 /// A horizontal box of layout primitives, with a precomputed width.
 #[derive(Debug, Clone)]
 pub struct HBox {
-    pub items: Vec<LayoutNode>,
+    /// Most boxes are a word or a space, held without a heap allocation.
+    pub items: SmallVec<[LayoutNode; 1]>,
     pub width: f64,
 }
 
@@ -26,6 +73,69 @@ pub struct HBox {
 pub struct Line {
     pub boxes: Vec<HBox>,
     pub width: f64,
+    /// Offset of the first box from the left margin.
+    pub x_offset: f64,
+    /// The page-breaker must not end a page after this line (e.g. headings).
+    pub keep_with_next: bool,
+    /// Extra vertical space above this line, in points; negative moves it up.
+    pub space_before: f64,
+    /// Height needed beyond the line height by tall content such as images,
+    /// in points. Unlike `space_before` it is kept at the top of a page.
+    pub extra_height: f64,
+    /// How many `\vfill`s stand above this line. The page-breaker shares
+    /// what is left of the page among the fills on it, adding each share to
+    /// `space_before`; unlike other space, fills are kept at the top of a page.
+    pub fills_before: usize,
+    /// How the line is placed within the line width; `x_offset` includes it.
+    pub align: Alignment,
+    /// The column the line is set in, counting from 0 at the left. Each
+    /// column starts at the top of the page; `x_offset` includes its offset.
+    pub column: usize,
+    /// What the line is part of, e.g. for the structure of a tagged PDF.
+    pub role: Role,
+    /// Index of the paragraph, heading or other block the line was set
+    /// from; consecutive lines of one block share it.
+    pub block: usize,
+    /// Where the line-breaker ended the line.
+    pub end: LineEnd,
+}
+
+/// Where a line ends, as the line-breaker chose it.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LineEnd {
+    /// At the end of its paragraph, or a line set whole, such as a table row.
+    #[default]
+    Paragraph,
+    /// At a space between words.
+    Glue,
+    /// Inside a word, with a hyphen.
+    Hyphen,
+    /// At a forced break `\\`.
+    Forced,
+}
+
+impl std::fmt::Display for LineEnd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LineEnd::Paragraph => "paragraph end",
+            LineEnd::Glue => "glue",
+            LineEnd::Hyphen => "hyphen",
+            LineEnd::Forced => "forced",
+        })
+    }
+}
+
+/// The kind of block a line belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Role {
+    #[default]
+    Paragraph,
+    /// A section heading of the given level, 1 for `\section`.
+    Heading(u8),
+    /// A running head or foot, repeated on every page rather than part of
+    /// the text.
+    Running,
 }
 
 /// A page: a sequence of lines.
@@ -34,160 +144,3810 @@ pub struct Page {
     pub lines: Vec<Line>,
 }
 
-/// Layout the AST into pages using a simple greedy line-breaker.
+/// Horizontal placement of a block within the line width.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Alignment {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+impl Alignment {
+    /// The alignment selected by an environment name, if it sets one.
+    pub(crate) fn from_environment(name: &str) -> Option<Self> {
+        match name {
+            "flushleft" => Some(Alignment::Left),
+            "center" => Some(Alignment::Center),
+            "flushright" => Some(Alignment::Right),
+            _ => None,
+        }
+    }
+
+    /// Offset from the left margin of a block `width` wide in a `line_width` measure.
+    fn offset(self, line_width: f64, width: f64) -> f64 {
+        let slack = (line_width - width).max(0.0);
+        match self {
+            Alignment::Left => 0.0,
+            Alignment::Center => slack / 2.0,
+            Alignment::Right => slack,
+        }
+    }
+}
+
+/// A run of HBoxes that is line-broken as a unit.
+#[derive(Debug, Clone, Default)]
+struct Paragraph {
+    boxes: Vec<HBox>,
+    keep_with_next: bool,
+    /// Placement of each line within the indented measure.
+    align: Alignment,
+    /// Left indentation of every line, e.g. inside a list.
+    indent: f64,
+    /// Right indentation of every line, inside a quote.
+    right_indent: f64,
+    /// Further indentation of the first line.
+    parindent: f64,
+    /// A list marker hung to the left of the first line.
+    label: Option<HBox>,
+    role: Role,
+    /// How the last line ends: forced when a `\\` closed the paragraph.
+    end: LineEnd,
+    /// The page-breaker must not end a page after the last line, closed by `\\*`.
+    keep_last_with_next: bool,
+}
+
+/// One entry of the vertical list produced by flattening.
+#[derive(Debug, Clone)]
+enum Block {
+    /// Inline material still to be broken into lines.
+    Paragraph(Paragraph),
+    /// Lines that were set directly, such as table rows.
+    Lines(Vec<Line>),
+    /// Vertical space, in points, added above the next line.
+    VSpace(f64),
+    /// A `\vfill` above the next line.
+    VFill,
+    /// The start of a `samepage` environment: a page must not end among
+    /// the lines from here to the matching [`Block::KeepEnd`].
+    KeepBegin,
+    KeepEnd,
+}
+
+impl HBox {
+    fn is_glue(&self) -> bool {
+        self.items.iter().all(|n| matches!(n, LayoutNode::Glue(_)))
+    }
+
+    /// How far this box can stretch when a line is justified.
+    fn stretch(&self) -> f64 {
+        if self.is_glue() {
+            self.width * GLUE_STRETCH
+        } else {
+            0.0
+        }
+    }
+
+    fn glue(width: f64) -> Self {
+        HBox {
+            items: smallvec![LayoutNode::Glue(width)],
+            width,
+        }
+    }
+
+    /// Split a one-word box at the last hyphenation point whose head, plus a
+    /// hyphen, fits in `room`; the head gets the hyphen, the tail the rest.
+    ///
+    /// A word with discretionary hyphens may only break at those; any other
+    /// word is hyphenated by pattern, and only if `auto` is set.
+    fn hyphenate(&self, room: f64, auto: bool) -> Option<(HBox, HBox)> {
+        let (head_end, tail_start) = self
+            .hyphen_breaks(auto)
+            .into_iter()
+            .rev()
+            .find(|&(i, _)| self.piece(0, i, true).width <= room)?;
+        Some((
+            self.piece(0, head_end, true),
+            self.piece(tail_start, usize::MAX, false),
+        ))
+    }
+
+    /// Where a one-word box may be hyphenated, each break as the end of the
+    /// head and the start of the tail, in bytes; see [`HBox::hyphenate`].
+    fn hyphen_breaks(&self, auto: bool) -> Vec<(usize, usize)> {
+        let [LayoutNode::Run(run)] = self.items.as_slice() else {
+            return Vec::new();
+        };
+        if run.text.contains(SOFT_HYPHEN) {
+            run.text
+                .match_indices(SOFT_HYPHEN)
+                .map(|(i, s)| (i, i + s.len()))
+                .collect()
+        } else if auto {
+            hyphenation_points(&run.text)
+                .into_iter()
+                .map(|i| (i, i))
+                .collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// The bytes `from..to` of a one-word box, `to` clamped to its end, as
+    /// a box of their own; followed by a hyphen if `hyphen` is set.
+    fn piece(&self, from: usize, to: usize, hyphen: bool) -> HBox {
+        let [LayoutNode::Run(run)] = self.items.as_slice() else {
+            return self.clone();
+        };
+        let advance = self.width / text_len(&run.text) as f64;
+        let mut text = run.text[from..to.min(run.text.len())].replace(SOFT_HYPHEN, "");
+        if hyphen {
+            text.push('-');
+        }
+        let width = text_len(&text) as f64 * advance;
+        HBox {
+            width,
+            items: smallvec![LayoutNode::Run(StyledRun {
+                text,
+                style: run.style.clone(),
+                width,
+            })],
+        }
+    }
+}
+
+/// U+00AD, which marks a discretionary hyphen inside a run's text until
+/// line breaking has decided whether to use it. `\-` is set as one, and a
+/// soft hyphen typed into the source is kept as it is, so both break alike.
+const SOFT_HYPHEN: char = '\u{ad}';
+
+/// The number of characters `text` is measured by: every character but
+/// discretionary hyphens advances by the same amount, whatever its encoded size.
+fn text_len(text: &str) -> usize {
+    text.chars().filter(|&c| c != SOFT_HYPHEN).count()
+}
+
+/// Font scale of headings by level, as LaTeX's `\Large` and `\large`.
+const HEADING_SCALES: [f64; 2] = [1.44, 1.2];
+
+/// Size of the `\maketitle` title, and of its author and date lines,
+/// relative to the body text (LaTeX's `\LARGE` and `\large`).
+const TITLE_SCALE: f64 = 1.728;
+const BYLINE_SCALE: f64 = 1.2;
+
+/// Space after the title, the author and the date of `\maketitle`, in ems.
+const TITLE_SPACES: [f64; 3] = [1.5, 1.0, 1.5];
+
+/// Space above a heading, in line heights.
+const HEADING_SPACE_BEFORE: f64 = 1.0;
+
+/// Space below a heading, in line heights.
+const HEADING_SPACE_AFTER: f64 = 0.5;
+
+/// Space between paragraphs, in line heights.
+const PARAGRAPH_SPACE: f64 = 0.5;
+
+/// Monospace glyphs advance 0.6 em against the 0.5 em assumed for the other faces.
+const MONOSPACE_WIDTH_RATIO: f64 = 1.2;
+
+/// Size of super- and subscripts relative to the body text.
+const SCRIPT_SCALE: f64 = 0.7;
+
+/// Length of the rule above footnotes, in points (LaTeX's 2 in).
+const FOOTNOTE_RULE_WIDTH: f64 = 144.0;
+
+/// Thickness of the rule above footnotes, in points.
+const FOOTNOTE_RULE_THICKNESS: f64 = 0.4;
+
+/// The proportional character advance, in ems, as the caller computes it.
+pub(crate) const CHAR_WIDTH_EM: f64 = 0.5;
+
+/// How far superscripts are raised, as a fraction of the line height.
+const SUPERSCRIPT_RISE: f64 = 0.3;
+
+/// How far subscripts are lowered, as a fraction of the line height.
+const SUBSCRIPT_DROP: f64 = 0.15;
+
+/// How far the numerator of a fraction is raised, in ems of the text around it.
+const NUMERATOR_RISE: f64 = 0.55;
+
+/// How far the denominator of a fraction is lowered, in ems of the text around it.
+const DENOMINATOR_DROP: f64 = 0.4;
+
+/// Height of the middle of a fraction bar above the baseline, in ems: TeX's
+/// math axis, level with a minus sign.
+const MATH_AXIS: f64 = 0.25;
+
+/// Thickness of a fraction bar, in points.
+const FRACTION_RULE_THICKNESS: f64 = 0.4;
+
+/// How far glyphs reach above and below the baseline, in ems of their size,
+/// for making room for raised and lowered material.
+const GLYPH_HEIGHT: f64 = 0.75;
+const GLYPH_DEPTH: f64 = 0.25;
+
+/// Indentation of list item bodies, in multiples of `char_width` (2.5 em, as in LaTeX).
+const LIST_INDENT: f64 = 5.0;
+
+/// Indentation of `quote` and `quotation` bodies from either margin, in
+/// multiples of `char_width` (2.5 em, as in LaTeX).
+const QUOTE_INDENT: f64 = 5.0;
+
+/// First-line indentation of paragraphs in a `quotation`, in multiples of
+/// `char_width` (1.5 em, as in LaTeX).
+const QUOTATION_PARINDENT: f64 = 3.0;
+
+/// `itemize` bullets by nesting depth; deeper lists keep the last one.
+const BULLETS: [&str; 3] = ["•", "◦", "▪"];
+
+/// Space between adjacent table columns, in multiples of `space_width`.
+const TABLE_COLUMN_GAP: f64 = 2.0;
+
+/// Thickness of the rules `|` and `\hline` draw in a table, in points
+/// (LaTeX's `\arrayrulewidth`).
+const TABLE_RULE_THICKNESS: f64 = 0.4;
+
+/// Which algorithm chooses the line breaks within a paragraph.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LineBreakMode {
+    /// Fill each line as far as possible before moving on.
+    #[default]
+    Greedy,
+    /// Choose all breaks of a paragraph together to minimise total badness.
+    KnuthPlass,
+}
+
+/// Whether the document is broken into pages.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pagination {
+    /// Pages the size of the paper.
+    #[default]
+    Paged,
+    /// One page as wide as the paper and as tall as the whole document,
+    /// in a single column, e.g. for previews on screen.
+    Continuous,
+}
+
+/// How far inter-word glue may stretch, as a fraction of its natural width.
+const GLUE_STRETCH: f64 = 0.5;
+
+/// Badness assigned to a line that cannot stretch at all, as in TeX.
+const MAX_BADNESS: f64 = 10_000.0;
+
+/// A penalty this high forbids a break, as in TeX.
+pub(crate) const MAX_PENALTY: f64 = 10_000.0;
+
+/// Height available for lines on an A4 page with the default margins, in
+/// points; other papers and margins gain or lose the difference in the
+/// height between the top and bottom margins.
+const TEXT_HEIGHT: f64 = 800.0;
+
+/// Room a running head or foot takes from the text, in line heights: its
+/// own line and one left blank between it and the text.
+const RUNNING_SPACE: f64 = 2.0;
+
+/// Layout the AST into pages.
 ///
 /// - `ast`: the expanded AST to lay out  
-/// - `line_width`: maximum width per line, in the same units as `char_width`/`space_width`  
+/// - `line_width`: width of the text block, in the same units as `char_width`/`space_width`;
+///   lines are as wide as one of its `opts.columns` columns  
 /// - `line_height`: assumed fixed line height (used only to decide how many lines per page)  
 /// - `char_width`: advance width for each character  
 /// - `space_width`: width of the glue inserted between words or nodes  
+/// - `opts`: the line-breaking algorithm and whether to hyphenate  
+///
+/// There is always at least one page: a document with nothing to set, such
+/// as one of only comments, is a single blank page.
 pub fn layout(
     ast: &Node,
     line_width: f64,
     line_height: f64,
     char_width: f64,
     space_width: f64,
+    opts: &CompileOptions,
+) -> Vec<Page> {
+    let metrics = (line_width, line_height, char_width, space_width);
+    layout_with(ast, metrics, opts, None)
+}
+
+/// [`layout`] with Knuth-Plass line breaking, whatever `opts.line_break`
+/// says. Paragraphs for which no feasible set of breaks exists are broken
+/// greedily instead.
+#[cfg(test)]
+pub fn layout_knuth_plass(
+    ast: &Node,
+    line_width: f64,
+    line_height: f64,
+    char_width: f64,
+    space_width: f64,
+    opts: &CompileOptions,
+) -> Vec<Page> {
+    let opts = CompileOptions {
+        line_break: LineBreakMode::KnuthPlass,
+        ..opts.clone()
+    };
+    layout(ast, line_width, line_height, char_width, space_width, &opts)
+}
+
+/// Paragraphs measured by earlier layouts of a document, for laying it out
+/// again after an edit; see [`layout_cached`].
+#[derive(Debug, Default)]
+pub struct LayoutCache {
+    /// The metrics and options the paragraphs were measured with.
+    settings: String,
+    /// The paragraphs flattened so far, keyed by a hash of the paragraph
+    /// and the block it began in.
+    paragraphs: HashMap<u64, CachedParagraph>,
+    /// The keys looked up by the current layout.
+    used: HashSet<u64>,
+}
+
+/// A paragraph in a [`LayoutCache`]: what it was flattened from, which a
+/// lookup compares in case another paragraph has the same hash, and what it
+/// was flattened to.
+#[derive(Debug)]
+struct CachedParagraph {
+    /// The paragraph's nodes.
+    nodes: Vec<Node>,
+    /// The `Debug` form of the block it began in.
+    begun: String,
+    /// The blocks it flattened to.
+    blocks: Vec<Block>,
+    /// Whether a tie was left pending after them.
+    tied: bool,
+}
+
+/// [`layout`], reusing the measured words of every top-level paragraph
+/// that is as it was the last time `cache` was used, as in an editor that
+/// lays a document out again on every keystroke.
+///
+/// Only paragraphs are cached: the line breaks and pages are found afresh,
+/// and come out as a full layout would give them. Paragraphs with
+/// footnotes, labels or references, whose setting depends on the rest of
+/// the document, are always measured again, as is everything when the
+/// metrics or options change. Paragraphs that are gone are dropped from
+/// the cache.
+pub fn layout_cached(
+    ast: &Node,
+    line_width: f64,
+    line_height: f64,
+    char_width: f64,
+    space_width: f64,
+    opts: &CompileOptions,
+    cache: &mut LayoutCache,
+) -> Vec<Page> {
+    let metrics = (line_width, line_height, char_width, space_width);
+    let settings = format!(
+        "{:?}",
+        (
+            metrics,
+            opts.columns,
+            opts.column_gap,
+            opts.paragraph_indent,
+            opts.tab_width,
+            opts.table_align,
+            opts.kerning,
+            &opts.fonts
+        )
+    );
+    if cache.settings != settings {
+        cache.settings = settings;
+        cache.paragraphs.clear();
+    }
+    let pages = layout_with(ast, metrics, opts, Some(&mut *cache));
+    let used = std::mem::take(&mut cache.used);
+    cache.paragraphs.retain(|key, _| used.contains(key));
+    pages
+}
+
+fn layout_with(
+    ast: &Node,
+    metrics: (f64, f64, f64, f64),
+    opts: &CompileOptions,
+    mut cache: Option<&mut LayoutCache>,
 ) -> Vec<Page> {
-    // 1) Flatten AST → HBoxes
-    let mut hboxes = Vec::new();
-    flatten_ast(ast, &mut hboxes, char_width, space_width);
+    // A `\ref` may come before its `\label`, so a document with references
+    // is laid out once to find where every label falls, and again to set
+    // the references to them.
+    let mut pass = layout_pass(ast, metrics, opts, HashMap::new(), cache.as_deref_mut());
+    if pass.xrefs.refs > 0 {
+        pass = layout_pass(ast, metrics, opts, pass.targets, cache);
+    }
+    for key in &pass.xrefs.undefined {
+        log::warn!("Reference `{}` is undefined; set as ??", key);
+    }
+    let mut pages = pass.pages;
+    if opts.header.is_some() || opts.footer.is_some() {
+        add_running(&mut pages, metrics, opts);
+    }
+    pages
+}
+
+/// Height available for lines on a page of the paper `opts` name, running
+/// head and foot included; unbounded on a continuous page.
+fn text_height(opts: &CompileOptions) -> f64 {
+    match opts.pagination {
+        Pagination::Paged => {
+            let between =
+                |paper: PageSize, margin: Length| paper.height().to_pt() - 2.0 * margin.to_pt();
+            TEXT_HEIGHT + between(opts.page_size, opts.margin) - between(PageSize::A4, MARGIN)
+        }
+        Pagination::Continuous => f64::INFINITY,
+    }
+}
+
+/// Set the running head `opts` asks for on the first line of every page,
+/// and the running foot on the last line of the text block, or below the
+/// text on a continuous page. `{page}` in either becomes the page number.
+fn add_running(
+    pages: &mut [Page],
+    (line_width, line_height, char_width, _): (f64, f64, f64, f64),
+    opts: &CompileOptions,
+) {
+    let running = |running: &RunningText, number: usize| {
+        let text = running.text.replace("{page}", &number.to_string());
+        let style = TextStyle::default();
+        let width = text_len(&text) as f64 * char_advance(&style, char_width);
+        Line {
+            boxes: vec![HBox {
+                items: smallvec![LayoutNode::Run(StyledRun { text, style, width })],
+                width,
+            }],
+            width,
+            x_offset: running.align.offset(line_width, width),
+            keep_with_next: false,
+            space_before: 0.0,
+            extra_height: 0.0,
+            fills_before: 0,
+            align: running.align,
+            column: 0,
+            role: Role::Running,
+            block: 0,
+            end: LineEnd::Paragraph,
+        }
+    };
+    let gap = (RUNNING_SPACE - 1.0) * line_height;
+    for (i, page) in pages.iter_mut().enumerate() {
+        if let Some(header) = &opts.header {
+            // Every column starts at the top of the page, where the head is.
+            let mut column = None;
+            for line in &mut page.lines {
+                if column != Some(line.column) {
+                    column = Some(line.column);
+                    line.space_before += match line.column {
+                        0 => gap,
+                        _ => gap + line_height,
+                    };
+                }
+            }
+            page.lines.insert(0, running(header, i + 1));
+        }
+        if let Some(footer) = &opts.footer {
+            // After a later column, the foot starts again from the top.
+            let used: f64 = match page.lines.last() {
+                Some(last) if last.column > 0 => 0.0,
+                _ => page
+                    .lines
+                    .iter()
+                    .map(|l| l.space_before + l.extra_height + line_height)
+                    .sum(),
+            };
+            let mut foot = running(footer, i + 1);
+            foot.space_before = match text_height(opts) {
+                height if height.is_finite() => (height - line_height - used).max(0.0),
+                _ => gap,
+            };
+            page.lines.push(foot);
+        }
+    }
+}
+
+/// What a `\label` names: the number of its section and the index of its page.
+#[derive(Debug, Clone, PartialEq)]
+struct Target {
+    number: String,
+    page: usize,
+}
 
-    // 2) Greedy line-breaking
+/// The labels and references met while flattening.
+#[derive(Debug, Default)]
+struct CrossReferences {
+    /// Where each label fell in the previous pass, by key.
+    targets: HashMap<String, Target>,
+    /// Every `\label` so far: its key, section number and block.
+    labels: Vec<(String, String, usize)>,
+    /// How many `\ref`s and `\pageref`s were set.
+    refs: usize,
+    /// Keys referenced but not in `targets`, each once.
+    undefined: Vec<String>,
+}
+
+/// One layout of the document, with references set from `targets`.
+struct Pass {
+    pages: Vec<Page>,
+    xrefs: CrossReferences,
+    /// Where the labels fell in this layout.
+    targets: HashMap<String, Target>,
+}
+
+fn layout_pass(
+    ast: &Node,
+    (line_width, line_height, char_width, space_width): (f64, f64, f64, f64),
+    opts: &CompileOptions,
+    targets: HashMap<String, Target>,
+    cache: Option<&mut LayoutCache>,
+) -> Pass {
+    let line_width = opts.column_width(line_width);
+
+    // 1) Flatten AST → paragraphs of HBoxes and pre-set lines. A font that
+    // cannot be read goes unkerned here; rendering reports it.
+    let mut flattener = Flattener::new(line_width, line_height, char_width, space_width);
+    flattener.parindent = opts.paragraph_indent.to_pt();
+    flattener.tab_width = opts.tab_width;
+    flattener.table_align = opts.table_align;
+    flattener.xrefs.targets = targets;
+    if opts.kerning {
+        flattener.shaper = Shaper::new(opts, FontCache::shared()).ok();
+    }
+    match (cache, ast) {
+        (Some(cache), Node::Seq(children)) => flattener.flatten_cached(children, cache),
+        _ => flattener.flatten(ast),
+    }
+    let xrefs = std::mem::take(&mut flattener.xrefs);
+
+    // 2) Line-breaking, one paragraph at a time, footnotes as blocks of their own
+    let n_blocks = flattener.blocks.len();
+    let mut notes: Vec<Vec<Line>> = flattener
+        .footnotes
+        .into_iter()
+        .enumerate()
+        .map(|(n, note)| {
+            let mut lines = Vec::new();
+            set_paragraph(note, line_width, opts, &mut lines);
+            for line in &mut lines {
+                line.block = n_blocks + n;
+            }
+            lines
+        })
+        .collect();
     let mut lines = Vec::new();
-    let mut curr = Vec::new();
-    let mut w = 0.0;
-    for hb in hboxes {
-        if w + hb.width > line_width && !curr.is_empty() {
+    let mut space = 0.0;
+    let mut fills = 0;
+    // Where each open `samepage` group begins.
+    let mut kept = Vec::new();
+    for (idx, block) in flattener.blocks.into_iter().enumerate() {
+        let first = lines.len();
+        match block {
+            Block::Paragraph(para) => {
+                let role = para.role;
+                set_paragraph(para, line_width, opts, &mut lines);
+                for line in &mut lines[first..] {
+                    line.role = role;
+                }
+            }
+            Block::Lines(set) => lines.extend(set),
+            Block::VSpace(height) => space += height,
+            Block::VFill => fills += 1,
+            Block::KeepBegin => kept.push(first),
+            // Every line of the group but its last is kept with the next.
+            Block::KeepEnd => {
+                let begin = kept.pop().unwrap_or(first);
+                if let Some((_, group)) = lines[begin..].split_last_mut() {
+                    for line in group {
+                        line.keep_with_next = true;
+                    }
+                }
+            }
+        }
+        for line in &mut lines[first..] {
+            line.block = idx;
+        }
+        if let Some(line) = lines.get_mut(first) {
+            line.space_before += space;
+            line.fills_before += fills;
+            space = 0.0;
+            fills = 0;
+        }
+    }
+
+    // Discretionary hyphens that were not broken at leave no trace.
+    for line in lines.iter_mut().chain(notes.iter_mut().flatten()) {
+        for item in line.boxes.iter_mut().flat_map(|hb| &mut hb.items) {
+            if let LayoutNode::Run(run) = item {
+                run.text.retain(|c| c != SOFT_HYPHEN);
+            }
+        }
+    }
+
+    // Lines with raised, lowered or enlarged material that reaches into the
+    // lines around them, such as fractions, are moved apart.
+    let em = char_width / CHAR_WIDTH_EM;
+    make_room(&mut lines, em, line_height);
+    for note in &mut notes {
+        make_room(note, em, line_height);
+    }
+
+    // 3) Column-breaking by accumulated line height, in the height the
+    // running head and foot leave, then columns onto pages
+    let running = [&opts.header, &opts.footer]
+        .iter()
+        .filter(|running| running.is_some())
+        .count() as f64
+        * RUNNING_SPACE
+        * line_height;
+    let page_height = text_height(opts) - running;
+    // Fills after the last line still share the last page.
+    let columns = paginate(lines, notes, fills, line_height, page_height);
+    let pages = set_columns(
+        columns,
+        opts.columns_per_page(),
+        line_width + opts.column_gap.to_pt(),
+    );
+
+    // A label is on the page of the first text of its block or after it;
+    // one at the very end is on the last page. Should a key be labelled
+    // twice, the last label wins.
+    let mut targets = HashMap::new();
+    for (key, number, block) in &xrefs.labels {
+        let page = pages
+            .iter()
+            .position(|page| {
+                page.lines
+                    .iter()
+                    .any(|line| line.block >= *block && line.block < n_blocks)
+            })
+            .unwrap_or(pages.len().saturating_sub(1));
+        let number = number.clone();
+        targets.insert(key.clone(), Target { number, page });
+    }
+    Pass {
+        pages,
+        xrefs,
+        targets,
+    }
+}
+
+/// Break a paragraph within its indented measure and place its lines.
+fn set_paragraph(
+    mut para: Paragraph,
+    line_width: f64,
+    opts: &CompileOptions,
+    lines: &mut Vec<Line>,
+) {
+    let first = lines.len();
+    let indent = para.indent;
+    let align = para.align;
+    let label = para.label.take();
+    let keep_with_next = para.keep_with_next;
+    let keep_last_with_next = para.keep_last_with_next;
+    let measure = line_width - indent - para.right_indent;
+    // The first line is indented by a kern in front of its first word.
+    if para.parindent != 0.0
+        && let Some(first) = para.boxes.first_mut()
+    {
+        first.items.insert(0, LayoutNode::Kern(para.parindent));
+        first.width += para.parindent;
+    }
+    match opts.line_break {
+        LineBreakMode::Greedy => break_paragraph(para, measure, opts.hyphenate, lines),
+        LineBreakMode::KnuthPlass => break_paragraph_optimal(para, measure, opts, lines),
+    }
+    for line in &mut lines[first..] {
+        if opts.justify
+            && align == Alignment::Left
+            && matches!(line.end, LineEnd::Glue | LineEnd::Hyphen)
+        {
+            justify(line, measure);
+        }
+        line.align = align;
+        line.x_offset += indent + align.offset(measure, line.width);
+    }
+    if keep_last_with_next && let Some(last) = lines[first..].last_mut() {
+        last.keep_with_next = true;
+    }
+
+    // The label hangs in the indentation, ending where the text begins.
+    if let Some(label) = label {
+        if lines.len() == first {
             lines.push(Line {
-                boxes: curr,
-                width: w,
+                boxes: Vec::new(),
+                width: 0.0,
+                x_offset: indent,
+                keep_with_next,
+                space_before: 0.0,
+                extra_height: 0.0,
+                fills_before: 0,
+                align,
+                column: 0,
+                role: Role::Paragraph,
+                block: 0,
+                end: LineEnd::Paragraph,
             });
-            curr = Vec::new();
-            w = 0.0;
         }
-        w += hb.width;
-        curr.push(hb);
-    }
-    if !curr.is_empty() {
-        lines.push(Line {
-            boxes: curr,
-            width: w,
-        });
+        let line = &mut lines[first];
+        line.x_offset -= label.width;
+        line.width += label.width;
+        line.boxes.insert(0, label);
     }
+}
 
-    // 3) Fixed page-breaking
-    let mut pages = Vec::new();
-    let max_lines = (800.0 / line_height).floor() as usize;
-    for chunk in lines.chunks(max_lines) {
-        pages.push(Page {
-            lines: chunk.to_vec(),
-        });
+/// Stretch the glue of `line` so that it fills `measure`, each space in
+/// proportion to how far it may stretch. A line without glue, or one that
+/// is already full, is left as it is.
+fn justify(line: &mut Line, measure: f64) {
+    let stretch: f64 = line.boxes.iter().map(HBox::stretch).sum();
+    let slack = measure - line.width;
+    if stretch <= 0.0 || slack <= 0.0 {
+        return;
     }
-    pages
+    let ratio = slack / stretch;
+    for hb in line.boxes.iter_mut().filter(|hb| hb.is_glue()) {
+        for item in &mut hb.items {
+            if let LayoutNode::Glue(width) = item {
+                *width += *width * GLUE_STRETCH * ratio;
+            }
+        }
+        hb.width += hb.width * GLUE_STRETCH * ratio;
+    }
+    line.width = measure;
 }
 
-/// Recursively flatten the AST into HBoxes.
+/// Greedily break one paragraph into lines, appending them to `lines`.
 ///
-/// Strategy:
-/// 1. Split each Text/StyledText on internal whitespace → words + inter-word glue.
-/// 2. Inject one `space_width` glue between every two AST siblings (Seq or Macro).
-fn flatten_ast(ast: &Node, hboxes: &mut Vec<HBox>, char_width: f64, space_width: f64) {
-    // A small helper to build a glue-box of the given width:
-    let glue = || HBox {
-        items: vec![LayoutNode::Glue(space_width)],
-        width: space_width,
+/// A word that does not fit on the current line is split at the last
+/// discretionary hyphen, or with `hyphenate` at the last hyphenation point,
+/// that leaves room for the hyphen.
+fn break_paragraph(mut para: Paragraph, line_width: f64, hyphenate: bool, lines: &mut Vec<Line>) {
+    // Glue at either end of a paragraph never produces visible space.
+    while para.boxes.last().is_some_and(HBox::is_glue) {
+        para.boxes.pop();
+    }
+    if para.boxes.is_empty() {
+        return;
+    }
+
+    let keep_with_next = para.keep_with_next;
+    let mut finish_line = |mut boxes: Vec<HBox>, end: LineEnd| {
+        // Glue at a line break is discarded rather than carried over.
+        while boxes.last().is_some_and(HBox::is_glue) {
+            boxes.pop();
+        }
+        lines.push(Line {
+            width: boxes_width(&boxes),
+            boxes,
+            x_offset: 0.0,
+            keep_with_next,
+            space_before: 0.0,
+            extra_height: 0.0,
+            fills_before: 0,
+            align: Alignment::Left,
+            column: 0,
+            role: Role::Paragraph,
+            block: 0,
+            end,
+        });
     };
 
-    match ast {
-        Node::Seq(children) => {
-            for (i, child) in children.iter().enumerate() {
-                if i > 0 {
-                    hboxes.push(glue());
+    // Each line's boxes are allocated at the size of the line before.
+    let next_line = |curr: &mut Vec<HBox>| std::mem::replace(curr, Vec::with_capacity(curr.len()));
+    let mut curr = Vec::new();
+    let mut w = 0.0;
+    let mut pending: VecDeque<HBox> = para.boxes.into();
+    while let Some(hb) = pending.pop_front() {
+        if w + hb.width > line_width {
+            if let Some((head, tail)) = hb.hyphenate(line_width - w, hyphenate) {
+                curr.push(head);
+                finish_line(next_line(&mut curr), LineEnd::Hyphen);
+                w = 0.0;
+                pending.push_front(tail);
+                continue;
+            }
+            if !curr.is_empty() {
+                finish_line(next_line(&mut curr), LineEnd::Glue);
+                w = 0.0;
+                if hb.is_glue() {
+                    continue;
+                }
+                if hb.width > line_width {
+                    // Too wide even for a line of its own: split it from the
+                    // start if it can be split at all.
+                    pending.push_front(hb);
+                    continue;
                 }
-                flatten_ast(child, hboxes, char_width, space_width);
             }
         }
+        w += hb.width;
+        curr.push(hb);
+    }
+    finish_line(curr, para.end);
+}
 
-        Node::Text(s) => {
-            // split on any run of whitespace → words
-            for (j, word) in s.split_whitespace().enumerate() {
-                if j > 0 {
-                    hboxes.push(glue());
-                }
-                let run = StyledRun {
-                    text: word.to_string(),
-                    style: TextStyle::Normal,
-                };
-                let width = word.len() as f64 * char_width;
-                hboxes.push(HBox {
-                    items: vec![LayoutNode::Run(run)],
-                    width,
+/// Break one paragraph with the Knuth-Plass total-fit algorithm.
+///
+/// Breaks are allowed at glue, and inside words where [`HBox::hyphenate`]
+/// would split them. Each candidate line is scored by its badness — how far
+/// its glue would have to stretch to fill `line_width` — and lines beyond
+/// `opts.tolerance` are ruled out. The breakpoints minimising the total
+/// demerits of the paragraph are found by dynamic programming: each line
+/// costs the square of its badness plus `opts.line_penalty`, a hyphen adds
+/// the square of `opts.hyphen_penalty`, and a line much looser than its
+/// neighbour adds `opts.adjacent_demerits`. The last line is set at its
+/// natural width. Falls back to [`break_paragraph`] when no feasible breaks
+/// exist, i.e. when some box is wider than the line.
+fn break_paragraph_optimal(
+    mut para: Paragraph,
+    line_width: f64,
+    opts: &CompileOptions,
+    lines: &mut Vec<Line>,
+) {
+    while para.boxes.last().is_some_and(HBox::is_glue) {
+        para.boxes.pop();
+    }
+    let boxes = &para.boxes;
+    let n = boxes.len();
+    if n == 0 {
+        return;
+    }
+
+    // Prefix sums of natural width and stretch.
+    let mut width = vec![0.0; n + 1];
+    let mut stretch = vec![0.0; n + 1];
+    for (i, hb) in boxes.iter().enumerate() {
+        width[i + 1] = width[i] + hb.width;
+        stretch[i + 1] = stretch[i] + hb.stretch();
+    }
+
+    // Candidate breakpoints in paragraph order: the start of the paragraph,
+    // the first glue of every run of glue, the hyphenation points of each
+    // word unless hyphens are forbidden, then the end.
+    let mut cands = vec![Breakpoint::glue(boxes, 0)];
+    for i in 0..n {
+        if i > 0 && boxes[i].is_glue() && !boxes[i - 1].is_glue() {
+            cands.push(Breakpoint::glue(boxes, i));
+        }
+        if opts.hyphen_penalty < MAX_PENALTY {
+            for (head_end, tail_start) in boxes[i].hyphen_breaks(opts.hyphenate) {
+                cands.push(Breakpoint {
+                    end: i,
+                    start: i + 1,
+                    hyphen: Some((head_end, tail_start)),
+                    head_width: boxes[i].piece(0, head_end, true).width,
+                    tail_width: boxes[i].piece(tail_start, usize::MAX, false).width,
                 });
             }
         }
+    }
+    cands.push(Breakpoint::glue(boxes, n));
+    let last = cands.len() - 1;
 
-        Node::StyledText(s, style) => {
-            for (j, word) in s.split_whitespace().enumerate() {
-                if j > 0 {
-                    hboxes.push(glue());
+    // The least demerits of the paragraph up to each breakpoint, ending in
+    // a line of each fitness class, and the breakpoint before that line.
+    let mut best = vec![[f64::INFINITY; FITNESS_CLASSES]; cands.len()];
+    let mut prev = vec![[(0, 0); FITNESS_CLASSES]; cands.len()];
+    best[0][DECENT] = 0.0;
+    for a in 0..last {
+        if best[a].iter().all(|d| d.is_infinite()) {
+            continue;
+        }
+        let from = &cands[a];
+        for (k, to) in cands.iter().enumerate().skip(a + 1) {
+            let natural = from.tail_width + width[to.end] - width[from.start] + to.head_width;
+            if natural > line_width {
+                break; // overfull, and every later break is wider still
+            }
+            let bad = if k == last {
+                0.0
+            } else {
+                badness(natural, stretch[to.end] - stretch[from.start], line_width)
+            };
+            if bad > opts.tolerance {
+                continue;
+            }
+            let mut demerits = (opts.line_penalty + bad).powi(2);
+            if to.hyphen.is_some() {
+                demerits += opts.hyphen_penalty.powi(2) * opts.hyphen_penalty.signum();
+            }
+            let fitness = fitness_class(bad);
+            for (class, before) in best[a].into_iter().enumerate() {
+                let mut total = before + demerits;
+                if class.abs_diff(fitness) > 1 {
+                    total += opts.adjacent_demerits;
+                }
+                if total < best[k][fitness] {
+                    best[k][fitness] = total;
+                    prev[k][fitness] = (a, class);
                 }
-                let run = StyledRun {
-                    text: word.to_string(),
-                    style: style.clone(),
-                };
-                let width = word.len() as f64 * char_width;
-                hboxes.push(HBox {
-                    items: vec![LayoutNode::Run(run)],
-                    width,
-                });
             }
         }
+    }
 
-        Node::Macro { name: _, args } => {
-            for (i, arg) in args.iter().enumerate() {
-                if i > 0 {
-                    hboxes.push(glue());
-                }
-                flatten_ast(arg, hboxes, char_width, space_width);
+    let Some(fitness) = (0..FITNESS_CLASSES)
+        .filter(|&class| best[last][class].is_finite())
+        .min_by(|&x, &y| best[last][x].total_cmp(&best[last][y]))
+    else {
+        return break_paragraph(para, line_width, opts.hyphenate, lines);
+    };
+
+    // Walk the chosen breakpoints back from the end of the paragraph.
+    let mut chosen = vec![last];
+    let mut node = (last, fitness);
+    while node.0 > 0 {
+        node = prev[node.0][node.1];
+        chosen.push(node.0);
+    }
+    chosen.reverse();
+
+    for pair in chosen.windows(2) {
+        let (from, to) = (&cands[pair[0]], &cands[pair[1]]);
+        let end = if pair[1] == last {
+            para.end
+        } else if to.hyphen.is_some() {
+            LineEnd::Hyphen
+        } else {
+            LineEnd::Glue
+        };
+        let line_boxes: Vec<HBox> = match (from.hyphen, to.hyphen) {
+            // Both breaks fall in one word: the line is the piece between.
+            (Some((_, tail_start)), Some((head_end, _))) if from.end == to.end => {
+                vec![boxes[to.end].piece(tail_start, head_end, true)]
             }
+            _ => {
+                let tail = from
+                    .hyphen
+                    .map(|(_, tail_start)| boxes[from.end].piece(tail_start, usize::MAX, false));
+                let head = to
+                    .hyphen
+                    .map(|(head_end, _)| boxes[to.end].piece(0, head_end, true));
+                tail.into_iter()
+                    .chain(boxes[from.start..to.end].iter().cloned())
+                    .chain(head)
+                    .collect()
+            }
+        };
+        lines.push(Line {
+            width: boxes_width(&line_boxes),
+            boxes: line_boxes,
+            x_offset: 0.0,
+            keep_with_next: para.keep_with_next,
+            space_before: 0.0,
+            extra_height: 0.0,
+            fills_before: 0,
+            align: Alignment::Left,
+            column: 0,
+            role: Role::Paragraph,
+            block: 0,
+            end,
+        });
+    }
+}
+
+/// A place the Knuth-Plass breaker may end a line.
+struct Breakpoint {
+    /// The line ends with the boxes before `end`, then the head of box
+    /// `end` if the break is a hyphen.
+    end: usize,
+    /// The next line starts with the tail of box `end` if the break is a
+    /// hyphen, then the boxes from `start` on.
+    start: usize,
+    /// Where a hyphen splits box `end`, as in [`HBox::hyphen_breaks`].
+    hyphen: Option<(usize, usize)>,
+    /// The widths of the head, with its hyphen, and of the tail.
+    head_width: f64,
+    tail_width: f64,
+}
+
+impl Breakpoint {
+    /// A break at the glue at `at`, which the next line leaves out; or at
+    /// the start or end of the paragraph.
+    fn glue(boxes: &[HBox], at: usize) -> Self {
+        let mut start = at;
+        while start < boxes.len() && boxes[start].is_glue() {
+            start += 1;
+        }
+        Breakpoint {
+            end: at,
+            start,
+            hyphen: None,
+            head_width: 0.0,
+            tail_width: 0.0,
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::ast::{Node, TextStyle};
+/// TeX's fitness classes of a line, which only stretches here: very loose,
+/// loose and decent. Neighbouring lines more than one class apart cost
+/// [`CompileOptions::adjacent_demerits`].
+const FITNESS_CLASSES: usize = 3;
+const DECENT: usize = 2;
 
-    #[test]
-    fn test_layout_simple() {
-        let ast = Node::Seq(vec![Node::Text("Hello world".into())]);
-        // line_width=100, line_height=20, char=6, space=6
-        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0);
-        assert_eq!(pages.len(), 1);
-        let line = &pages[0].lines[0];
-        // expect: "Hello" glue "world" ⇒ 3 boxes
-        assert_eq!(line.boxes.len(), 3);
+/// The fitness class of a line of badness `bad`, as TeX assigns it.
+fn fitness_class(bad: f64) -> usize {
+    if bad > 99.0 {
+        0
+    } else if bad > 12.0 {
+        1
+    } else {
+        DECENT
     }
+}
 
-    #[test]
-    fn test_line_break() {
-        let long = "ThisIsAVeryLongWordWithoutSpaces";
-        let ast = Node::Seq(vec![Node::Text(long.into())]);
-        let pages = layout(&ast, 50.0, 20.0, 6.0, 6.0);
-        // too narrow => a single HBox per line
-        assert_eq!(pages[0].lines[0].boxes.len(), 1);
+/// TeX's badness of a line with the given natural width and total stretch.
+///
+/// Glue never shrinks, so callers only pass lines that fit at natural width.
+fn badness(natural: f64, stretch: f64, line_width: f64) -> f64 {
+    if natural >= line_width {
+        return 0.0;
     }
+    if stretch <= 0.0 {
+        return MAX_BADNESS;
+    }
+    let ratio = (line_width - natural) / stretch;
+    (100.0 * ratio.powi(3)).min(MAX_BADNESS)
+}
 
-    #[test]
-    fn test_layout_styled() {
-        let ast = Node::Seq(vec![
-            Node::StyledText("Bold".into(), TextStyle::Bold),
-            Node::Text("and normal".into()),
+/// Split lines into pages, or columns, whose lines, with the space above
+/// them, fill at most `page_height`.
+///
+/// A line marked `keep_with_next` is never left as the last line of a page:
+/// it is carried over to the next page together with the line after it,
+/// unless every line of the page is so marked, as in a `samepage` taller
+/// than a page, which is then broken where the page is full. As in TeX,
+/// space requested above the first line of a page is dropped.
+///
+/// The lines of each note in `notes` go at the foot of the page that
+/// references it, below a short rule, and take their room from the text.
+///
+/// What room a page has left is shared among the `\vfill`s on it, counting
+/// `trailing_fills` more on the last page, which stand below its last line.
+fn paginate(
+    lines: Vec<Line>,
+    mut notes: Vec<Vec<Line>>,
+    trailing_fills: usize,
+    line_height: f64,
+    page_height: f64,
+) -> Vec<Page> {
+    let height = |lines: &[Line]| -> f64 {
+        lines
+            .iter()
+            .map(|l| l.space_before + l.extra_height + line_height)
+            .sum()
+    };
+    let note_heights: Vec<f64> = notes.iter().map(|note| height(note)).collect();
+    let notes_height =
+        |lines: &[Line]| -> f64 { footnote_refs(lines).map(|n| note_heights[n]).sum() };
+    // Notes take their own height and, on a page that has any, the rule's.
+    let foot = |notes: f64| {
+        if notes > 0.0 {
+            notes + line_height
+        } else {
+            0.0
+        }
+    };
+
+    let mut pages = Vec::new();
+    let mut finish = |mut lines: Vec<Line>, trailing_fills: usize| {
+        let fills = lines.iter().map(|l| l.fills_before).sum::<usize>() + trailing_fills;
+        if fills > 0 && page_height.is_finite() {
+            let room = page_height - height(&lines) - foot(notes_height(&lines));
+            let share = room.max(0.0) / fills as f64;
+            for line in &mut lines {
+                line.space_before += share * line.fills_before as f64;
+            }
+        }
+        let refs: Vec<usize> = footnote_refs(&lines).collect();
+        if !refs.is_empty() {
+            let mut block = vec![footnote_rule(line_height)];
+            for n in refs {
+                block.append(&mut notes[n]);
+            }
+            // The rule goes down as far as the notes under it allow; on a
+            // page of unbounded height, it follows the text.
+            let room = page_height - height(&lines) - height(&block);
+            block[0].space_before = if room.is_finite() { room.max(0.0) } else { 0.0 };
+            lines.append(&mut block);
+        }
+        pages.push(Page { lines });
+    };
+    // Which lines begin and end their paragraph, or other block.
+    let n = lines.len();
+    let starts: Vec<bool> = (0..n)
+        .map(|i| i == 0 || lines[i - 1].block != lines[i].block)
+        .collect();
+    let ends: Vec<bool> = (0..n)
+        .map(|i| i + 1 == n || lines[i + 1].block != lines[i].block)
+        .collect();
+
+    let mut curr: Vec<Line> = Vec::new();
+    let mut used = 0.0;
+    let mut used_by_notes = 0.0;
+    for (i, mut line) in lines.into_iter().enumerate() {
+        let needed = line.space_before + line.extra_height + line_height;
+        let with_notes = used_by_notes + notes_height(std::slice::from_ref(&line));
+        if !curr.is_empty() && used + needed + foot(with_notes) > page_height + 1e-9 {
+            // Walk back over trailing keep-with-next lines, and over lines
+            // that would leave the first line of a paragraph alone at the
+            // foot of the page (an orphan) or its last alone at the top of
+            // the next (a widow), but never empty the page.
+            let first = i - curr.len();
+            let mut split = curr.len();
+            loop {
+                let before = split;
+                while split > 1 && curr[split - 1].keep_with_next {
+                    split -= 1;
+                }
+                if split > 1 && ends[first + split] && !starts[first + split] {
+                    split -= 1;
+                }
+                if split > 1 && starts[first + split - 1] && !ends[first + split - 1] {
+                    split -= 1;
+                }
+                if split == before {
+                    break;
+                }
+            }
+            if split == 1 && curr.iter().all(|line| line.keep_with_next) {
+                split = curr.len();
+            }
+            // The next page is allocated at the size of this one.
+            let mut next = Vec::with_capacity(curr.len());
+            next.extend(curr.drain(split..));
+            finish(std::mem::replace(&mut curr, next), 0);
+            if let Some(top) = curr.first_mut() {
+                top.space_before = 0.0;
+            }
+            used = height(&curr);
+            used_by_notes = notes_height(&curr);
+        }
+        if curr.is_empty() {
+            line.space_before = 0.0;
+        }
+        used += line.space_before + line.extra_height + line_height;
+        used_by_notes += notes_height(std::slice::from_ref(&line));
+        curr.push(line);
+    }
+    // A document with no lines at all is still one page, left blank.
+    if !curr.is_empty() || n == 0 {
+        finish(curr, trailing_fills);
+    }
+    pages
+}
+
+/// How far `items` reach above and below the baseline of a line of text
+/// set `line_height` apart.
+fn extent<'a>(
+    items: impl Iterator<Item = &'a LayoutNode>,
+    em: f64,
+    line_height: f64,
+) -> (f64, f64) {
+    items
+        .filter_map(|item| match item {
+            LayoutNode::Run(run) if run.style.script != Script::Baseline => {
+                let (size, rise) = run_metrics(&run.style, em, em * LEADING);
+                Some((rise + size * GLYPH_HEIGHT, size * GLYPH_DEPTH - rise))
+            }
+            // Text set larger than the body takes lines as much taller.
+            LayoutNode::Run(run) if run.style.scale > 1.0 => {
+                let depth = em * run.style.scale * GLYPH_DEPTH;
+                Some((line_height * run.style.scale - depth, depth))
+            }
+            LayoutNode::Run(run) => Some((
+                em * run.style.scale * GLYPH_HEIGHT,
+                em * run.style.scale * GLYPH_DEPTH,
+            )),
+            LayoutNode::Phantom { height, depth, .. } => Some((*height, *depth)),
+            _ => None,
+        })
+        .fold((0.0_f64, 0.0_f64), |(above, below), (high, low)| {
+            (above.max(high), below.max(low))
+        })
+}
+
+/// Move each of `lines` down by as much as the scripts, fractions and
+/// enlarged text on it reach higher above its baseline than a line of text
+/// set `line_height` apart leaves room for, or as those on the line before
+/// reach lower.
+fn make_room(lines: &mut [Line], em: f64, line_height: f64) {
+    let room_above = (line_height - em * GLYPH_DEPTH).max(em * GLYPH_HEIGHT);
+    let room_below = (line_height - em * GLYPH_HEIGHT).max(em * GLYPH_DEPTH);
+    let mut carried = 0.0;
+    for line in lines {
+        let (above, below) = extent(line.boxes.iter().flat_map(|hb| &hb.items), em, line_height);
+        line.extra_height += (above - room_above).max(0.0) + carried;
+        carried = (below - room_below).max(0.0);
+    }
+}
+
+/// The footnotes `lines` reference, in order.
+fn footnote_refs(lines: &[Line]) -> impl Iterator<Item = usize> + '_ {
+    lines
+        .iter()
+        .flat_map(|line| &line.boxes)
+        .flat_map(|hb| &hb.items)
+        .filter_map(|item| match item {
+            LayoutNode::Footnote(n) => Some(*n),
+            _ => None,
+        })
+}
+
+/// The short rule separating footnotes from the text above them.
+fn footnote_rule(line_height: f64) -> Line {
+    Line {
+        boxes: vec![HBox {
+            items: smallvec![LayoutNode::Rule {
+                width: FOOTNOTE_RULE_WIDTH,
+                height: FOOTNOTE_RULE_THICKNESS,
+                raise: 0.0,
+            }],
+            width: FOOTNOTE_RULE_WIDTH,
+        }],
+        width: FOOTNOTE_RULE_WIDTH,
+        x_offset: 0.0,
+        keep_with_next: false,
+        space_before: 0.0,
+        extra_height: (FOOTNOTE_RULE_THICKNESS - line_height).max(0.0),
+        fills_before: 0,
+        align: Alignment::Left,
+        column: 0,
+        role: Role::Paragraph,
+        block: 0,
+        end: LineEnd::Paragraph,
+    }
+}
+
+/// TeX's "Overfull \hbox" message for every line that sticks out of its
+/// column of a `line_width` text block, because something in it could not be
+/// broken, quoting the line's text.
+pub(crate) fn overfull_warnings(
+    pages: &[Page],
+    line_width: f64,
+    opts: &CompileOptions,
+) -> Vec<String> {
+    let measure = opts.column_width(line_width);
+    let advance = measure + opts.column_gap.to_pt();
+    let mut warnings = Vec::new();
+    for (idx, page) in pages.iter().enumerate() {
+        for line in &page.lines {
+            let right = line.x_offset - line.column as f64 * advance + line.width;
+            if right > measure + 1e-6 {
+                warnings.push(format!(
+                    "Overfull \\hbox ({:.2}pt too wide) on page {}: {}",
+                    right - measure,
+                    idx + 1,
+                    line_text(line)
+                ));
+            }
+        }
+    }
+    warnings
+}
+
+/// How one line of the document was broken, as [`CompileResult::trace`]
+/// reports it.
+///
+/// [`CompileResult::trace`]: crate::CompileResult::trace
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LineTrace {
+    /// The page the line is on, counting from 1.
+    pub page: usize,
+    /// The words of the line.
+    pub text: String,
+    /// The text of the last box on the line, the one that closed it.
+    pub closed_by: String,
+    pub end: LineEnd,
+    /// The width of the line, in points.
+    pub width: f64,
+    /// The space between the end of the line and the right edge of its
+    /// column, in points; negative when the line is overfull.
+    pub remaining: f64,
+}
+
+impl std::fmt::Display for LineTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "page {}: {} after `{}`, {:.2}pt wide, {:.2}pt left: {}",
+            self.page, self.end, self.closed_by, self.width, self.remaining, self.text
+        )
+    }
+}
+
+/// A [`LineTrace`] for every line of `pages`, set in a `line_width` text
+/// block, in the order they are read.
+pub(crate) fn trace(pages: &[Page], line_width: f64, opts: &CompileOptions) -> Vec<LineTrace> {
+    let measure = opts.column_width(line_width);
+    let advance = measure + opts.column_gap.to_pt();
+    let mut lines: Vec<(usize, &Line)> = pages
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, page)| page.lines.iter().map(move |line| (idx, line)))
+        .collect();
+    // Columns hold their lines one after the other.
+    lines.sort_by_key(|(idx, line)| (*idx, line.column));
+    lines
+        .into_iter()
+        .map(|(idx, line)| {
+            let right = line.x_offset - line.column as f64 * advance + line.width;
+            let closed_by = line.boxes.iter().rev().find(|hb| !hb.is_glue());
+            LineTrace {
+                page: idx + 1,
+                text: line_text(line),
+                closed_by: closed_by
+                    .map_or_else(String::new, |hb| boxes_text(std::slice::from_ref(hb))),
+                end: line.end,
+                width: line.width,
+                remaining: measure - right,
+            }
+        })
+        .collect()
+}
+
+/// The words of `line`, separated by single spaces.
+pub(crate) fn line_text(line: &Line) -> String {
+    boxes_text(&line.boxes)
+}
+
+/// The words of `boxes`, separated by single spaces.
+fn boxes_text(boxes: &[HBox]) -> String {
+    let mut text = String::new();
+    for item in boxes.iter().flat_map(|hb| &hb.items) {
+        match item {
+            LayoutNode::Run(run) => text.push_str(&run.text),
+            LayoutNode::Glue(_) | LayoutNode::Kern(_) | LayoutNode::Phantom { .. } => {
+                text.push(' ')
+            }
+            LayoutNode::Image { .. } | LayoutNode::Rule { .. } | LayoutNode::Footnote(_) => {}
+        }
+    }
+    text.trim().to_string()
+}
+
+/// Place consecutive columns side by side, `per_page` to a page, each
+/// `advance` further right than the one before.
+fn set_columns(columns: Vec<Page>, per_page: usize, advance: f64) -> Vec<Page> {
+    if per_page == 1 {
+        return columns;
+    }
+    let mut pages: Vec<Page> = Vec::with_capacity(columns.len().div_ceil(per_page));
+    for (idx, mut column) in columns.into_iter().enumerate() {
+        let n = idx % per_page;
+        for line in &mut column.lines {
+            line.column = n;
+            line.x_offset += n as f64 * advance;
+        }
+        // The first column becomes the page, and the others join it.
+        match pages.last_mut() {
+            Some(page) if n > 0 => page.lines.append(&mut column.lines),
+            _ => pages.push(column),
+        }
+    }
+    pages
+}
+
+/// Whether inter-word glue goes before `nodes[i]`, which is not the first
+/// sibling, nor next to a tie or discretionary hyphen.
+fn glued(nodes: &[Node], i: usize) -> bool {
+    // A footnote mark also sticks to the word before it, and a label,
+    // which sets nothing, adds no space of its own.
+    let tied = nodes[i].joins_neighbours()
+        || matches!(nodes[i], Node::Footnote(_) | Node::Label { .. })
+        || (i > 0 && nodes[i - 1].joins_neighbours());
+    i > 0 && !tied
+}
+
+/// Walks the AST and collects the vertical list of blocks.
+///
+/// Strategy:
+/// 1. Split each Text/StyledText on internal whitespace → words + inter-word glue.
+/// 2. Inject one `space_width` glue between every two AST siblings (Seq or Macro).
+/// 3. Headings, paragraph and forced breaks, and tables close the current paragraph.
+struct Flattener {
+    blocks: Vec<Block>,
+    line_width: f64,
+    line_height: f64,
+    char_width: f64,
+    space_width: f64,
+    /// Placement of paragraphs and blocks, set by `center`/`flushright`/`flushleft`.
+    align: Alignment,
+    /// Left indentation given to new paragraphs.
+    indent: f64,
+    /// Right indentation given to new paragraphs.
+    right_indent: f64,
+    /// First-line indentation of paragraphs that follow a paragraph break.
+    parindent: f64,
+    /// Columns between the tab stops of verbatim text.
+    tab_width: usize,
+    /// Where tables are placed, if not as the text around them.
+    table_align: Option<Alignment>,
+    /// How many lists the current material is nested in.
+    list_depth: usize,
+    /// A tie was just set: the next word joins the last box.
+    tied: bool,
+    /// Where to look up kerning, when it is on.
+    shaper: Option<Shaper>,
+    /// The body of every footnote so far, in the order they are numbered.
+    footnotes: Vec<Paragraph>,
+    /// Labels so far and the targets references are set from.
+    xrefs: CrossReferences,
+    /// In a table cell or footnote, the block of the table or the mark,
+    /// which labels in it belong to.
+    label_block: Option<usize>,
+}
+
+impl Flattener {
+    fn new(line_width: f64, line_height: f64, char_width: f64, space_width: f64) -> Self {
+        Flattener {
+            blocks: Vec::new(),
+            line_width,
+            line_height,
+            char_width,
+            space_width,
+            align: Alignment::Left,
+            indent: 0.0,
+            right_indent: 0.0,
+            parindent: 0.0,
+            tab_width: 8,
+            table_align: None,
+            list_depth: 0,
+            tied: false,
+            shaper: None,
+            footnotes: Vec::new(),
+            xrefs: CrossReferences::default(),
+            label_block: None,
+        }
+    }
+
+    /// Width of `text` set in `style`: a fixed advance per character, plus
+    /// the font's kerning when it is on.
+    fn measure(&self, text: &str, style: &TextStyle) -> f64 {
+        measure(text, style, self.char_width, self.shaper.as_ref())
+    }
+
+    /// An empty paragraph in the current context.
+    fn new_paragraph(&self) -> Paragraph {
+        Paragraph {
+            indent: self.indent,
+            right_indent: self.right_indent,
+            align: self.align,
+            ..Paragraph::default()
+        }
+    }
+
+    /// The paragraph currently being filled, opening one if needed.
+    fn paragraph(&mut self) -> &mut Paragraph {
+        if !matches!(self.blocks.last(), Some(Block::Paragraph(_))) {
+            self.blocks.push(Block::Paragraph(self.new_paragraph()));
+        }
+        match self.blocks.last_mut() {
+            Some(Block::Paragraph(para)) => para,
+            _ => unreachable!("a paragraph was just pushed"),
+        }
+    }
+
+    /// Open a fresh paragraph, replacing the current one if it is still empty.
+    fn start_paragraph(&mut self) {
+        self.tied = false;
+        let fresh = self.new_paragraph();
+        let para = self.paragraph();
+        if para.boxes.is_empty() && para.label.is_none() {
+            *para = fresh;
+        } else {
+            self.blocks.push(Block::Paragraph(fresh));
+        }
+    }
+
+    /// Append inter-word glue to the current paragraph; glue never starts a
+    /// paragraph, nor follows other glue, so what sets nothing adds no space.
+    fn push_glue(&mut self) {
+        let space_width = self.space_width;
+        let para = self.paragraph();
+        if !para.boxes.last().is_none_or(HBox::is_glue) {
+            para.boxes.push(HBox::glue(space_width));
+        }
+    }
+
+    /// Split `s` on whitespace and append one HBox per word, separated by glue.
+    ///
+    /// No-break spaces (U+00A0) do not split words.
+    fn push_words(&mut self, s: &str, style: &TextStyle) {
+        let words = s
+            .split(|c: char| c.is_whitespace() && c != '\u{a0}')
+            .filter(|w| !w.is_empty());
+        for (j, word) in words.enumerate() {
+            if j > 0 {
+                self.push_glue();
+            }
+            let width = self.measure(word, style);
+            let run = || StyledRun {
+                text: word.to_string(),
+                style: style.clone(),
+                width,
+            };
+            let tied = std::mem::take(&mut self.tied);
+            let para = self.paragraph();
+            match para.boxes.last_mut() {
+                Some(last) if tied => {
+                    match last.items.last_mut() {
+                        // After a discretionary hyphen the word carries on.
+                        Some(LayoutNode::Run(prev)) if prev.style == *style => {
+                            prev.text.push_str(word);
+                            prev.width += width;
+                        }
+                        _ => last.items.push(LayoutNode::Run(run())),
+                    }
+                    last.width += width;
+                }
+                _ => para.boxes.push(HBox {
+                    items: smallvec![LayoutNode::Run(run())],
+                    width,
+                }),
+            }
+        }
+    }
+
+    /// Append a tie: space that joins the last box to the next word, so the
+    /// line-breaker cannot separate them.
+    fn push_tie(&mut self) {
+        let space_width = self.space_width;
+        let para = self.paragraph();
+        match para.boxes.last_mut() {
+            Some(last) => {
+                last.items.push(LayoutNode::Glue(space_width));
+                last.width += space_width;
+            }
+            None => para.boxes.push(HBox::glue(space_width)),
+        }
+        self.tied = true;
+    }
+
+    /// Append fixed space to the last box, which the next word then continues.
+    fn push_kern(&mut self, width: f64) {
+        self.push_space(LayoutNode::Kern(width), width);
+    }
+
+    /// Append `item`, space `width` wide, to the last box, which the next
+    /// word then continues.
+    fn push_space(&mut self, item: LayoutNode, width: f64) {
+        let para = self.paragraph();
+        match para.boxes.last_mut() {
+            Some(last) if !last.is_glue() => {
+                last.items.push(item);
+                last.width += width;
+            }
+            _ => para.boxes.push(HBox {
+                items: smallvec![item],
+                width,
+            }),
+        }
+        self.tied = true;
+    }
+
+    /// Room for `items`, `natural` wide, which is not drawn; only as wide,
+    /// or as high and deep, as they are if `width` or `height` asks.
+    fn phantom(&self, items: &[LayoutNode], natural: f64, width: bool, height: bool) -> LayoutNode {
+        let em = em(&TextStyle::default(), self.char_width);
+        let (above, below) = match height {
+            true => extent(items.iter(), em, self.line_height),
+            false => (0.0, 0.0),
+        };
+        LayoutNode::Phantom {
+            width: if width { natural } else { 0.0 },
+            height: above,
+            depth: below,
+        }
+    }
+
+    /// Set `item`, of the given size, on a line of its own that is tall
+    /// enough to hold it.
+    fn push_figure(&mut self, item: LayoutNode, width: f64, height: f64) {
+        self.start_paragraph();
+        self.blocks.push(Block::Lines(vec![Line {
+            boxes: vec![HBox {
+                items: smallvec![item],
+                width,
+            }],
+            width,
+            x_offset: self.align.offset(self.line_width, width),
+            keep_with_next: false,
+            space_before: 0.0,
+            extra_height: (height - self.line_height).max(0.0),
+            fills_before: 0,
+            align: self.align,
+            column: 0,
+            role: Role::Paragraph,
+            block: 0,
+            end: LineEnd::Paragraph,
+        }]));
+        self.start_paragraph();
+    }
+
+    /// Set verbatim text in the monospace face, one line per source line,
+    /// keeping every space. Tabs move on to the next tab stop, and the
+    /// lines are never broken.
+    fn verbatim(&mut self, text: &str) {
+        self.start_paragraph();
+        let style = TextStyle::from(FontFace::Monospace);
+        let lines = text
+            .lines()
+            .map(|line| {
+                let line = expand_tabs(line, self.tab_width);
+                let width = self.measure(&line, &style);
+                let boxes = if line.is_empty() {
+                    Vec::new()
+                } else {
+                    vec![HBox {
+                        items: smallvec![LayoutNode::Run(StyledRun {
+                            text: line,
+                            style: style.clone(),
+                            width,
+                        })],
+                        width,
+                    }]
+                };
+                Line {
+                    boxes,
+                    width,
+                    x_offset: self.indent,
+                    keep_with_next: false,
+                    space_before: 0.0,
+                    extra_height: 0.0,
+                    fills_before: 0,
+                    align: Alignment::Left,
+                    column: 0,
+                    role: Role::Paragraph,
+                    block: 0,
+                    end: LineEnd::Paragraph,
+                }
+            })
+            .collect();
+        self.blocks.push(Block::Lines(lines));
+        self.start_paragraph();
+    }
+
+    /// Set the title, author and date of `\maketitle` centred on lines of
+    /// their own, the title largest, each kept with the next. Parts left
+    /// empty take no room.
+    fn title_block(&mut self, parts: [&Vec<Node>; 3]) {
+        let outer = self.align;
+        self.align = Alignment::Center;
+        let em = em(&TextStyle::default(), self.char_width);
+        let scales = [TITLE_SCALE, BYLINE_SCALE, BYLINE_SCALE];
+        for ((part, scale), space) in parts.into_iter().zip(scales).zip(TITLE_SPACES) {
+            if part.is_empty() {
+                continue;
+            }
+            self.start_paragraph();
+            let part: Vec<Node> = part.iter().map(|node| enlarge(node, scale)).collect();
+            self.flatten_all(&part);
+            self.paragraph().keep_with_next = true;
+            self.blocks.push(Block::VSpace(space * em));
+        }
+        self.align = outer;
+        self.start_paragraph();
+    }
+
+    /// Set `body` as a block quotation, indented from both margins; with
+    /// `first_lines`, as in `quotation`, the first line of each of its
+    /// paragraphs is indented further. Quotes inside quotes, and lists
+    /// inside either, add to the margins they are in.
+    fn quote(&mut self, body: &[Node], first_lines: bool) {
+        let outer = (self.indent, self.right_indent, self.parindent);
+        let margin = QUOTE_INDENT * self.char_width;
+        self.indent += margin;
+        self.right_indent += margin;
+        self.start_paragraph();
+        if first_lines {
+            self.parindent = QUOTATION_PARINDENT * self.char_width;
+            let parindent = self.parindent;
+            self.paragraph().parindent = parindent;
+        }
+        self.flatten_all(body);
+        (self.indent, self.right_indent, self.parindent) = outer;
+        self.start_paragraph();
+    }
+
+    /// Set the next footnote's number as a mark after the last word, and
+    /// its body aside, starting with the same number, for the page foot.
+    fn footnote(&mut self, body: &[Node]) {
+        let n = self.footnotes.len();
+        // Reserve the number before any notes inside this one take theirs.
+        self.footnotes.push(Paragraph::default());
+        let mark = (n + 1).to_string();
+        let style = TextStyle {
+            script: Script::Superscript,
+            ..TextStyle::default()
+        };
+        self.tied = true;
+        self.push_words(&mark, &style);
+        if let Some(last) = self.paragraph().boxes.last_mut() {
+            last.items.push(LayoutNode::Footnote(n));
+        }
+        self.tied = true;
+
+        let width = self.measure(&mark, &style);
+        let mut boxes = vec![
+            HBox {
+                items: smallvec![LayoutNode::Run(StyledRun {
+                    text: mark,
+                    style,
+                    width,
+                })],
+                width,
+            },
+            HBox::glue(self.space_width),
+        ];
+        let block = self.blocks.len() - 1;
+        boxes.extend(self.cell_boxes(body, block));
+        self.footnotes[n].boxes = boxes;
+    }
+
+    /// Mark a discretionary hyphen at the end of the last word, which the
+    /// next word then continues.
+    fn push_discretionary(&mut self) {
+        let para = self.paragraph();
+        let Some(LayoutNode::Run(run)) = para.boxes.last_mut().and_then(|hb| hb.items.last_mut())
+        else {
+            return;
+        };
+        run.text.push(SOFT_HYPHEN);
+        self.tied = true;
+    }
+
+    /// Flatten the top-level `nodes` of a document one paragraph at a
+    /// time, taking the blocks of paragraphs `cache` has seen from it.
+    ///
+    /// A paragraph starts in the empty paragraph the break before it left,
+    /// if any, which is part of its key.
+    fn flatten_cached(&mut self, nodes: &[Node], cache: &mut LayoutCache) {
+        let mut from = 0;
+        while from < nodes.len() {
+            let to = nodes[from..]
+                .iter()
+                .position(|node| *node == Node::ParBreak)
+                .map_or(nodes.len(), |n| from + n);
+            if to > from {
+                self.flatten_paragraph(nodes, from..to, cache);
+            }
+            if to < nodes.len() {
+                if glued(nodes, to) {
+                    self.push_glue();
+                }
+                self.flatten(&Node::ParBreak);
+            }
+            from = to + 1;
+        }
+    }
+
+    /// Flatten `nodes[range]`, a paragraph of the top-level `nodes`, as
+    /// [`Self::flatten_all`] would, or take its blocks from `cache`.
+    fn flatten_paragraph(&mut self, nodes: &[Node], range: Range<usize>, cache: &mut LayoutCache) {
+        if glued(nodes, range.start) {
+            self.push_glue();
+        }
+        let paragraph = &nodes[range];
+        let start = match self.blocks.last() {
+            Some(Block::Paragraph(para)) if para.boxes.is_empty() => self.blocks.len() - 1,
+            _ => self.blocks.len(),
+        };
+        if self.tied {
+            self.flatten_all(paragraph);
+            return;
+        }
+        // The block begun is at most an empty paragraph, compared by its
+        // `Debug` form rather than field by field.
+        let begun = format!("{:?}", &self.blocks[start..]);
+        let mut hasher = DefaultHasher::new();
+        paragraph.hash(&mut hasher);
+        begun.hash(&mut hasher);
+        let key = hasher.finish();
+        cache.used.insert(key);
+        match cache.paragraphs.get(&key) {
+            Some(cached) if cached.nodes == paragraph && cached.begun == begun => {
+                self.blocks.truncate(start);
+                self.blocks.extend(cached.blocks.iter().cloned());
+                self.tied = cached.tied;
+            }
+            _ => {
+                // Footnotes, labels and references are set from the rest
+                // of the document, so a paragraph with any is not kept.
+                let marks = |f: &Self| f.footnotes.len() + f.xrefs.labels.len() + f.xrefs.refs;
+                let before = marks(self);
+                self.flatten_all(paragraph);
+                if marks(self) == before {
+                    let cached = CachedParagraph {
+                        nodes: paragraph.to_vec(),
+                        begun,
+                        blocks: self.blocks[start..].to_vec(),
+                        tied: self.tied,
+                    };
+                    cache.paragraphs.insert(key, cached);
+                }
+            }
+        }
+    }
+
+    /// Flatten siblings, separated by inter-word glue except around ties
+    /// and discretionary hyphens.
+    fn flatten_all(&mut self, nodes: &[Node]) {
+        for (i, node) in nodes.iter().enumerate() {
+            if glued(nodes, i) {
+                self.push_glue();
+            }
+            self.flatten(node);
+        }
+    }
+
+    fn flatten(&mut self, ast: &Node) {
+        match ast {
+            Node::Seq(children) => self.flatten_all(children),
+
+            Node::Text(s) => self.push_words(s, &TextStyle::default()),
+
+            Node::StyledText(s, style) => self.push_words(s, style),
+
+            Node::Heading {
+                level,
+                number,
+                title,
+            } => {
+                let scale = HEADING_SCALES[usize::from(*level).clamp(1, 2) - 1];
+                let style = TextStyle {
+                    face: FontFace::Bold,
+                    scale,
+                    ..TextStyle::default()
+                };
+                self.blocks
+                    .push(Block::VSpace(self.line_height * HEADING_SPACE_BEFORE));
+                self.start_paragraph();
+                self.push_words(number, &style);
+                self.push_glue();
+                let title: Vec<Node> = title.iter().map(|node| restyle(node, &style)).collect();
+                self.flatten_all(&title);
+                let para = self.paragraph();
+                para.keep_with_next = true;
+                para.role = Role::Heading(*level);
+                self.blocks
+                    .push(Block::VSpace(self.line_height * HEADING_SPACE_AFTER));
+                self.start_paragraph();
+            }
+
+            Node::TitleBlock {
+                title,
+                author,
+                date,
+            } => self.title_block([title, author, date]),
+
+            Node::Macro { args, .. } => self.flatten_all(args),
+
+            Node::ParBreak => {
+                // Only a paragraph with something in it is ended; further
+                // breaks add no more space, as in TeX. The first paragraph,
+                // and those after headings and lists, are not indented.
+                if matches!(self.blocks.last(), Some(Block::Paragraph(para))
+                    if !para.boxes.is_empty() || para.label.is_some())
+                {
+                    self.blocks
+                        .push(Block::VSpace(self.line_height * PARAGRAPH_SPACE));
+                    self.start_paragraph();
+                    let parindent = self.parindent;
+                    self.paragraph().parindent = parindent;
+                }
+            }
+
+            Node::NoIndent => {
+                // Inside a paragraph that has begun, as in TeX, it does nothing.
+                let para = self.paragraph();
+                if para.boxes.is_empty() {
+                    para.parindent = 0.0;
+                }
+            }
+
+            Node::LineBreak {
+                space,
+                no_page_break,
+            } => {
+                if let Some(Block::Paragraph(para)) = self.blocks.last_mut() {
+                    para.end = LineEnd::Forced;
+                    para.keep_last_with_next = *no_page_break;
+                }
+                if let Some(space) = space {
+                    self.blocks.push(Block::VSpace(space.to_pt()));
+                }
+                self.start_paragraph();
+            }
+
+            Node::Image {
+                path,
+                width,
+                pixels: (px_w, px_h),
+            } => {
+                // One pixel is one point unless a width is given; either way
+                // the image is shrunk to fit the line.
+                let natural = f64::from((*px_w).max(1));
+                let width = width.map_or(natural, Length::to_pt).min(self.line_width);
+                let height = width * f64::from(*px_h) / natural;
+                let image = LayoutNode::Image {
+                    path: path.clone(),
+                    width,
+                    height,
+                };
+                self.push_figure(image, width, height);
+            }
+
+            Node::Rule { width, height } => {
+                let width = width
+                    .map_or(self.line_width, Length::to_pt)
+                    .min(self.line_width);
+                let height = height.to_pt();
+                let rule = LayoutNode::Rule {
+                    width,
+                    height,
+                    raise: 0.0,
+                };
+                self.push_figure(rule, width, height);
+            }
+
+            Node::VSpace(height) => self.blocks.push(Block::VSpace(*height)),
+
+            Node::VFill => self.blocks.push(Block::VFill),
+
+            Node::Phantom {
+                body,
+                width,
+                height,
+            } => {
+                let block = self.blocks.len();
+                let boxes = self.cell_boxes(body, block);
+                let items: Vec<LayoutNode> = boxes.iter().flat_map(|hb| hb.items.clone()).collect();
+                let phantom = self.phantom(&items, boxes_width(&boxes), *width, *height);
+                let LayoutNode::Phantom { width, .. } = phantom else {
+                    unreachable!("a phantom was just made")
+                };
+                self.push_space(phantom, width);
+            }
+
+            Node::NonBreakingSpace => self.push_tie(),
+
+            Node::HSpace(width) => self.push_kern(width.to_pt()),
+
+            Node::DiscretionaryHyphen => self.push_discretionary(),
+
+            // Comments are kept for source tools and never typeset.
+            Node::Comment(_) => {}
+
+            Node::Verbatim(text) => self.verbatim(text),
+
+            Node::Footnote(body) => self.footnote(body),
+
+            Node::Label { key, number } => {
+                let block = self.label_block.unwrap_or(match self.blocks.last() {
+                    Some(Block::Paragraph(_)) => self.blocks.len() - 1,
+                    _ => self.blocks.len(),
+                });
+                self.xrefs.labels.push((key.clone(), number.clone(), block));
+            }
+
+            Node::Ref { key, page } => {
+                self.xrefs.refs += 1;
+                let text = match self.xrefs.targets.get(key) {
+                    Some(target) if *page => (target.page + 1).to_string(),
+                    Some(target) => target.number.clone(),
+                    None => {
+                        if !self.xrefs.undefined.contains(key) {
+                            self.xrefs.undefined.push(key.clone());
+                        }
+                        "??".to_string()
+                    }
+                };
+                self.push_words(&text, &TextStyle::default());
+            }
+
+            Node::Math(body) => {
+                // Inline math is set as one unbreakable box.
+                let mut hbox = HBox {
+                    items: SmallVec::new(),
+                    width: 0.0,
+                };
+                for node in body {
+                    self.math_runs(node, Script::Baseline, &mut hbox);
+                }
+                self.paragraph().boxes.push(hbox);
+            }
+
+            // Scripts outside `$...$` cannot be produced by the parser.
+            Node::Superscript(arg) | Node::Subscript(arg) => self.flatten(arg),
+
+            // A fraction in running text is set as math all the same.
+            Node::Frac { .. } => self.flatten(&Node::Math(vec![ast.clone()])),
+
+            Node::Environment { name, body } if name == "quote" || name == "quotation" => {
+                self.quote(body, name == "quotation");
+            }
+
+            Node::Environment { name, body } if name == "abstract" => {
+                // A centred, bold heading over a quotation.
+                let outer = self.align;
+                self.align = Alignment::Center;
+                self.start_paragraph();
+                self.push_words("Abstract", &FontFace::Bold.into());
+                self.paragraph().keep_with_next = true;
+                self.align = outer;
+                self.quote(body, true);
+            }
+
+            Node::Environment { name, body } if name == "samepage" => {
+                self.start_paragraph();
+                self.blocks.push(Block::KeepBegin);
+                self.flatten_all(body);
+                self.blocks.push(Block::KeepEnd);
+                self.start_paragraph();
+            }
+
+            Node::Environment { name, body } => {
+                let outer = self.align;
+                if let Some(align) = Alignment::from_environment(name) {
+                    self.align = align;
+                    self.start_paragraph();
+                }
+                self.flatten_all(body);
+                if self.align != outer {
+                    self.align = outer;
+                    self.start_paragraph();
+                }
+            }
+
+            Node::Table { spec, rows, hlines } => self.table(spec, rows, hlines),
+
+            Node::List { kind, items } => {
+                // Each level is indented further, and numbered from 1 again.
+                let outer = self.indent;
+                self.indent += LIST_INDENT * self.char_width;
+                let bullet = BULLETS[self.list_depth.min(BULLETS.len() - 1)];
+                self.list_depth += 1;
+                let mut number = 0;
+                for item in items {
+                    self.start_paragraph();
+                    let marker = match (kind, &item.label) {
+                        // A description's term begins the first line, out
+                        // where the list's own text begins, and its
+                        // definition follows on.
+                        (ListKind::Description, label) => {
+                            self.paragraph().parindent = -LIST_INDENT * self.char_width;
+                            let bold = FontFace::Bold.into();
+                            for node in label.iter().flatten() {
+                                self.flatten(&restyle(node, &bold));
+                            }
+                            self.push_glue();
+                            None
+                        }
+                        // A label stands in for the bullet or number, which
+                        // is then not counted.
+                        (_, Some(label)) => Some(collect_plain_text(&Node::seq(label.clone()))),
+                        (ListKind::Enumerate, None) => {
+                            number += 1;
+                            Some(format!("{}.", number))
+                        }
+                        (ListKind::Itemize, None) => Some(bullet.to_string()),
+                    };
+                    if let Some(marker) = marker {
+                        let label = self.label_box(marker);
+                        self.paragraph().label = Some(label);
+                    }
+                    self.flatten_all(&item.body);
+                }
+                self.list_depth -= 1;
+                self.indent = outer;
+                self.start_paragraph();
+            }
+        }
+    }
+
+    /// Append the runs of math material `node` to `hbox`, in `script` position.
+    fn math_runs(&self, node: &Node, script: Script, hbox: &mut HBox) {
+        let mut push = |text: &str, style: &TextStyle| {
+            let style = TextStyle {
+                script,
+                ..style.clone()
+            };
+            let width = self.measure(text, &style);
+            hbox.width += width;
+            hbox.items.push(LayoutNode::Run(StyledRun {
+                text: text.to_string(),
+                style,
+                width,
+            }));
+        };
+        match node {
+            Node::Text(s) => push(s, &TextStyle::default()),
+            Node::StyledText(s, style) => push(s, style),
+            // Scripts within a fraction stay level with the part they are in.
+            Node::Superscript(arg) | Node::Subscript(arg)
+                if matches!(script, Script::Numerator | Script::Denominator) =>
+            {
+                self.math_runs(arg, script, hbox)
+            }
+            Node::Superscript(arg) => self.math_runs(arg, Script::Superscript, hbox),
+            Node::Subscript(arg) => self.math_runs(arg, Script::Subscript, hbox),
+            Node::Frac { num, den } if script == Script::Baseline => self.fraction(num, den, hbox),
+            // Fractions are not stacked within fractions or scripts, but set
+            // on one level as `num/den`.
+            Node::Frac { num, den } => {
+                for node in num {
+                    self.math_runs(node, script, hbox);
+                }
+                self.math_runs(&Node::text("/"), script, hbox);
+                for node in den {
+                    self.math_runs(node, script, hbox);
+                }
+            }
+            Node::Seq(children) | Node::Math(children) => {
+                for child in children {
+                    self.math_runs(child, script, hbox);
+                }
+            }
+            Node::Macro { args, .. } => {
+                for arg in args {
+                    self.math_runs(arg, script, hbox);
+                }
+            }
+            Node::HSpace(width) => {
+                hbox.width += width.to_pt();
+                hbox.items.push(LayoutNode::Kern(width.to_pt()));
+            }
+            Node::Phantom {
+                body,
+                width,
+                height,
+            } => {
+                let mut inner = HBox {
+                    items: SmallVec::new(),
+                    width: 0.0,
+                };
+                for node in body {
+                    self.math_runs(node, script, &mut inner);
+                }
+                let phantom = self.phantom(&inner.items, inner.width, *width, *height);
+                if let LayoutNode::Phantom { width, .. } = phantom {
+                    hbox.width += width;
+                }
+                hbox.items.push(phantom);
+            }
+            // Structural material has no meaning inside math.
+            _ => {}
+        }
+    }
+
+    /// Append `num` stacked over `den` to `hbox`, each centred on a bar as
+    /// wide as the wider of them.
+    fn fraction(&self, num: &[Node], den: &[Node], hbox: &mut HBox) {
+        let part = |nodes: &[Node], script| {
+            let mut part = HBox {
+                items: SmallVec::new(),
+                width: 0.0,
+            };
+            for node in nodes {
+                self.math_runs(node, script, &mut part);
+            }
+            part
+        };
+        let num = part(num, Script::Numerator);
+        let den = part(den, Script::Denominator);
+        let width = num.width.max(den.width);
+        // After each part, the pen goes back to where the fraction starts.
+        for part in [num, den] {
+            let offset = (width - part.width) / 2.0;
+            hbox.items.push(LayoutNode::Kern(offset));
+            hbox.items.extend(part.items);
+            hbox.items.push(LayoutNode::Kern(-(offset + part.width)));
+        }
+        let em = self.char_width / CHAR_WIDTH_EM;
+        hbox.items.push(LayoutNode::Rule {
+            width,
+            height: FRACTION_RULE_THICKNESS,
+            raise: em * MATH_AXIS - FRACTION_RULE_THICKNESS / 2.0,
+        });
+        hbox.width += width;
+    }
+
+    /// A list marker followed by a space, to hang left of an item's text.
+    fn label_box(&self, marker: String) -> HBox {
+        let text_width = text_len(&marker) as f64 * self.char_width;
+        HBox {
+            items: smallvec![
+                LayoutNode::Run(StyledRun {
+                    text: marker,
+                    style: TextStyle::default(),
+                    width: text_width,
+                }),
+                LayoutNode::Glue(self.space_width),
+            ],
+            width: text_width + self.space_width,
+        }
+    }
+
+    /// Lay a table out as a grid of single-line cells.
+    ///
+    /// Each column is as wide as its widest cell, which sets its cells as
+    /// `spec` aligns them, with a rule wherever `spec` has one between them
+    /// and above each row in `hlines`. The columns of a `tabular*` are
+    /// spread out to its width. The whole table is then positioned on the
+    /// line according to the table alignment option, or else the current
+    /// alignment.
+    fn table(&mut self, spec: &TableSpec, rows: &[TableRow], hlines: &[usize]) {
+        let block = self.blocks.len();
+        let cells: Vec<Vec<Vec<HBox>>> = rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|cell| self.cell_boxes(cell, block))
+                    .collect()
+            })
+            .collect();
+        let n_cols = cells
+            .iter()
+            .map(Vec::len)
+            .max()
+            .unwrap_or(0)
+            .max(spec.align.len());
+        let mut col_widths = vec![0.0_f64; n_cols];
+        for row in &cells {
+            for (j, cell) in row.iter().enumerate() {
+                col_widths[j] = col_widths[j].max(boxes_width(cell));
+            }
+        }
+        // Whether there is a rule before each column, and after the last.
+        let rules: Vec<bool> = (0..=n_cols)
+            .map(|j| spec.rules.get(j) == Some(&true))
+            .collect();
+        let mut gap = TABLE_COLUMN_GAP * self.space_width;
+        let table_width = |gap: f64| {
+            let edges = [rules[0], rules[n_cols]].iter().filter(|&&r| r).count();
+            col_widths.iter().sum::<f64>()
+                + gap * n_cols.saturating_sub(1) as f64
+                + gap / 2.0 * edges as f64
+                + TABLE_RULE_THICKNESS * rules.iter().filter(|&&r| r).count() as f64
+        };
+        if let Some(width) = spec.width
+            && n_cols > 1
+        {
+            let width = match width {
+                TableWidth::Fixed(length) => length.to_pt(),
+                TableWidth::Text(fraction) => fraction * self.line_width,
+            };
+            gap += ((width - table_width(gap)) / (n_cols - 1) as f64).max(0.0);
+        }
+        let table_width = table_width(gap);
+        let align = self.table_align.unwrap_or(self.align);
+        let x_offset = align.offset(self.line_width, table_width);
+
+        // Rules span the height of a row, which reaches from the depth of
+        // its text to a line height above that.
+        let depth = em(&TextStyle::default(), self.char_width) * GLYPH_DEPTH;
+        let vrule = HBox {
+            items: smallvec![LayoutNode::Rule {
+                width: TABLE_RULE_THICKNESS,
+                height: self.line_height,
+                raise: -depth,
+            }],
+            width: TABLE_RULE_THICKNESS,
+        };
+        // Drawn across the table from its start, leaving the pen there.
+        let hrule = |raise: f64| HBox {
+            items: smallvec![
+                LayoutNode::Rule {
+                    width: table_width,
+                    height: TABLE_RULE_THICKNESS,
+                    raise,
+                },
+                LayoutNode::Kern(-table_width),
+            ],
+            width: 0.0,
+        };
+
+        let mut lines = Vec::new();
+        let last_row = cells.len().saturating_sub(1);
+        for (i, row) in cells.into_iter().enumerate() {
+            let mut boxes = Vec::new();
+            if hlines.contains(&i) {
+                boxes.push(hrule(self.line_height - depth - TABLE_RULE_THICKNESS));
+            }
+            if i == last_row && hlines.contains(&rows.len()) {
+                boxes.push(hrule(-depth));
+            }
+            // Space owed before the next thing drawn.
+            let mut pad = 0.0;
+            let rule = |boxes: &mut Vec<HBox>, pad: &mut f64| {
+                if *pad > 0.0 {
+                    boxes.push(HBox::glue(*pad));
+                }
+                boxes.push(vrule.clone());
+                *pad = 0.0;
+            };
+            let mut row = row.into_iter();
+            for (j, &col_width) in col_widths.iter().enumerate() {
+                if j > 0 && rules[j] {
+                    pad += gap / 2.0;
+                    rule(&mut boxes, &mut pad);
+                    pad += gap / 2.0;
+                } else if j > 0 {
+                    pad += gap;
+                } else if rules[0] {
+                    rule(&mut boxes, &mut pad);
+                    pad += gap / 2.0;
+                }
+                let cell = row.next().unwrap_or_default();
+                let cell_width = boxes_width(&cell);
+                let lead = match spec.align.get(j) {
+                    Some(ColumnAlign::Center) => (col_width - cell_width) / 2.0,
+                    Some(ColumnAlign::Right) => col_width - cell_width,
+                    Some(ColumnAlign::Left) | None => 0.0,
+                };
+                pad += lead;
+                if !cell.is_empty() {
+                    if pad > 0.0 {
+                        boxes.push(HBox::glue(pad));
+                    }
+                    boxes.extend(cell);
+                    pad = 0.0;
+                }
+                pad += col_width - cell_width - lead;
+            }
+            if rules[n_cols] {
+                pad += gap / 2.0;
+                rule(&mut boxes, &mut pad);
+            }
+            lines.push(Line {
+                width: boxes_width(&boxes),
+                boxes,
+                x_offset,
+                keep_with_next: false,
+                space_before: 0.0,
+                extra_height: 0.0,
+                fills_before: 0,
+                align: self.align,
+                column: 0,
+                role: Role::Paragraph,
+                block: 0,
+                end: LineEnd::Paragraph,
+            });
+        }
+        self.blocks.push(Block::Lines(lines));
+    }
+
+    /// Flatten one table cell into a single run of boxes, which belongs to
+    /// `block`.
+    fn cell_boxes(&mut self, cell: &[Node], block: usize) -> Vec<HBox> {
+        let mut sub = Flattener::new(
+            self.line_width,
+            self.line_height,
+            self.char_width,
+            self.space_width,
+        );
+        sub.shaper = self.shaper.clone();
+        sub.table_align = self.table_align;
+        // Notes in the cell are numbered on from the ones before it.
+        sub.footnotes = std::mem::take(&mut self.footnotes);
+        sub.xrefs = std::mem::take(&mut self.xrefs);
+        sub.label_block = Some(self.label_block.unwrap_or(block));
+        sub.flatten_all(cell);
+        self.footnotes = std::mem::take(&mut sub.footnotes);
+        self.xrefs = std::mem::take(&mut sub.xrefs);
+        let mut boxes: Vec<HBox> = sub
+            .blocks
+            .into_iter()
+            .flat_map(|block| match block {
+                Block::Paragraph(para) => para.boxes,
+                Block::Lines(lines) => lines.into_iter().flat_map(|l| l.boxes).collect(),
+                // A table row has a single line, so vertical space has nowhere
+                // to go, nor has a page break.
+                Block::VSpace(_) | Block::VFill | Block::KeepBegin | Block::KeepEnd => Vec::new(),
+            })
+            .collect();
+        while boxes.last().is_some_and(HBox::is_glue) {
+            boxes.pop();
+        }
+        boxes
+    }
+}
+
+/// `line` with each tab replaced by the spaces that reach the next multiple
+/// of `tab_width` columns; with a width of 0, tabs are dropped.
+fn expand_tabs(line: &str, tab_width: usize) -> String {
+    let mut expanded = String::with_capacity(line.len());
+    let mut column = 0;
+    for c in line.chars() {
+        if c == '\t' {
+            let stop = match tab_width {
+                0 => column,
+                width => (column / width + 1) * width,
+            };
+            expanded.extend(std::iter::repeat_n(' ', stop - column));
+            column = stop;
+        } else {
+            expanded.push(c);
+            column += 1;
+        }
+    }
+    expanded
+}
+
+/// Give the text under a heading title the heading's bold face and size,
+/// keeping any other styling such as color.
+fn restyle(node: &Node, heading: &TextStyle) -> Node {
+    match node {
+        Node::Text(s) => Node::StyledText(s.clone(), heading.clone()),
+        Node::StyledText(s, style) => Node::StyledText(
+            s.clone(),
+            TextStyle {
+                face: heading.face.clone(),
+                scale: heading.scale,
+                ..style.clone()
+            },
+        ),
+        Node::Seq(children) => Node::Seq(children.iter().map(|c| restyle(c, heading)).collect()),
+        other => other.clone(),
+    }
+}
+
+/// `node` with its text set at `scale` times the body size, in the face it has.
+fn enlarge(node: &Node, scale: f64) -> Node {
+    match node {
+        Node::Text(s) => Node::StyledText(
+            s.clone(),
+            TextStyle {
+                scale,
+                ..TextStyle::default()
+            },
+        ),
+        Node::StyledText(s, style) => Node::StyledText(
+            s.clone(),
+            TextStyle {
+                scale,
+                ..style.clone()
+            },
+        ),
+        Node::Seq(children) => Node::Seq(children.iter().map(|c| enlarge(c, scale)).collect()),
+        other => other.clone(),
+    }
+}
+
+/// The em of `style`, given the proportional `char_width`, which is
+/// [`CHAR_WIDTH_EM`] of the body text's em.
+fn em(style: &TextStyle, char_width: f64) -> f64 {
+    let em = char_width / CHAR_WIDTH_EM * style.scale;
+    match style.script {
+        Script::Baseline => em,
+        Script::Superscript | Script::Subscript | Script::Numerator | Script::Denominator => {
+            em * SCRIPT_SCALE
+        }
+    }
+}
+
+/// Width of `text` set in `style`, given the proportional `char_width`: a
+/// fixed advance per character, plus the kerning `shaper` finds, if any.
+pub(crate) fn measure(
+    text: &str,
+    style: &TextStyle,
+    char_width: f64,
+    shaper: Option<&Shaper>,
+) -> f64 {
+    let kerning = shaper.map_or(0.0, |shaper| shaper.kerning(&style.face, text));
+    text_len(text) as f64 * char_advance(style, char_width) + kerning * em(style, char_width)
+}
+
+/// Advance of one character in `style`, given the proportional `char_width`.
+fn char_advance(style: &TextStyle, char_width: f64) -> f64 {
+    let advance = match style.face {
+        FontFace::Monospace => char_width * MONOSPACE_WIDTH_RATIO,
+        _ => char_width,
+    } * style.scale;
+    match style.script {
+        Script::Baseline => advance,
+        Script::Superscript | Script::Subscript | Script::Numerator | Script::Denominator => {
+            advance * SCRIPT_SCALE
+        }
+    }
+}
+
+/// Font size and upward baseline shift of a run in `style`, for body text of
+/// `font_size` set on lines `line_height` apart.
+pub fn run_metrics(style: &TextStyle, font_size: f64, line_height: f64) -> (f64, f64) {
+    let font_size = font_size * style.scale;
+    match style.script {
+        Script::Baseline => (font_size, 0.0),
+        Script::Superscript => (font_size * SCRIPT_SCALE, line_height * SUPERSCRIPT_RISE),
+        Script::Subscript => (font_size * SCRIPT_SCALE, -line_height * SUBSCRIPT_DROP),
+        Script::Numerator => (font_size * SCRIPT_SCALE, font_size * NUMERATOR_RISE),
+        Script::Denominator => (font_size * SCRIPT_SCALE, -font_size * DENOMINATOR_DROP),
+    }
+}
+
+/// Total width of a run of boxes.
+fn boxes_width(boxes: &[HBox]) -> f64 {
+    boxes.iter().map(|b| b.width).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{FontFace, ListItem, Node, Rgb};
+
+    fn knuth_plass() -> CompileOptions {
+        CompileOptions {
+            line_break: LineBreakMode::KnuthPlass,
+            ..CompileOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_layout_simple() {
+        let ast = Node::Seq(vec![Node::Text("Hello world".into())]);
+        // line_width=100, line_height=20, char=6, space=6
+        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        assert_eq!(pages.len(), 1);
+        let line = &pages[0].lines[0];
+        // expect: "Hello" glue "world" ⇒ 3 boxes
+        assert_eq!(line.boxes.len(), 3);
+    }
+
+    #[test]
+    fn test_line_break() {
+        let long = "ThisIsAVeryLongWordWithoutSpaces";
+        let ast = Node::Seq(vec![Node::Text(long.into())]);
+        let pages = layout(&ast, 50.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        // too narrow => a single HBox per line
+        assert_eq!(pages[0].lines[0].boxes.len(), 1);
+    }
+
+    #[test]
+    fn test_layout_styled() {
+        let ast = Node::Seq(vec![
+            Node::StyledText("Bold".into(), FontFace::Bold.into()),
+            Node::Text("and normal".into()),
         ]);
-        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0);
+        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
         let line = &pages[0].lines[0];
         // Bold | space | "and" | space | "normal" => 5 boxes
         assert_eq!(line.boxes.len(), 5);
     }
+
+    #[test]
+    fn test_heading_keeps_with_next_line() {
+        // One word per line; 800 / 20 = 40 lines per page.
+        let mut children: Vec<Node> = (0..39).map(|_| Node::text("xxxxx")).collect();
+        children.push(Node::Heading {
+            level: 1,
+            number: "1".into(),
+            title: vec![Node::text("Head")],
+        });
+        children.push(Node::text("body"));
+        let pages = layout(
+            &Node::Seq(children),
+            60.0,
+            20.0,
+            6.0,
+            6.0,
+            &CompileOptions::default(),
+        );
+
+        // The heading would be line 40 of page one; it moves down instead.
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].lines.len(), 39);
+        let first = &pages[1].lines[0];
+        assert!(first.keep_with_next);
+        assert_eq!(first.space_before, 0.0);
+        assert!(matches!(&first.boxes[0].items[0], LayoutNode::Run(r) if r.text == "1"));
+        match &first.boxes[2].items[0] {
+            LayoutNode::Run(r) => {
+                assert_eq!(r.text, "Head");
+                assert_eq!(r.style.face, FontFace::Bold);
+                assert!(r.style.scale > 1.0);
+            }
+            other => panic!("Expected the title run, got {:?}", other),
+        }
+        let second = &pages[1].lines[1];
+        assert_eq!(second.space_before, 20.0 * HEADING_SPACE_AFTER);
+        assert!(matches!(&second.boxes[0].items[0], LayoutNode::Run(r) if r.text == "body"));
+    }
+
+    #[test]
+    fn test_vspace_adds_space_before_next_line() {
+        let ast = Node::Seq(vec![
+            Node::Text("above".into()),
+            Node::VSpace(28.0),
+            Node::Text("below".into()),
+        ]);
+        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let lines = &pages[0].lines;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].space_before, 0.0);
+        assert_eq!(lines[1].space_before, 28.0);
+    }
+
+    #[test]
+    fn test_line_break_space_widens_the_gap() {
+        let gap = |src: &str| {
+            let ast = crate::parser::parse(src).unwrap();
+            let ast = crate::expand::expand_macros(&ast).unwrap();
+            let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+            assert_eq!(pages[0].lines.len(), 2);
+            pages[0].lines[1].space_before
+        };
+        assert_eq!(gap(r"above \\ below"), 0.0);
+        assert!((gap(r"above \\[5mm] below") - Length::mm(5.0).to_pt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_cached_layout_matches_a_full_layout() {
+        let opts = CompileOptions {
+            paragraph_indent: Length::pt(12.0),
+            ..CompileOptions::default()
+        };
+        let doc = |second: &str| {
+            let src = format!(
+                "\\section{{Intro}} First paragraph.\n\n{}\n\n\
+                 A note\\footnote{{aside}} here.\n\nLast~one, \\textbf{{bold}}.",
+                second
+            );
+            crate::expand::expand_macros(&crate::parser::parse(&src).unwrap()).unwrap()
+        };
+        let full = |ast: &Node| format!("{:?}", layout(ast, 200.0, 20.0, 6.0, 6.0, &opts));
+        let mut cache = LayoutCache::default();
+        let mut cached = |ast: &Node| {
+            format!(
+                "{:?}",
+                layout_cached(ast, 200.0, 20.0, 6.0, 6.0, &opts, &mut cache)
+            )
+        };
+
+        let before = doc("Second draft.");
+        assert_eq!(cached(&before), full(&before));
+        let after = doc("Second paragraph, now long enough to take more than one line.");
+        assert_eq!(cached(&after), full(&after));
+        // Three paragraphs are kept, the edited one in its new form; the
+        // one with a footnote never is.
+        assert_eq!(cache.paragraphs.len(), 3);
+        let kept = format!("{:?}", cache.paragraphs.values().collect::<Vec<_>>());
+        assert!(kept.contains("enough"));
+        assert!(!kept.contains("draft"));
+
+        // An entry under the same key but for another paragraph, as a hash
+        // collision would leave, is not taken.
+        for cached in cache.paragraphs.values_mut() {
+            cached.nodes = vec![Node::Text("other".into())];
+            cached.blocks.clear();
+        }
+        assert_eq!(
+            format!(
+                "{:?}",
+                layout_cached(&after, 200.0, 20.0, 6.0, 6.0, &opts, &mut cache)
+            ),
+            full(&after)
+        );
+    }
+
+    #[test]
+    fn test_vfill_shares_the_rest_of_the_page() {
+        let opts = CompileOptions::default();
+        let lay = |src: &str| {
+            let ast = crate::expand::expand_macros(&crate::parser::parse(src).unwrap()).unwrap();
+            layout(&ast, 100.0, 20.0, 6.0, 6.0, &opts)
+        };
+        let room = text_height(&opts) - 2.0 * 20.0;
+        // Between two fills, the lines are centred on the page.
+        let pages = lay(r"\vfill Title \\ Author \vfill");
+        assert_eq!(pages.len(), 1);
+        assert!((pages[0].lines[0].space_before - room / 2.0).abs() < 1e-9);
+        assert_eq!(pages[0].lines[1].space_before, 0.0);
+        // A single fill pushes what follows it to the foot of the page.
+        let pages = lay(r"Title \vfill Author");
+        assert!((pages[0].lines[1].space_before - room).abs() < 1e-9);
+        // A fill that falls at a page break goes over with the line after it.
+        let full = (0..40).map(|i| format!("l{} \\\\ ", i)).collect::<String>();
+        let pages = lay(&format!(r"{} \vfill last", full));
+        assert_eq!(pages[0].lines.len(), 40);
+        assert!((pages[1].lines[0].space_before - (room + 20.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_vspace_counts_towards_page_height() {
+        // 40 lines of 20pt fill a page; 30pt of space pushes two of them over.
+        let mut children: Vec<Node> = (0..40).map(|i| Node::Text(format!("l{}", i))).collect();
+        children.insert(1, Node::VSpace(30.0));
+        let ast = Node::Seq(
+            children
+                .into_iter()
+                .flat_map(|n| {
+                    [
+                        n,
+                        Node::LineBreak {
+                            space: None,
+                            no_page_break: false,
+                        },
+                    ]
+                })
+                .collect(),
+        );
+        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].lines.len(), 38);
+        // Space above the first line of a page is discarded.
+        assert_eq!(pages[1].lines[0].space_before, 0.0);
+    }
+
+    #[test]
+    fn test_starred_line_break_keeps_the_next_line_on_its_page() {
+        // 40 lines of 20pt fill a page, so the page breaks after the 40th.
+        let pages_for = |last_break: &str| {
+            let src = (1..40).map(|i| format!("l{} \\\\ ", i)).collect::<String>()
+                + "l40 "
+                + last_break
+                + " l41";
+            let ast = crate::parser::parse(&src).unwrap();
+            let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+            pages.iter().map(|p| p.lines.len()).collect::<Vec<_>>()
+        };
+        assert_eq!(pages_for(r"\\"), vec![40, 1]);
+        // `\\*` takes the 40th line over with the 41st.
+        assert_eq!(pages_for(r"\\*"), vec![39, 2]);
+    }
+
+    #[test]
+    fn test_samepage_moves_to_the_next_page_whole() {
+        // 40 lines of 20pt fill a page; 37 come before a group of `kept`.
+        let pages_for = |env: &str, kept: usize| {
+            let lines = |name: &str, n: usize| {
+                let lines: Vec<String> = (1..=n).map(|i| format!("{}{}", name, i)).collect();
+                lines.join(r" \\ ")
+            };
+            let src = format!(
+                r"{} \begin{{{env}}} {} \end{{{env}}} after",
+                lines("a", 37),
+                lines("b", kept)
+            );
+            let ast = crate::parser::parse(&src).unwrap();
+            let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+            pages.iter().map(|p| p.lines.len()).collect::<Vec<_>>()
+        };
+        // Three of the five lines would fit, but none stay behind.
+        assert_eq!(pages_for("center", 5), vec![40, 3]);
+        assert_eq!(pages_for("samepage", 5), vec![37, 6]);
+        // The group's last line may still end a page.
+        assert_eq!(pages_for("samepage", 3), vec![40, 1]);
+        // A group taller than a page starts a page, and breaks where it is full.
+        assert_eq!(pages_for("samepage", 45), vec![37, 40, 6]);
+    }
+
+    #[test]
+    fn test_page_breaks_leave_no_lone_paragraph_line() {
+        // One word per line, 40 lines of 20pt to a page, 10pt between
+        // paragraphs; `lead` lines come before a four-line paragraph.
+        let pages_for = |lead: usize| {
+            let word = || Node::text("xxxxxxxx");
+            let mut children: Vec<Node> = (0..lead).map(|_| word()).collect();
+            children.push(Node::ParBreak);
+            children.extend((0..4).map(|_| word()));
+            let ast = Node::Seq(children);
+            let pages = layout(&ast, 70.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+            pages.iter().map(|p| p.lines.len()).collect::<Vec<_>>()
+        };
+        // One line of the paragraph would fit: it moves to the next page.
+        assert_eq!(pages_for(38), vec![38, 4]);
+        // Three lines would fit: one goes over with the last line.
+        assert_eq!(pages_for(36), vec![38, 2]);
+    }
+
+    #[test]
+    fn test_two_columns_share_the_page() {
+        // 80 lines of 20pt fill two 800pt columns, 100pt wide in a 210pt block.
+        let ast = Node::Seq(
+            (0..80)
+                .flat_map(|i| {
+                    [
+                        Node::Text(format!("l{}", i)),
+                        Node::LineBreak {
+                            space: None,
+                            no_page_break: false,
+                        },
+                    ]
+                })
+                .collect(),
+        );
+        let opts = CompileOptions {
+            columns: 2,
+            column_gap: Length::pt(10.0),
+            ..CompileOptions::default()
+        };
+        let pages = layout(&ast, 210.0, 20.0, 6.0, 6.0, &opts);
+        assert_eq!(pages.len(), 1);
+        let (left, right): (Vec<&Line>, Vec<&Line>) =
+            pages[0].lines.iter().partition(|l| l.column == 0);
+        assert_eq!(left.len(), 40);
+        assert_eq!(right.len(), 40);
+        assert!(left.iter().all(|l| l.x_offset == 0.0));
+        assert!(right.iter().all(|l| l.x_offset == 110.0));
+        // Lines are broken to the column width, not the block width.
+        assert!(pages[0].lines.iter().all(|l| l.width <= 100.0));
+    }
+
+    #[test]
+    fn test_hspace_is_a_fixed_gap() {
+        // Where `b` starts, measured from the start of `a`, on the line holding them.
+        let gap = |src: &str, opts: &CompileOptions| -> f64 {
+            let ast = crate::parser::parse(src).unwrap();
+            let ast = crate::expand::expand_macros(&ast).unwrap();
+            let pages = layout(&ast, 200.0, 20.0, 6.0, 6.0, opts);
+            let line = pages[0].lines.last().unwrap();
+            let mut x = 0.0;
+            let mut start_a = None;
+            for item in line.boxes.iter().flat_map(|hb| &hb.items) {
+                match item {
+                    LayoutNode::Run(run) if run.text == "a" => start_a = Some(x),
+                    LayoutNode::Run(run) if run.text == "b" => return x - start_a.unwrap(),
+                    _ => {}
+                }
+                x += match item {
+                    LayoutNode::Run(run) => run.width,
+                    LayoutNode::Glue(w) | LayoutNode::Kern(w) => *w,
+                    _ => 0.0,
+                };
+            }
+            panic!("No `b` in {:?}", line);
+        };
+        let ten_mm = Length::mm(10.0).to_pt();
+        for opts in [CompileOptions::default(), knuth_plass()] {
+            assert!((gap(r"a\hspace{10mm}b", &opts) - (6.0 + ten_mm)).abs() < 1e-9);
+            assert!(
+                (gap(r"xxxxxxxxxx yyyyyyyyyy a\hspace{10mm}b", &opts) - (6.0 + ten_mm)).abs()
+                    < 1e-9
+            );
+            assert!((gap(r"a\hspace{-3pt}b", &opts) - 3.0).abs() < 1e-9);
+            // A quad is an em, as wide as the text is tall.
+            assert!((gap(r"a\quad b", &opts) - (6.0 + FONT_SIZE_PT)).abs() < 1e-9);
+            assert!((gap(r"$a\,b$", &opts) - (6.0 + 2.0)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_phantom_takes_room_without_drawing() {
+        let lines = |src: &str| {
+            let ast = crate::parser::parse(src).unwrap();
+            let ast = crate::expand::expand_macros(&ast).unwrap();
+            let pages = layout(&ast, 200.0, 14.4, 6.0, 6.0, &CompileOptions::default());
+            pages[0].lines.clone()
+        };
+        let line = &lines(r"\phantom{XXX}Y")[0];
+        let mut x = 0.0;
+        for item in line.boxes.iter().flat_map(|hb| &hb.items) {
+            match item {
+                LayoutNode::Run(run) => {
+                    assert_eq!(run.text, "Y", "the phantom text is not drawn");
+                    break;
+                }
+                LayoutNode::Phantom { width, .. } | LayoutNode::Kern(width) => x += width,
+                _ => {}
+            }
+        }
+        assert_eq!(x, 18.0);
+        assert_eq!(lines(r"\hphantom{XXX}Y")[0].width, 24.0);
+        assert_eq!(lines(r"\vphantom{XXX}Y")[0].width, 6.0);
+        // Only the heights that are kept make a line taller.
+        assert!(lines(r"x\vphantom{$\frac{a}{b}$}")[0].extra_height > 0.0);
+        assert_eq!(lines(r"x\hphantom{$\frac{a}{b}$}")[0].extra_height, 0.0);
+    }
+
+    #[test]
+    fn test_kerning_narrows_av() {
+        let width = |kerning: bool| {
+            let opts = CompileOptions {
+                kerning,
+                ..CompileOptions::default()
+            };
+            let pages = layout(&Node::text("AV"), 200.0, 20.0, 6.0, 6.0, &opts);
+            pages[0].lines[0].width
+        };
+        assert_eq!(width(false), 12.0);
+        assert!(width(true) < 12.0);
+    }
+
+    #[test]
+    fn test_paragraphs_after_the_first_are_indented() {
+        let opts = CompileOptions {
+            paragraph_indent: Length::pt(15.0),
+            ..CompileOptions::default()
+        };
+        let ast = crate::parser::parse("One.\n\nTwo.\n\n\\noindent Three.\n\nFour.").unwrap();
+        let ast = crate::expand::expand_macros(&ast).unwrap();
+        let pages = layout(&ast, 200.0, 20.0, 6.0, 6.0, &opts);
+        let lines = &pages[0].lines;
+        let indent = |line: &Line| match line.boxes[0].items[0] {
+            LayoutNode::Kern(width) => width,
+            _ => 0.0,
+        };
+        let text: Vec<String> = lines.iter().map(line_text).collect();
+        assert_eq!(text, ["One.", "Two.", "Three.", "Four."]);
+        assert_eq!(
+            lines.iter().map(indent).collect::<Vec<_>>(),
+            [0.0, 15.0, 0.0, 15.0]
+        );
+        assert_eq!(lines[1].width, lines[0].width + 15.0);
+
+        let pages = layout(&ast, 200.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        assert!(pages[0].lines.iter().all(|line| indent(line) == 0.0));
+    }
+
+    #[test]
+    fn test_quote_is_indented_on_both_sides() {
+        let words = "aaaa bbbb cccc dddd eeee ffff gggg hhhh";
+        let src = format!(r"{words} \begin{{quote}}{words}\end{{quote}} {words}");
+        let ast = crate::expand::expand_macros(&crate::parser::parse(&src).unwrap()).unwrap();
+        let pages = layout(&ast, 200.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let lines = &pages[0].lines;
+        // 200 pt holds six words a line; 200 - 2 × 30 pt holds four.
+        let text: Vec<String> = lines.iter().map(line_text).collect();
+        assert_eq!(text[1], "gggg hhhh");
+        assert_eq!(text[2], "aaaa bbbb cccc dddd");
+        assert_eq!(text[4], "aaaa bbbb cccc dddd eeee ffff");
+        let offsets: Vec<f64> = lines.iter().map(|line| line.x_offset).collect();
+        assert_eq!(offsets, [0.0, 0.0, 30.0, 30.0, 0.0, 0.0]);
+        assert!(lines[2..4].iter().all(|line| line.width <= 140.0));
+    }
+
+    #[test]
+    fn test_quotation_indents_first_lines() {
+        let src = "\\begin{quotation}One.\n\nTwo.\\end{quotation}\n\nAfter.";
+        let ast = crate::expand::expand_macros(&crate::parser::parse(src).unwrap()).unwrap();
+        let pages = layout(&ast, 200.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let lines = &pages[0].lines;
+        assert_eq!(lines.len(), 3);
+        for line in &lines[..2] {
+            assert_eq!(line.x_offset, 30.0);
+            assert!(matches!(line.boxes[0].items[0], LayoutNode::Kern(18.0)));
+        }
+        assert_eq!(lines[2].x_offset, 0.0);
+        assert!(matches!(lines[2].boxes[0].items[0], LayoutNode::Run(_)));
+    }
+
+    #[test]
+    fn test_empty_styled_text_adds_no_space() {
+        let ast = crate::parser::parse(r"a \textbf{} b \textbf{{}} c").unwrap();
+        let ast = crate::expand::expand_macros(&ast).unwrap();
+        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let line = &pages[0].lines[0];
+        assert_eq!(line.boxes.len(), 5);
+        assert_eq!(line_text(line), "a b c");
+        assert_eq!(line.width, 30.0);
+    }
+
+    #[test]
+    fn test_tie_keeps_words_together() {
+        // "b" would fit on the first line, but "a~b" together would not.
+        let ast = crate::parser::parse(r"xxxxxx a~b").unwrap();
+        let ast = crate::expand::expand_macros(&ast).unwrap();
+        let pages = layout(&ast, 54.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let lines = &pages[0].lines;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].width, 36.0);
+        let texts: Vec<&str> = lines[1].boxes[0]
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                LayoutNode::Run(run) => Some(run.text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(texts, ["a", "b"]);
+        assert_eq!(lines[1].width, 18.0);
+    }
+
+    #[test]
+    fn test_fraction_is_stacked_on_a_bar() {
+        let ast = crate::parser::parse("$\\frac{1}{22}$\n\nNext").unwrap();
+        let ast = crate::expand::expand_macros(&ast).unwrap();
+        let pages = layout(&ast, 100.0, 14.4, 6.0, 6.0, &CompileOptions::default());
+        let (line, next) = (&pages[0].lines[0], &pages[0].lines[1]);
+        let items = &line.boxes[0].items;
+        let (num, den) = match (&items[1], &items[4]) {
+            (LayoutNode::Run(num), LayoutNode::Run(den)) => (num, den),
+            other => panic!("Expected the two parts, got {:?}", other),
+        };
+        assert_eq!((num.text.as_str(), den.text.as_str()), ("1", "22"));
+        let LayoutNode::Rule {
+            width,
+            height,
+            raise,
+        } = items[6]
+        else {
+            panic!("Expected a fraction bar, got {:?}", items[6]);
+        };
+        // The bar is as wide as the denominator, and the numerator centred on it.
+        assert!((width - den.width).abs() < 1e-9);
+        assert_eq!(line.width, width);
+        assert!(
+            matches!(items[0], LayoutNode::Kern(k) if (k - (width - num.width) / 2.0).abs() < 1e-9)
+        );
+
+        let (num_size, num_rise) = run_metrics(&num.style, 12.0, 14.4);
+        let (den_size, den_rise) = run_metrics(&den.style, 12.0, 14.4);
+        assert!(num_rise - num_size * GLYPH_DEPTH > raise + height);
+        assert!(den_rise + den_size * GLYPH_HEIGHT < raise);
+        // The fraction is taller and deeper than a line of text makes room for.
+        assert!(line.extra_height > 0.0);
+        assert!(next.extra_height > 0.0);
+    }
+
+    #[test]
+    fn test_running_head_and_foot_are_on_every_page() {
+        let para = "word ".repeat(40);
+        let src = vec![para.as_str(); 60].join("\n\n");
+        let ast = crate::parser::parse(&src).unwrap();
+        let ast = crate::expand::expand_macros(&ast).unwrap();
+        let opts = CompileOptions {
+            header: Some(RunningText {
+                text: "Draft, page {page}".into(),
+                align: Alignment::Right,
+            }),
+            footer: Some(RunningText {
+                text: "{page}".into(),
+                align: Alignment::Center,
+            }),
+            ..CompileOptions::default()
+        };
+        let pages = layout(&ast, 300.0, 14.4, 6.0, 6.0, &opts);
+        assert!(pages.len() > 1);
+        for (i, page) in pages.iter().enumerate() {
+            let (head, foot) = (&page.lines[0], &page.lines[page.lines.len() - 1]);
+            assert_eq!((head.role, foot.role), (Role::Running, Role::Running));
+            assert_eq!(line_text(head), format!("Draft, page {}", i + 1));
+            assert!((head.x_offset + head.width - 300.0).abs() < 1e-9);
+            assert_eq!(line_text(foot), (i + 1).to_string());
+            assert!((foot.x_offset - (300.0 - foot.width) / 2.0).abs() < 1e-9);
+
+            // The head is on the first line of the text block and the foot on
+            // the last, each a blank line away from the text.
+            let baselines: Vec<f64> = page
+                .lines
+                .iter()
+                .scan(0.0, |y, line| {
+                    let baseline = *y + line.space_before + line.extra_height;
+                    *y = baseline + 14.4;
+                    Some(baseline)
+                })
+                .collect();
+            let n = baselines.len();
+            assert_eq!(baselines[0], 0.0);
+            assert!((baselines[1] - 2.0 * 14.4).abs() < 1e-9);
+            assert!(baselines[n - 2] <= TEXT_HEIGHT - 3.0 * 14.4 + 1e-9);
+            assert!((baselines[n - 1] - (TEXT_HEIGHT - 14.4)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_large_text_is_set_larger_on_a_taller_line() {
+        let ast = crate::parser::parse("small {\\large big} small\n\nNext").unwrap();
+        let ast = crate::expand::expand_macros(&ast).unwrap();
+        let pages = layout(&ast, 200.0, 14.4, 6.0, 6.0, &CompileOptions::default());
+        let (line, next) = (&pages[0].lines[0], &pages[0].lines[1]);
+        let runs: Vec<&StyledRun> = line
+            .boxes
+            .iter()
+            .flat_map(|hb| &hb.items)
+            .filter_map(|item| match item {
+                LayoutNode::Run(run) => Some(run),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(runs.len(), 3);
+        let size = |run: &StyledRun| run_metrics(&run.style, 12.0, 14.4).0;
+        assert_eq!(runs[1].text, "big");
+        assert!(size(runs[1]) > size(runs[0]));
+        assert_eq!(size(runs[2]), size(runs[0]));
+        // "big" is measured at its size, and its line is taller.
+        assert!(runs[1].width > runs[0].width * 3.0 / 5.0 * 1.1);
+        let room = 14.4 - 12.0 * GLYPH_DEPTH;
+        assert!((line.extra_height - room * 0.2).abs() < 1e-9);
+        assert_eq!(next.extra_height, 0.0);
+    }
+
+    #[test]
+    fn test_superscript_is_smaller() {
+        let ast = crate::parser::parse("$x^2$").unwrap();
+        let ast = crate::expand::expand_macros(&ast).unwrap();
+        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let line = &pages[0].lines[0];
+        assert_eq!(line.boxes.len(), 1);
+        let runs: Vec<&StyledRun> = line.boxes[0]
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                LayoutNode::Run(run) => Some(run),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(runs[0].text, "x");
+        assert_eq!(runs[1].text, "2");
+        assert_eq!(runs[1].style.script, Script::Superscript);
+        assert!((line.width - (6.0 + 6.0 * SCRIPT_SCALE)).abs() < 1e-9);
+        let (size, rise) = run_metrics(&runs[1].style, 12.0, 14.4);
+        assert!(size < 12.0 && rise > 0.0);
+    }
+
+    #[test]
+    fn test_long_word_is_hyphenated() {
+        let ast = Node::Seq(vec![Node::Text(
+            "a supercalifragilisticexpialidocious word".into(),
+        )]);
+        let opts = CompileOptions {
+            hyphenate: true,
+            ..CompileOptions::default()
+        };
+        let pages = layout(&ast, 60.0, 20.0, 6.0, 6.0, &opts);
+        let lines = &pages[0].lines;
+        assert!(lines.len() > 2);
+        for line in lines {
+            assert!(line.width <= 60.0, "line overflows: {:?}", line);
+        }
+        let words: Vec<&str> = lines
+            .iter()
+            .flat_map(|line| &line.boxes)
+            .flat_map(|hb| &hb.items)
+            .filter_map(|item| match item {
+                LayoutNode::Run(run) => Some(run.text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert!(words[1].ends_with('-'));
+        let joined: String = words[1..words.len() - 1]
+            .iter()
+            .map(|w| w.trim_end_matches('-'))
+            .collect();
+        assert_eq!(joined, "supercalifragilisticexpialidocious");
+    }
+
+    #[test]
+    fn test_run_advances_add_up_to_line_width() {
+        let ast = crate::parser::parse(
+            r"\begin{itemize}\item Some \textbf{bold} and \texttt{mono} text with $x^2_i$ math, a~tie and a supercalifragilisticexpialidocious word\end{itemize}",
+        )
+        .unwrap();
+        let ast = crate::expand::expand_macros(&ast).unwrap();
+        let opts = CompileOptions {
+            hyphenate: true,
+            ..CompileOptions::default()
+        };
+        let pages = layout(&ast, 90.0, 20.0, 5.0, 4.0, &opts);
+        assert!(pages[0].lines.len() > 2);
+        for line in &pages[0].lines {
+            // Step along the line the way the renderers do.
+            let advance: f64 = line
+                .boxes
+                .iter()
+                .flat_map(|hb| &hb.items)
+                .map(|item| match item {
+                    LayoutNode::Run(run) => run.width,
+                    LayoutNode::Glue(w) | LayoutNode::Kern(w) => *w,
+                    LayoutNode::Image { width, .. }
+                    | LayoutNode::Rule { width, .. }
+                    | LayoutNode::Phantom { width, .. } => *width,
+                    LayoutNode::Footnote(_) => 0.0,
+                })
+                .sum();
+            assert!((advance - line.width).abs() < 1e-9, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn test_accented_words_measure_by_characters() {
+        let width = |word: &str| {
+            let pages = layout(
+                &Node::Seq(vec![Node::text(word)]),
+                100.0,
+                20.0,
+                6.0,
+                6.0,
+                &CompileOptions::default(),
+            );
+            pages[0].lines[0].width
+        };
+        assert_eq!(width("café"), width("cafe"));
+        assert_eq!(width("naïveté"), 7.0 * 6.0);
+    }
+
+    #[test]
+    fn test_discretionary_hyphen_only_shows_at_a_break() {
+        let texts = |line_width: f64| -> Vec<Vec<String>> { texts_of(r"xxx hel\-lo", line_width) };
+        // "xxx hello" is 54pt wide; "xxx hel-" is 48pt.
+        assert_eq!(texts(60.0), vec![vec!["xxx", "hello"]]);
+        assert_eq!(texts(50.0), vec![vec!["xxx", "hel-"], vec!["lo"]]);
+    }
+
+    #[test]
+    fn test_soft_hyphen_in_the_source_is_discretionary() {
+        let texts = |line_width: f64| texts_of("xxx hel\u{ad}lo", line_width);
+        assert_eq!(texts(60.0), vec![vec!["xxx", "hello"]]);
+        assert_eq!(texts(50.0), vec![vec!["xxx", "hel-"], vec!["lo"]]);
+    }
+
+    /// The text of each run on each line of `src` set `line_width` wide.
+    fn texts_of(src: &str, line_width: f64) -> Vec<Vec<String>> {
+        let ast = crate::parser::parse(src).unwrap();
+        let ast = crate::expand::expand_macros(&ast).unwrap();
+        let pages = layout(&ast, line_width, 20.0, 6.0, 6.0, &CompileOptions::default());
+        pages[0]
+            .lines
+            .iter()
+            .map(|line| {
+                line.boxes
+                    .iter()
+                    .flat_map(|hb| &hb.items)
+                    .filter_map(|item| match item {
+                        LayoutNode::Run(run) => Some(run.text.clone()),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_color_survives_layout() {
+        let red = TextStyle {
+            color: Rgb::new(255, 0, 0),
+            ..TextStyle::default()
+        };
+        let ast = Node::Seq(vec![Node::StyledText("warm words".into(), red)]);
+        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        for hb in &pages[0].lines[0].boxes {
+            if let LayoutNode::Run(run) = &hb.items[0] {
+                assert_eq!(run.style.color, Rgb::new(255, 0, 0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_itemize_indents_items() {
+        let ast = Node::Seq(vec![Node::List {
+            kind: ListKind::Itemize,
+            items: ["One", "Two"]
+                .map(|text| ListItem {
+                    label: None,
+                    body: vec![Node::text(text)],
+                })
+                .to_vec(),
+        }]);
+        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let lines = &pages[0].lines;
+        assert_eq!(lines.len(), 2);
+        for line in lines {
+            // The bullet hangs left of the indented item text.
+            assert!(matches!(&line.boxes[0].items[0], LayoutNode::Run(r) if r.text == "•"));
+            assert!(line.x_offset > 0.0);
+            assert_eq!(line.x_offset + line.boxes[0].width, 5.0 * 6.0);
+        }
+    }
+
+    /// A page-by-page account of `pages`: one row per line with its place
+    /// and width, then its boxes separated by `|`.
+    fn summary(pages: &[Page]) -> String {
+        let mut out = String::new();
+        for (idx, page) in pages.iter().enumerate() {
+            for line in &page.lines {
+                let boxes: Vec<String> = line
+                    .boxes
+                    .iter()
+                    .map(|hb| {
+                        hb.items
+                            .iter()
+                            .map(|item| match item {
+                                LayoutNode::Run(run) => run.text.clone(),
+                                LayoutNode::Glue(_) | LayoutNode::Kern(_) => " ".into(),
+                                LayoutNode::Phantom { .. } => "[phantom]".into(),
+                                LayoutNode::Image { .. } => "[image]".into(),
+                                LayoutNode::Rule { .. } => "[rule]".into(),
+                                LayoutNode::Footnote(n) => format!("[note {}]", n),
+                            })
+                            .collect()
+                    })
+                    .collect();
+                out += &format!(
+                    "{} {} {:.3} {:.3} {:.3} {}\n",
+                    idx + 1,
+                    line.column,
+                    line.x_offset,
+                    line.space_before,
+                    line.width,
+                    boxes.join("|")
+                );
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_layout_matches_reference() {
+        let para = "Typesetting is the composition of text by means of arranging \
+            physical types or their digital equivalents. Stored letters and \
+            other symbols are retrieved and ordered according to a language's \
+            orthography for visual display, with hyphenation~where it helps.";
+        let src = format!(
+            r"\section{{Intro}} {para}
+
+            \begin{{itemize}} \item One \begin{{enumerate}} \item Two \end{{enumerate}} \end{{itemize}}
+            \begin{{center}} Centred $x^2 + y_1$ text \end{{center}}
+            \begin{{tabular}}{{ll}} a & bb \\ ccc & d \end{{tabular}}
+            \subsection{{More}} {para} {para} \\[4pt] {para}
+
+            {para} {para} {para} {para} {para} {para} {para} {para}"
+        );
+        let ast = crate::parser::parse(&src).unwrap();
+        let ast = crate::expand::expand_macros(&ast).unwrap();
+        let mut out = String::new();
+        for opts in [
+            CompileOptions::default(),
+            CompileOptions {
+                hyphenate: true,
+                columns: 2,
+                ..knuth_plass()
+            },
+        ] {
+            out += &summary(&layout(&ast, 300.0, 14.4, 6.0, 6.0, &opts));
+        }
+        assert_eq!(out, include_str!("../fixtures/layout.txt"));
+    }
+
+    #[test]
+    fn test_footnotes_go_to_the_page_foot() {
+        let ast =
+            crate::parser::parse(r"One\footnote{First note.} two\footnote{Second.} three").unwrap();
+        let ast = crate::expand::expand_macros(&ast).unwrap();
+        let pages = layout(&ast, 300.0, 14.4, 6.0, 6.0, &CompileOptions::default());
+        let lines = &pages[0].lines;
+        let text: Vec<String> = lines.iter().map(line_text).collect();
+        assert_eq!(text, ["One1 two2 three", "", "1 First note.", "2 Second."]);
+
+        // The marks are raised, and stick to the word before them.
+        let first = &lines[0].boxes[0];
+        assert!(matches!(&first.items[1], LayoutNode::Run(run)
+            if run.text == "1" && run.style.script == Script::Superscript));
+        assert!(matches!(first.items[2], LayoutNode::Footnote(0)));
+
+        // A rule, then the notes, ending at the foot of the page.
+        assert!(matches!(
+            lines[1].boxes[0].items[0],
+            LayoutNode::Rule { .. }
+        ));
+        let bottom: f64 = lines
+            .iter()
+            .map(|l| l.space_before + l.extra_height + 14.4)
+            .sum();
+        assert!((bottom - TEXT_HEIGHT).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_styled_footnotes_and_references_are_set() {
+        let src = r"\section{Two}\label{two} word\textbf{x\footnote{note}} \emph{see \ref{two}}";
+        let ast = crate::expand::expand_macros(&crate::parser::parse(src).unwrap()).unwrap();
+        let pages = layout(&ast, 300.0, 14.4, 6.0, 6.0, &CompileOptions::default());
+        let text: Vec<String> = pages[0].lines.iter().map(line_text).collect();
+        assert_eq!(text, ["1 Two", "word x1 see 1", "", "1 note"]);
+    }
+
+    #[test]
+    fn test_nested_lists_indent_further() {
+        let src = r"\begin{itemize}
+            \item Outer
+            \begin{itemize} \item Inner \item Inner \end{itemize}
+            \item Outer
+            \begin{enumerate} \item Inner \item Inner \end{enumerate}
+        \end{itemize}";
+        let ast = crate::expand::expand_macros(&crate::parser::parse(src).unwrap()).unwrap();
+        let pages = layout(&ast, 200.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        // Each line's marker and where the item text after it starts.
+        let lines: Vec<(&str, f64)> = pages[0]
+            .lines
+            .iter()
+            .map(|l| match &l.boxes[0].items[0] {
+                LayoutNode::Run(r) => (r.text.as_str(), l.x_offset + l.boxes[0].width),
+                other => panic!("Expected a marker, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                ("•", 30.0),
+                ("◦", 60.0),
+                ("◦", 60.0),
+                ("•", 30.0),
+                ("1.", 60.0),
+                ("2.", 60.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_enumerate_numbers_items() {
+        let ast = Node::Seq(vec![Node::List {
+            kind: ListKind::Enumerate,
+            items: ["One", "Two"]
+                .map(|text| ListItem {
+                    label: None,
+                    body: vec![Node::text(text)],
+                })
+                .to_vec(),
+        }]);
+        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let markers: Vec<&str> = pages[0]
+            .lines
+            .iter()
+            .map(|l| match &l.boxes[0].items[0] {
+                LayoutNode::Run(r) => r.text.as_str(),
+                _ => "",
+            })
+            .collect();
+        assert_eq!(markers, ["1.", "2."]);
+
+        // A labelled item is marked by its label, and not counted.
+        let texts = texts_of(
+            r"\begin{enumerate}\item a \item[(x)] b \item c\end{enumerate}",
+            100.0,
+        );
+        assert_eq!(texts, [["1.", "a"], ["(x)", "b"], ["2.", "c"]]);
+    }
+
+    #[test]
+    fn test_description_sets_its_terms_in_bold() {
+        let src = r"\begin{description}\item[Apple] A fruit\end{description}";
+        let ast = crate::expand::expand_macros(&crate::parser::parse(src).unwrap()).unwrap();
+        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let line = &pages[0].lines[0];
+        let runs: Vec<&StyledRun> = line
+            .boxes
+            .iter()
+            .flat_map(|hb| &hb.items)
+            .filter_map(|item| match item {
+                LayoutNode::Run(run) => Some(run),
+                _ => None,
+            })
+            .collect();
+        let words: Vec<(&str, &FontFace)> = runs
+            .iter()
+            .map(|run| (run.text.as_str(), &run.style.face))
+            .collect();
+        assert_eq!(
+            words,
+            [
+                ("Apple", &FontFace::Bold),
+                ("A", &FontFace::Normal),
+                ("fruit", &FontFace::Normal)
+            ]
+        );
+        // The term starts where the list does, with no bullet hung before
+        // it, and the definition runs on under the indentation, so the
+        // first line has room for more.
+        assert_eq!(line.x_offset, 5.0 * 6.0);
+        assert!(matches!(
+            line.boxes[0].items[..],
+            [LayoutNode::Kern(kern), LayoutNode::Run(_)] if kern == -line.x_offset
+        ));
+        let texts = texts_of(
+            r"\begin{description}\item[Term] one two three four\end{description}",
+            90.0,
+        );
+        assert_eq!(texts, [vec!["Term", "one", "two"], vec!["three", "four"]]);
+    }
+
+    #[test]
+    fn test_centered_paragraph_lines() {
+        let ast = Node::Seq(vec![Node::Environment {
+            name: "center".into(),
+            body: vec![Node::text("aaaa bbbb cc")],
+        }]);
+        let pages = layout(&ast, 60.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let lines = &pages[0].lines;
+        assert_eq!(lines.len(), 2);
+        for line in lines {
+            assert_eq!(line.align, Alignment::Center);
+            assert_eq!(line.x_offset, (60.0 - line.width) / 2.0);
+        }
+        // "aaaa bbbb" is 54 wide, "cc" 12.
+        assert_eq!(lines[0].x_offset, 3.0);
+        assert_eq!(lines[1].x_offset, 24.0);
+    }
+
+    #[test]
+    fn test_justified_lines_fill_the_measure() {
+        let justified = CompileOptions {
+            justify: true,
+            ..CompileOptions::default()
+        };
+        let lay = |src: &str, opts: &CompileOptions| {
+            let ast = crate::expand::expand_macros(&crate::parser::parse(src).unwrap()).unwrap();
+            layout(&ast, 60.0, 20.0, 6.0, 6.0, opts).remove(0).lines
+        };
+        let widths = |lines: &[Line]| lines.iter().map(|l| l.width).collect::<Vec<_>>();
+        // "aa bb cc" is 48 wide; its two spaces stretch from 6 to 12.
+        let src = "aa bb cc dd ee ff gg";
+        let lines = lay(src, &justified);
+        assert_eq!(widths(&lines), [60.0, 60.0, 12.0]);
+        assert_eq!(line_text(&lines[0]), "aa bb cc");
+        assert!(matches!(
+            lines[0].boxes[1].items[..],
+            [LayoutNode::Glue(12.0)]
+        ));
+        let ragged = lay(src, &CompileOptions::default());
+        assert_eq!(widths(&ragged), [48.0, 48.0, 12.0]);
+        // Lines ended by `\\`, and those of centered text, keep their width.
+        assert_eq!(
+            widths(&lay(r"aa \\ bb cc dd ee", &justified)),
+            [12.0, 60.0, 12.0]
+        );
+        let centered = lay(r"\begin{center}aa bb cc dd\end{center}", &justified);
+        assert_eq!(widths(&centered), [48.0, 12.0]);
+    }
+
+    #[test]
+    fn test_flushright_paragraph_lines() {
+        let ast = Node::Seq(vec![
+            Node::Environment {
+                name: "flushright".into(),
+                body: vec![Node::text("right")],
+            },
+            Node::text("left"),
+        ]);
+        let pages = layout(&ast, 60.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let lines = &pages[0].lines;
+        assert_eq!(lines[0].align, Alignment::Right);
+        assert_eq!(lines[0].x_offset, 60.0 - 30.0);
+        assert_eq!(lines[1].align, Alignment::Left);
+        assert_eq!(lines[1].x_offset, 0.0);
+    }
+
+    #[test]
+    fn test_centering_declaration_centers_the_rest_of_its_group() {
+        let src = r"{\centering a b\\c d} e f {x \raggedleft y}";
+        let ast = crate::expand::expand_macros(&crate::parser::parse(src).unwrap()).unwrap();
+        let pages = layout(&ast, 60.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let placed: Vec<_> = pages[0]
+            .lines
+            .iter()
+            .map(|line| (line_text(line), line.align, line.x_offset))
+            .collect();
+        assert_eq!(
+            placed,
+            [
+                ("a b".to_string(), Alignment::Center, 21.0),
+                ("c d".to_string(), Alignment::Center, 21.0),
+                ("e f x".to_string(), Alignment::Left, 0.0),
+                ("y".to_string(), Alignment::Right, 54.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_table_cells_line_up_in_columns() {
+        let src = r"\begin{tabular}{ll} a & bbb \\ cc & d \end{tabular}";
+        let ast = crate::expand::expand_macros(&crate::parser::parse(src).unwrap()).unwrap();
+        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let lines = &pages[0].lines;
+        assert_eq!(lines.len(), 2);
+
+        // The x of every run on a line, with its text.
+        let cells = |line: &Line| {
+            let mut x = line.x_offset;
+            let mut cells = Vec::new();
+            for hbox in &line.boxes {
+                if let Some(LayoutNode::Run(run)) = hbox.items.first() {
+                    cells.push((x, run.text.clone()));
+                }
+                x += hbox.width;
+            }
+            cells
+        };
+        // The first column is as wide as "cc"; the second starts one gap later.
+        let second = 12.0 + TABLE_COLUMN_GAP * 6.0;
+        assert_eq!(
+            cells(&lines[0]),
+            [(0.0, "a".to_string()), (second, "bbb".to_string())]
+        );
+        assert_eq!(
+            cells(&lines[1]),
+            [(0.0, "cc".to_string()), (second, "d".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_table_columns_align_their_cells_between_rules() {
+        let src = r"\begin{tabular}{r|c} \hline a & bbb \\ cc & d \\ \hline \end{tabular}";
+        let ast = crate::parser::parse(src).unwrap();
+        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let lines = &pages[0].lines;
+        assert_eq!(lines.len(), 2);
+
+        // Where every run and rule on a line starts, and how far it reaches.
+        let items = |line: &Line| {
+            let mut x = line.x_offset;
+            let mut items = Vec::new();
+            for item in line.boxes.iter().flat_map(|hb| &hb.items) {
+                let width = match item {
+                    LayoutNode::Run(run) => run.width,
+                    LayoutNode::Rule { width, .. } => *width,
+                    LayoutNode::Glue(w) | LayoutNode::Kern(w) => {
+                        x += w;
+                        continue;
+                    }
+                    _ => 0.0,
+                };
+                items.push((x, x + width, item.clone()));
+                x += width;
+            }
+            items
+        };
+        let runs = |line: &Line| -> Vec<(f64, f64, String)> {
+            items(line)
+                .into_iter()
+                .filter_map(|(from, to, item)| match item {
+                    LayoutNode::Run(run) => Some((from, to, run.text)),
+                    _ => None,
+                })
+                .collect()
+        };
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+
+        // The first column is as wide as "cc", and sets "a" flush right.
+        let (a, cc) = (&runs(&lines[0])[0], &runs(&lines[1])[0]);
+        assert_eq!((a.2.as_str(), cc.2.as_str()), ("a", "cc"));
+        assert!(close(a.1, 12.0) && close(cc.1, 12.0), "{:?} {:?}", a, cc);
+        // Half a gap either side of the rule, the second column centres "d"
+        // under "bbb".
+        let second = 12.0 + 6.0 + TABLE_RULE_THICKNESS + 6.0;
+        let (bbb, d) = (&runs(&lines[0])[1], &runs(&lines[1])[1]);
+        assert!(
+            close(bbb.0, second) && close(d.0, second + 6.0),
+            "{:?} {:?}",
+            bbb,
+            d
+        );
+
+        // Each row has the rule between its columns, as high as the line;
+        // the first row is ruled above, and the last below.
+        // Text reaches 3pt below the baseline, a quarter of its 12pt em.
+        let table_width = second + 18.0;
+        assert!(close(lines[0].width, table_width));
+        for (line, hline_raise) in lines.iter().zip([20.0 - 3.0 - TABLE_RULE_THICKNESS, -3.0]) {
+            let rules: Vec<(f64, f64, f64, f64)> = items(line)
+                .into_iter()
+                .filter_map(|(from, to, item)| match item {
+                    LayoutNode::Rule { height, raise, .. } => Some((from, to, height, raise)),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(rules.len(), 2, "{:?}", rules);
+            let (from, to, height, raise) = rules[0];
+            assert!(close(from, 0.0) && close(to, table_width));
+            assert!(close(height, TABLE_RULE_THICKNESS) && close(raise, hline_raise));
+            let (from, to, height, raise) = rules[1];
+            assert!(close(from, 18.0) && close(to, 18.0 + TABLE_RULE_THICKNESS));
+            assert!(close(height, 20.0) && close(raise, -3.0));
+        }
+
+        // A `tabular*` spreads its columns out to its width.
+        let src = r"\begin{tabular*}{\textwidth}{lr} a & b \end{tabular*}";
+        let ast = crate::parser::parse(src).unwrap();
+        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        assert!(close(pages[0].lines[0].width, 100.0));
+    }
+
+    #[test]
+    fn test_centered_table() {
+        let cell = |s: &str| vec![Node::text(s)];
+        let table = Node::Table {
+            spec: Box::default(),
+            rows: vec![vec![cell("a"), cell("bbb")], vec![cell("cc"), cell("d")]],
+            hlines: Vec::new(),
+        };
+        let ast = Node::Seq(vec![Node::Environment {
+            name: "center".into(),
+            body: vec![table],
+        }]);
+        let pages = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let lines = &pages[0].lines;
+        assert_eq!(lines.len(), 2);
+
+        // Columns are 12 and 18 wide with a 12-wide gap between them.
+        let table_width = 12.0 + 12.0 + 18.0;
+        for line in lines {
+            assert_eq!(line.x_offset, (100.0 - table_width) / 2.0);
+        }
+        // Second-column cells start at the same x in both rows.
+        let second_col_x = |line: &Line| line.boxes[0].width + line.boxes[1].width;
+        assert_eq!(second_col_x(&lines[0]), 24.0);
+        assert_eq!(second_col_x(&lines[1]), 24.0);
+
+        // The table alignment option places a table whatever the text
+        // around it, and only the table.
+        let src = r"\begin{center} \begin{tabular}{ll} a & bbb \end{tabular} \end{center} x";
+        let ast = crate::parser::parse(src).unwrap();
+        let opts = CompileOptions {
+            table_align: Some(Alignment::Right),
+            ..CompileOptions::default()
+        };
+        let lines = layout(&ast, 100.0, 20.0, 6.0, 6.0, &opts).remove(0).lines;
+        assert_eq!(lines[0].x_offset, 100.0 - (6.0 + 12.0 + 18.0));
+        assert_eq!(lines[1].x_offset, 0.0);
+    }
+
+    /// Total badness of every line but the last, as the breakers score them.
+    fn total_badness(pages: &[Page], line_width: f64) -> f64 {
+        let lines: Vec<&Line> = pages.iter().flat_map(|p| &p.lines).collect();
+        lines[..lines.len() - 1]
+            .iter()
+            .map(|l| badness(l.width, l.boxes.iter().map(HBox::stretch).sum(), line_width))
+            .sum()
+    }
+
+    #[test]
+    fn test_knuth_plass_beats_greedy() {
+        let ast = Node::Seq(vec![Node::text("a bcd e fghi j klmnop qrstu vwxyza")]);
+        let greedy = layout(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let optimal = layout_knuth_plass(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+
+        let count = |pages: &[Page]| pages[0].lines.len();
+        assert_eq!(count(&greedy), 3);
+        assert_eq!(count(&optimal), 3);
+        assert!(total_badness(&optimal, 100.0) < total_badness(&greedy, 100.0) / 2.0);
+        for line in &optimal[0].lines {
+            assert!(line.width <= 100.0);
+        }
+    }
+
+    #[test]
+    fn test_knuth_plass_falls_back_to_greedy() {
+        // No feasible breaks: the long word overflows any line.
+        let ast = Node::Seq(vec![Node::text("a ThisIsAVeryLongWordWithoutSpaces b")]);
+        let pages = layout_knuth_plass(&ast, 50.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        assert_eq!(pages[0].lines.len(), 3);
+    }
+
+    #[test]
+    fn test_knuth_plass_forced_break() {
+        let ast = Node::Seq(vec![
+            Node::text("a b"),
+            Node::LineBreak {
+                space: None,
+                no_page_break: false,
+            },
+            Node::text("c"),
+        ]);
+        let pages = layout_knuth_plass(&ast, 100.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        assert_eq!(pages[0].lines.len(), 2);
+        assert_eq!(pages[0].lines[0].boxes.len(), 3);
+    }
+
+    #[test]
+    fn test_hyphen_penalty_trades_hyphens_for_looser_lines() {
+        let ast = Node::Seq(vec![Node::text(
+            "Typesetting is the composition of text by means of arranging physical \
+             types or their digital equivalents. Stored letters and other symbols \
+             are retrieved and ordered according to a language's orthography.",
+        )]);
+        let hyphens = |hyphen_penalty: f64| {
+            let opts = CompileOptions {
+                hyphenate: true,
+                hyphen_penalty,
+                ..knuth_plass()
+            };
+            let pages = layout(&ast, 200.0, 20.0, 6.0, 6.0, &opts);
+            let lines = &pages[0].lines;
+            assert!(lines.iter().all(|line| line.width <= 200.0));
+            lines
+                .iter()
+                .filter(|line| line_text(line).ends_with('-'))
+                .count()
+        };
+        let (cheap, dear) = (hyphens(50.0), hyphens(1000.0));
+        assert!(dear < cheap, "{} hyphens, then {}", cheap, dear);
+        assert_eq!(hyphens(MAX_PENALTY), 0);
+    }
 }
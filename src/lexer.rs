@@ -5,9 +5,10 @@ use logos::Logos;
 /// A token produced by the TeX lexer.
 #[derive(Logos, Debug, PartialEq)]
 pub enum Token {
-    /// Skip comments starting with `%` up to end of line.
-    #[regex(r"%[^\n]*", logos::skip)]
-    Comment,
+    /// A `%` comment up to the end of the line, holding the text after the `%`.
+    /// `lex` drops these unless asked to keep them.
+    #[regex(r"%[^\n]*", |lex| lex.slice()[1..].to_string())]
+    Comment(String),
 
     /// Left brace `{`.
     #[token("{")]
@@ -17,21 +18,84 @@ pub enum Token {
     #[token("}")]
     RBrace,
 
-    /// TeX command starting with backslash, e.g. `\textbf`.
+    /// Left bracket `[`, opening an optional argument.
+    #[token("[")]
+    LBracket,
+
+    /// Right bracket `]`.
+    #[token("]")]
+    RBracket,
+
+    /// Forced line break `\\` (also the row separator in `tabular`), or
+    /// `\\*`, after which the page must not break.
+    #[token("\\\\", |_| false)]
+    #[token("\\\\*", |_| true)]
+    LineBreak(bool),
+
+    /// A blank line, which ends the paragraph like `\par`; a control space
+    /// right before it is dropped with the rest of the blank.
+    #[regex(r"[ \t\r]*\n[ \t\r]*\n\s*")]
+    #[regex(r"\\[ \t\r]*\n[ \t\r]*\n\s*")]
+    ParBreak,
+
+    /// Discretionary hyphen `\-`: a place where a word may be hyphenated.
+    #[token("\\-")]
+    DiscretionaryHyphen,
+
+    /// Alignment tab `&` separating table cells (plain text outside tables).
+    #[token("&")]
+    Ampersand,
+
+    /// Tie `~`: a space the line-breaker may not break at.
+    #[token("~")]
+    Tie,
+
+    /// `$`, opening or closing inline math.
+    #[token("$")]
+    Dollar,
+
+    /// Superscript marker `^` (math mode only; plain text elsewhere).
+    #[token("^")]
+    Caret,
+
+    /// Subscript marker `_` (math mode only; plain text elsewhere).
+    #[token("_")]
+    Underscore,
+
+    /// TeX command starting with backslash, e.g. `\textbf`, or one of the
+    /// spaces `\,`, `\;` and `\!`, named by their symbol.
     #[regex(r"\\[a-zA-Z]+", callback = |lex| lex.slice()[1..].to_string(), priority = 2)]
+    #[regex(r"\\[,;!]", |lex| lex.slice()[1..].to_string())]
     Command(String),
 
-    /// Drop all whitespace: spaces, tabs, newlines
+    /// Starred variant of a command, e.g. `\section*`, holding the name
+    /// without the star.
+    #[regex(r"\\[a-zA-Z]+\*", callback = |lex| { let s = lex.slice(); s[1..s.len() - 1].to_string() }, priority = 2)]
+    StarredCommand(String),
+
+    /// Drop all whitespace: spaces, tabs, newlines, and the control space
+    /// `\ ` (a backslash before a space or line break), which is only a word
+    /// break, as in `Dr.\ Smith`.
     #[regex(r"\s+", logos::skip)]
+    #[regex(r"\\[ \t\r\n]", logos::skip)]
     Whitespace,
 
     #[regex(r"//[^\n]*", logos::skip)]
     CppComment,
 
-    /// Any sequence of characters not including `\\`, `{`, `}`, whitespace, or `%`.
-    #[regex(r"[^\\{}\s%]+", callback = |lex| lex.slice().to_string(), priority = 1)]
+    /// Any sequence of characters not including `\\`, `{`, `}`, `[`, `]`, `&`, `~`, `$`, `^`, `_`, whitespace, or `%`;
+    /// or one of `&`, `#`, `_`, `%`, `$`, `{` and `}` escaped with a backslash;
+    /// or a backslash, written `\textbackslash`. As after any command name,
+    /// the spaces after that are not a word break; nor is a `{}` after it.
+    #[regex(r"[^\\{}\[\]\s%&~$^_]+", callback = |lex| lex.slice().to_string(), priority = 1)]
+    #[regex(r"\\[&#_%${}]", |lex| lex.slice()[1..].to_string())]
+    #[regex(r"\\textbackslash(\{\}|[ \t]*)", |_| "\\".to_string(), priority = 3)]
     Text(String),
 
+    /// The body of a `verbatim` environment, taken from the source as it
+    /// is; `lex` produces it in place of the tokens the body would give.
+    Verbatim(String),
+
     /// Catch any unrecognized character.
     #[error]
     Error,
@@ -40,24 +104,151 @@ pub enum Token {
 /// A spanned token: the token plus its start and end byte offsets in the input.
 pub type SpannedToken = (Token, usize, usize);
 
+/// What `lex` does with `%` comments.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Comments {
+    /// Drop them, as typesetting does.
+    #[default]
+    Skip,
+    /// Emit them as `Token::Comment`, for tools that rewrite the source.
+    Keep,
+}
+
 /// Lex the input TeX string into a vector of spanned tokens.
-pub fn lex(input: &str) -> Vec<SpannedToken> {
-    let mut tokens = Vec::new();
+///
+/// `^` and `_` are only script markers between `$` signs; elsewhere they are
+/// ordinary characters and stay part of the surrounding text. In math, a
+/// script applies to one character, so text right after a marker is split
+/// after its first character. Likewise `&` only separates cells inside a
+/// `tabular` or `tabular*` environment, and an escaped character joins the
+/// text around it.
+///
+/// The body of a `verbatim` environment is not tokenized at all: it becomes
+/// a single `Token::Verbatim` between the `\begin{verbatim}` and
+/// `\end{verbatim}` tokens.
+pub fn lex(input: &str, comments: Comments) -> Vec<SpannedToken> {
+    let mut tokens: Vec<SpannedToken> = Vec::new();
+    let mut in_math = false;
+    let mut tables = 0usize;
     let mut lexer = Token::lexer(input);
-    while let Some(tok) = lexer.next() {
+    while let Some(mut tok) = lexer.next() {
         let span = lexer.span();
-        tokens.push((tok, span.start, span.end));
+        if tok == Token::Command("begin".into())
+            && let Some((verbatim, end)) = verbatim(input, span.end)
+        {
+            tokens.push((tok, span.start, span.end));
+            tokens.extend(verbatim);
+            lexer.bump(end - span.end);
+            continue;
+        }
+        match tok {
+            Token::Dollar => in_math = !in_math,
+            Token::Command(ref name)
+                if input[span.end..].starts_with("{tabular}")
+                    || input[span.end..].starts_with("{tabular*}") =>
+            {
+                match name.as_str() {
+                    "begin" => tables += 1,
+                    "end" => tables = tables.saturating_sub(1),
+                    _ => {}
+                }
+            }
+            Token::Ampersand if tables == 0 => tok = Token::Text("&".into()),
+            Token::Caret | Token::Underscore if !in_math => {
+                tok = Token::Text(lexer.slice().to_string());
+            }
+            Token::Comment(_) if comments == Comments::Skip => continue,
+            _ => {}
+        }
+        match (tok, tokens.last_mut()) {
+            // Rejoin text that a literal `^`, `_` or `&`, or an escaped
+            // character, was cut out of.
+            (Token::Text(text), Some((Token::Text(prev), _, prev_end)))
+                if !in_math && *prev_end == span.start =>
+            {
+                prev.push_str(&text);
+                *prev_end = span.end;
+            }
+            (Token::Text(text), Some((Token::Caret | Token::Underscore, _, _)))
+                if in_math && text.chars().count() > 1 =>
+            {
+                let first = text.chars().next().map_or(0, char::len_utf8);
+                let split = span.start + first;
+                tokens.push((Token::Text(text[..first].to_string()), span.start, split));
+                tokens.push((Token::Text(text[first..].to_string()), split, span.end));
+            }
+            (tok, _) => tokens.push((tok, span.start, span.end)),
+        }
     }
     tokens
 }
 
+/// The tokens of a `verbatim` environment whose `\begin` ends at `start`,
+/// from its name to the closing `\end{verbatim}`, and the offset just past
+/// them. `None` unless such an environment starts there and is closed.
+///
+/// As in LaTeX, the line break right after `\begin{verbatim}` and the one
+/// right before `\end{verbatim}` are not part of the body.
+fn verbatim(input: &str, start: usize) -> Option<(Vec<SpannedToken>, usize)> {
+    const NAME: &str = "verbatim";
+    const END: &str = "\\end{verbatim}";
+    let body_start = start + "{verbatim}".len();
+    if !input[start..].starts_with("{verbatim}") {
+        return None;
+    }
+    let body_end = body_start + input[body_start..].find(END)?;
+    let raw = &input[body_start..body_end];
+    let body = raw
+        .strip_prefix("\r\n")
+        .or_else(|| raw.strip_prefix('\n'))
+        .unwrap_or(raw);
+    let body = body
+        .strip_suffix("\r\n")
+        .or_else(|| body.strip_suffix('\n'))
+        .unwrap_or(body);
+
+    // The name's tokens, at `at`: `{`, `verbatim` and `}`.
+    let name = |at: usize| {
+        [
+            (Token::LBrace, at, at + 1),
+            (Token::Text(NAME.into()), at + 1, at + 1 + NAME.len()),
+            (Token::RBrace, at + 1 + NAME.len(), at + 2 + NAME.len()),
+        ]
+    };
+    let end_name = body_end + "\\end".len();
+    let mut tokens = Vec::from(name(start));
+    tokens.push((Token::Verbatim(body.to_string()), body_start, body_end));
+    tokens.push((Token::Command("end".into()), body_end, end_name));
+    tokens.extend(name(end_name));
+    Some((tokens, body_end + END.len()))
+}
+
+/// Convert a byte offset in `input` into a 1-based `(line, column)` pair.
+///
+/// Columns count characters, not bytes. Offsets past the end of the input
+/// resolve to the position just after the last character.
+pub fn line_col(input: &str, offset: usize) -> (usize, usize) {
+    let mut end = offset.min(input.len());
+    while !input.is_char_boundary(end) {
+        end -= 1;
+    }
+    let before = &input[..end];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     /// Helper to get just the token kinds from lexed output.
     fn kinds(input: &str) -> Vec<Token> {
-        lex(input).into_iter().map(|(t, _, _)| t).collect()
+        lex(input, Comments::Skip)
+            .into_iter()
+            .map(|(t, _, _)| t)
+            .collect()
     }
 
     #[test]
@@ -70,11 +261,88 @@ mod tests {
         assert_eq!(kinds("\\textbf"), vec![Token::Command("textbf".into())]);
     }
 
+    #[test]
+    fn test_spaces_are_commands() {
+        assert_eq!(
+            kinds("a\\,b\\;\\!"),
+            vec![
+                Token::Text("a".into()),
+                Token::Command(",".into()),
+                Token::Text("b".into()),
+                Token::Command(";".into()),
+                Token::Command("!".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_starred_command() {
+        assert_eq!(
+            kinds("\\section*{A} 5*3"),
+            vec![
+                Token::StarredCommand("section".into()),
+                Token::LBrace,
+                Token::Text("A".into()),
+                Token::RBrace,
+                Token::Text("5*3".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_control_space_is_a_word_break() {
+        assert_eq!(
+            kinds("Dr.\\ Smith \\textbf{x}\\\nyz"),
+            vec![
+                Token::Text("Dr.".into()),
+                Token::Text("Smith".into()),
+                Token::Command("textbf".into()),
+                Token::LBrace,
+                Token::Text("x".into()),
+                Token::RBrace,
+                Token::Text("yz".into()),
+            ]
+        );
+        assert_eq!(
+            kinds("a\\ \n\nb"),
+            vec![
+                Token::Text("a".into()),
+                Token::ParBreak,
+                Token::Text("b".into())
+            ]
+        );
+        assert_eq!(kinds("a \\"), vec![Token::Text("a".into()), Token::Error]);
+    }
+
     #[test]
     fn test_braces() {
         assert_eq!(kinds("{ }"), vec![Token::LBrace, Token::RBrace]);
     }
 
+    #[test]
+    fn test_blank_line_breaks_paragraph() {
+        assert_eq!(
+            kinds("a\nb \n \n\n c"),
+            vec![
+                Token::Text("a".into()),
+                Token::Text("b".into()),
+                Token::ParBreak,
+                Token::Text("c".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verbatim_body_is_one_token() {
+        let input = "\\begin{verbatim}\n  \\x{ % }\n\\end{verbatim} y";
+        let tokens = lex(input, Comments::Skip);
+        assert_eq!(tokens[4].0, Token::Verbatim("  \\x{ % }".into()));
+        assert_eq!(&input[tokens[4].1..tokens[4].2], "\n  \\x{ % }\n");
+        assert_eq!(tokens[5].0, Token::Command("end".into()));
+        assert_eq!(tokens.last().unwrap().0, Token::Text("y".into()));
+        assert_eq!(tokens.len(), 10);
+    }
+
     #[test]
     fn test_mixed() {
         let input = "\\emph{Word} and text";
@@ -89,6 +357,117 @@ mod tests {
         assert_eq!(kinds(input), expected);
     }
 
+    #[test]
+    fn test_brackets() {
+        assert_eq!(
+            kinds(r"\item[a]"),
+            vec![
+                Token::Command("item".into()),
+                Token::LBracket,
+                Token::Text("a".into()),
+                Token::RBracket,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_table_tokens() {
+        let tokens = kinds(r"\begin{tabular}{ll} a & b \\ \end{tabular}");
+        assert_eq!(
+            tokens[7..11],
+            [
+                Token::Text("a".into()),
+                Token::Ampersand,
+                Token::Text("b".into()),
+                Token::LineBreak(false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ampersand_is_text_outside_tables() {
+        assert_eq!(
+            kinds("R&D & co"),
+            vec![
+                Token::Text("R&D".into()),
+                Token::Text("&".into()),
+                Token::Text("co".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_escaped_characters_join_the_text() {
+        assert_eq!(
+            kinds(r"a\_b 50\% \#1 \{x\}"),
+            vec![
+                Token::Text("a_b".into()),
+                Token::Text("50%".into()),
+                Token::Text("#1".into()),
+                Token::Text("{x}".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_textbackslash_is_a_backslash_in_the_word() {
+        assert_eq!(
+            kinds(r"a\textbackslash b \textbackslash{}c \textbackslashed"),
+            vec![
+                Token::Text("a\\b".into()),
+                Token::Text("\\c".into()),
+                Token::Command("textbackslashed".into()),
+            ]
+        );
+        // A backslash that starts no command is left for the parser to report.
+        assert_eq!(kinds("a \\"), vec![Token::Text("a".into()), Token::Error]);
+    }
+
+    #[test]
+    fn test_tie() {
+        assert_eq!(
+            kinds("Fig.~3"),
+            vec![
+                Token::Text("Fig.".into()),
+                Token::Tie,
+                Token::Text("3".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_discretionary_hyphen() {
+        assert_eq!(
+            kinds(r"hel\-lo"),
+            vec![
+                Token::Text("hel".into()),
+                Token::DiscretionaryHyphen,
+                Token::Text("lo".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scripts_only_in_math() {
+        assert_eq!(
+            kinds("snake_case x^2"),
+            vec![Token::Text("snake_case".into()), Token::Text("x^2".into()),]
+        );
+        assert_eq!(
+            kinds("$x^23_i$"),
+            vec![
+                Token::Dollar,
+                Token::Text("x".into()),
+                Token::Caret,
+                Token::Text("2".into()),
+                Token::Text("3".into()),
+                Token::Underscore,
+                Token::Text("i".into()),
+                Token::Dollar,
+            ]
+        );
+    }
+
     #[test]
     fn test_comment() {
         // Text before comment, comment skipped, then More
@@ -97,4 +476,27 @@ mod tests {
             vec![Token::Text("Text".into()), Token::Text("More".into()),]
         );
     }
+
+    #[test]
+    fn test_comment_kept() {
+        let tokens = lex("a % hi\nb", Comments::Keep);
+        assert_eq!(
+            tokens,
+            vec![
+                (Token::Text("a".into()), 0, 1),
+                (Token::Comment(" hi".into()), 2, 6),
+                (Token::Text("b".into()), 7, 8),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_line_col() {
+        let input = "ab\ncdé\nf";
+        assert_eq!(line_col(input, 0), (1, 1));
+        assert_eq!(line_col(input, 3), (2, 1));
+        assert_eq!(line_col(input, 7), (2, 4));
+        assert_eq!(line_col(input, 8), (3, 1));
+        assert_eq!(line_col(input, 100), (3, 2));
+    }
 }
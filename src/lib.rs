@@ -3,12 +3,130 @@
 // Module declarations
 mod ast;
 mod backend;
+mod error;
 mod expand;
+mod hyphenate;
+mod include;
 mod layout;
 mod lexer;
+mod measure;
+mod options;
+mod outline;
 mod parser;
+mod pdf;
+mod punctuation;
+mod shaping;
+mod subset;
+mod tagged;
+mod text;
+mod units;
+#[cfg(feature = "wasm")]
+mod wasm;
 
-pub use ast::Node;
+pub use ast::{FontFace, Node, Rgb, Script, TextStyle, Visitor, walk};
+pub use backend::FontCache;
+pub use error::{CompileError, Diagnostic, Severity, SourcePos};
+pub use expand::MacroRegistry;
+pub use layout::{
+    Alignment, HBox, LayoutCache, LayoutNode, Line, LineBreakMode, LineEnd, LineTrace, Page,
+    Pagination, Role, StyledRun,
+};
+pub use measure::measure_text;
+pub use options::{
+    CompileOptions, Fonts, PageRange, PageSize, RunningText, SpecialChars, UnknownCommand,
+};
+pub use text::{DocumentStats, ExtractOptions};
+pub use units::Length;
+#[cfg(feature = "wasm")]
+pub use wasm::compile_wasm;
+
+use std::path::Path;
+
+/// A compiled PDF, with what was learned about the document on the way.
+#[derive(Debug)]
+pub struct CompileResult {
+    pub pdf: Vec<u8>,
+    pub page_count: usize,
+    /// Problems that did not stop compilation, such as overfull lines.
+    pub warnings: Vec<String>,
+    /// How every line was broken, when [`CompileOptions::trace`] asks for
+    /// it; empty otherwise.
+    pub trace: Vec<LineTrace>,
+}
+
+/// Parse a TeX-like input string and dump its AST as pretty-printed JSON.
+///
+/// The tree is the raw parse, before macro expansion, so it mirrors the
+/// source exactly. Useful for tooling and for reporting parser bugs.
+pub fn parse_to_json(input: &str) -> Result<String, CompileError> {
+    let ast = parser::parse(input)?;
+    Ok(serde_json::to_string_pretty(&ast).expect("an AST always serializes to JSON"))
+}
+
+/// Parse `input` keeping its `%` comments as [`Node::Comment`]s, e.g. to
+/// build a formatter that must write the source back out.
+///
+/// The text of each comment is everything after the `%`, spaces included.
+pub fn parse_preserving_comments(input: &str) -> Result<Node, CompileError> {
+    parser::parse_with(input, lexer::Comments::Keep)
+}
+
+/// Check `input` for errors without rendering it, reporting every problem
+/// found rather than just the first, in source order.
+///
+/// Meant for editor integration: each [`Diagnostic`] carries the byte span it
+/// applies to. An empty list means `input` should compile, barring missing
+/// fonts or unwritable output.
+///
+/// ```
+/// let diagnostics = latex_rs::validate(r"\textcolor{mauve}{a} $x^$");
+/// assert_eq!(diagnostics.len(), 2);
+/// ```
+pub fn validate(input: &str) -> Vec<Diagnostic> {
+    let (nodes, mut diagnostics) = parser::parse_recovering(input);
+    for (node, span) in nodes {
+        if let Err(e) = expand::expand_macros(&node) {
+            diagnostics.push(Diagnostic::error(e.to_string(), span));
+        }
+    }
+    diagnostics.sort_by_key(|d| d.span.start);
+    diagnostics
+}
+
+/// The running text of `input`, with its commands and styling stripped, for
+/// tools such as spell checkers.
+///
+/// Words are separated by single spaces, and paragraphs by blank lines;
+/// math and `verbatim` bodies are left out. See [`extract_text_with`] to
+/// keep them.
+///
+/// ```
+/// let text = latex_rs::extract_text(r"\textbf{Hello} \emph{world}").unwrap();
+/// assert_eq!(text, "Hello world");
+/// ```
+pub fn extract_text(input: &str) -> Result<String, CompileError> {
+    extract_text_with(input, ExtractOptions::default())
+}
+
+/// The running text of `input`, as [`extract_text`] gives it, with math
+/// and `verbatim` bodies kept as `opts` asks.
+pub fn extract_text_with(input: &str, opts: ExtractOptions) -> Result<String, CompileError> {
+    let ast = parser::parse(input)?;
+    let ast = include::resolve_inputs(&ast, Path::new(""), None, &CompileOptions::default())?;
+    let expanded = expand::expand_macros(&ast)?;
+    Ok(text::plain_text(&expanded, opts))
+}
+
+/// The word, character and paragraph counts of `input`, taken from the
+/// text [`extract_text`] gives, so that commands and math are not counted.
+///
+/// ```
+/// let stats = latex_rs::document_stats(r"\emph{Two} words.").unwrap();
+/// assert_eq!((stats.words, stats.paragraphs), (2, 1));
+/// ```
+pub fn document_stats(input: &str) -> Result<DocumentStats, CompileError> {
+    extract_text(input).map(|text| DocumentStats::of(&text))
+}
 
 /// Compile a TeX-like input string into a PDF byte vector.
 ///
@@ -17,36 +135,176 @@ pub use ast::Node;
 ///
 /// # Returns
 /// - `Ok(Vec<u8>)`: PDF bytes on success
-/// - `Err(String)`: error message on failure
-pub fn compile(input: &str) -> Result<Vec<u8>, String> {
-    // 1. Parse input into AST
-    let ast = parser::parse(input)?;
+/// - `Err(CompileError)`: what went wrong, and where in `input`
+pub fn compile(input: &str) -> Result<Vec<u8>, CompileError> {
+    compile_with(input, &CompileOptions::default())
+}
 
-    // 2. Expand macros (flatten sequences)
-    let expanded = expand::expand_macros(&ast);
-
-    // 2) Compute usable width in points from A4 − 10 mm margins
-    let page_w_mm = 210.0;
-    let margin_mm = 10.0; // your PDF backend also uses 10 mm margins
-    let usable_mm = page_w_mm - 2.0 * margin_mm; // 190 mm
-    let pts_per_mm = 72.0 / 25.4; // 1 in = 25.4 mm = 72 pt
-    let line_width_pt = usable_mm * pts_per_mm; // ≈ 539 pt
-
-    // 3) Pick your font size and line height in pt
-    let font_size_pt = 12.0;
-    let line_height_pt = font_size_pt * 1.2; // 14.4 pt leading
-
-    eprintln!(
-        "→ Page: {} mm, margins: {} mm → usable: {} mm → {} pt (≈{:.1} mm)",
-        page_w_mm,
-        margin_mm,
-        usable_mm,
+/// Compile a TeX-like input string into a PDF byte vector using `opts`.
+///
+/// Font files named in `opts` are read once per process, through
+/// [`FontCache::shared`].
+pub fn compile_with(input: &str, opts: &CompileOptions) -> Result<Vec<u8>, CompileError> {
+    compile_with_cache(input, opts, FontCache::shared())
+}
+
+/// Compile a TeX-like input string into a PDF byte vector using `opts`,
+/// loading font files through `fonts`.
+pub fn compile_with_cache(
+    input: &str,
+    opts: &CompileOptions,
+    fonts: &FontCache,
+) -> Result<Vec<u8>, CompileError> {
+    let pages = compile_to_pages(input, opts)?;
+    backend::render_pdf(&pages, opts, fonts)
+}
+
+/// Compile the TeX file at `path` into a PDF byte vector.
+///
+/// Unlike [`compile`], which looks files named by `\input{name}` and
+/// `\include{name}` up in the current directory, this looks them up in the
+/// directory `path` is in, as the main file of a multi-file document.
+pub fn compile_file(path: impl AsRef<Path>) -> Result<Vec<u8>, CompileError> {
+    compile_file_with(path, &CompileOptions::default())
+}
+
+/// Compile the TeX file at `path` into a PDF byte vector using `opts`; see
+/// [`compile_file`].
+pub fn compile_file_with(
+    path: impl AsRef<Path>,
+    opts: &CompileOptions,
+) -> Result<Vec<u8>, CompileError> {
+    let path = path.as_ref();
+    let input = std::fs::read_to_string(path).map_err(|e| CompileError::Input {
+        path: path.to_path_buf(),
+        message: e.to_string(),
+    })?;
+    let pages = lay_out(&input, Some(path), opts, None)?;
+    backend::render_pdf(&pages, opts, FontCache::shared())
+}
+
+/// Compile a TeX-like input string into a PDF using `opts`, reporting the
+/// number of pages and any warnings along with it.
+///
+/// As in TeX, a line wider than the text, because a word or box in it could
+/// not be broken, is set anyway and reported as an "Overfull \hbox".
+/// Characters that no font has a glyph for are reported as well. With
+/// [`CompileOptions::trace`] set, how each line was broken is reported too.
+pub fn compile_detailed(input: &str, opts: &CompileOptions) -> Result<CompileResult, CompileError> {
+    let pages = compile_to_pages(input, opts)?;
+    let mut warnings = layout::overfull_warnings(&pages, text_width(opts).to_pt(), opts);
+    let trace = if opts.trace {
+        layout::trace(&pages, text_width(opts).to_pt(), opts)
+    } else {
+        Vec::new()
+    };
+    let mut pdf = Vec::new();
+    warnings.extend(backend::write_pdf(
+        &pages,
+        opts,
+        FontCache::shared(),
+        &mut pdf,
+    )?);
+    Ok(CompileResult {
+        pdf,
+        page_count: pages.len(),
+        warnings,
+        trace,
+    })
+}
+
+/// Compile a TeX-like input string into a PDF written to `w`.
+///
+/// Each page is written into `w` as soon as it is drawn, and dropped then,
+/// so that no more than one page is ever held as PDF; the fonts and the
+/// outline follow the last page. The whole document is still laid out
+/// first, since a reference may point at a page further on.
+pub fn compile_to_writer(
+    input: &str,
+    opts: &CompileOptions,
+    w: &mut dyn std::io::Write,
+) -> Result<(), CompileError> {
+    let pages = compile_to_pages(input, opts)?;
+    backend::write_pdf(pages, opts, FontCache::shared(), w).map(drop)
+}
+
+/// Compile a TeX-like input string into an SVG document using `opts`.
+///
+/// All pages are stacked in one drawing; see [`compile_to_pages`] to render
+/// them some other way.
+pub fn compile_to_svg(input: &str, opts: &CompileOptions) -> Result<String, CompileError> {
+    let pages = compile_to_pages(input, opts)?;
+    Ok(backend::render_svg(&pages, opts))
+}
+
+/// Compile a TeX-like input string into an HTML page using `opts`.
+///
+/// The page is written from the expanded document rather than from laid-out
+/// pages, as headings, paragraphs and styled text that a browser sets to
+/// fit its window.
+///
+/// ```
+/// let html = latex_rs::compile_to_html(r"\textbf{Hi}", &Default::default()).unwrap();
+/// assert!(html.contains("<p><strong>Hi</strong></p>"));
+/// ```
+pub fn compile_to_html(input: &str, opts: &CompileOptions) -> Result<String, CompileError> {
+    let expanded = expand_input(input, None, opts)?;
+    Ok(backend::render_html(&expanded, opts))
+}
+
+/// Parse, expand and lay out `input`, stopping before any output format is produced.
+///
+/// The returned pages hold positioned runs of styled text, which is all a
+/// renderer needs; use this to drive a backend other than PDF.
+///
+/// ```
+/// use latex_rs::{compile_to_pages, CompileOptions};
+///
+/// let pages = compile_to_pages(r"First \\ second", &CompileOptions::default()).unwrap();
+/// assert_eq!(pages[0].lines.len(), 2);
+/// ```
+pub fn compile_to_pages(input: &str, opts: &CompileOptions) -> Result<Vec<Page>, CompileError> {
+    lay_out(input, None, opts, None)
+}
+
+/// [`compile_to_pages`] for a document that is laid out again and again as
+/// it is edited, measuring only the paragraphs that changed since `cache`
+/// was last used.
+///
+/// The pages are those [`compile_to_pages`] gives; a fresh
+/// [`LayoutCache::default`] starts a document.
+pub fn compile_to_pages_with_cache(
+    input: &str,
+    opts: &CompileOptions,
+    cache: &mut LayoutCache,
+) -> Result<Vec<Page>, CompileError> {
+    lay_out(input, None, opts, Some(cache))
+}
+
+/// [`compile_to_pages`] for `input` read from the file `path`, if any, whose
+/// directory input files are then looked up in.
+fn lay_out(
+    input: &str,
+    path: Option<&Path>,
+    opts: &CompileOptions,
+    cache: Option<&mut LayoutCache>,
+) -> Result<Vec<Page>, CompileError> {
+    let expanded = expand_input(input, path, opts)?;
+
+    // 3) Compute usable width in points from the paper width − the margins
+    let usable = text_width(opts); // 190 mm on A4 by default
+    let line_width_pt = usable.to_pt(); // ≈ 539 pt
+
+    log::debug!(
+        "page {} mm, margins {} mm, usable width {} mm ({:.2} pt)",
+        (opts.page_size.width().to_mm() * 10.0).round() / 10.0,
+        (opts.margin.to_mm() * 10.0).round() / 10.0,
+        (usable.to_mm() * 10.0).round() / 10.0,
         line_width_pt,
-        line_width_pt * 25.4 / 72.0,
     );
 
-    let font_size_pt = 12.0;
-    let line_height_pt = font_size_pt * 1.2;
+    let font_size_pt = opts.font_size.to_pt();
+    let line_height_pt = opts.line_height();
 
     // Instead of hard‐coding CHAR_WIDTH and SPACE_WIDTH in layout.rs,
     // compute them here, in pt:
@@ -54,22 +312,135 @@ pub fn compile(input: &str) -> Result<Vec<u8>, String> {
     let space_width_pt = char_width_pt; // one “space” = one char width
 
     // 4) Layout in “pt” units
-    let pages = layout::layout(
-        &expanded,
-        line_width_pt,
-        line_height_pt,
-        char_width_pt,
-        space_width_pt,
-    );
-    // 4. Render to PDF
-    let pdf_data = backend::render_pdf(&pages);
+    let pages = match cache {
+        Some(cache) => layout::layout_cached(
+            &expanded,
+            line_width_pt,
+            line_height_pt,
+            char_width_pt,
+            space_width_pt,
+            opts,
+            cache,
+        ),
+        None => layout::layout(
+            &expanded,
+            line_width_pt,
+            line_height_pt,
+            char_width_pt,
+            space_width_pt,
+            opts,
+        ),
+    };
+    log::debug!("laid out {} page(s)", pages.len());
+    Ok(pages)
+}
+
+/// Parse and expand `input`, read from the file `path` if any, as far as
+/// every backend needs it.
+fn expand_input(
+    input: &str,
+    path: Option<&Path>,
+    opts: &CompileOptions,
+) -> Result<Node, CompileError> {
+    opts.check()?;
+
+    // 1. Parse input into AST, splicing in the files it inputs
+    let ast = parser::parse_limited(
+        input,
+        lexer::Comments::Skip,
+        opts.max_nesting,
+        opts.special_chars,
+    )?;
+    let dir = path.and_then(Path::parent).unwrap_or(Path::new(""));
+    let ast = include::resolve_inputs(&ast, dir, path, opts)?;
 
-    Ok(pdf_data)
+    // 2. Expand macros (flatten sequences)
+    let mut expanded = expand::expand_with(&ast, opts).map_err(|e| e.locate_command(input))?;
+    if opts.smart_punctuation {
+        expanded = punctuation::smart_punctuation(&expanded);
+    }
+    Ok(expanded)
+}
+
+/// Width of the text block between the margins `opts` set on its paper.
+fn text_width(opts: &CompileOptions) -> Length {
+    Length::pt(opts.page_size.width().to_pt() - 2.0 * opts.margin.to_pt())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Mutex;
+
+    /// Collects log messages, standing in for the logger a binary would install.
+    struct Capture(Mutex<Vec<String>>);
+
+    impl log::Log for Capture {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+
+    #[test]
+    fn test_diagnostics_go_to_the_logger() {
+        // Only the first test to get here installs the logger.
+        if log::set_logger(&CAPTURE).is_ok() {
+            log::set_max_level(log::LevelFilter::Debug);
+        }
+        compile("Hello world").expect("Compile failed");
+        let messages = CAPTURE.0.lock().unwrap();
+        assert!(messages.iter().any(|m| m.starts_with("page 210 mm")));
+    }
+
+    #[test]
+    fn test_unknown_commands_follow_the_policy() {
+        if log::set_logger(&CAPTURE).is_ok() {
+            log::set_max_level(log::LevelFilter::Debug);
+        }
+        let src = "Some text\n\\\\notacommand \\notacommand{x}";
+        let with = |policy| CompileOptions {
+            unknown_commands: policy,
+            ..CompileOptions::default()
+        };
+        let second_line = |opts: &CompileOptions| {
+            let pages = compile_to_pages(src, opts).unwrap();
+            layout::line_text(&pages[0].lines[1])
+        };
+
+        assert_eq!(second_line(&with(UnknownCommand::Ignore)), "notacommand x");
+        assert!(
+            !CAPTURE
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|m| m.contains("notacommand"))
+        );
+
+        assert_eq!(second_line(&with(UnknownCommand::Warn)), "notacommand x");
+        assert!(
+            CAPTURE
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|m| m == "Unknown command `\\notacommand`; its arguments are set as text")
+        );
+
+        // The error points at the command, not at the line break before it.
+        let err = compile_with(src, &with(UnknownCommand::Error)).unwrap_err();
+        let pos = err.pos().expect("the command is in the input");
+        assert_eq!((pos.line, pos.column), (2, 15));
+        assert_eq!(err.to_string(), "2:15: Unknown command `\\notacommand`");
+    }
 
     #[test]
     fn test_compile_hello() {
@@ -77,4 +448,652 @@ mod tests {
         let pdf = compile(tex).expect("Compile failed");
         assert!(pdf.starts_with(b"%PDF-"), "Output is not a PDF");
     }
+
+    #[test]
+    fn test_parse_to_json() {
+        let json = parse_to_json(r"\textbf{x}").expect("Parse failed");
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let mac = &value["Seq"][0]["Macro"];
+        assert_eq!(mac["name"], "textbf");
+        assert_eq!(mac["args"][0]["Seq"][0]["Text"], "x");
+    }
+
+    #[test]
+    fn test_parse_preserving_comments() {
+        let ast = parse_preserving_comments("a % hi\nb").unwrap();
+        assert_eq!(
+            ast,
+            Node::Seq(vec![
+                Node::Text("a".into()),
+                Node::Comment(" hi".into()),
+                Node::Text("b".into()),
+            ])
+        );
+        // Comments never reach the typeset output.
+        let plain = parser::parse("a % hi\nb").unwrap();
+        assert_eq!(
+            plain,
+            Node::Seq(vec![Node::Text("a".into()), Node::Text("b".into())])
+        );
+    }
+
+    #[test]
+    fn test_compile_knuth_plass() {
+        let opts = CompileOptions {
+            line_break: LineBreakMode::KnuthPlass,
+            ..CompileOptions::default()
+        };
+        let pdf = compile_with("Hello \\\\ world", &opts).expect("Compile failed");
+        assert!(pdf.starts_with(b"%PDF-"), "Output is not a PDF");
+    }
+
+    #[test]
+    fn test_compile_to_writer() {
+        let tex = vec!["line \\\\"; 120].concat();
+        let opts = CompileOptions::default();
+        assert!(compile_to_pages(&tex, &opts).unwrap().len() > 1);
+        let mut out = Vec::new();
+        compile_to_writer(&tex, &opts, &mut out).expect("Compile failed");
+        assert!(out.starts_with(b"%PDF-"), "Output is not a PDF");
+        assert!(out.ends_with(b"%%EOF"), "Output is truncated");
+        // Everything reaches the writer that `compile_with` would have returned.
+        assert_eq!(out.len(), compile_with(&tex, &opts).unwrap().len());
+
+        // Each page follows its content, before the next page's, and the
+        // fonts the pages share come after them all.
+        let text = String::from_utf8_lossy(&out);
+        let offsets = |what: &str| {
+            text.match_indices(what)
+                .map(|(at, _)| at)
+                .collect::<Vec<_>>()
+        };
+        let (contents, pages) = (offsets("BT\n"), offsets("/Type/Page/"));
+        assert!(pages.len() > 1);
+        for (page, next) in pages.iter().zip(&pages[1..]) {
+            assert!(
+                contents
+                    .iter()
+                    .any(|content| page < content && content < next)
+            );
+        }
+        assert!(offsets("/FontFile2")[0] > *pages.last().unwrap());
+    }
+
+    #[test]
+    fn test_compile_to_writer_reports_write_errors() {
+        struct Full;
+        impl std::io::Write for Full {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::StorageFull.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let err = compile_to_writer("Hello", &CompileOptions::default(), &mut Full).unwrap_err();
+        assert!(matches!(err, CompileError::Io(_)));
+    }
+
+    #[test]
+    fn test_truncated_input_is_an_error() {
+        for src in [
+            "\\",
+            "{",
+            "\\textbf{",
+            "\\textcolor{red}{",
+            "$x^",
+            "$x_{",
+            "\\begin{",
+            "\\begin{itemize}",
+            "\\end{",
+            "a \\\\[",
+            "a \\\\[5mm",
+        ] {
+            assert!(compile(src).is_err(), "{:?} compiled", src);
+        }
+    }
+
+    #[test]
+    fn test_no_prefix_of_a_document_panics() {
+        let doc = r"\section{Intro} Some \textbf{bold}, $x^2_{i}$ and a~tie \\[2mm]
+\begin{itemize} \item \textcolor{red}{one} \item two \end{itemize}
+\begin{tabular}{ll} a & b \\ c & d \end{tabular} % done";
+        for end in (0..=doc.len()).filter(|&i| doc.is_char_boundary(i)) {
+            let _ = compile_to_pages(&doc[..end], &CompileOptions::default());
+        }
+    }
+
+    #[test]
+    fn test_double_spacing_halves_lines_per_page() {
+        let tex = vec!["line \\\\"; 120].concat();
+        let lines_on_first_page = |line_spacing| {
+            let opts = CompileOptions {
+                line_spacing,
+                ..CompileOptions::default()
+            };
+            compile_to_pages(&tex, &opts).unwrap()[0].lines.len()
+        };
+        // 55 single-spaced lines fit in the 800pt text block; 27 double-spaced ones.
+        assert_eq!(lines_on_first_page(2.0), lines_on_first_page(1.0) / 2);
+    }
+
+    #[test]
+    fn test_margin_and_font_size_set_the_page() {
+        let opts = CompileOptions {
+            margin: Length::mm(30.0),
+            font_size: Length::pt(24.0),
+            ..CompileOptions::default()
+        };
+        // The text starts 30 mm in and down, at twice the size, on lines
+        // twice as far apart.
+        let svg = compile_to_svg("one \\\\ two", &opts).unwrap();
+        assert!(svg.contains(r#"font-size="24">"#), "{}", svg);
+        assert!(svg.contains(r#"<text x="85.04" y="85.04">one</text>"#));
+        assert!(svg.contains(r#"<text x="85.04" y="113.84">two</text>"#));
+        // Lines are as wide as the paper leaves between the margins.
+        let words = vec!["word"; 200].join(" ");
+        let narrow = compile_to_pages(&words, &opts).unwrap();
+        assert!(
+            narrow[0]
+                .lines
+                .iter()
+                .all(|line| line.width <= text_width(&opts).to_pt())
+        );
+        let wide = compile_to_pages(&words, &CompileOptions::default()).unwrap();
+        assert!(narrow[0].lines.len() > wide[0].lines.len());
+
+        for opts in [
+            CompileOptions {
+                font_size: Length::pt(0.0),
+                ..CompileOptions::default()
+            },
+            CompileOptions {
+                margin: Length::mm(110.0),
+                ..CompileOptions::default()
+            },
+        ] {
+            assert!(matches!(
+                compile_to_pages("Text", &opts),
+                Err(CompileError::InvalidOption(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_validate_reports_every_error() {
+        let src = "A $x^$ here,\n\\vspace{3furlongs} and \\textcolor{red}{fine} text.";
+        let diagnostics = validate(src);
+        assert_eq!(diagnostics.len(), 2, "{:?}", diagnostics);
+        assert!(diagnostics.iter().all(|d| d.severity == Severity::Error));
+        assert_eq!(&src[diagnostics[0].span.clone()], "^");
+        assert_eq!(&src[diagnostics[1].span.clone()], "\\vspace{3furlongs}");
+        assert!(diagnostics[1].message.contains("3furlongs"));
+
+        // Both stray braces, not just the first.
+        assert_eq!(validate("a } b } c").len(), 2);
+        assert!(validate(r"\section{Fine} \textbf{text}").is_empty());
+    }
+
+    #[test]
+    fn test_deep_nesting_is_an_error() {
+        let nested = |depth: usize| format!("{}x{}", "{".repeat(depth), "}".repeat(depth));
+        let opts = CompileOptions::default();
+        match compile_to_pages(&nested(10_000), &opts) {
+            Err(CompileError::NestingTooDeep { limit: 256, pos }) => {
+                assert_eq!(pos.unwrap().column, 257)
+            }
+            other => panic!("Expected NestingTooDeep, got {:?}", other.map(|_| ())),
+        }
+        assert!(compile_to_pages(&nested(256), &opts).is_ok());
+        let shallow = CompileOptions {
+            max_nesting: 3,
+            ..CompileOptions::default()
+        };
+        assert!(compile_to_pages(&nested(3), &shallow).is_ok());
+        assert!(compile_to_pages(&nested(4), &shallow).is_err());
+        assert!(compile_to_pages(r"\section[\section[\section[\section[x]]]]", &shallow).is_err());
+        assert!(validate(&nested(10_000)).len() == 1);
+    }
+
+    #[test]
+    fn test_overfull_word_is_reported() {
+        let word = "x".repeat(120);
+        let src = format!("A {} b", word);
+        let result = compile_detailed(&src, &CompileOptions::default()).unwrap();
+        assert!(result.pdf.starts_with(b"%PDF-"));
+        assert_eq!(result.page_count, 1);
+        assert_eq!(result.warnings.len(), 1);
+        assert!(result.warnings[0].starts_with("Overfull \\hbox ("));
+        assert!(result.warnings[0].ends_with(&word));
+
+        let fits = compile_detailed("A short line", &CompileOptions::default()).unwrap();
+        assert!(fits.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_trace_records_a_forced_break() {
+        let opts = CompileOptions {
+            trace: true,
+            ..CompileOptions::default()
+        };
+        let trace = compile_detailed(r"Hello there \\ world", &opts)
+            .unwrap()
+            .trace;
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace[0].end, LineEnd::Forced);
+        assert_eq!(trace[0].closed_by, "there");
+        assert_eq!(trace[0].text, "Hello there");
+        let measure = text_width(&opts).to_pt();
+        assert!((trace[0].width + trace[0].remaining - measure).abs() < 1e-9);
+        assert!(
+            trace[0]
+                .to_string()
+                .starts_with("page 1: forced after `there`")
+        );
+        assert_eq!(trace[1].end, LineEnd::Paragraph);
+
+        let untraced = compile_detailed(r"Hello \\ world", &CompileOptions::default()).unwrap();
+        assert!(untraced.trace.is_empty());
+    }
+
+    #[test]
+    fn test_tagged_pdf_has_a_structure_tree() {
+        let src = "\\section{Intro}\n\nSome text.\n\nMore text.";
+        let contains = |pdf: &[u8], s: &str| pdf.windows(s.len()).any(|w| w == s.as_bytes());
+        let plain = compile_with(src, &CompileOptions::default()).unwrap();
+        assert!(!contains(&plain, "/StructTreeRoot"));
+
+        let opts = CompileOptions {
+            tagged_pdf: true,
+            ..CompileOptions::default()
+        };
+        let tagged = compile_with(src, &opts).unwrap();
+        for s in [
+            "/StructTreeRoot",
+            "/MarkInfo<</Marked true>>",
+            "/S/H1",
+            "/S/P",
+        ] {
+            assert!(contains(&tagged, s), "no {}", s);
+        }
+        assert!(contains(&tagged, "/H1 <</MCID 0>> BDC"));
+        assert!(contains(&tagged, "/P <</MCID 2>> BDC"));
+        assert!(tagged.ends_with(b"%%EOF"));
+    }
+
+    #[test]
+    fn test_running_head_is_an_artifact() {
+        let src = "Some text.\n\nMore text.";
+        let contains = |pdf: &[u8], s: &str| pdf.windows(s.len()).any(|w| w == s.as_bytes());
+        let opts = CompileOptions {
+            header: Some(RunningText {
+                text: "Page {page}".into(),
+                ..RunningText::default()
+            }),
+            tagged_pdf: true,
+            ..CompileOptions::default()
+        };
+        let tagged = compile_with(src, &opts).unwrap();
+        assert!(contains(&tagged, "/Artifact <</Type/Pagination>> BDC"));
+        // The text is numbered as it would be without the head.
+        assert!(contains(&tagged, "/P <</MCID 0>> BDC"));
+        assert!(contains(&tagged, "/P <</MCID 1>> BDC"));
+        assert!(
+            compile_to_svg(src, &opts)
+                .unwrap()
+                .contains(">Page 1</text>")
+        );
+    }
+
+    #[test]
+    fn test_deterministic_output_is_byte_identical() {
+        let src = "\\section{Intro}\n\nSome \\textbf{bold} text.";
+        let opts = CompileOptions {
+            deterministic: true,
+            ..CompileOptions::default()
+        };
+        let first = compile_with(src, &opts).unwrap();
+        assert_eq!(first, compile_with(src, &opts).unwrap());
+        let tagged = CompileOptions {
+            tagged_pdf: true,
+            ..opts
+        };
+        assert_eq!(
+            compile_with(src, &tagged).unwrap(),
+            compile_with(src, &tagged).unwrap()
+        );
+        // Without the option, every file gets an ID of its own.
+        let plain = CompileOptions::default();
+        assert_ne!(
+            compile_with(src, &plain).unwrap(),
+            compile_with(src, &plain).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sections_become_bookmarks() {
+        let src = "\\section{One}\n\nText.\n\n\\vspace{750pt}\n\n\\section{Two}\n\n\\subsection{Detail}\n\nMore.";
+        let pdf = compile(src).unwrap();
+        let pdf = String::from_utf8_lossy(&pdf);
+        // Each outline item, from its object number to the end of its dictionary.
+        let item = |title: &str| {
+            let at = pdf
+                .find(&format!("/Title {}", outline::text_string(title)))
+                .expect(title);
+            let start = pdf[..at].rfind('\n').unwrap() + 1;
+            &pdf[start..at + pdf[at..].find("endobj").unwrap()]
+        };
+        let id = |item: &str| item.split(' ').next().unwrap().parse::<u32>().ok();
+        let pages = pdf::references(&pdf, "/Kids").unwrap();
+        let page = |item: &str| item.split("/Dest[").nth(1).and_then(id);
+        let (one, two, detail) = (item("1 One"), item("2 Two"), item("2.1 Detail"));
+        assert_eq!(page(one), Some(pages[0]));
+        assert_eq!(page(two), Some(pages[1]));
+        assert_eq!(page(detail), Some(pages[1]));
+
+        // The sections are the two entries at the top, the subsection is under the second.
+        let parent = |item| pdf::reference(item, "/Parent");
+        assert_eq!(parent(one), parent(two));
+        assert_eq!(pdf::reference(one, "/Next"), id(two));
+        assert_eq!(parent(detail), id(two));
+        assert!(pdf.contains(&format!(
+            "<</Type/Outlines/First {} 0 R/Last {} 0 R/Count 3>>",
+            id(one).unwrap(),
+            id(two).unwrap()
+        )));
+        assert!(pdf.contains("/PageMode/UseOutlines"));
+    }
+
+    #[test]
+    fn test_maketitle_centres_the_title_on_page_one() {
+        let src = "\\title{On Layout}\\author{A. Writer}\\date{May 2025}\\maketitle\n\n\\begin{abstract}We lay out text.\\end{abstract}\n\nBody.";
+        let pages = compile_to_pages(src, &CompileOptions::default()).unwrap();
+        let lines: Vec<String> = pages[0].lines.iter().map(layout::line_text).collect();
+        assert_eq!(
+            lines,
+            [
+                "On Layout",
+                "A. Writer",
+                "May 2025",
+                "Abstract",
+                "We lay out text.",
+                "Body."
+            ]
+        );
+        let title = &pages[0].lines[0];
+        let measure = text_width(&CompileOptions::default()).to_pt();
+        assert_eq!(title.align, Alignment::Center);
+        assert!((title.x_offset + title.width / 2.0 - measure / 2.0).abs() < 1e-6);
+        assert!(matches!(&title.boxes[0].items[0], LayoutNode::Run(run) if run.style.scale > 1.5));
+        // The abstract is indented from both margins.
+        let body = &pages[0].lines[4];
+        assert!(body.x_offset > 0.0);
+    }
+
+    #[test]
+    fn test_penalties_out_of_range_are_refused() {
+        let opts = CompileOptions {
+            hyphen_penalty: 20_000.0,
+            ..CompileOptions::default()
+        };
+        let err = compile_to_pages("Text", &opts).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid option: `hyphen_penalty` cannot be 20000"
+        );
+        for opts in [
+            CompileOptions {
+                line_penalty: -1.0,
+                ..CompileOptions::default()
+            },
+            CompileOptions {
+                adjacent_demerits: f64::INFINITY,
+                ..CompileOptions::default()
+            },
+            CompileOptions {
+                tolerance: f64::NAN,
+                ..CompileOptions::default()
+            },
+        ] {
+            assert!(matches!(
+                compile_to_pages("Text", &opts),
+                Err(CompileError::InvalidOption(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_main_file_inputs_a_chapter_beside_it() {
+        let dir = std::env::temp_dir().join("latex_rs_compile_file");
+        std::fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.tex");
+        std::fs::write(&main, "Before.\n\n\\input{chapter}\n\nAfter.").unwrap();
+        std::fs::write(dir.join("chapter.tex"), "\\section{Chapter}\n\nInside.").unwrap();
+
+        let input = std::fs::read_to_string(&main).unwrap();
+        let pages = lay_out(&input, Some(&main), &CompileOptions::default(), None).unwrap();
+        let lines: Vec<String> = pages[0].lines.iter().map(layout::line_text).collect();
+        assert_eq!(lines, ["Before.", "1 Chapter", "Inside.", "After."]);
+        assert!(compile_file(&main).unwrap().starts_with(b"%PDF-"));
+        // A string has no directory of its own; the chapter is not beside it.
+        assert!(matches!(
+            compile(&input),
+            Err(CompileError::Input { path, .. }) if path == Path::new("chapter.tex")
+        ));
+    }
+
+    #[test]
+    fn test_references_resolve_before_their_labels() {
+        let src = "See section~\\ref{two} on page~\\pageref{two} but not \\ref{three} yet\n\n\\section{One}\n\nText.\n\n\\vspace{790pt}\n\n\\section{Two}\\label{two}\n\nText.";
+        let pages = compile_to_pages(src, &CompileOptions::default()).unwrap();
+        assert_eq!(pages.len(), 2);
+        assert_eq!(
+            layout::line_text(&pages[0].lines[0]),
+            "See section 2 on page 2 but not ?? yet"
+        );
+    }
+
+    #[test]
+    fn test_continuous_pagination_sets_one_tall_page() {
+        let src = "One\n\n\\vspace{790pt}\n\nTwo\n\n\\vspace{790pt}\n\nThree";
+        let opts = CompileOptions {
+            pagination: Pagination::Continuous,
+            columns: 2,
+            ..CompileOptions::default()
+        };
+        let pages = compile_to_pages(src, &opts).unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].lines.len(), 3);
+        assert!(pages[0].lines.iter().all(|line| line.column == 0));
+
+        let pdf = compile_with(src, &opts).unwrap();
+        let pdf = String::from_utf8_lossy(&pdf);
+        assert_eq!(
+            pdf::references(&pdf, "/Kids").map(|kids| kids.len()),
+            Some(1)
+        );
+        // As tall as the lines and the space above them, with the 10 mm
+        // margins above and below, to two decimals.
+        let lines: f64 = pages[0]
+            .lines
+            .iter()
+            .map(|line| line.space_before + line.extra_height + opts.line_height())
+            .sum();
+        let height = lines + 2.0 * opts.margin.to_pt();
+        assert!(height > opts.page_size.height().to_pt());
+        assert!(pdf.contains(&format!("/MediaBox[0 0 595.28 {:.2}]", height)));
+    }
+
+    #[test]
+    fn test_empty_documents_are_one_blank_page() {
+        let page_count = |pdf: &[u8]| {
+            pdf::references(&String::from_utf8_lossy(pdf), "/Kids").map(|kids| kids.len())
+        };
+        let first = CompileOptions {
+            pages: PageRange::parse("1"),
+            ..CompileOptions::default()
+        };
+        for src in ["", "   \n\t\n\n  ", "% just a comment"] {
+            let pages = compile_to_pages(src, &CompileOptions::default()).unwrap();
+            assert_eq!(pages.len(), 1, "{:?}", src);
+            assert!(pages[0].lines.is_empty());
+            let result = compile_detailed(src, &CompileOptions::default()).unwrap();
+            assert!(result.pdf.starts_with(b"%PDF-"));
+            assert_eq!(page_count(&result.pdf), Some(1));
+            assert_eq!((result.page_count, result.warnings.len()), (1, 0));
+            // The page is there to ask for, in either format.
+            assert_eq!(page_count(&compile_with(src, &first).unwrap()), Some(1));
+            let svg = compile_to_svg(src, &first).unwrap();
+            assert_eq!(svg.matches("<g ").count(), 1);
+            assert!(!svg.contains("<text"));
+        }
+    }
+
+    #[test]
+    fn test_page_range_renders_only_those_pages() {
+        let src = "One\n\n\\vspace{790pt}\n\nTwo\n\n\\vspace{790pt}\n\nThree";
+        assert_eq!(
+            compile_to_pages(src, &CompileOptions::default())
+                .unwrap()
+                .len(),
+            3
+        );
+        let page_count = |pdf: &[u8]| {
+            pdf::references(&String::from_utf8_lossy(pdf), "/Kids").map(|kids| kids.len())
+        };
+        assert_eq!(page_count(&compile(src).unwrap()), Some(3));
+
+        let second = CompileOptions {
+            pages: PageRange::parse("2-2"),
+            ..CompileOptions::default()
+        };
+        assert_eq!(page_count(&compile_with(src, &second).unwrap()), Some(1));
+        let svg = compile_to_svg(src, &second).unwrap();
+        assert_eq!(svg.matches("<g ").count(), 1);
+
+        let past_the_end = CompileOptions {
+            pages: PageRange::parse("4-5"),
+            ..CompileOptions::default()
+        };
+        assert_eq!(
+            compile_with(src, &past_the_end),
+            Err(CompileError::NoPagesInRange {
+                first: 4,
+                last: 5,
+                page_count: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_missing_glyphs_are_reported() {
+        let opts = CompileOptions::default();
+        let result = compile_detailed("Kanji: 漢字, 漢.", &opts).unwrap();
+        assert_eq!(
+            result.warnings,
+            ["No glyph for U+6F22, U+5B57 in the fonts; drawn as \u{25a1}"]
+        );
+
+        // U+2312 is missing from DejaVu Sans, but not from its monospace face.
+        let arc = "An arc: \u{2312}";
+        assert_eq!(compile_detailed(arc, &opts).unwrap().warnings.len(), 1);
+        let opts = CompileOptions {
+            fonts: Fonts {
+                fallback: Some("fonts/DejaVuSansMono.ttf".into()),
+                ..Fonts::default()
+            },
+            ..CompileOptions::default()
+        };
+        assert!(compile_detailed(arc, &opts).unwrap().warnings.is_empty());
+    }
+
+    #[test]
+    fn test_par_lays_out_like_a_blank_line() {
+        let layout = |src| format!("{:?}", compile_to_pages(src, &CompileOptions::default()));
+        assert_eq!(layout(r"a \par b"), layout("a\n\nb"));
+        let pages = compile_to_pages(r"a \par\par b", &CompileOptions::default()).unwrap();
+        assert_eq!(pages[0].lines.len(), 2);
+        assert!(pages[0].lines[1].space_before > 0.0);
+        assert_eq!(layout(r"a \par\par b"), layout(r"a \par b"));
+    }
+
+    #[test]
+    fn test_verbatim_is_set_literally() {
+        let opts = CompileOptions::default();
+        let pages = compile_to_pages(r"\begin{verbatim}\textbf{x}\end{verbatim}", &opts).unwrap();
+        match &pages[0].lines[0].boxes[0].items[0] {
+            LayoutNode::Run(run) => {
+                assert_eq!(run.text, r"\textbf{x}");
+                assert_eq!(run.style.face, FontFace::Monospace);
+            }
+            other => panic!("Expected a run, got {:?}", other),
+        }
+
+        let pages =
+            compile_to_pages("\\begin{verbatim}\na  b\n\n c\n\\end{verbatim}", &opts).unwrap();
+        let text = |line: &Line| match line.boxes.first().map(|b| &b.items[0]) {
+            Some(LayoutNode::Run(run)) => run.text.clone(),
+            _ => String::new(),
+        };
+        let lines: Vec<_> = pages[0].lines.iter().map(text).collect();
+        assert_eq!(lines, ["a  b", "", " c"]);
+    }
+
+    #[test]
+    fn test_verbatim_tabs_reach_the_next_tab_stop() {
+        let src = "\\begin{verbatim}\nabc\td\n\tx\ty\n12345678\tz\n\\end{verbatim} a\tb";
+        let lines = |tab_width| {
+            let opts = CompileOptions {
+                tab_width,
+                ..CompileOptions::default()
+            };
+            let pages = compile_to_pages(src, &opts).unwrap();
+            let texts: Vec<String> = pages[0]
+                .lines
+                .iter()
+                .map(|line| match &line.boxes[0].items[0] {
+                    LayoutNode::Run(run) => run.text.clone(),
+                    other => panic!("Expected a run, got {:?}", other),
+                })
+                .collect();
+            // The verbatim text is as wide as its columns.
+            let columns = texts[0].chars().count() as f64;
+            assert!((pages[0].lines[0].width - columns * 7.2).abs() < 1e-9);
+            texts
+        };
+        // A tab at column 3 moves on to column 8, or 4.
+        assert_eq!(
+            lines(8),
+            ["abc     d", "        x       y", "12345678        z", "a"]
+        );
+        assert_eq!(lines(4), ["abc d", "    x   y", "12345678    z", "a"]);
+        assert_eq!(lines(0)[0], "abcd");
+        // Outside verbatim, a tab only separates words.
+        let html = compile_to_html("a\tb", &CompileOptions::default()).unwrap();
+        assert!(html.contains("<p>a b</p>") && html.contains("pre { tab-size: 8; }"));
+    }
+
+    #[test]
+    fn test_smart_punctuation_is_opt_in() {
+        let first_word = |smart_punctuation| {
+            let opts = CompileOptions {
+                smart_punctuation,
+                ..CompileOptions::default()
+            };
+            let pages = compile_to_pages("``Hi''", &opts).unwrap();
+            match &pages[0].lines[0].boxes[0].items[0] {
+                LayoutNode::Run(run) => run.text.clone(),
+                other => panic!("Expected a run, got {:?}", other),
+            }
+        };
+        assert_eq!(first_word(false), "``Hi''");
+        assert_eq!(first_word(true), "\u{201c}Hi\u{201d}");
+    }
+
+    #[test]
+    fn test_compile_to_svg() {
+        let svg =
+            compile_to_svg("Hello world", &CompileOptions::default()).expect("Compile failed");
+        assert!(svg.contains(">Hello</text>") && svg.contains(">world</text>"));
+    }
 }
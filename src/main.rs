@@ -1,24 +1,127 @@
 mod cli;
+mod config;
+mod logger;
+mod watch;
 use clap::Parser;
-use cli::Cli;
-use latex_rs::compile;
+use cli::{Cli, Emit, Format};
+use latex_rs::{
+    CompileOptions, compile_detailed, compile_to_html, compile_to_svg, compile_with,
+    document_stats, parse_to_json,
+};
+use std::io::{self, Read, Write};
 use std::{error::Error, fs}; // so Cli::parse() is available
 
 fn main() -> Result<(), Box<dyn Error>> {
     // parse args
     let cli = Cli::parse();
+    if cli.verbose {
+        logger::init(log::LevelFilter::Debug);
+    }
 
-    // read input .tex
-    let tex = fs::read_to_string(&cli.input)
-        .map_err(|e| format!("Failed to read {}: {}", cli.input.display(), e))?;
+    // line breaks are only traced on the way to a PDF
+    if cli.trace && cli.format != Format::Pdf {
+        return Err("--trace needs --format pdf".into());
+    }
 
-    // compile to PDF bytes
-    let pdf = compile(&tex).map_err(|e| format!("Compilation error: {}", e))?;
+    // keep recompiling on every change instead of compiling once, if asked to
+    if let (true, Some(input)) = (cli.watch, &cli.input) {
+        if cli.emit == Emit::Ast || cli.writes_to_stdout() {
+            return Err("--watch needs a document written to a file with --output".into());
+        }
+        return watch::watch(input, || build(&cli, &read_input(&cli)?));
+    }
 
-    // write output .pdf
-    fs::write(&cli.output, &pdf)
-        .map_err(|e| format!("Failed to write {}: {}", cli.output.display(), e))?;
+    let tex = read_input(&cli)?;
 
-    println!("Written PDF to {}", cli.output.display());
+    // dump the AST instead of compiling, if asked to
+    if cli.emit == Emit::Ast {
+        let json = parse_to_json(&tex).map_err(|e| format!("Parse error: {}", e))?;
+        println!("{}", json);
+        return Ok(());
+    }
+
+    // count the words instead of compiling, if asked to
+    if cli.stats {
+        let stats = document_stats(&tex).map_err(|e| format!("Compilation error: {}", e))?;
+        println!("{}", stats);
+        return Ok(());
+    }
+
+    let message = build(&cli, &tex)?;
+    if !cli.writes_to_stdout() {
+        println!("{}", message);
+    }
+
+    // show the document, if asked to; it is written either way, so failing
+    // to open it is only a warning
+    match cli.file_to_open() {
+        Ok(Some(path)) => {
+            if let Err(e) = opener::open(path) {
+                eprintln!("Warning: failed to open {}: {}", path.display(), e);
+            }
+        }
+        Ok(None) => {}
+        Err(warning) => eprintln!("Warning: {}", warning),
+    }
     Ok(())
 }
+
+/// Read the input .tex, from stdin when no file is given.
+fn read_input(cli: &Cli) -> Result<String, Box<dyn Error>> {
+    let tex = match &cli.input {
+        Some(path) => fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?,
+        None => {
+            let mut buf = String::new();
+            io::stdin()
+                .read_to_string(&mut buf)
+                .map_err(|e| format!("Failed to read stdin: {}", e))?;
+            buf
+        }
+    };
+    Ok(tex)
+}
+
+/// Compile `tex` and write the document out, returning a line describing
+/// what was written.
+fn build(cli: &Cli, tex: &str) -> Result<String, Box<dyn Error>> {
+    let output = cli
+        .output
+        .as_ref()
+        .ok_or("--output is required when emitting a document")?;
+
+    // compile to the requested format, with the options the config file
+    // sets, and the flags over those
+    let mut opts = match &cli.config {
+        Some(path) => config::load(path)?,
+        None => CompileOptions::default(),
+    };
+    cli.override_options(&mut opts);
+    let (bytes, kind) = match cli.format {
+        Format::Pdf if opts.trace => {
+            let result = compile_detailed(tex, &opts).map(|result| {
+                for line in &result.trace {
+                    eprintln!("{}", line);
+                }
+                result.pdf
+            });
+            (result, "PDF")
+        }
+        Format::Pdf => (compile_with(tex, &opts), "PDF"),
+        Format::Svg => (compile_to_svg(tex, &opts).map(String::into_bytes), "SVG"),
+        Format::Html => (compile_to_html(tex, &opts).map(String::into_bytes), "HTML"),
+    };
+    let bytes = bytes.map_err(|e| format!("Compilation error: {}", e))?;
+
+    // write output; stdout gets the raw bytes, untouched by any text handling
+    if cli.writes_to_stdout() {
+        let mut out = io::stdout().lock();
+        out.write_all(&bytes)
+            .and_then(|()| out.flush())
+            .map_err(|e| format!("Failed to write stdout: {}", e))?;
+    } else {
+        fs::write(output, &bytes)
+            .map_err(|e| format!("Failed to write {}: {}", output.display(), e))?;
+    }
+    Ok(format!("Written {} to {}", kind, output.display()))
+}
@@ -0,0 +1,69 @@
+//! Text widths as layout measures them, for tools that need to know where
+//! text ends, such as to place a cursor, without compiling a document.
+
+use crate::ast::TextStyle;
+use crate::layout::{CHAR_WIDTH_EM, measure};
+
+/// The width, in points, of `text` set in `style` in body text of
+/// `size_pt` points.
+///
+/// This is the width a compile with the default options gives the text:
+/// the style's face, size and script count as they do in a document, and
+/// soft hyphens take no room until a line is broken at one. Kerning, which
+/// is off by default, is not applied.
+///
+/// ```
+/// use latex_rs::{FontFace, TextStyle, measure_text};
+///
+/// let bold = TextStyle::from(FontFace::Bold);
+/// assert_eq!(measure_text("word", &bold, 12.0), 24.0);
+/// ```
+pub fn measure_text(text: &str, style: &TextStyle, size_pt: f64) -> f64 {
+    measure(text, style, size_pt * CHAR_WIDTH_EM, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{FontFace, Script};
+    use crate::options::CompileOptions;
+
+    #[test]
+    fn test_appending_a_character_widens_the_text() {
+        let styles = [
+            TextStyle::default(),
+            FontFace::Monospace.into(),
+            TextStyle {
+                scale: 1.44,
+                ..TextStyle::default()
+            },
+            TextStyle {
+                script: Script::Subscript,
+                ..TextStyle::default()
+            },
+        ];
+        for style in &styles {
+            let mut text = String::new();
+            let mut width = measure_text(&text, style, 12.0);
+            assert_eq!(width, 0.0);
+            for c in "Wide words, ünïcode & digits 0123".chars() {
+                text.push(c);
+                let wider = measure_text(&text, style, 12.0);
+                assert!(wider > width, "{:?} in {:?}", text, style);
+                width = wider;
+            }
+            // Twice the size, twice the width.
+            assert_eq!(measure_text(&text, style, 24.0), 2.0 * width);
+        }
+    }
+
+    #[test]
+    fn test_matches_the_width_of_a_laid_out_line() {
+        let ast = crate::parser::parse(r"hel\-lo").unwrap();
+        let ast = crate::expand::expand_macros(&ast).unwrap();
+        let pages = crate::layout::layout(&ast, 200.0, 20.0, 6.0, 6.0, &CompileOptions::default());
+        let width = measure_text("hel\u{ad}lo", &TextStyle::default(), 12.0);
+        assert_eq!(width, pages[0].lines[0].width);
+        assert_eq!(width, 30.0);
+    }
+}
@@ -0,0 +1,392 @@
+use serde::{Deserialize, Deserializer, de};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::ast::{FontFace, Rgb};
+use crate::error::CompileError;
+use crate::expand::MacroRegistry;
+use crate::layout::{Alignment, LineBreakMode, MAX_PENALTY, Pagination};
+use crate::units::Length;
+
+/// Body text size by default, in points.
+pub(crate) const FONT_SIZE_PT: f64 = 12.0;
+
+/// Margin on every side of the page by default.
+pub(crate) const MARGIN: Length = Length::mm(10.0);
+
+/// Distance between baselines of single-spaced text, relative to the font size.
+pub(crate) const LEADING: f64 = 1.2;
+
+/// How deep groups, arguments and environments may be nested by default.
+pub(crate) const MAX_NESTING: usize = 256;
+
+/// Settings that control how a document is compiled.
+///
+/// Deserializes from a table naming any of the fields, e.g. a config file;
+/// the others keep their defaults. Lengths are written with their unit, as
+/// in `column_gap = "12pt"`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompileOptions {
+    /// The paper the document is set on.
+    pub page_size: PageSize,
+    /// Space between the edges of the paper and the text, on every side.
+    pub margin: Length,
+    /// Size of the body text, which `\large` and the like scale, and which
+    /// the lines are spaced by.
+    pub font_size: Length,
+    /// Stretch the spaces of every line of a left-aligned paragraph but its
+    /// last, and those ended by `\\`, so that the lines fill the measure;
+    /// otherwise the right edge is ragged.
+    pub justify: bool,
+    /// Break the document into pages of that paper, or set it on one page
+    /// of its width, as tall as the document.
+    pub pagination: Pagination,
+    /// Algorithm used to break paragraphs into lines.
+    pub line_break: LineBreakMode,
+    /// Hyphenate words that do not fit at the end of a line.
+    pub hyphenate: bool,
+    /// What every line costs the Knuth-Plass breaker, as TeX's
+    /// `\linepenalty`; higher values favour fewer lines. From 0 to 10000.
+    ///
+    /// This and the next three settings weigh one way of breaking a
+    /// paragraph against another; the greedy breaker ignores them.
+    pub line_penalty: f64,
+    /// What ending a line with a hyphen costs, as TeX's `\hyphenpenalty`:
+    /// raise it for fewer hyphens and looser lines. From -10000 to 10000,
+    /// which forbids hyphens.
+    pub hyphen_penalty: f64,
+    /// What a line costs when it is much looser than the line before it, or
+    /// much tighter, as TeX's `\adjdemerits`. Not negative.
+    pub adjacent_demerits: f64,
+    /// The worst badness a line may have, as TeX's `\tolerance`; from 0, for
+    /// lines whose spaces never stretch, to 10000, which allows any line.
+    pub tolerance: f64,
+    /// Font files to typeset with instead of the bundled DejaVu faces.
+    pub fonts: Fonts,
+    /// Turn ``` `` ```, `''`, `` ` ``, `'`, `--` and `---` into curly quotes
+    /// and dashes, as TeX does.
+    pub smart_punctuation: bool,
+    /// Line spacing as a multiple of single spacing, e.g. 1.5 for one-and-a-half
+    /// or 2.0 for double spacing.
+    pub line_spacing: f64,
+    /// Number of columns the text is set in, filled one after the other; a
+    /// continuous page has only one.
+    pub columns: usize,
+    /// Space between adjacent columns.
+    pub column_gap: Length,
+    /// How far the first line of a paragraph that follows another is
+    /// indented, as TeX's `\parindent`; `\noindent` drops it for one
+    /// paragraph. LaTeX's article class uses 15 pt.
+    pub paragraph_indent: Length,
+    /// How many columns apart the tab stops of a `verbatim` environment
+    /// are: a tab there moves on to the next stop. Elsewhere a tab is a
+    /// space like any other.
+    pub tab_width: usize,
+    /// Where every `tabular` is placed on its line, whatever the text
+    /// around it; when `None`, a table is placed as that text is aligned,
+    /// so centered in a `center` environment or after `\centering`.
+    pub table_align: Option<Alignment>,
+    /// The moment `\today` gives the date of, and the PDF is dated, in UTC;
+    /// the time of compilation when `None`. Fix it for reproducible output.
+    #[serde(skip)]
+    pub now: Option<SystemTime>,
+    /// Compile the same document to the same bytes every time, for caching
+    /// and diffing: the PDF is dated `now`, or the Unix epoch, and gets a
+    /// fixed ID.
+    pub deterministic: bool,
+    /// How deep groups, arguments and environments may be nested; deeper
+    /// input is refused with [`CompileError::NestingTooDeep`](crate::CompileError::NestingTooDeep)
+    /// rather than risking a stack overflow.
+    pub max_nesting: usize,
+    /// What a bare `&` outside a table, `#`, or `_` outside math is: the
+    /// character itself, or a mistake to report with the escape that sets it.
+    pub special_chars: SpecialChars,
+    /// What becomes of a command that neither a built-in nor a registered
+    /// macro interprets, such as a misspelt `\textbf`.
+    pub unknown_commands: UnknownCommand,
+    /// Record where and why every line was broken, for
+    /// [`CompileResult::trace`](crate::CompileResult::trace), e.g. to find
+    /// out why a line is overfull or loose.
+    pub trace: bool,
+    /// Tag paragraphs and headings in the PDF, so that screen readers and
+    /// text extraction see the document's structure.
+    pub tagged_pdf: bool,
+    /// Move pairs of letters such as `AV` closer together, or further apart,
+    /// by the amounts the font's `kern` table gives.
+    pub kerning: bool,
+    /// Set `fi` and `fl` as single glyphs when the font has them.
+    pub ligatures: bool,
+    /// Drawn in place of characters that neither the face they are set in
+    /// nor [`Fonts::fallback`] has a glyph for.
+    pub missing_glyph: char,
+    /// Embed only the glyphs the document draws of each font file, rather
+    /// than the whole file; a page of text then weighs kilobytes, not the
+    /// megabytes of the bundled faces.
+    pub subset_fonts: bool,
+    /// A line set above the text of every page, such as a running title.
+    pub header: Option<RunningText>,
+    /// A line set below the text of every page, such as the page number.
+    pub footer: Option<RunningText>,
+    /// Render only these pages, e.g. to preview part of a long document;
+    /// every page when `None`. The whole document is still laid out, so
+    /// page breaks and numbering are those of the full document.
+    pub pages: Option<PageRange>,
+    /// Fill every page of the PDF with this color before drawing on it, e.g.
+    /// for slides; written as `\textcolor` takes it, such as `"#fdf6e3"`.
+    /// The text keeps its own colors, so choosing one it stays readable on
+    /// is up to you. White paper when `None`.
+    pub background_color: Option<Rgb>,
+    /// Macros defined by the program compiling the document; see
+    /// [`MacroRegistry`].
+    #[serde(skip)]
+    pub macros: MacroRegistry,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            page_size: PageSize::default(),
+            margin: MARGIN,
+            font_size: Length::pt(FONT_SIZE_PT),
+            justify: false,
+            pagination: Pagination::default(),
+            line_break: LineBreakMode::default(),
+            hyphenate: false,
+            line_penalty: 10.0,
+            hyphen_penalty: 50.0,
+            adjacent_demerits: 10_000.0,
+            tolerance: 10_000.0,
+            fonts: Fonts::default(),
+            smart_punctuation: false,
+            line_spacing: 1.0,
+            columns: 1,
+            column_gap: Length::pt(10.0),
+            paragraph_indent: Length::pt(0.0),
+            tab_width: 8,
+            table_align: None,
+            now: None,
+            deterministic: false,
+            max_nesting: MAX_NESTING,
+            special_chars: SpecialChars::default(),
+            unknown_commands: UnknownCommand::default(),
+            trace: false,
+            tagged_pdf: false,
+            kerning: false,
+            ligatures: false,
+            missing_glyph: '\u{25a1}',
+            subset_fonts: true,
+            header: None,
+            footer: None,
+            pages: None,
+            background_color: None,
+            macros: MacroRegistry::default(),
+        }
+    }
+}
+
+impl CompileOptions {
+    /// Distance between baselines of body text.
+    pub fn line_height(&self) -> f64 {
+        self.font_size.to_pt() * LEADING * self.line_spacing
+    }
+
+    /// Width of one column of a text block `text_width` points wide.
+    pub fn column_width(&self, text_width: f64) -> f64 {
+        let columns = self.columns_per_page() as f64;
+        (text_width - (columns - 1.0) * self.column_gap.to_pt()) / columns
+    }
+
+    /// Check that the line breaking penalties, the font size and the margin
+    /// are within their ranges.
+    pub(crate) fn check(&self) -> Result<(), CompileError> {
+        let penalty = -MAX_PENALTY..=MAX_PENALTY;
+        let cost = 0.0..=MAX_PENALTY;
+        let (font_size, margin) = (self.font_size.to_pt(), self.margin.to_pt());
+        // The margins must leave room for text on the paper.
+        let paper = self
+            .page_size
+            .width()
+            .to_pt()
+            .min(self.page_size.height().to_pt());
+        let settings = [
+            (
+                "line_penalty",
+                self.line_penalty,
+                cost.contains(&self.line_penalty),
+            ),
+            (
+                "hyphen_penalty",
+                self.hyphen_penalty,
+                penalty.contains(&self.hyphen_penalty),
+            ),
+            (
+                "adjacent_demerits",
+                self.adjacent_demerits,
+                (0.0..f64::INFINITY).contains(&self.adjacent_demerits),
+            ),
+            ("tolerance", self.tolerance, cost.contains(&self.tolerance)),
+            (
+                "font_size",
+                font_size,
+                font_size > 0.0 && font_size.is_finite(),
+            ),
+            ("margin", margin, (0.0..paper / 2.0).contains(&margin)),
+        ];
+        match settings.into_iter().find(|&(_, _, valid)| !valid) {
+            // A NaN is in no range, so it is refused as well.
+            Some((name, value, _)) => Err(CompileError::InvalidOption(format!(
+                "`{}` cannot be {}",
+                name, value
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// How many columns the text is set in: `columns`, but only one on a
+    /// continuous page.
+    pub(crate) fn columns_per_page(&self) -> usize {
+        match self.pagination {
+            Pagination::Paged => self.columns.max(1),
+            Pagination::Continuous => 1,
+        }
+    }
+
+    /// Whether the page at `index`, counted from 0, is to be rendered.
+    pub(crate) fn renders_page(&self, index: usize) -> bool {
+        self.pages.is_none_or(|range| range.contains(index + 1))
+    }
+}
+
+/// A running head or foot: a line repeated on every page, above or below
+/// the text, which gives up two lines of its height to it.
+///
+/// Deserializes from a table of both, e.g. in a config file:
+///
+/// ```toml
+/// [footer]
+/// text = "Page {page}"
+/// align = "center"
+/// ```
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RunningText {
+    /// The text, set as it is but for `{page}`, which becomes the number of
+    /// the page, counted from 1.
+    pub text: String,
+    /// Where the text sits across the page, on the left by default.
+    pub align: Alignment,
+}
+
+/// A run of pages, numbered from 1 as a reader counts them.
+///
+/// Deserializes from the same strings [`PageRange::parse`] reads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageRange {
+    pub first: usize,
+    pub last: usize,
+}
+
+impl<'de> Deserialize<'de> for PageRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let spec = String::deserialize(deserializer)?;
+        PageRange::parse(&spec)
+            .ok_or_else(|| de::Error::custom(format!("invalid page range `{}`", spec)))
+    }
+}
+
+impl PageRange {
+    /// Parse a range written as `2-3`, or `2` for a single page. Pages are
+    /// numbered from 1, and the range must not run backwards.
+    pub fn parse(spec: &str) -> Option<Self> {
+        let (first, last) = spec.split_once('-').unwrap_or((spec, spec));
+        let number = |n: &str| n.trim().parse::<usize>().ok().filter(|&n| n > 0);
+        let (first, last) = (number(first)?, number(last)?);
+        (first <= last).then_some(PageRange { first, last })
+    }
+
+    /// Whether page `number`, counted from 1, is in the range.
+    pub fn contains(self, number: usize) -> bool {
+        (self.first..=self.last).contains(&number)
+    }
+}
+
+/// How `&`, `#` and `_` are read where TeX would not accept them: `&`
+/// outside a table, `#` anywhere, and `_` outside math. Escaped as `\&`,
+/// `\#` and `\_` they are always the characters themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpecialChars {
+    /// Set them as they are, e.g. for `R&D` or `snake_case`.
+    #[default]
+    Literal,
+    /// Refuse them with a [`CompileError::Parse`] naming the escape, as TeX
+    /// does. Names, such as a color, a file or a label key, may still hold
+    /// them.
+    Reject,
+}
+
+/// What becomes of a command nothing interprets, such as `\foobar{x}`.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownCommand {
+    /// Refuse it with a [`CompileError::UnknownCommand`] at its first use.
+    Error,
+    /// Log a warning naming it, and set its arguments as text.
+    Warn,
+    /// Set its arguments as text, dropping the name without a word.
+    #[default]
+    Ignore,
+}
+
+/// Paper sizes, in portrait.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PageSize {
+    /// 210 × 297 mm.
+    #[default]
+    A4,
+    /// US Letter, 8.5 × 11 in.
+    Letter,
+}
+
+impl PageSize {
+    pub const fn width(self) -> Length {
+        match self {
+            PageSize::A4 => Length::mm(210.0),
+            PageSize::Letter => Length::in_(8.5),
+        }
+    }
+
+    pub const fn height(self) -> Length {
+        match self {
+            PageSize::A4 => Length::mm(297.0),
+            PageSize::Letter => Length::in_(11.0),
+        }
+    }
+}
+
+/// TrueType or OpenType files for each face; `None` keeps the bundled face.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Fonts {
+    pub normal: Option<PathBuf>,
+    pub bold: Option<PathBuf>,
+    pub italic: Option<PathBuf>,
+    pub monospace: Option<PathBuf>,
+    /// A font for characters the face they are set in has no glyph for,
+    /// such as CJK or emoji; there is none by default.
+    pub fallback: Option<PathBuf>,
+}
+
+impl Fonts {
+    /// The file named for `face`, if any.
+    pub(crate) fn path(&self, face: &FontFace) -> Option<&PathBuf> {
+        match face {
+            FontFace::Normal => self.normal.as_ref(),
+            FontFace::Bold => self.bold.as_ref(),
+            FontFace::Italic => self.italic.as_ref(),
+            FontFace::Monospace => self.monospace.as_ref(),
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! The document outline, or bookmarks: an entry for every heading that
+//! jumps to it, with subsections nested under their section.
+//!
+//! The outline is written once every page is, as the entries jump to them.
+
+use std::fmt::Write as _;
+use std::io;
+
+use crate::pdf::Writer;
+
+/// A heading, as the outline lists it.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Bookmark {
+    /// 1 for a section, 2 for a subsection.
+    pub(crate) level: u8,
+    /// The heading's text, number included.
+    pub(crate) title: String,
+    /// Index of the page the heading is set on.
+    pub(crate) page: usize,
+    /// Height of the top of the heading above the foot of the page, in points.
+    pub(crate) top: f64,
+}
+
+/// Write `bookmarks`, in document order, into `pdf` as outline `root` of the
+/// document whose pages are `pages`.
+pub(crate) fn write_outline(
+    pdf: &mut Writer,
+    root: u32,
+    pages: &[u32],
+    bookmarks: &[Bookmark],
+) -> io::Result<()> {
+    let ids: Vec<u32> = bookmarks.iter().map(|_| pdf.reserve()).collect();
+    // Each entry belongs to the closest entry of a higher level before it,
+    // or to the outline itself.
+    let parents: Vec<Option<usize>> = (0..bookmarks.len())
+        .map(|i| {
+            (0..i)
+                .rev()
+                .find(|&j| bookmarks[j].level < bookmarks[i].level)
+        })
+        .collect();
+    let children = |parent: Option<usize>| -> Vec<usize> {
+        (0..bookmarks.len())
+            .filter(|&i| parents[i] == parent)
+            .collect()
+    };
+    let id = |entry: Option<usize>| entry.map_or(root, |i| ids[i]);
+
+    for (i, bookmark) in bookmarks.iter().enumerate() {
+        let page = pages[bookmark.page];
+        let mut dict = format!(
+            "<</Title {}/Parent {} 0 R",
+            text_string(&bookmark.title),
+            id(parents[i])
+        );
+        let siblings = children(parents[i]);
+        let at = siblings.iter().position(|&s| s == i).unwrap_or(0);
+        if let Some(&prev) = at.checked_sub(1).and_then(|p| siblings.get(p)) {
+            let _ = write!(dict, "/Prev {} 0 R", ids[prev]);
+        }
+        if let Some(&next) = siblings.get(at + 1) {
+            let _ = write!(dict, "/Next {} 0 R", ids[next]);
+        }
+        write_children(
+            &mut dict,
+            &children(Some(i)),
+            &ids,
+            descendants(bookmarks, i),
+        );
+        let _ = write!(
+            dict,
+            "/Dest[{} 0 R/XYZ null {:.2} null]>>",
+            page, bookmark.top
+        );
+        pdf.object(ids[i], &dict)?;
+    }
+
+    let mut outlines = String::from("<</Type/Outlines");
+    write_children(&mut outlines, &children(None), &ids, bookmarks.len());
+    outlines.push_str(">>");
+    pdf.object(root, &outlines)
+}
+
+/// Add the `/First`, `/Last` and `/Count` entries of an outline item with
+/// `children`, which are open and show `count` entries in all.
+fn write_children(dict: &mut String, children: &[usize], ids: &[u32], count: usize) {
+    if let (Some(&first), Some(&last)) = (children.first(), children.last()) {
+        let _ = write!(
+            dict,
+            "/First {} 0 R/Last {} 0 R/Count {}",
+            ids[first], ids[last], count
+        );
+    }
+}
+
+/// How many entries are nested under entry `i`, at any depth.
+fn descendants(bookmarks: &[Bookmark], i: usize) -> usize {
+    bookmarks[i + 1..]
+        .iter()
+        .take_while(|b| b.level > bookmarks[i].level)
+        .count()
+}
+
+/// `text` as a PDF text string: UTF-16BE behind a byte order mark, in hex.
+pub(crate) fn text_string(text: &str) -> String {
+    let mut hex = String::from("<FEFF");
+    for unit in text.encode_utf16() {
+        let _ = write!(hex, "{:04X}", unit);
+    }
+    hex.push('>');
+    hex
+}
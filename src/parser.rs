@@ -1,24 +1,455 @@
-use crate::ast::Node;
-use crate::lexer::{Token, lex};
+use crate::ast::{ColumnAlign, ListItem, ListKind, Node, TableRow, TableSpec, TableWidth};
+use crate::error::{CompileError, Diagnostic};
+use crate::expand::takes_argument;
+use crate::lexer::{Comments, SpannedToken, Token, lex, line_col};
+use crate::options::{MAX_NESTING, SpecialChars};
+use crate::units::Length;
+use std::ops::Range;
 
-pub fn parse(input: &str) -> Result<Node, String> {
-    let toks = lex(input)
+/// A parse failure at a token index; `parse` resolves it to a source position.
+#[derive(Debug)]
+struct ParseError {
+    at: usize,
+    message: String,
+    kind: ParseErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ParseErrorKind {
+    Syntax,
+    /// An unmatched `{` or `}`.
+    Unbalanced,
+    /// Nesting deeper than the given limit.
+    TooDeep(usize),
+}
+
+impl ParseError {
+    fn new(at: usize, message: impl Into<String>) -> Self {
+        ParseError {
+            at,
+            message: message.into(),
+            kind: ParseErrorKind::Syntax,
+        }
+    }
+
+    fn unbalanced(at: usize, message: impl Into<String>) -> Self {
+        ParseError {
+            kind: ParseErrorKind::Unbalanced,
+            ..ParseError::new(at, message)
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Node, CompileError> {
+    parse_with(input, Comments::Skip)
+}
+
+/// Parse `input`, keeping `%` comments as `Node::Comment` if `comments` asks to.
+pub fn parse_with(input: &str, comments: Comments) -> Result<Node, CompileError> {
+    parse_limited(input, comments, MAX_NESTING, SpecialChars::Literal)
+}
+
+/// Parse `input`, refusing groups, optional arguments and environments
+/// nested more than `max_nesting` deep, and stray special characters if
+/// `special_chars` asks to.
+pub(crate) fn parse_limited(
+    input: &str,
+    comments: Comments,
+    max_nesting: usize,
+    special_chars: SpecialChars,
+) -> Result<Node, CompileError> {
+    let tokens = lex(input, comments);
+    if special_chars == SpecialChars::Reject
+        && let Some((offset, message)) = stray_special(input, &tokens)
+    {
+        return Err(CompileError::parse(input, offset, message));
+    }
+    let (toks, starts): (Vec<Token>, Vec<usize>) = bare_brackets_as_text(tokens)
         .into_iter()
-        .map(|(t, _, _)| t)
-        .collect::<Vec<_>>();
-    let (ast, pos) = parse_sequence(&toks, 0)?;
-    if pos != toks.len() {
-        Err(format!("Unexpected tokens remaining at pos {}", pos))
-    } else {
-        Ok(ast)
+        .map(|(t, start, _)| (t, start))
+        .unzip();
+    let to_compile_error = |e: ParseError| {
+        let offset = starts.get(e.at).copied().unwrap_or(input.len());
+        match e.kind {
+            ParseErrorKind::Syntax => CompileError::parse(input, offset, e.message),
+            ParseErrorKind::Unbalanced => CompileError::unbalanced_braces(input, offset, e.message),
+            ParseErrorKind::TooDeep(limit) => CompileError::nesting_too_deep(input, offset, limit),
+        }
+    };
+
+    check_braces(&toks).map_err(to_compile_error)?;
+    check_environments(&toks, |i| line_col(input, starts[i])).map_err(to_compile_error)?;
+    check_nesting(&toks, max_nesting).map_err(to_compile_error)?;
+    let (ast, pos) = parse_sequence(&toks, 0).map_err(to_compile_error)?;
+    match toks.get(pos) {
+        Some(tok) => Err(to_compile_error(ParseError::new(
+            pos,
+            format!("Unexpected {:?}", tok),
+        ))),
+        None => Ok(ast),
+    }
+}
+
+/// Parse `input` one top-level node at a time, carrying on past errors.
+///
+/// Returns the nodes that parsed, each with its byte span, and a diagnostic
+/// for every error. After an error, parsing resumes at the next `}` or
+/// command. Unbalanced braces or environments make the structure of the
+/// whole document doubtful, so only those are reported when there are any.
+pub(crate) fn parse_recovering(input: &str) -> (Vec<(Node, Range<usize>)>, Vec<Diagnostic>) {
+    let (toks, spans): (Vec<Token>, Vec<Range<usize>>) =
+        bare_brackets_as_text(lex(input, Comments::Skip))
+            .into_iter()
+            .map(|(t, start, end)| (t, start..end))
+            .unzip();
+    let diagnostic = |e: ParseError| {
+        let span = spans.get(e.at).cloned().unwrap_or(input.len()..input.len());
+        Diagnostic::error(e.message, span)
+    };
+
+    let mut structural = brace_errors(&toks);
+    if structural.is_empty() {
+        structural = environment_errors(&toks, |i| line_col(input, spans[i].start));
+    }
+    if structural.is_empty() {
+        structural.extend(check_nesting(&toks, MAX_NESTING).err());
+    }
+    if !structural.is_empty() {
+        return (Vec::new(), structural.into_iter().map(diagnostic).collect());
+    }
+
+    let mut nodes = Vec::new();
+    let mut diagnostics = Vec::new();
+    let mut pos = 0;
+    while let Some(tok) = toks.get(pos) {
+        match tok {
+            // The rest of a group or environment that an error cut short.
+            Token::RBrace => pos += 1,
+            Token::Command(name) if name == "end" => {
+                pos = parse_env_name(&toks, pos + 1).map_or(pos + 1, |(_, next)| next);
+            }
+            _ => match parse_node(&toks, pos) {
+                Ok((node, next)) => {
+                    nodes.push((node, spans[pos].start..spans[next - 1].end));
+                    pos = next;
+                }
+                Err(e) => {
+                    let resume = (e.at.max(pos) + 1..toks.len())
+                        .find(|&i| {
+                            matches!(
+                                toks[i],
+                                Token::RBrace | Token::Command(_) | Token::StarredCommand(_)
+                            )
+                        })
+                        .unwrap_or(toks.len());
+                    diagnostics.push(diagnostic(e));
+                    pos = resume;
+                }
+            },
+        }
+    }
+    (nodes, diagnostics)
+}
+
+/// Match every `{` with a `}` before parsing, so that a missing or stray
+/// brace is reported where it is rather than where parsing gives up.
+fn check_braces(tokens: &[Token]) -> Result<(), ParseError> {
+    brace_errors(tokens).into_iter().next().map_or(Ok(()), Err)
+}
+
+/// Every stray `}` in source order, then every unclosed `{`, innermost first.
+fn brace_errors(tokens: &[Token]) -> Vec<ParseError> {
+    let mut errors = Vec::new();
+    let mut open = Vec::new();
+    for (i, tok) in tokens.iter().enumerate() {
+        match tok {
+            Token::LBrace => open.push(i),
+            Token::RBrace if open.pop().is_none() => {
+                errors.push(ParseError::unbalanced(i, "Unmatched '}'"));
+            }
+            _ => {}
+        }
+    }
+    while let Some(i) = open.pop() {
+        errors.push(ParseError::unbalanced(i, "Unclosed '{'"));
+    }
+    errors
+}
+
+/// Refuse input nested more than `limit` deep in groups, optional arguments
+/// and environments, before parsing it recursively could overflow the stack.
+/// Expects braces and environments to be balanced.
+fn check_nesting(tokens: &[Token], limit: usize) -> Result<(), ParseError> {
+    let mut open: Vec<&Token> = Vec::new();
+    for (i, tok) in tokens.iter().enumerate() {
+        match tok {
+            Token::LBrace => open.push(tok),
+            // Brackets only open a group as a command's optional argument.
+            Token::LBracket
+                if i > 0
+                    && matches!(
+                        tokens[i - 1],
+                        Token::Command(_) | Token::StarredCommand(_) | Token::RBracket
+                    ) =>
+            {
+                open.push(tok)
+            }
+            Token::Command(name) if name == "begin" => open.push(tok),
+            Token::RBrace | Token::RBracket | Token::Command(_) => {
+                let opener = match tok {
+                    Token::RBrace => Token::LBrace,
+                    Token::RBracket => Token::LBracket,
+                    Token::Command(name) if name == "end" => Token::Command("begin".into()),
+                    _ => continue,
+                };
+                if open.last() == Some(&&opener) {
+                    open.pop();
+                }
+            }
+            _ => {}
+        }
+        if open.len() > limit {
+            return Err(ParseError {
+                kind: ParseErrorKind::TooDeep(limit),
+                ..ParseError::new(i, format!("Nested more than {} deep", limit))
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Commands whose first argument is a name, such as a color, a file or a
+/// label key, rather than text to set.
+const NAME_COMMANDS: &[&str] = &[
+    "textcolor",
+    "includegraphics",
+    "input",
+    "include",
+    "label",
+    "ref",
+    "pageref",
+];
+
+/// Commands that take `[...]` optional arguments. After any other command a
+/// `[` is text, as TeX would set it.
+const OPTION_COMMANDS: &[&str] = &[
+    "documentclass",
+    "usepackage",
+    "section",
+    "subsection",
+    "item",
+    "includegraphics",
+];
+
+/// The byte offset of the first bare `&`, `#` or `_` that TeX would refuse,
+/// and a message naming the escape that sets it: `&` outside a table, `#`
+/// outside a name argument, `_` outside math and name arguments.
+fn stray_special(input: &str, tokens: &[SpannedToken]) -> Option<(usize, String)> {
+    let mut in_math = false;
+    // The end of the name argument being read, as a token index.
+    let mut name_until = None;
+    for (i, (tok, start, end)) in tokens.iter().enumerate() {
+        match tok {
+            Token::Dollar => in_math = !in_math,
+            Token::Command(name) if NAME_COMMANDS.contains(&name.as_str()) => {
+                name_until = name_argument(tokens, i + 1);
+            }
+            // The lexer leaves `&` a token of its own only inside tables.
+            Token::Text(_) => {
+                let in_name = name_until.is_some_and(|until| i < until);
+                let text = &input[*start..*end];
+                for (at, c) in text.char_indices() {
+                    if text[..at].ends_with('\\') {
+                        continue;
+                    }
+                    let message = match c {
+                        '&' => "Stray `&` outside a table; write `\\&` for an ampersand",
+                        '#' if !in_name => "Stray `#`; write `\\#` for a hash sign",
+                        '_' if !in_name && !in_math => {
+                            "Stray `_` outside math; write `\\_` for an underscore"
+                        }
+                        _ => continue,
+                    };
+                    return Some((start + at, message.into()));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// `tokens` with the brackets that do not delimit an optional argument made
+/// text, part of the word they touch, so that `see [1] here` sets `[1]` as
+/// one word, as it would without brackets being tokens at all.
+///
+/// A `[` opens an argument right after `\\`, after one of the
+/// [`OPTION_COMMANDS`], or after the `]` of such a command's argument before
+/// it; the next `]` at the same brace depth closes it, as the parser reads
+/// them.
+fn bare_brackets_as_text(tokens: Vec<SpannedToken>) -> Vec<SpannedToken> {
+    let mut out: Vec<SpannedToken> = Vec::with_capacity(tokens.len());
+    // Whether the last token out is text that a bare bracket is part of.
+    let mut joinable = false;
+    // The brace depth of each argument bracket still open, and whether
+    // another argument may follow it, as a command's may.
+    let mut open: Vec<(usize, bool)> = Vec::new();
+    let mut depth: usize = 0;
+    let mut after_argument = false;
+    for (tok, start, end) in tokens {
+        let follows_command = after_argument
+            || matches!(
+                out.last(),
+                Some((Token::Command(name) | Token::StarredCommand(name), _, _))
+                    if OPTION_COMMANDS.contains(&name.as_str())
+            );
+        let follows_break = matches!(out.last(), Some((Token::LineBreak(_), _, _)));
+        after_argument = false;
+        let bare = match tok {
+            Token::LBrace => {
+                depth += 1;
+                None
+            }
+            Token::RBrace => {
+                depth = depth.saturating_sub(1);
+                None
+            }
+            Token::LBracket if follows_command || follows_break => {
+                open.push((depth, follows_command));
+                None
+            }
+            Token::RBracket if open.last().is_some_and(|&(d, _)| d == depth) => {
+                after_argument = open.pop().is_some_and(|(_, chains)| chains);
+                None
+            }
+            Token::LBracket => Some("["),
+            Token::RBracket => Some("]"),
+            _ => None,
+        };
+        let (tok, bracket) = match bare {
+            Some(bracket) => (Token::Text(bracket.into()), true),
+            None => (tok, false),
+        };
+        if let (Token::Text(text), Some((Token::Text(last), _, last_end))) = (&tok, out.last_mut())
+            && *last_end == start
+            && (bracket || joinable)
+        {
+            last.push_str(text);
+            *last_end = end;
+            joinable = true;
+            continue;
+        }
+        joinable = bracket;
+        out.push((tok, start, end));
     }
+    out
 }
 
-fn parse_sequence(tokens: &[Token], mut pos: usize) -> Result<(Node, usize), String> {
+/// The index just past the brace argument of a command whose optional
+/// arguments start at `pos`, if it has one.
+fn name_argument(tokens: &[SpannedToken], mut pos: usize) -> Option<usize> {
+    while tokens
+        .get(pos)
+        .is_some_and(|(t, _, _)| *t == Token::LBracket)
+    {
+        pos += tokens[pos..]
+            .iter()
+            .position(|(t, _, _)| *t == Token::RBracket)?
+            + 1;
+    }
+    if tokens.get(pos)?.0 != Token::LBrace {
+        return None;
+    }
+    let mut depth: usize = 0;
+    for (i, (tok, _, _)) in tokens.iter().enumerate().skip(pos) {
+        match tok {
+            Token::LBrace => depth += 1,
+            Token::RBrace => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            return Some(i + 1);
+        }
+    }
+    None
+}
+
+fn parse_sequence(tokens: &[Token], pos: usize) -> Result<(Node, usize), ParseError> {
+    let (children, pos) = parse_until(tokens, pos, |_| false)?;
+    Ok((Node::Seq(children), pos))
+}
+
+/// Match every `\end{name}` with the innermost open `\begin`, so that a
+/// mismatch names both environments. `position` gives the line and column
+/// of a token.
+fn check_environments(
+    tokens: &[Token],
+    position: impl Fn(usize) -> (usize, usize),
+) -> Result<(), ParseError> {
+    environment_errors(tokens, position)
+        .into_iter()
+        .next()
+        .map_or(Ok(()), Err)
+}
+
+/// Every mismatched or stray `\end` in source order, then every unclosed
+/// `\begin`, innermost first. A mismatched `\end` still closes the
+/// innermost environment.
+fn environment_errors(
+    tokens: &[Token],
+    position: impl Fn(usize) -> (usize, usize),
+) -> Vec<ParseError> {
+    let mut errors = Vec::new();
+    let mut open: Vec<(String, usize)> = Vec::new();
+    for (i, tok) in tokens.iter().enumerate() {
+        let Token::Command(cmd) = tok else { continue };
+        // A malformed name is left for the parser to report.
+        let Ok((name, _)) = parse_env_name(tokens, i + 1) else {
+            continue;
+        };
+        match cmd.as_str() {
+            "begin" => open.push((name, i)),
+            "end" => match open.pop() {
+                Some((expected, _)) if expected == name => {}
+                Some((expected, at)) => {
+                    let (line, column) = position(at);
+                    errors.push(ParseError::new(
+                        i,
+                        format!(
+                            "Expected \\end{{{}}} for the \\begin at {}:{}, found \\end{{{}}}",
+                            expected, line, column, name
+                        ),
+                    ));
+                }
+                None => errors.push(ParseError::new(
+                    i,
+                    format!("\\end{{{}}} without a matching \\begin", name),
+                )),
+            },
+            _ => {}
+        }
+    }
+    while let Some((name, at)) = open.pop() {
+        errors.push(ParseError::new(
+            at,
+            format!("Missing \\end{{{}}} for this environment", name),
+        ));
+    }
+    errors
+}
+
+/// Parse nodes until a closing brace, an `\end`, or a token matching `stop`.
+fn parse_until(
+    tokens: &[Token],
+    mut pos: usize,
+    stop: fn(&Token) -> bool,
+) -> Result<(Vec<Node>, usize), ParseError> {
     let mut children = Vec::new();
-    while pos < tokens.len() {
-        match &tokens[pos] {
+    while let Some(tok) = tokens.get(pos) {
+        match tok {
             Token::RBrace => break,
+            Token::Command(name) if name == "end" => break,
+            t if stop(t) => break,
             _ => {
                 let (node, new_pos) = parse_node(tokens, pos)?;
                 children.push(node);
@@ -26,52 +457,445 @@ fn parse_sequence(tokens: &[Token], mut pos: usize) -> Result<(Node, usize), Str
             }
         }
     }
-    Ok((Node::Seq(children), pos))
+    Ok((children, pos))
+}
+
+/// The token at `pos`, or an error if the input ends before it.
+fn token_at(tokens: &[Token], pos: usize) -> Result<&Token, ParseError> {
+    tokens
+        .get(pos)
+        .ok_or_else(|| ParseError::new(pos, "Unexpected end of input"))
 }
 
-fn parse_node(tokens: &[Token], pos: usize) -> Result<(Node, usize), String> {
-    match &tokens[pos] {
+fn parse_node(tokens: &[Token], pos: usize) -> Result<(Node, usize), ParseError> {
+    match token_at(tokens, pos)? {
         // Token::Whitespace(n) => {
         //     // emit a Whitespace node, consuming 1 token
         //     Ok((Node::Whitespace(*n), pos + 1))
         // }
         Token::Text(text) => Ok((Node::Text(text.clone()), pos + 1)),
-        Token::Command(name) => {
-            // Expect a brace-argument next
-            let mut args = Vec::new();
-            let mut cur = pos + 1;
-            if cur < tokens.len() && tokens[cur] == Token::LBrace {
-                // parse group as single arg
-                let (arg_node, new_pos) = parse_group(tokens, cur)?;
-                args.push(arg_node);
-                cur = new_pos;
-            }
+        Token::Ampersand => Ok((Node::Text("&".into()), pos + 1)),
+        // Brackets only delimit arguments right after a command name.
+        Token::LBracket => Ok((Node::Text("[".into()), pos + 1)),
+        Token::RBracket => Ok((Node::Text("]".into()), pos + 1)),
+        Token::LineBreak(no_page_break) => {
+            let (space, cur) = parse_break_space(tokens, pos + 1)?;
             Ok((
-                Node::Macro {
-                    name: name.clone(),
-                    args,
+                Node::LineBreak {
+                    space,
+                    no_page_break: *no_page_break,
                 },
                 cur,
             ))
         }
+        Token::ParBreak => Ok((Node::ParBreak, pos + 1)),
+        Token::Tie => Ok((Node::NonBreakingSpace, pos + 1)),
+        Token::DiscretionaryHyphen => Ok((Node::DiscretionaryHyphen, pos + 1)),
+        Token::Comment(text) => Ok((Node::Comment(text.clone()), pos + 1)),
+        Token::Dollar => parse_math(tokens, pos),
+        Token::Caret => {
+            let (arg, cur) = parse_script_arg(tokens, pos, '^')?;
+            Ok((Node::Superscript(Box::new(arg)), cur))
+        }
+        Token::Underscore => {
+            let (arg, cur) = parse_script_arg(tokens, pos, '_')?;
+            Ok((Node::Subscript(Box::new(arg)), cur))
+        }
+        Token::Command(name) if name == "begin" => parse_environment(tokens, pos),
+        Token::Command(name) if name == "par" => Ok((Node::ParBreak, pos + 1)),
+        Token::Command(name) => parse_command(tokens, pos, name, false),
+        Token::StarredCommand(name) => parse_command(tokens, pos, name, true),
         Token::LBrace => parse_group(tokens, pos),
-        unexpected => Err(format!("Unexpected token at {}: {:?}", pos, unexpected)),
+        // The lexer makes nothing else of a backslash followed by no
+        // command name, as at the very end of the input.
+        Token::Error => Err(ParseError::new(
+            pos,
+            "A `\\` must start a command; write `\\textbackslash` for a backslash",
+        )),
+        unexpected => Err(ParseError::new(
+            pos,
+            format!("Unexpected token {:?}", unexpected),
+        )),
+    }
+}
+
+/// Parse the command `name` at `pos` with its optional and brace arguments.
+///
+/// A command may go without the arguments it takes when something else
+/// follows it, but a built-in one that needs them may not end the input.
+///
+/// Kept out of [`parse_node`], which every nested group recurses through,
+/// so that deep nesting takes less stack.
+fn parse_command(
+    tokens: &[Token],
+    pos: usize,
+    name: &str,
+    starred: bool,
+) -> Result<(Node, usize), ParseError> {
+    // Optional bracket arguments come first
+    let mut opt_args = Vec::new();
+    let mut cur = pos + 1;
+    while tokens.get(cur) == Some(&Token::LBracket) {
+        let (opt, new_pos) = parse_bracket_group(tokens, cur)?;
+        opt_args.push(opt);
+        cur = new_pos;
+    }
+    // Expect brace-arguments next, up to the command's arity
+    let mut args = Vec::new();
+    while args.len() < arity(name) && tokens.get(cur) == Some(&Token::LBrace) {
+        // parse group as single arg
+        let (arg_node, new_pos) = parse_group(tokens, cur)?;
+        args.push(arg_node);
+        cur = new_pos;
     }
+    if args.len() < arity(name) && cur == tokens.len() && takes_argument(name) {
+        return Err(ParseError::new(
+            pos,
+            format!(
+                "The input ends where `\\{}` expects an argument; give it one in braces",
+                name
+            ),
+        ));
+    }
+    Ok((
+        Node::Macro {
+            name: name.to_string(),
+            opt_args,
+            args,
+            starred,
+        },
+        cur,
+    ))
 }
 
-fn parse_group(tokens: &[Token], pos: usize) -> Result<(Node, usize), String> {
-    // tokens[pos] should be LBrace
-    if tokens[pos] != Token::LBrace {
-        return Err(format!("Expected '{{' at {}, found {:?}", pos, tokens[pos]));
+/// Parse `$...$` starting at the opening `$`.
+fn parse_math(tokens: &[Token], pos: usize) -> Result<(Node, usize), ParseError> {
+    let (body, cur) = parse_until(tokens, pos + 1, |t| *t == Token::Dollar)?;
+    if tokens.get(cur) == Some(&Token::Dollar) {
+        Ok((Node::Math(body), cur + 1))
+    } else {
+        Err(ParseError::new(pos, "Unclosed '$'"))
+    }
+}
+
+/// Parse the argument of the `^` or `_` (`marker`) at `pos`: a brace group
+/// or a single token.
+fn parse_script_arg(
+    tokens: &[Token],
+    pos: usize,
+    marker: char,
+) -> Result<(Node, usize), ParseError> {
+    match tokens.get(pos + 1) {
+        Some(Token::LBrace | Token::Text(_) | Token::Command(_) | Token::StarredCommand(_)) => {
+            parse_node(tokens, pos + 1)
+        }
+        _ => Err(ParseError::new(
+            pos,
+            format!("Missing argument after `{}`", marker),
+        )),
+    }
+}
+
+/// Parse the optional `[length]` directly after a `\\`.
+fn parse_break_space(tokens: &[Token], pos: usize) -> Result<(Option<Length>, usize), ParseError> {
+    if tokens.get(pos) != Some(&Token::LBracket) {
+        return Ok((None, pos));
+    }
+    match (tokens.get(pos + 1), tokens.get(pos + 2)) {
+        (Some(Token::Text(spec)), Some(Token::RBracket)) => match Length::parse(spec) {
+            Some(length) => Ok((Some(length), pos + 3)),
+            None => Err(ParseError::new(
+                pos + 1,
+                format!("Invalid length `{}` after \\\\", spec),
+            )),
+        },
+        _ => Err(ParseError::new(pos, "Expected a length in `\\\\[...]`")),
+    }
+}
+
+/// How many brace groups a command takes; any further groups are ordinary groups.
+fn arity(name: &str) -> usize {
+    match name {
+        "textcolor" | "rule" | "frac" => 2,
+        // Declarations affect what follows them rather than taking an argument.
+        "bfseries" | "itshape" | "normalfont" | "centering" | "raggedright" | "raggedleft" => 0,
+        "tiny" | "scriptsize" | "footnotesize" | "small" | "normalsize" | "large" | "Large"
+        | "LARGE" | "huge" | "Huge" => 0,
+        // As are rules, spaces, logos, the date and the title block, which
+        // stand alone.
+        "hrule" | "hline" | "vfill" | "noindent" | "TeX" | "LaTeX" | "today" | "maketitle" => 0,
+        "," | ";" | "!" | "quad" | "qquad" => 0,
+        _ => 1,
+    }
+}
+
+fn parse_group(tokens: &[Token], pos: usize) -> Result<(Node, usize), ParseError> {
+    let tok = token_at(tokens, pos)?;
+    if *tok != Token::LBrace {
+        return Err(ParseError::new(
+            pos,
+            format!("Expected '{{', found {:?}", tok),
+        ));
     }
     // Parse inner sequence starting after LBrace
-    let (inner, mut cur) = parse_sequence(tokens, pos + 1)?;
+    let (inner, cur) = parse_sequence(tokens, pos + 1)?;
     // Expect RBrace
-    if cur < tokens.len() && tokens[cur] == Token::RBrace {
+    if tokens.get(cur) == Some(&Token::RBrace) {
         Ok((inner, cur + 1))
     } else {
-        Err(format!("Unclosed '{{' at {}", pos))
+        Err(ParseError::unbalanced(pos, "Unclosed '{'"))
+    }
+}
+
+/// Parse a `[...]` optional argument starting at the `[` token.
+fn parse_bracket_group(tokens: &[Token], pos: usize) -> Result<(Node, usize), ParseError> {
+    let (inner, cur) = parse_until(tokens, pos + 1, |t| *t == Token::RBracket)?;
+    if tokens.get(cur) == Some(&Token::RBracket) {
+        Ok((Node::Seq(inner), cur + 1))
+    } else {
+        Err(ParseError::new(pos, "Unclosed '['"))
+    }
+}
+
+/// Parse `\begin{name} ... \end{name}` starting at the `\begin` token.
+fn parse_environment(tokens: &[Token], pos: usize) -> Result<(Node, usize), ParseError> {
+    let (name, mut cur) = parse_env_name(tokens, pos + 1)?;
+    let list = match name.as_str() {
+        "itemize" => Some(ListKind::Itemize),
+        "enumerate" => Some(ListKind::Enumerate),
+        "description" => Some(ListKind::Description),
+        _ => None,
+    };
+    let node = if let Some(kind) = list {
+        let (items, next) = parse_list_items(tokens, cur)?;
+        cur = next;
+        Node::List { kind, items }
+    } else if let Some(Token::Verbatim(text)) = tokens.get(cur) {
+        // Only ever lexed right after `\begin{verbatim}`.
+        cur += 1;
+        Node::Verbatim(text.clone())
+    } else if name == "tabular" || name == "tabular*" {
+        let mut spec = TableSpec::default();
+        if name == "tabular*" {
+            let (group, next) = parse_group(tokens, cur)?;
+            spec.width = Some(table_width(&group).ok_or_else(|| {
+                ParseError::new(
+                    cur,
+                    "Expected a width such as `10cm` or `0.8\\textwidth` for tabular*",
+                )
+            })?);
+            cur = next;
+        }
+        if tokens.get(cur) == Some(&Token::LBrace) {
+            let (group, next) = parse_group(tokens, cur)?;
+            spec.rules.push(false);
+            column_spec(&group, &mut spec).map_err(|message| ParseError::new(cur, message))?;
+            cur = next;
+        }
+        let (rows, hlines, next) = parse_tabular_rows(tokens, cur)?;
+        cur = next;
+        Node::Table {
+            spec: Box::new(spec),
+            rows,
+            hlines,
+        }
+    } else {
+        let (body, next) = parse_until(tokens, cur, |_| false)?;
+        cur = next;
+        Node::Environment {
+            name: name.clone(),
+            body,
+        }
+    };
+
+    if tokens.get(cur) != Some(&Token::Command("end".into())) {
+        return Err(ParseError::new(
+            pos,
+            format!("Missing \\end{{{}}} for this environment", name),
+        ));
+    }
+    let (end_name, cur) = parse_env_name(tokens, cur + 1)?;
+    if end_name != name {
+        return Err(ParseError::new(
+            cur - 3,
+            format!("\\begin{{{}}} ended by \\end{{{}}}", name, end_name),
+        ));
+    }
+    Ok((node, cur))
+}
+
+/// Parse the body of a list environment: a sequence of `\item`s, each
+/// perhaps labelled as `\item[label]`.
+fn parse_list_items(tokens: &[Token], pos: usize) -> Result<(Vec<ListItem>, usize), ParseError> {
+    fn is_item(t: &Token) -> bool {
+        matches!(t, Token::Command(name) if name == "item")
+    }
+
+    let (lead, mut cur) = parse_until(tokens, pos, is_item)?;
+    if !lead.is_empty() {
+        return Err(ParseError::new(
+            pos,
+            "Expected \\item at the start of a list",
+        ));
+    }
+    let mut items = Vec::new();
+    while tokens.get(cur).is_some_and(is_item) {
+        cur += 1;
+        let mut label = None;
+        if tokens.get(cur) == Some(&Token::LBracket) {
+            let (group, next) = parse_bracket_group(tokens, cur)?;
+            if let Node::Seq(nodes) = group {
+                label = Some(nodes);
+            }
+            cur = next;
+        }
+        let (body, next) = parse_until(tokens, cur, is_item)?;
+        items.push(ListItem { label, body });
+        cur = next;
+    }
+    Ok((items, cur))
+}
+
+/// Parse the `{name}` group following `\begin` or `\end`.
+fn parse_env_name(tokens: &[Token], pos: usize) -> Result<(String, usize), ParseError> {
+    match (tokens.get(pos), tokens.get(pos + 1), tokens.get(pos + 2)) {
+        (Some(Token::LBrace), Some(Token::Text(name)), Some(Token::RBrace)) => {
+            Ok((name.clone(), pos + 3))
+        }
+        _ => Err(ParseError::new(pos, "Expected environment name")),
+    }
+}
+
+/// The width of a `tabular*`, written as a length or as a fraction of
+/// `\textwidth` (or `\linewidth`).
+fn table_width(group: &Node) -> Option<TableWidth> {
+    let Node::Seq(nodes) = group else {
+        return None;
+    };
+    match nodes.as_slice() {
+        [Node::Text(spec)] => Length::parse(spec).map(TableWidth::Fixed),
+        [Node::Macro { name, args, .. }] if args.is_empty() => text_width(name, 1.0),
+        [Node::Text(factor), Node::Macro { name, args, .. }] if args.is_empty() => {
+            text_width(name, factor.trim().parse().ok()?)
+        }
+        _ => None,
+    }
+}
+
+/// `fraction` of the text width, if `name` is a command standing for it.
+fn text_width(name: &str, fraction: f64) -> Option<TableWidth> {
+    matches!(name, "textwidth" | "linewidth").then_some(TableWidth::Text(fraction))
+}
+
+/// Read the column specifier `group` of a `tabular` into `columns`, whose
+/// `rules` already has the entry for the rules before the first column.
+///
+/// `l`, `c` and `r` set a column's alignment, and `|` a vertical rule.
+/// Paragraph columns such as `p{3cm}` are set as `l`, `*{3}{c}` repeats a
+/// specifier, and `@{...}`, `!{...}`, `>{...}` and `<{...}` are skipped.
+fn column_spec(group: &Node, columns: &mut TableSpec) -> Result<(), String> {
+    // The specifier read one character or group at a time.
+    enum Item<'a> {
+        Char(char),
+        Group(&'a Node),
+        Other,
+    }
+    fn argument<'a>(
+        items: &mut impl Iterator<Item = Item<'a>>,
+        after: char,
+    ) -> Result<&'a Node, String> {
+        match items.next() {
+            Some(Item::Group(group)) => Ok(group),
+            _ => Err(format!(
+                "Expected a {{...}} argument after `{}` in the column specifier",
+                after
+            )),
+        }
+    }
+    fn push(columns: &mut TableSpec, align: ColumnAlign) {
+        columns.align.push(align);
+        columns.rules.push(false);
+    }
+    let Node::Seq(nodes) = group else {
+        return Err("Expected a column specifier in braces".into());
+    };
+    let mut items = nodes.iter().flat_map(|node| match node {
+        Node::Text(text) => text.chars().map(Item::Char).collect(),
+        Node::Seq(_) => vec![Item::Group(node)],
+        _ => vec![Item::Other],
+    });
+    while let Some(item) = items.next() {
+        match item {
+            Item::Char('l') => push(columns, ColumnAlign::Left),
+            Item::Char('c') => push(columns, ColumnAlign::Center),
+            Item::Char('r') => push(columns, ColumnAlign::Right),
+            Item::Char('|') => {
+                if let Some(rule) = columns.rules.last_mut() {
+                    *rule = true;
+                }
+            }
+            Item::Char(c @ ('p' | 'm' | 'b')) => {
+                argument(&mut items, c)?;
+                push(columns, ColumnAlign::Left);
+            }
+            Item::Char(c @ ('@' | '!' | '>' | '<')) => {
+                argument(&mut items, c)?;
+            }
+            Item::Char('*') => {
+                let count = crate::expand::collect_plain_text(argument(&mut items, '*')?);
+                let count: usize = count
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Expected a count in `*{{{}}}`", count))?;
+                let spec = argument(&mut items, '*')?;
+                for _ in 0..count {
+                    column_spec(spec, columns)?;
+                }
+            }
+            Item::Char(c) if c.is_whitespace() => {}
+            Item::Char(c) => return Err(format!("Unknown column specifier `{}`", c)),
+            Item::Group(_) | Item::Other => {
+                return Err("Unexpected argument in the column specifier".into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse the body of a `tabular`: cells split by `&`, rows split by `\\`,
+/// and the rows each `\hline` before a row's first cell is drawn above.
+fn parse_tabular_rows(
+    tokens: &[Token],
+    mut pos: usize,
+) -> Result<(Vec<TableRow>, Vec<usize>, usize), ParseError> {
+    let mut rows = Vec::new();
+    let mut hlines = Vec::new();
+    let mut row = Vec::new();
+    loop {
+        while row.is_empty() && tokens.get(pos) == Some(&Token::Command("hline".into())) {
+            hlines.push(rows.len());
+            pos += 1;
+        }
+        let (cell, next) = parse_until(tokens, pos, |t| {
+            matches!(t, Token::Ampersand | Token::LineBreak(_))
+        })?;
+        row.push(cell);
+        pos = next;
+        match tokens.get(pos) {
+            Some(Token::Ampersand) => pos += 1,
+            Some(Token::LineBreak(_)) => {
+                rows.push(std::mem::take(&mut row));
+                // Row spacing is not supported; the length is only checked.
+                pos = parse_break_space(tokens, pos + 1)?.1;
+            }
+            _ => break,
+        }
     }
+    // A trailing `\\` leaves one empty cell behind, which is not a row.
+    if !(row.len() == 1 && row[0].is_empty()) {
+        rows.push(row);
+    }
+    Ok((rows, hlines, pos))
 }
 
 #[cfg(test)]
@@ -79,7 +903,10 @@ mod tests {
     // Bring your parse() function into scope:
     use crate::parser::parse;
     // Bring your AST Node enum into scope:
-    use crate::ast::Node;
+    use crate::ast::{ColumnAlign, ListItem, ListKind, Node, TableSpec, TableWidth};
+    use crate::error::CompileError;
+    use crate::options::SpecialChars;
+    use crate::units::Length;
 
     #[test]
     fn test_simple_text() {
@@ -106,8 +933,396 @@ mod tests {
             ast,
             Node::Seq(vec![Node::Macro {
                 name: "textbf".into(),
+                opt_args: vec![],
                 args: vec![Node::Seq(vec![Node::Text("Bold".into())])],
+                starred: false,
+            }])
+        );
+    }
+
+    #[test]
+    fn test_optional_argument() {
+        let ast = parse(r"\section[Short]{Long Title}").unwrap();
+        assert_eq!(
+            ast,
+            Node::Seq(vec![Node::Macro {
+                name: "section".into(),
+                opt_args: vec![Node::Seq(vec![Node::Text("Short".into())])],
+                args: vec![Node::Seq(vec![
+                    Node::Text("Long".into()),
+                    Node::Text("Title".into()),
+                ])],
+                starred: false,
+            }])
+        );
+    }
+
+    #[test]
+    fn test_logo_takes_no_argument() {
+        assert_eq!(
+            parse(r"\LaTeX{} and \TeX").unwrap(),
+            Node::Seq(vec![
+                Node::r#macro("LaTeX", vec![]),
+                Node::Seq(vec![]),
+                Node::Text("and".into()),
+                Node::r#macro("TeX", vec![]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_bare_brackets_are_text() {
+        let words = |src: &str| {
+            let pages = crate::compile_to_pages(src, &crate::CompileOptions::default()).unwrap();
+            pages[0]
+                .lines
+                .iter()
+                .flat_map(|line| &line.boxes)
+                .flat_map(|hb| &hb.items)
+                .filter_map(|item| match item {
+                    crate::LayoutNode::Run(run) => Some(run.text.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(words("see [1] here"), ["see", "[1]", "here"]);
+        assert_eq!(words(r"\textbf{x}[1]"), ["x", "[1]"]);
+        assert_eq!(words("a[b c]d ] [ e"), ["a[b", "c]d", "]", "[", "e"]);
+        // Right after a command that takes options, they still delimit one.
+        assert_eq!(words(r"a\\[2pt][b]"), ["a", "[b]"]);
+        // After any other command they are text, kept whole.
+        assert_eq!(words(r"\LaTeX [1] ok"), ["LaTeX", "[1]", "ok"]);
+        assert_eq!(words(r"\noindent [x] text"), ["[x]", "text"]);
+        assert_eq!(words(r"\par [1] x \today [2]")[..2], ["[1]", "x"]);
+        assert_eq!(
+            words(r"\quad [a]b \maketitle [draft]").last().unwrap(),
+            "[draft]"
+        );
+        assert_eq!(
+            parse(r"\LaTeX [1]").unwrap(),
+            Node::Seq(vec![
+                Node::r#macro("LaTeX", vec![]),
+                Node::Text("[1]".into())
+            ])
+        );
+        assert_eq!(parse("a [b]").unwrap().to_tex(), "a [b]");
+    }
+
+    #[test]
+    fn test_tabular() {
+        let ast = parse(r"\begin{tabular}{ll} a & b \\ c & d \\ \end{tabular}").unwrap();
+        let cell = |s: &str| vec![Node::Text(s.into())];
+        assert_eq!(
+            ast,
+            Node::Seq(vec![Node::Table {
+                spec: Box::new(TableSpec {
+                    align: vec![ColumnAlign::Left; 2],
+                    rules: vec![false; 3],
+                    width: None,
+                }),
+                rows: vec![vec![cell("a"), cell("b")], vec![cell("c"), cell("d")]],
+                hlines: Vec::new(),
+            }])
+        );
+    }
+
+    #[test]
+    fn test_tabular_columns_and_rules() {
+        let ast = parse(
+            r"\begin{tabular*}{0.5\textwidth}{|l|*{2}{c}@{ }p{2cm}r|} \hline a \\ \hline\hline b \\ \hline \end{tabular*}",
+        )
+        .unwrap();
+        let Node::Seq(nodes) = ast else {
+            panic!("Expected a sequence")
+        };
+        let [Node::Table { spec, rows, hlines }] = nodes.as_slice() else {
+            panic!("Expected a table, got {:?}", nodes)
+        };
+        use ColumnAlign::*;
+        assert_eq!(spec.align, [Left, Center, Center, Left, Right]);
+        assert_eq!(spec.rules, [true, true, false, false, false, true]);
+        assert_eq!(spec.width, Some(TableWidth::Text(0.5)));
+        assert_eq!(rows.len(), 2);
+        assert_eq!(hlines, &[0, 1, 1, 2]);
+
+        for (src, message) in [
+            (
+                r"\begin{tabular}{lx} a \end{tabular}",
+                "Unknown column specifier `x`",
+            ),
+            (r"\begin{tabular}{lp} a \end{tabular}", "argument after `p`"),
+            (
+                r"\begin{tabular*}{wide}{l} a \end{tabular*}",
+                "Expected a width",
+            ),
+        ] {
+            let err = parse(src).unwrap_err();
+            assert!(err.to_string().contains(message), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_itemize() {
+        let ast = parse(r"\begin{enumerate} \item One \item Two words \end{enumerate}").unwrap();
+        assert_eq!(
+            ast,
+            Node::Seq(vec![Node::List {
+                kind: ListKind::Enumerate,
+                items: vec![
+                    ListItem {
+                        label: None,
+                        body: vec![Node::Text("One".into())],
+                    },
+                    ListItem {
+                        label: None,
+                        body: vec![Node::Text("Two".into()), Node::Text("words".into())],
+                    },
+                ],
             }])
         );
     }
+
+    #[test]
+    fn test_items_take_an_optional_label() {
+        let ast =
+            parse(r"\begin{description} \item[Apple] A fruit \item[] b \item c \end{description}")
+                .unwrap();
+        let Node::Seq(nodes) = ast else {
+            unreachable!()
+        };
+        let Node::List { kind, items } = &nodes[0] else {
+            panic!("Expected a list, got {:?}", nodes[0]);
+        };
+        assert_eq!(*kind, ListKind::Description);
+        let labels: Vec<_> = items.iter().map(|item| item.label.as_deref()).collect();
+        assert_eq!(
+            labels,
+            [Some(&[Node::text("Apple")][..]), Some(&[][..]), None]
+        );
+        assert_eq!(items[0].body, [Node::text("A"), Node::text("fruit")]);
+        // A bracket later in the item is its text.
+        let ast = parse(r"\begin{itemize} \item a [b] \end{itemize}").unwrap();
+        assert_eq!(ast.to_tex(), r"\begin{itemize} \item a [b] \end{itemize}");
+        assert!(parse(r"\begin{description} \item[Apple A fruit \end{description}").is_err());
+    }
+
+    #[test]
+    fn test_environment_mismatch() {
+        assert!(parse(r"\begin{center} x \end{flushright}").is_err());
+    }
+
+    #[test]
+    fn test_nested_environments() {
+        let ast =
+            parse(r"\begin{center} \begin{itemize} \item x \end{itemize} \end{center}").unwrap();
+        assert!(matches!(
+            ast,
+            Node::Seq(ref nodes) if matches!(&nodes[0], Node::Environment { name, .. } if name == "center")
+        ));
+    }
+
+    #[test]
+    fn test_mismatched_end_names_both_environments() {
+        let err =
+            parse("\\begin{center}\n\\begin{itemize} \\item x\n\\end{center}\n\\end{itemize}")
+                .unwrap_err();
+        let pos = err.pos().unwrap();
+        assert_eq!((pos.line, pos.column), (3, 1));
+        assert!(
+            err.to_string().starts_with(
+                "3:1: Expected \\end{itemize} for the \\begin at 2:1, found \\end{center}"
+            ),
+            "{}",
+            err
+        );
+        assert!(parse(r"x \end{center}").is_err());
+    }
+
+    #[test]
+    fn test_error_reports_line_and_column() {
+        let src = "First line\nsecond {line\nthird line";
+        let err = parse(src).unwrap_err();
+        let pos = err.pos().expect("parse errors carry a position");
+        assert_eq!((pos.line, pos.column), (2, 8));
+        assert_eq!(pos.offset, 18);
+        assert_eq!(
+            err.to_string(),
+            "2:8: Unclosed '{'\n  | second {line\n  |        ^"
+        );
+    }
+
+    #[test]
+    fn test_missing_close_brace() {
+        let err = parse(r"\textbf{bold} \emph{open").unwrap_err();
+        assert!(matches!(err, CompileError::UnbalancedBraces { .. }));
+        let pos = err.pos().unwrap();
+        assert_eq!((pos.line, pos.column), (1, 20));
+    }
+
+    #[test]
+    fn test_input_ending_inside_a_command() {
+        let err = parse("Some \\textbf\n").unwrap_err();
+        assert!(matches!(err, CompileError::Parse { .. }), "{:?}", err);
+        let pos = err.pos().unwrap();
+        assert_eq!((pos.line, pos.column), (1, 6));
+        assert!(err.to_string().contains("`\\textbf` expects an argument"));
+        // Something other than the end may follow a command without its argument.
+        assert!(parse(r"\textbf x").is_ok());
+        // Commands taking no argument may end the input, as may ones the
+        // parser does not know, such as registered macros.
+        assert!(parse(r"x \LaTeX").is_ok());
+        assert!(parse(r"x \version").is_ok());
+
+        for src in [r"\textbf{", "{"] {
+            let err = parse(src).unwrap_err();
+            assert!(
+                matches!(err, CompileError::UnbalancedBraces { .. }),
+                "{:?}",
+                err
+            );
+            assert!(err.to_string().contains("Unclosed '{'"));
+        }
+    }
+
+    #[test]
+    fn test_extra_close_brace() {
+        let err = parse("one {two}\nthree} four").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "2:6: Unmatched '}'\n  | three} four\n  |      ^"
+        );
+        assert!(matches!(err, CompileError::UnbalancedBraces { .. }));
+        // A stray brace inside math is a brace error, not an unclosed `$`.
+        assert!(matches!(
+            parse("$a}$"),
+            Err(CompileError::UnbalancedBraces { .. })
+        ));
+    }
+
+    #[test]
+    fn test_line_break_with_space() {
+        let line_break = |space, no_page_break| Node::LineBreak {
+            space,
+            no_page_break,
+        };
+        assert_eq!(
+            parse(r"a \\[5mm] b \\ c \\*[1mm] d \\* e").unwrap(),
+            Node::Seq(vec![
+                Node::Text("a".into()),
+                line_break(Some(Length::mm(5.0)), false),
+                Node::Text("b".into()),
+                line_break(None, false),
+                Node::Text("c".into()),
+                line_break(Some(Length::mm(1.0)), true),
+                Node::Text("d".into()),
+                line_break(None, true),
+                Node::Text("e".into()),
+            ])
+        );
+        assert!(parse(r"a \\[far] b").is_err());
+    }
+
+    #[test]
+    fn test_par_and_blank_line() {
+        let expected = Node::Seq(vec![
+            Node::Text("a".into()),
+            Node::ParBreak,
+            Node::Text("b".into()),
+        ]);
+        assert_eq!(parse(r"a \par b").unwrap(), expected);
+        assert_eq!(parse("a\n\nb").unwrap(), expected);
+    }
+
+    #[test]
+    fn test_math_scripts() {
+        let ast = parse("$x^2_{ij}$").unwrap();
+        assert_eq!(
+            ast,
+            Node::Seq(vec![Node::Math(vec![
+                Node::Text("x".into()),
+                Node::Superscript(Box::new(Node::Text("2".into()))),
+                Node::Subscript(Box::new(Node::Seq(vec![Node::Text("ij".into())]))),
+            ])])
+        );
+        for (src, expected) in [
+            ("$x^$", "Missing argument after `^`"),
+            ("$x_$ y", "Missing argument after `_`"),
+        ] {
+            match parse(src) {
+                Err(CompileError::Parse { message, .. }) => assert_eq!(message, expected),
+                other => panic!("expected a parse error, got {:?}", other),
+            }
+        }
+        assert!(parse("$x").is_err());
+    }
+
+    #[test]
+    fn test_special_chars_are_literal_by_default() {
+        assert_eq!(
+            parse(r"R&D, C# and snake_case \& \# \_").unwrap(),
+            Node::Seq(
+                ["R&D,", "C#", "and", "snake_case", "&", "#", "_"]
+                    .map(Node::text)
+                    .to_vec()
+            )
+        );
+    }
+
+    #[test]
+    fn test_stray_special_chars_are_rejected_when_asked() {
+        let strict = |src| {
+            super::parse_limited(
+                src,
+                crate::lexer::Comments::Skip,
+                crate::options::MAX_NESTING,
+                SpecialChars::Reject,
+            )
+        };
+        let message = |src| match strict(src) {
+            Err(CompileError::Parse { message, pos, .. }) => (message, pos.column),
+            other => panic!("expected a parse error, got {:?}", other),
+        };
+        assert_eq!(
+            message("Research R&D"),
+            (
+                "Stray `&` outside a table; write `\\&` for an ampersand".into(),
+                11
+            )
+        );
+        assert_eq!(
+            message(r"\# and C#"),
+            ("Stray `#`; write `\\#` for a hash sign".into(), 9)
+        );
+        assert_eq!(
+            message("$x_1$ snake_case"),
+            (
+                "Stray `_` outside math; write `\\_` for an underscore".into(),
+                12
+            )
+        );
+
+        // Escaped, in tables and math, or in names, they are all fine.
+        let fine = r"R\&D \#1 a\_b $x_1$ \begin{tabular}{ll} a & b \end{tabular}
+            \textcolor{#FF0000}{red} \label{sec_intro} \includegraphics[width=1cm]{my_plot.png}";
+        assert!(strict(fine).is_ok(), "{:?}", strict(fine));
+    }
+
+    #[test]
+    fn test_backslashes() {
+        assert_eq!(
+            parse(r"a\textbackslash b").unwrap(),
+            Node::Seq(vec![Node::Text("a\\b".into())])
+        );
+        match parse("Ends in a \\") {
+            Err(CompileError::Parse { message, pos, .. }) => {
+                assert!(message.contains("\\textbackslash"), "{}", message);
+                assert_eq!(pos.column, 11);
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+        assert_eq!(
+            parse("Dr.\\ Smith").unwrap(),
+            Node::Seq(vec![Node::Text("Dr.".into()), Node::Text("Smith".into())])
+        );
+    }
 }
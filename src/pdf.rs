@@ -0,0 +1,175 @@
+//! The PDF file format, written front to back: each object goes out to the
+//! writer as soon as it is made, and the cross-reference table at the end
+//! points back at them all. Objects may refer to ones not yet written by
+//! reserving their numbers first.
+
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use std::fmt::Write as _;
+use std::io::{self, BufWriter, Write};
+
+/// A PDF file being written.
+pub(crate) struct Writer<'w> {
+    out: BufWriter<&'w mut dyn Write>,
+    /// How many bytes have been written so far.
+    len: usize,
+    /// Byte offset of each object, by object number less one; 0 for one
+    /// reserved but not written yet.
+    offsets: Vec<usize>,
+}
+
+impl<'w> Writer<'w> {
+    /// Start a PDF 1.4 file in `out`; marked content needs 1.4.
+    pub(crate) fn new(out: &'w mut dyn Write) -> io::Result<Self> {
+        let mut writer = Writer {
+            out: BufWriter::new(out),
+            len: 0,
+            offsets: Vec::new(),
+        };
+        // The comment of bytes above 127 marks the file as binary.
+        writer.put(b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n")?;
+        Ok(writer)
+    }
+
+    /// A fresh object number, for an object written later.
+    pub(crate) fn reserve(&mut self) -> u32 {
+        self.offsets.push(0);
+        self.offsets.len() as u32
+    }
+
+    /// Write object `id`: a dictionary, or any other value.
+    pub(crate) fn object(&mut self, id: u32, body: &str) -> io::Result<()> {
+        self.begin(id)?;
+        self.put(body.as_bytes())?;
+        self.put(b"\nendobj\n")
+    }
+
+    /// Write stream object `id` holding `data`, with the `entries` of its
+    /// dictionary besides `/Length`.
+    pub(crate) fn stream(&mut self, id: u32, entries: &str, data: &[u8]) -> io::Result<()> {
+        self.begin(id)?;
+        self.put(format!("<</Length {}{}>>stream\n", data.len(), entries).as_bytes())?;
+        self.put(data)?;
+        self.put(b"\nendstream\nendobj\n")
+    }
+
+    /// End the file with its cross-reference table and its trailer, which
+    /// names the catalog `root`, the document information `info` and the
+    /// file's `id`, and flush it.
+    pub(crate) fn finish(mut self, root: u32, info: u32, id: &[u8; 32]) -> io::Result<()> {
+        let xref = self.len;
+        let mut table = format!("xref\n0 {}\n0000000000 65535 f \n", self.offsets.len() + 1);
+        for &offset in &self.offsets {
+            match offset {
+                0 => table.push_str("0000000000 65535 f \n"),
+                _ => {
+                    let _ = writeln!(table, "{:010} 00000 n ", offset);
+                }
+            }
+        }
+        let id = String::from_utf8_lossy(id);
+        let _ = write!(
+            table,
+            "trailer\n<</Size {}/Root {} 0 R/Info {} 0 R/ID[({})({})]>>\nstartxref\n{}\n%%EOF",
+            self.offsets.len() + 1,
+            root,
+            info,
+            id,
+            id,
+            xref
+        );
+        self.put(table.as_bytes())?;
+        self.out.flush()
+    }
+
+    fn begin(&mut self, id: u32) -> io::Result<()> {
+        self.offsets[id as usize - 1] = self.len;
+        self.put(format!("{} 0 obj", id).as_bytes())
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)?;
+        self.len += bytes.len();
+        Ok(())
+    }
+}
+
+/// `data` compressed for a stream with `/Filter/FlateDecode`.
+pub(crate) fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(data)
+        .expect("writing to a Vec cannot fail");
+    encoder.finish().expect("writing to a Vec cannot fail")
+}
+
+/// The text after the entry named `key` in `dict`, e.g. `/Pages`; a longer
+/// name such as `/PageMode` does not match.
+#[cfg(test)]
+fn value<'d>(dict: &'d str, key: &str) -> Option<&'d str> {
+    dict.match_indices(key).find_map(|(at, _)| {
+        let rest = &dict[at + key.len()..];
+        (!rest.starts_with(|c: char| c.is_ascii_alphanumeric())).then_some(rest)
+    })
+}
+
+/// The object an `N 0 R` entry refers to.
+#[cfg(test)]
+pub(crate) fn reference(dict: &str, key: &str) -> Option<u32> {
+    let rest = value(dict, key)?.trim_start();
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+/// The objects an array entry `[N 0 R ...]` refers to.
+#[cfg(test)]
+pub(crate) fn references(dict: &str, key: &str) -> Option<Vec<u32>> {
+    let rest = value(dict, key)?.trim_start().strip_prefix('[')?;
+    let items: Vec<&str> = rest[..rest.find(']')?].split_whitespace().collect();
+    items.chunks(3).map(|r| r[0].parse().ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dictionary_entries() {
+        let catalog = "<</Type/Catalog/PageMode/UseNone/Pages 5 0 R>>";
+        assert_eq!(reference(catalog, "/Pages"), Some(5));
+        assert_eq!(
+            references("<</Count 2/Kids[28 0 R 31 0 R]>>", "/Kids"),
+            Some(vec![28, 31])
+        );
+    }
+
+    #[test]
+    fn test_objects_are_found_through_the_table() {
+        let mut out = Vec::new();
+        let mut pdf = Writer::new(&mut out).unwrap();
+        let (catalog, pages) = (pdf.reserve(), pdf.reserve());
+        let info = pdf.reserve();
+        pdf.object(pages, "<</Type/Pages/Kids[]/Count 0>>").unwrap();
+        pdf.object(catalog, &format!("<</Type/Catalog/Pages {} 0 R>>", pages))
+            .unwrap();
+        pdf.stream(info, "/Note 1", b"data").unwrap();
+        pdf.finish(catalog, info, b"00000000000000000000000000000000")
+            .unwrap();
+
+        // Offsets count the bytes of the binary comment, which are not text.
+        let at = |what: &str| out.windows(what.len()).rposition(|w| w == what.as_bytes());
+        let xref = at("\nxref\n").unwrap() + 1;
+        let text = std::str::from_utf8(&out[xref..]).unwrap();
+        assert!(text.ends_with(&format!("startxref\n{}\n%%EOF", xref)));
+        let entries: Vec<&str> = text.lines().skip(2).take(4).collect();
+        assert_eq!(entries[0], "0000000000 65535 f ");
+        // Each entry is the offset of its object, written in any order.
+        for (id, entry) in (1..).zip(&entries[1..]) {
+            let offset: usize = entry[..10].parse().unwrap();
+            assert_eq!(at(&format!("{} 0 obj<<", id)), Some(offset));
+        }
+        assert!(at("<</Length 4/Note 1>>stream\ndata\nendstream").is_some());
+    }
+}
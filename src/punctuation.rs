@@ -0,0 +1,90 @@
+//! TeX's ASCII spellings of typographic punctuation: ``` `` ``` and `''` for
+//! double quotes, `` ` `` and `'` for single quotes, `--` and `---` for the
+//! en and em dash.
+
+use std::convert::Infallible;
+
+use crate::ast::{FontFace, Node, TextStyle, Visitor, walk};
+
+/// Replacements in the order they are tried, so that `---` is not read as
+/// `--` followed by `-`.
+const SUBSTITUTIONS: [(&str, &str); 6] = [
+    ("---", "\u{2014}"),
+    ("--", "\u{2013}"),
+    ("``", "\u{201c}"),
+    ("''", "\u{201d}"),
+    ("`", "\u{2018}"),
+    ("'", "\u{2019}"),
+];
+
+/// Replace the ASCII spellings throughout `ast`, except in math, where `'`
+/// is a prime, and in monospace text, which is shown as typed.
+pub(crate) fn smart_punctuation(ast: &Node) -> Node {
+    let Ok(node) = SmartPunctuation.visit(ast);
+    node
+}
+
+struct SmartPunctuation;
+
+impl Visitor for SmartPunctuation {
+    type Error = Infallible;
+
+    fn visit(&mut self, node: &Node) -> Result<Node, Infallible> {
+        match node {
+            Node::Math(_) => Ok(node.clone()),
+            _ => walk(self, node),
+        }
+    }
+
+    fn visit_text(&mut self, text: &str) -> Result<Node, Infallible> {
+        Ok(Node::Text(substitute(text)))
+    }
+
+    fn visit_styled_text(&mut self, text: &str, style: &TextStyle) -> Result<Node, Infallible> {
+        let text = match style.face {
+            FontFace::Monospace => text.to_string(),
+            _ => substitute(text),
+        };
+        Ok(Node::StyledText(text, style.clone()))
+    }
+}
+
+fn substitute(text: &str) -> String {
+    SUBSTITUTIONS
+        .iter()
+        .fold(text.to_string(), |text, (from, to)| text.replace(from, to))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(text: &str) -> String {
+        match smart_punctuation(&Node::text(text)) {
+            Node::Text(text) => text,
+            other => panic!("Expected text, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_quotes() {
+        assert_eq!(convert("``hello''"), "\u{201c}hello\u{201d}");
+        assert_eq!(convert("`hello'"), "\u{2018}hello\u{2019}");
+        assert_eq!(convert("don't"), "don\u{2019}t");
+    }
+
+    #[test]
+    fn test_dashes() {
+        assert_eq!(convert("1--2"), "1\u{2013}2");
+        assert_eq!(convert("yes---no"), "yes\u{2014}no");
+        assert_eq!(convert("a-b"), "a-b");
+    }
+
+    #[test]
+    fn test_math_and_monospace_are_left_alone() {
+        let math = Node::Math(vec![Node::text("f'")]);
+        assert_eq!(smart_punctuation(&math), math);
+        let code = Node::StyledText("--flag".into(), FontFace::Monospace.into());
+        assert_eq!(smart_punctuation(&code), code);
+    }
+}
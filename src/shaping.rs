@@ -0,0 +1,177 @@
+//! Kerning and ligatures, read from the fonts themselves.
+//!
+//! Kerning comes from the legacy `kern` table, which the bundled DejaVu
+//! faces carry alongside `GPOS`. Ligatures are set as the Unicode
+//! presentation forms U+FB01 and U+FB02, since the PDF backend looks glyphs
+//! up by character; a face that does not map them keeps the separate letters.
+
+use std::borrow::Cow;
+
+use ttf_parser::Face;
+
+use crate::ast::FontFace;
+use crate::backend::{FontCache, FontData, font_data};
+use crate::error::CompileError;
+use crate::options::CompileOptions;
+
+/// Letter pairs set as one glyph, and the character that glyph is mapped to.
+const LIGATURES: [(&str, char); 2] = [("fi", '\u{fb01}'), ("fl", '\u{fb02}')];
+
+/// The font files of the four faces, and which adjustments to make with them.
+#[derive(Clone)]
+pub(crate) struct Shaper {
+    /// Normal, bold, italic and monospace, in that order.
+    faces: [FontData; 4],
+    kerning: bool,
+    ligatures: bool,
+}
+
+impl Shaper {
+    /// A shaper for the fonts `opts` names, loaded through `cache`.
+    pub(crate) fn new(opts: &CompileOptions, cache: &FontCache) -> Result<Self, CompileError> {
+        let load = |face| font_data(&opts.fonts, &face, cache);
+        Ok(Shaper {
+            faces: [
+                load(FontFace::Normal)?,
+                load(FontFace::Bold)?,
+                load(FontFace::Italic)?,
+                load(FontFace::Monospace)?,
+            ],
+            kerning: opts.kerning,
+            ligatures: opts.ligatures,
+        })
+    }
+
+    /// The font file for `face`.
+    fn font(&self, face: &FontFace) -> &FontData {
+        let index = match face {
+            FontFace::Normal => 0,
+            FontFace::Bold => 1,
+            FontFace::Italic => 2,
+            FontFace::Monospace => 3,
+        };
+        &self.faces[index]
+    }
+
+    /// The kerning before each character of `text` that has any, in ems,
+    /// with the character's byte offset; empty when kerning is off.
+    pub(crate) fn kerns(&self, face: &FontFace, text: &str) -> Vec<(usize, f64)> {
+        if !self.kerning {
+            return Vec::new();
+        }
+        let font = self.font(face);
+        let metrics = font.metrics();
+        // Kerning by glyph class is only in the file; files are checked
+        // when they are loaded.
+        let face = match metrics.kern_classes() {
+            true => Face::parse(font.bytes(), 0).ok(),
+            false => None,
+        };
+        let em = f64::from(metrics.units_per_em());
+        let pair = |left: char, right: char| match &face {
+            Some(face) => {
+                let (left, right) = (face.glyph_index(left)?, face.glyph_index(right)?);
+                let value = face
+                    .tables()
+                    .kern?
+                    .subtables
+                    .into_iter()
+                    .filter(|s| s.horizontal && !s.variable && !s.has_cross_stream)
+                    .find_map(|s| s.glyphs_kerning(left, right))?;
+                Some(f64::from(value) / em)
+            }
+            None => metrics.kern(left, right),
+        };
+        text.char_indices()
+            .zip(text.chars().skip(1))
+            .filter_map(|((i, left), right)| {
+                let value = pair(left, right).filter(|&v| v != 0.0)?;
+                Some((i + left.len_utf8(), value))
+            })
+            .collect()
+    }
+
+    /// How much kerning narrows (or widens, if negative) `text`, in ems.
+    pub(crate) fn kerning(&self, face: &FontFace, text: &str) -> f64 {
+        self.kerns(face, text).iter().map(|&(_, k)| k).sum()
+    }
+
+    /// `text` split where kerning moves the next glyph, each piece with its
+    /// distance from the start of `text` in ems; a single piece at 0 when
+    /// nothing is kerned.
+    pub(crate) fn pieces<'t>(&self, face: &FontFace, text: &'t str) -> Vec<(f64, &'t str)> {
+        let kerns = self.kerns(face, text);
+        if kerns.is_empty() {
+            return vec![(0.0, text)];
+        }
+        let metrics = self.font(face).metrics();
+        let mut pieces = Vec::with_capacity(kerns.len() + 1);
+        let (mut start, mut offset) = (0, 0.0);
+        for (at, kern) in kerns {
+            let piece = &text[start..at];
+            pieces.push((offset, piece));
+            offset += metrics.advance(piece) + kern;
+            start = at;
+        }
+        pieces.push((offset, &text[start..]));
+        pieces
+    }
+
+    /// `text` with `fi` and `fl` set as ligatures, when they are on and the
+    /// font has a glyph for them.
+    pub(crate) fn ligate<'t>(&self, face: &FontFace, text: &'t str) -> Cow<'t, str> {
+        if !self.ligatures || !LIGATURES.iter().any(|(pair, _)| text.contains(pair)) {
+            return Cow::Borrowed(text);
+        }
+        let metrics = self.font(face).metrics();
+        let mut text = text.to_string();
+        for (pair, ligature) in LIGATURES {
+            if metrics.glyph(ligature).is_some() {
+                text = text.replace(pair, &ligature.to_string());
+            }
+        }
+        Cow::Owned(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shaper(kerning: bool, ligatures: bool) -> Shaper {
+        let opts = CompileOptions {
+            kerning,
+            ligatures,
+            ..CompileOptions::default()
+        };
+        Shaper::new(&opts, &FontCache::new()).unwrap()
+    }
+
+    #[test]
+    fn test_av_is_kerned() {
+        let on = shaper(true, false);
+        assert!(on.kerning(&FontFace::Normal, "AV") < 0.0);
+        assert_eq!(on.kerning(&FontFace::Normal, "xx"), 0.0);
+        let pieces = on.pieces(&FontFace::Normal, "AVAx");
+        assert_eq!(
+            pieces.iter().map(|&(_, p)| p).collect::<Vec<_>>(),
+            ["A", "V", "Ax"]
+        );
+        assert!(pieces.windows(2).all(|w| w[0].0 < w[1].0));
+
+        let off = shaper(false, false);
+        assert_eq!(off.kerning(&FontFace::Normal, "AV"), 0.0);
+        assert_eq!(off.pieces(&FontFace::Normal, "AV"), [(0.0, "AV")]);
+    }
+
+    #[test]
+    fn test_ligatures() {
+        let on = shaper(false, true);
+        assert_eq!(
+            on.ligate(&FontFace::Normal, "fine flow"),
+            "\u{fb01}ne \u{fb02}ow"
+        );
+        let off = shaper(false, false);
+        assert_eq!(off.ligate(&FontFace::Normal, "fine"), "fine");
+    }
+}
@@ -0,0 +1,234 @@
+//! Cutting a TrueType font down to the glyphs a document draws, before it
+//! is embedded.
+//!
+//! Glyphs keep their ids, so text is encoded as it would be for the whole
+//! font: the outlines of the glyphs not drawn are emptied, and the tables
+//! only layout reads, such as `GSUB` and `kern`, are left out.
+
+use std::collections::BTreeMap;
+
+/// The tables a PDF viewer may read to draw the glyphs of an embedded
+/// TrueType font.
+const KEPT_TABLES: [&[u8; 4]; 14] = [
+    b"OS/2", b"cmap", b"cvt ", b"fpgm", b"gasp", b"glyf", b"head", b"hhea", b"hmtx", b"loca",
+    b"maxp", b"name", b"post", b"prep",
+];
+
+/// Flags of a component of a composite glyph.
+const ARG_1_AND_2_ARE_WORDS: u16 = 0x0001;
+const WE_HAVE_A_SCALE: u16 = 0x0008;
+const MORE_COMPONENTS: u16 = 0x0020;
+const WE_HAVE_AN_X_AND_Y_SCALE: u16 = 0x0040;
+const WE_HAVE_A_TWO_BY_TWO: u16 = 0x0080;
+
+/// What the checksums of a font's tables and its `head.checkSumAdjustment`
+/// add up to.
+const CHECKSUM_MAGIC: u32 = 0xB1B0_AFBA;
+
+/// `font` with only the outlines of `.notdef`, of `glyphs` and of the
+/// glyphs they are composed of.
+///
+/// `None` unless `font` is a single TrueType font with its outlines in
+/// `glyf`; a collection or a font with CFF outlines is best embedded whole.
+pub(crate) fn subset(font: &[u8], glyphs: impl IntoIterator<Item = u16>) -> Option<Vec<u8>> {
+    let tables = tables(font)?;
+    let (head, loca, glyf) = (
+        tables.get(b"head")?,
+        tables.get(b"loca")?,
+        tables.get(b"glyf")?,
+    );
+    let long = read_u16(head, 50)? == 1;
+    let offset = |glyph: usize| match long {
+        true => read_u32(loca, glyph * 4).map(|at| at as usize),
+        false => read_u16(loca, glyph * 2).map(|at| usize::from(at) * 2),
+    };
+    let outline = |glyph: usize| glyf.get(offset(glyph)?..offset(glyph + 1)?);
+    let count = (loca.len() / if long { 4 } else { 2 }).checked_sub(1)?;
+
+    let mut keep = vec![false; count];
+    let mut pending: Vec<u16> = std::iter::once(0).chain(glyphs).collect();
+    while let Some(glyph) = pending.pop() {
+        match keep.get_mut(usize::from(glyph)) {
+            Some(kept) if !*kept => *kept = true,
+            _ => continue,
+        }
+        pending.extend(components(outline(usize::from(glyph))?));
+    }
+
+    let mut new_glyf = Vec::new();
+    let mut new_loca = Vec::with_capacity(loca.len());
+    for (glyph, &kept) in keep.iter().enumerate() {
+        push_offset(&mut new_loca, new_glyf.len(), long);
+        if kept {
+            new_glyf.extend_from_slice(outline(glyph)?);
+            new_glyf.resize(new_glyf.len().next_multiple_of(4), 0);
+        }
+    }
+    push_offset(&mut new_loca, new_glyf.len(), long);
+
+    let mut kept = BTreeMap::new();
+    for tag in KEPT_TABLES {
+        let Some(&table) = tables.get(tag) else {
+            continue;
+        };
+        let table = match tag {
+            b"glyf" => std::mem::take(&mut new_glyf),
+            b"loca" => std::mem::take(&mut new_loca),
+            // Version 3 of `post` names no glyphs.
+            b"post" => {
+                let mut post = table.get(..32)?.to_vec();
+                post[..4].copy_from_slice(&0x0003_0000u32.to_be_bytes());
+                post
+            }
+            _ => table.to_vec(),
+        };
+        kept.insert(*tag, table);
+    }
+    Some(write(font.get(..4)?, kept))
+}
+
+/// The tables of the TrueType font `font`, by tag.
+fn tables(font: &[u8]) -> Option<BTreeMap<[u8; 4], &[u8]>> {
+    if !matches!(read_u32(font, 0)?, 0x0001_0000 | 0x7472_7565) {
+        return None;
+    }
+    let mut tables = BTreeMap::new();
+    for i in 0..usize::from(read_u16(font, 4)?) {
+        let record = 12 + 16 * i;
+        let tag = font.get(record..record + 4)?.try_into().ok()?;
+        let start = read_u32(font, record + 8)? as usize;
+        let len = read_u32(font, record + 12)? as usize;
+        tables.insert(tag, font.get(start..start + len)?);
+    }
+    Some(tables)
+}
+
+/// The glyphs a composite glyph with the outline `glyph` is made of; none
+/// for a simple glyph.
+fn components(glyph: &[u8]) -> Vec<u16> {
+    let mut components = Vec::new();
+    if glyph.is_empty() || (read_u16(glyph, 0).unwrap_or(0) as i16) >= 0 {
+        return components;
+    }
+    let mut at = 10;
+    while let (Some(flags), Some(component)) = (read_u16(glyph, at), read_u16(glyph, at + 2)) {
+        components.push(component);
+        at += 4 + if flags & ARG_1_AND_2_ARE_WORDS != 0 {
+            4
+        } else {
+            2
+        };
+        at += if flags & WE_HAVE_A_TWO_BY_TWO != 0 {
+            8
+        } else if flags & WE_HAVE_AN_X_AND_Y_SCALE != 0 {
+            4
+        } else if flags & WE_HAVE_A_SCALE != 0 {
+            2
+        } else {
+            0
+        };
+        if flags & MORE_COMPONENTS == 0 {
+            break;
+        }
+    }
+    components
+}
+
+/// Append a `loca` entry for a glyph starting `at` bytes into `glyf`.
+fn push_offset(loca: &mut Vec<u8>, at: usize, long: bool) {
+    if long {
+        loca.extend_from_slice(&(at as u32).to_be_bytes());
+    } else {
+        loca.extend_from_slice(&((at / 2) as u16).to_be_bytes());
+    }
+}
+
+/// A font file of `tables`, whose sfnt version is `version`, with its
+/// checksums filled in.
+fn write(version: &[u8], tables: BTreeMap<[u8; 4], Vec<u8>>) -> Vec<u8> {
+    let count = tables.len() as u16;
+    let selector = count.max(1).ilog2() as u16;
+    let search_range = (1 << selector) * 16;
+    let mut font = version.to_vec();
+    for value in [count, search_range, selector, count * 16 - search_range] {
+        font.extend_from_slice(&value.to_be_bytes());
+    }
+
+    let mut offset = font.len() + 16 * tables.len();
+    let mut head = None;
+    for (tag, table) in &tables {
+        font.extend_from_slice(tag);
+        font.extend_from_slice(&checksum(table).to_be_bytes());
+        font.extend_from_slice(&(offset as u32).to_be_bytes());
+        font.extend_from_slice(&(table.len() as u32).to_be_bytes());
+        if tag == b"head" {
+            head = Some(offset);
+        }
+        offset += table.len().next_multiple_of(4);
+    }
+    for table in tables.values() {
+        font.extend_from_slice(table);
+        font.resize(font.len().next_multiple_of(4), 0);
+    }
+
+    // `head` holds what makes the whole file add up, worked out with zero there.
+    if let Some(head) = head.filter(|head| head + 12 <= font.len()) {
+        font[head + 8..head + 12].fill(0);
+        let adjustment = CHECKSUM_MAGIC.wrapping_sub(checksum(&font));
+        font[head + 8..head + 12].copy_from_slice(&adjustment.to_be_bytes());
+    }
+    font
+}
+
+/// The sum of `data` as big-endian 32-bit words, padded with zeros.
+fn checksum(data: &[u8]) -> u32 {
+    data.chunks(4).fold(0u32, |sum, word| {
+        let mut padded = [0; 4];
+        padded[..word.len()].copy_from_slice(word);
+        sum.wrapping_add(u32::from_be_bytes(padded))
+    })
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SANS: &[u8] = include_bytes!("../fonts/DejaVuSans.ttf");
+
+    #[test]
+    fn glyphs_not_asked_for_lose_their_outlines() {
+        let face = ttf_parser::Face::parse(SANS, 0).unwrap();
+        let glyph = |c| face.glyph_index(c).unwrap();
+        let id = |c| glyph(c).0;
+        let subset = subset(SANS, [id('H'), id('é')]).unwrap();
+        assert!(subset.len() * 10 < SANS.len());
+
+        let cut = ttf_parser::Face::parse(&subset, 0).unwrap();
+        assert_eq!(cut.number_of_glyphs(), face.number_of_glyphs());
+        assert_eq!(cut.glyph_index('x'), Some(glyph('x')));
+        assert!(cut.glyph_bounding_box(glyph('H')).is_some());
+        assert!(cut.glyph_bounding_box(glyph('x')).is_none());
+        // `é` is composed of `e` and an accent, which are kept with it.
+        assert_eq!(
+            cut.glyph_bounding_box(glyph('é')),
+            face.glyph_bounding_box(glyph('é'))
+        );
+        assert!(cut.glyph_bounding_box(glyph('e')).is_some());
+        assert!(cut.tables().gsub.is_none());
+        assert_eq!(checksum(&subset), CHECKSUM_MAGIC);
+    }
+
+    #[test]
+    fn only_truetype_fonts_are_subset() {
+        assert!(subset(b"OTTO\0\0", [1]).is_none());
+        assert!(subset(b"", [1]).is_none());
+    }
+}
@@ -0,0 +1,142 @@
+//! Tagged PDF: a structure tree marking the paragraphs and headings of a
+//! document, for screen readers and text extraction.
+//!
+//! Each text object is wrapped in marked content as it is drawn, and the
+//! elements it belongs to are written with its page; the tree above them
+//! follows the last page.
+
+use std::fmt::Write as _;
+use std::io;
+
+use crate::layout::Role;
+use crate::pdf::Writer;
+
+/// The structure tree of a document being written.
+pub(crate) struct Structure {
+    root: u32,
+    document: u32,
+    parent_tree: u32,
+    /// Every element written so far, in order.
+    elements: Vec<u32>,
+    /// The `/Nums` of the parent tree, for the pages written so far.
+    parents: String,
+    /// How many pages have been written.
+    pages: usize,
+    /// The elements of the page being drawn, with their role and the marked
+    /// content that makes them up.
+    page_elements: Vec<(u32, Role, Vec<usize>)>,
+    /// The element each piece of marked content on the page belongs to, in order.
+    page_parents: Vec<u32>,
+    /// The layout block the last element was started for.
+    block: Option<usize>,
+}
+
+impl Structure {
+    /// A tree with no elements yet, its objects numbered in `pdf`.
+    pub(crate) fn new(pdf: &mut Writer) -> Self {
+        Structure {
+            root: pdf.reserve(),
+            document: pdf.reserve(),
+            parent_tree: pdf.reserve(),
+            elements: Vec::new(),
+            parents: String::new(),
+            pages: 0,
+            page_elements: Vec::new(),
+            page_parents: Vec::new(),
+            block: None,
+        }
+    }
+
+    /// Open marked content in `content` for a text object set from layout
+    /// block `block` in `role`, numbered from 0 on every page, starting a new
+    /// element wherever the block changes. Running heads and feet are marked
+    /// as artifacts, outside the structure.
+    pub(crate) fn begin(
+        &mut self,
+        pdf: &mut Writer,
+        content: &mut String,
+        role: Role,
+        block: usize,
+    ) {
+        if role == Role::Running {
+            let _ = writeln!(content, "/{} <</Type/Pagination>> BDC", tag_name(role));
+            return;
+        }
+        if self.block != Some(block) {
+            self.block = Some(block);
+            self.page_elements.push((pdf.reserve(), role, Vec::new()));
+        }
+        let (id, _, mcids) = self.page_elements.last_mut().expect("pushed above");
+        let mcid = self.page_parents.len();
+        mcids.push(mcid);
+        self.page_parents.push(*id);
+        let _ = writeln!(content, "/{} <</MCID {}>> BDC", tag_name(role), mcid);
+    }
+
+    /// Close the marked content opened last.
+    pub(crate) fn end(content: &mut String) {
+        content.push_str("EMC\n");
+    }
+
+    /// Write the elements of the page just drawn, object `page`, which is
+    /// given `/StructParents` [`pages`](Structure::pages) before the call.
+    pub(crate) fn finish_page(&mut self, pdf: &mut Writer, page: u32) -> io::Result<()> {
+        let parents: Vec<String> = self
+            .page_parents
+            .drain(..)
+            .map(|id| format!("{} 0 R", id))
+            .collect();
+        let _ = write!(self.parents, "{}[{}]", self.pages, parents.join(" "));
+        for (id, role, mcids) in self.page_elements.drain(..) {
+            let mcids: Vec<String> = mcids.iter().map(usize::to_string).collect();
+            let element = format!(
+                "<</Type/StructElem/S/{}/P {} 0 R/Pg {} 0 R/K[{}]>>",
+                tag_name(role),
+                self.document,
+                page,
+                mcids.join(" ")
+            );
+            pdf.object(id, &element)?;
+            self.elements.push(id);
+        }
+        self.pages += 1;
+        self.block = None;
+        Ok(())
+    }
+
+    /// The key the page being drawn has in the parent tree.
+    pub(crate) fn pages(&self) -> usize {
+        self.pages
+    }
+
+    /// Write the tree above the elements, returning its root for the catalog.
+    pub(crate) fn finish(self, pdf: &mut Writer) -> io::Result<u32> {
+        let root = format!(
+            "<</Type/StructTreeRoot/K {} 0 R/ParentTree {} 0 R/ParentTreeNextKey {}>>",
+            self.document, self.parent_tree, self.pages
+        );
+        pdf.object(self.root, &root)?;
+        let elements: Vec<String> = self
+            .elements
+            .iter()
+            .map(|id| format!("{} 0 R", id))
+            .collect();
+        let document = format!(
+            "<</Type/StructElem/S/Document/P {} 0 R/K[{}]>>",
+            self.root,
+            elements.join(" ")
+        );
+        pdf.object(self.document, &document)?;
+        pdf.object(self.parent_tree, &format!("<</Nums[{}]>>", self.parents))?;
+        Ok(self.root)
+    }
+}
+
+/// The structure type of an element, also used as its marked-content tag.
+fn tag_name(role: Role) -> String {
+    match role {
+        Role::Paragraph => "P".into(),
+        Role::Heading(level) => format!("H{}", level),
+        Role::Running => "Artifact".into(),
+    }
+}
@@ -0,0 +1,237 @@
+//! The running text of a document, without its markup, for tools such as
+//! spell checkers that know nothing of TeX.
+
+use crate::ast::Node;
+
+/// What [`extract_text_with`](crate::extract_text_with) keeps besides the
+/// running text; by default, neither.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ExtractOptions {
+    /// The letters and digits of inline math, which a spell checker would
+    /// mostly flag.
+    pub math: bool,
+    /// The bodies of `verbatim` environments, as paragraphs of their own.
+    pub verbatim: bool,
+}
+
+/// How long a document is, counted in the text [`extract_text`] gives.
+///
+/// [`extract_text`]: crate::extract_text
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DocumentStats {
+    pub words: usize,
+    /// Letters, digits and punctuation; spaces are not counted.
+    pub characters: usize,
+    /// Paragraphs of running text, counting each heading, list item and
+    /// table row as one, as well as each footnote.
+    pub paragraphs: usize,
+}
+
+impl DocumentStats {
+    /// The counts for `text`, as [`plain_text`] gives it.
+    pub(crate) fn of(text: &str) -> Self {
+        DocumentStats {
+            words: text.split_whitespace().count(),
+            characters: text.chars().filter(|c| !c.is_whitespace()).count(),
+            paragraphs: text.split("\n\n").filter(|p| !p.is_empty()).count(),
+        }
+    }
+}
+
+impl std::fmt::Display for DocumentStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} words, {} characters, {} paragraphs",
+            self.words, self.characters, self.paragraphs
+        )
+    }
+}
+
+/// The text of the expanded `ast`: its words separated by single spaces,
+/// forced line breaks as newlines and paragraphs by blank lines.
+///
+/// Headings, list items, table rows and the parts of a title block are
+/// paragraphs of their own; footnotes follow the text, one paragraph each.
+pub(crate) fn plain_text(ast: &Node, opts: ExtractOptions) -> String {
+    let mut text = Text {
+        opts,
+        ..Text::default()
+    };
+    text.node(ast);
+    text.end_paragraph();
+    for note in std::mem::take(&mut text.footnotes) {
+        text.paragraph(&note);
+    }
+    text.paragraphs.join("\n\n")
+}
+
+#[derive(Default)]
+struct Text {
+    opts: ExtractOptions,
+    paragraphs: Vec<String>,
+    /// The paragraph being collected.
+    current: String,
+    /// The next word follows the last without a space, as after a tie.
+    tied: bool,
+    /// Footnote bodies met so far, in order.
+    footnotes: Vec<Vec<Node>>,
+}
+
+impl Text {
+    fn node(&mut self, node: &Node) {
+        match node {
+            Node::Text(text) | Node::StyledText(text, _) => self.words(text),
+            Node::Seq(children) => self.all(children),
+            Node::Macro { args, .. } => self.all(args),
+            Node::Environment { body, .. } => {
+                self.end_paragraph();
+                self.all(body);
+                self.end_paragraph();
+            }
+            Node::Heading { title, .. } => self.paragraph(title),
+            Node::TitleBlock {
+                title,
+                author,
+                date,
+            } => {
+                for part in [title, author, date] {
+                    self.paragraph(part);
+                }
+            }
+            Node::List { items, .. } => {
+                for item in items {
+                    let label = item.label.iter().flatten();
+                    self.paragraph(&label.chain(&item.body).cloned().collect::<Vec<_>>());
+                }
+            }
+            Node::Table { rows, .. } => {
+                for row in rows {
+                    self.end_paragraph();
+                    for cell in row {
+                        self.all(cell);
+                    }
+                    self.end_paragraph();
+                }
+            }
+            Node::ParBreak => self.end_paragraph(),
+            Node::LineBreak { .. } if !self.current.is_empty() => {
+                self.current.push('\n');
+                self.tied = true;
+            }
+            // A word broken at a discretionary hyphen is still one word.
+            Node::DiscretionaryHyphen => self.tied = true,
+            Node::NonBreakingSpace | Node::HSpace(_) => {
+                self.current.push(' ');
+                self.tied = true;
+            }
+            Node::Math(body) if self.opts.math => self.all(body),
+            Node::Frac { num, den } if self.opts.math => {
+                self.all(num);
+                self.current.push('/');
+                self.tied = true;
+                self.all(den);
+            }
+            Node::Superscript(arg) | Node::Subscript(arg) => {
+                self.tied = true;
+                self.node(arg);
+            }
+            Node::Verbatim(text) if self.opts.verbatim => {
+                self.end_paragraph();
+                self.current.push_str(text.trim_matches('\n'));
+                self.end_paragraph();
+            }
+            Node::Footnote(body) => self.footnotes.push(body.clone()),
+            _ => {}
+        }
+    }
+
+    fn all(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            self.node(node);
+        }
+    }
+
+    /// `nodes` as a paragraph of their own.
+    fn paragraph(&mut self, nodes: &[Node]) {
+        self.end_paragraph();
+        self.all(nodes);
+        self.end_paragraph();
+    }
+
+    fn words(&mut self, text: &str) {
+        for word in text.split_whitespace() {
+            if !self.tied && !self.current.is_empty() {
+                self.current.push(' ');
+            }
+            self.current.push_str(word);
+            self.tied = false;
+        }
+        // Space inside a run of text separates it from what follows, too.
+        if text.ends_with(char::is_whitespace) {
+            self.tied = false;
+        }
+    }
+
+    fn end_paragraph(&mut self) {
+        let paragraph = std::mem::take(&mut self.current);
+        let paragraph = paragraph.trim_end();
+        if !paragraph.is_empty() {
+            self.paragraphs.push(paragraph.to_string());
+        }
+        self.tied = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extract(src: &str, opts: ExtractOptions) -> String {
+        let ast = crate::parser::parse(src).unwrap();
+        plain_text(&crate::expand::expand_macros(&ast).unwrap(), opts)
+    }
+
+    #[test]
+    fn paragraphs_are_separated_by_blank_lines() {
+        let src = r"\section{Intro} Some text\footnote{A note.} here.
+
+            \begin{itemize} \item One \item Two \end{itemize}
+            Tied~words, hy\-phen and $x^2$ \\ broken.
+            \begin{verbatim}
+            code
+            \end{verbatim}";
+        assert_eq!(
+            extract(src, ExtractOptions::default()),
+            "Intro\n\nSome text here.\n\nOne\n\nTwo\n\nTied words, hyphen and\nbroken.\n\nA note."
+        );
+    }
+
+    #[test]
+    fn math_and_verbatim_are_kept_when_asked() {
+        let src = "Area $\\pi r^2$ here.\n\n\\begin{verbatim}\nlet x = 1;\n\\end{verbatim}";
+        let opts = ExtractOptions {
+            math: true,
+            verbatim: true,
+        };
+        assert_eq!(extract(src, opts), "Area r2 here.\n\nlet x = 1;");
+    }
+
+    #[test]
+    fn stats_count_the_extracted_text() {
+        let src = r"\section{Intro} Hello, \textbf{bold} world $x + y$ here.
+
+            Second paragraph\footnote{A note.} ends.";
+        let stats = DocumentStats::of(&extract(src, ExtractOptions::default()));
+        assert_eq!(
+            stats,
+            DocumentStats {
+                words: 10,
+                characters: 51,
+                paragraphs: 4,
+            }
+        );
+        assert_eq!(stats.to_string(), "10 words, 51 characters, 4 paragraphs");
+        assert_eq!(DocumentStats::of(""), DocumentStats::default());
+    }
+}
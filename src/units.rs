@@ -0,0 +1,113 @@
+//! Typographic lengths and the conversions between their units.
+
+/// Points per inch; a point is 1/72 in, as in PDF (TeX's `bp`).
+const PT_PER_IN: f64 = 72.0;
+
+/// Millimetres per inch.
+const MM_PER_IN: f64 = 25.4;
+
+use serde::{Deserialize, Deserializer, Serialize, de};
+use std::hash::{Hash, Hasher};
+
+/// A length, stored canonically in PostScript points.
+///
+/// Serializes as `{"pt": ...}`, and deserializes from a number and a unit
+/// as [`Length::parse`] reads them, e.g. `"12pt"`.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default, Serialize)]
+pub struct Length {
+    pt: f64,
+}
+
+impl<'de> Deserialize<'de> for Length {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let spec = String::deserialize(deserializer)?;
+        Length::parse(&spec).ok_or_else(|| de::Error::custom(format!("invalid length `{}`", spec)))
+    }
+}
+
+/// Hashes its points, with `-0.0` as `0.0`, so that equal lengths hash alike.
+impl Hash for Length {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.pt + 0.0).to_bits().hash(state);
+    }
+}
+
+impl Length {
+    pub const fn pt(pt: f64) -> Self {
+        Length { pt }
+    }
+
+    pub const fn mm(mm: f64) -> Self {
+        Length::in_(mm / MM_PER_IN)
+    }
+
+    pub const fn cm(cm: f64) -> Self {
+        Length::mm(cm * 10.0)
+    }
+
+    /// Inches; named `in_` because `in` is a keyword.
+    pub const fn in_(inches: f64) -> Self {
+        Length::pt(inches * PT_PER_IN)
+    }
+
+    pub const fn to_pt(self) -> f64 {
+        self.pt
+    }
+
+    pub const fn to_mm(self) -> f64 {
+        self.pt / PT_PER_IN * MM_PER_IN
+    }
+
+    /// Parse a length written as a number and a unit, e.g. `3mm`, `-12pt`
+    /// or `1.5 cm`. Accepts the units `pt`, `mm`, `cm` and `in`.
+    pub fn parse(spec: &str) -> Option<Self> {
+        let spec = spec.trim();
+        let (number, unit) = spec.split_at(spec.find(|c: char| c.is_ascii_alphabetic())?);
+        let value: f64 = number.trim().parse().ok()?;
+        let length = match unit {
+            "pt" => Length::pt(value),
+            "mm" => Length::mm(value),
+            "cm" => Length::cm(value),
+            "in" => Length::in_(value),
+            _ => return None,
+        };
+        Some(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn test_round_trips() {
+        for v in [0.0, 1.0, 10.0, 210.0, -3.5] {
+            assert!(close(Length::mm(v).to_mm(), v));
+            assert!(close(Length::pt(v).to_pt(), v));
+            assert!(close(Length::pt(Length::mm(v).to_pt()).to_mm(), v));
+        }
+    }
+
+    #[test]
+    fn test_unit_relations() {
+        assert!(close(Length::in_(1.0).to_pt(), 72.0));
+        assert!(close(Length::in_(1.0).to_mm(), 25.4));
+        assert!(close(Length::cm(2.54).to_pt(), 72.0));
+        assert!(close(Length::mm(10.0).to_pt(), 28.346456692913385));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Length::parse("12pt"), Some(Length::pt(12.0)));
+        assert_eq!(Length::parse("-3mm"), Some(Length::mm(-3.0)));
+        assert_eq!(Length::parse(" 1.5 cm "), Some(Length::cm(1.5)));
+        assert_eq!(Length::parse("2in"), Some(Length::in_(2.0)));
+        assert_eq!(Length::parse("3furlongs"), None);
+        assert_eq!(Length::parse("mm"), None);
+        assert_eq!(Length::parse("12"), None);
+    }
+}
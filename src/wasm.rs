@@ -0,0 +1,55 @@
+//! The compiler as a JavaScript function, for the browser, through
+//! wasm-bindgen. Built with the `wasm` feature:
+//!
+//! ```text
+//! cargo build --lib --release --target wasm32-unknown-unknown --features wasm
+//! wasm-bindgen --target web target/wasm32-unknown-unknown/release/latex_rs.wasm --out-dir pkg
+//! ```
+
+use std::time::SystemTime;
+
+use wasm_bindgen::prelude::*;
+
+use crate::{CompileOptions, compile_with};
+
+/// Compile a TeX-like input string into the bytes of a PDF, a `Uint8Array`
+/// to JavaScript, or throw what went wrong as a string.
+///
+/// The document is typeset in the bundled fonts, which are built into the
+/// module, and nothing is read from disk: there is none in the browser, so
+/// an `\input` fails. `\today` is the date the browser's clock gives.
+#[wasm_bindgen]
+pub fn compile_wasm(input: &str) -> Result<Vec<u8>, JsValue> {
+    let opts = CompileOptions {
+        now: Some(now()),
+        ..CompileOptions::default()
+    };
+    compile_with(input, &opts).map_err(|err| JsValue::from_str(&err.to_string()))
+}
+
+/// The time now, from JavaScript's clock on wasm32-unknown-unknown, where
+/// [`SystemTime::now`] has none to read and panics.
+#[cfg(target_arch = "wasm32")]
+fn now() -> SystemTime {
+    let millis = js_sys::Date::now();
+    SystemTime::UNIX_EPOCH + std::time::Duration::from_secs_f64(millis / 1000.0)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn now() -> SystemTime {
+    SystemTime::now()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Throwing builds a JavaScript value, which only a wasm32 build can, so
+    // only documents that compile are tried here.
+    #[test]
+    fn test_compiles_to_pdf_bytes() {
+        let pdf = compile_wasm(r"\section{Browser} Typeset \textbf{here}, \today.").unwrap();
+        assert!(pdf.starts_with(b"%PDF-"));
+        assert!(pdf.windows(14).any(|w| w == b"DejaVuSans-Bol"));
+    }
+}
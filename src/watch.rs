@@ -0,0 +1,111 @@
+//! `--watch`: recompile whenever the input file changes.
+
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::error::Error;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Events closer together than this, as one save often produces, trigger a
+/// single rebuild.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Run `rebuild` now and again after every change to `input`, until the
+/// watcher fails. A failed rebuild is reported and watching carries on.
+pub fn watch<F>(input: &Path, mut rebuild: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut() -> Result<String, Box<dyn Error>>,
+{
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    // Watch the directory rather than the file: many editors save by
+    // writing a new file and renaming it over the old one.
+    let dir = match input.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    println!("Watching {} for changes", input.display());
+
+    report(rebuild());
+    loop {
+        let event = rx.recv()?;
+        if !touches(&event, input.file_name()) {
+            if let Err(e) = event {
+                eprintln!("[{}] Watch error: {}", timestamp(), e);
+            }
+            continue;
+        }
+        // Let the burst of events from one save settle before rebuilding.
+        while rx.recv_timeout(DEBOUNCE).is_ok() {}
+        report(rebuild());
+    }
+}
+
+/// Whether `event` changed the file called `name`.
+fn touches(event: &notify::Result<Event>, name: Option<&OsStr>) -> bool {
+    let Ok(event) = event else {
+        return false;
+    };
+    matches!(
+        event.kind,
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Any
+    ) && event.paths.iter().any(|path| path.file_name() == name)
+}
+
+/// Print the outcome of one rebuild, stamped with the time of day.
+fn report(outcome: Result<String, Box<dyn Error>>) {
+    match outcome {
+        Ok(message) => println!("[{}] {}", timestamp(), message),
+        Err(e) => eprintln!("[{}] {}", timestamp(), e),
+    }
+}
+
+/// The current UTC time of day as `HH:MM:SS`.
+fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    clock(secs)
+}
+
+/// Format seconds since the Unix epoch as a UTC time of day.
+fn clock(secs: u64) -> String {
+    let day = secs % 86_400;
+    format!("{:02}:{:02}:{:02}", day / 3600, day % 3600 / 60, day % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, ModifyKind, RemoveKind};
+
+    #[test]
+    fn test_clock() {
+        assert_eq!(clock(0), "00:00:00");
+        assert_eq!(clock(86_400 + 13 * 3600 + 5 * 60 + 9), "13:05:09");
+    }
+
+    #[test]
+    fn test_only_changes_to_the_input_count() {
+        let name = Some(OsStr::new("doc.tex"));
+        let event = |kind, path: &str| Ok(Event::new(kind).add_path(path.into()));
+        assert!(touches(
+            &event(EventKind::Modify(ModifyKind::Any), "dir/doc.tex"),
+            name
+        ));
+        assert!(touches(
+            &event(EventKind::Create(CreateKind::File), "doc.tex"),
+            name
+        ));
+        assert!(!touches(
+            &event(EventKind::Modify(ModifyKind::Any), "dir/doc.pdf"),
+            name
+        ));
+        assert!(!touches(
+            &event(EventKind::Remove(RemoveKind::File), "doc.tex"),
+            name
+        ));
+    }
+}